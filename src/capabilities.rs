@@ -0,0 +1,383 @@
+//! Capabilities advertised by clients and servers to negotiate which protocol
+//! features can be used.
+//!
+//! <https://git-scm.com/docs/protocol-capabilities>
+
+use std::fmt::{Display, Formatter};
+
+use bytes::BytesMut;
+
+use crate::{Error, PktLine};
+
+/// A set of capabilities, each either a bare `name` or a `name=value` pair.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CapabilitySet(Vec<String>);
+
+impl CapabilitySet {
+    /// Parses a space separated list of capabilities, as sent after the first ref
+    /// in a v1 ref advertisement or after the first `want` in a v1 fetch request.
+    ///
+    /// Capabilities are always ASCII, so any invalid UTF-8 is replaced rather than
+    /// rejected.
+    #[must_use]
+    pub fn parse(input: &[u8]) -> Self {
+        String::from_utf8_lossy(input).split_whitespace().collect()
+    }
+
+    /// Adds a capability to the set, in the form `name` or `name=value`.
+    pub fn insert(&mut self, capability: impl Into<String>) {
+        self.0.push(capability.into());
+    }
+
+    /// Adds a `symref=<name>:<target>` capability, telling a v1 client that the ref
+    /// `name` is a symbolic ref pointing at `target`. Servers should send this for
+    /// `HEAD` so clones check out the default branch rather than detaching `HEAD`.
+    pub fn insert_symref(&mut self, name: &str, target: &str) {
+        self.insert(format!("symref={name}:{target}"));
+    }
+
+    /// Returns true if the set contains the capability `name`, with or without a value.
+    #[must_use]
+    pub fn contains(&self, name: &str) -> bool {
+        self.iter().any(|(n, _)| n == name)
+    }
+
+    /// Returns the value of the first `name=value` capability with the given `name`.
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.iter().find(|(n, _)| *n == name).and_then(|(_, v)| v)
+    }
+
+    /// Iterates over every capability in the set as a `(name, value)` pair.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Option<&str>)> {
+        self.0
+            .iter()
+            .map(|capability| match capability.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (capability.as_str(), None),
+            })
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl<S: Into<String>> FromIterator<S> for CapabilitySet {
+    fn from_iter<T: IntoIterator<Item = S>>(iter: T) -> Self {
+        Self(iter.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<S: Into<String>> Extend<S> for CapabilitySet {
+    fn extend<T: IntoIterator<Item = S>>(&mut self, iter: T) {
+        self.0.extend(iter.into_iter().map(Into::into));
+    }
+}
+
+impl Display for CapabilitySet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, capability) in self.0.iter().enumerate() {
+            if i != 0 {
+                f.write_str(" ")?;
+            }
+
+            f.write_str(capability)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The capability advertisement a protocol v2 server starts with: `version 2`, a
+/// line for each capability, then a flush.
+///
+/// Capabilities are written in the order `git upload-pack` writes them: `agent`,
+/// `ls-refs`, `fetch`, `server-option`, `object-format`, `session-id`, then any added
+/// with [`CapabilityAdvertisement::with_capability`] in the order they were added.
+///
+/// <https://git-scm.com/docs/protocol-v2#_capability_advertisement>
+#[derive(Debug, Default, Clone)]
+pub struct CapabilityAdvertisement {
+    agent: Option<String>,
+    /// The features of `ls-refs`, such as `unborn`, or `None` if it isn't supported.
+    ls_refs: Option<Vec<String>>,
+    /// The features of `fetch`, such as `shallow`, or `None` if it isn't supported.
+    fetch: Option<Vec<String>>,
+    server_option: bool,
+    object_format: Option<String>,
+    session_id: Option<String>,
+    other: Vec<(String, Option<String>)>,
+}
+
+impl CapabilityAdvertisement {
+    /// Advertises the server's `agent`, such as `packfile/0.1`.
+    #[must_use]
+    pub fn with_agent(mut self, agent: impl Into<String>) -> Self {
+        self.agent = Some(agent.into());
+        self
+    }
+
+    /// Advertises the `ls-refs` command, along with any of its `features`.
+    #[must_use]
+    pub fn with_ls_refs<S: Into<String>>(mut self, features: impl IntoIterator<Item = S>) -> Self {
+        self.ls_refs = Some(features.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Advertises the `fetch` command, along with any of its `features`, such as
+    /// `shallow` or `wait-for-done`.
+    #[must_use]
+    pub fn with_fetch<S: Into<String>>(mut self, features: impl IntoIterator<Item = S>) -> Self {
+        self.fetch = Some(features.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Advertises `server-option`, allowing clients to send server-specific options
+    /// with their commands.
+    #[must_use]
+    pub fn with_server_option(mut self) -> Self {
+        self.server_option = true;
+        self
+    }
+
+    /// Advertises the hash algorithm the repository uses, such as `sha1`. Clients
+    /// assume `sha1` if this isn't advertised.
+    #[must_use]
+    pub fn with_object_format(mut self, object_format: impl Into<String>) -> Self {
+        self.object_format = Some(object_format.into());
+        self
+    }
+
+    /// Advertises an identifier for this session, which clients may log to help
+    /// correlate requests.
+    #[must_use]
+    pub fn with_session_id(mut self, session_id: impl Into<String>) -> Self {
+        self.session_id = Some(session_id.into());
+        self
+    }
+
+    /// Advertises any other capability, as `key` or `key=value`.
+    #[must_use]
+    pub fn with_capability(mut self, key: impl Into<String>, value: Option<String>) -> Self {
+        self.other.push((key.into(), value));
+        self
+    }
+
+    /// Writes the advertisement out to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the advertisement fails to serialise.
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        let features = |name: &str, features: &[String]| {
+            if features.is_empty() {
+                name.to_string()
+            } else {
+                format!("{name}={}", features.join(" "))
+            }
+        };
+
+        let lines = [
+            Some("version 2".to_string()),
+            self.agent.as_ref().map(|agent| format!("agent={agent}")),
+            self.ls_refs.as_deref().map(|f| features("ls-refs", f)),
+            self.fetch.as_deref().map(|f| features("fetch", f)),
+            self.server_option.then(|| "server-option".to_string()),
+            self.object_format
+                .as_ref()
+                .map(|format| format!("object-format={format}")),
+            self.session_id
+                .as_ref()
+                .map(|id| format!("session-id={id}")),
+        ];
+
+        for line in lines.into_iter().flatten() {
+            PktLine::text(&line).encode_to(buf)?;
+        }
+
+        for (key, value) in &self.other {
+            match value {
+                Some(value) => PktLine::text(&format!("{key}={value}")).encode_to(buf)?,
+                None => PktLine::text(key).encode_to(buf)?,
+            };
+        }
+
+        PktLine::Flush.encode_to(buf)?;
+
+        Ok(())
+    }
+}
+
+/// The side-band capability negotiated with a client, which decides how large each
+/// packet multiplexing the packfile with progress messages can be.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Sideband {
+    /// `side-band`, where packets are at most 1000 bytes.
+    SideBand,
+    /// `side-band-64k`, where packets are at most 65520 bytes. Protocol v2 always
+    /// uses this for the packfile section of a fetch response.
+    SideBand64k,
+}
+
+impl Sideband {
+    /// Picks the side-band capability to use from those a client sent, preferring
+    /// `side-band-64k` when both are present. Returns `None` if the client supports
+    /// neither, in which case the packfile must be sent without any framing.
+    #[must_use]
+    pub fn from_capabilities(caps: &CapabilitySet) -> Option<Self> {
+        if caps.contains("side-band-64k") {
+            Some(Self::SideBand64k)
+        } else if caps.contains("side-band") {
+            Some(Self::SideBand)
+        } else {
+            None
+        }
+    }
+
+    /// The maximum length of a packet, including its 4 byte length prefix.
+    #[must_use]
+    pub const fn max_packet_len(self) -> usize {
+        match self {
+            Self::SideBand => 1000,
+            Self::SideBand64k => 65520,
+        }
+    }
+
+    /// The maximum amount of data a single packet can carry, once the length prefix
+    /// and the channel byte are taken off. This is the `chunk_size` to pass to
+    /// [`crate::PktLine::sideband_chunks`].
+    #[must_use]
+    pub const fn chunk_size(self) -> usize {
+        self.max_packet_len() - 5
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::capabilities::{CapabilitySet, Sideband};
+
+    #[test]
+    fn parse() {
+        let caps = CapabilitySet::parse(b"multi_ack side-band-64k agent=git/2.39.5 ofs-delta");
+
+        assert!(caps.contains("side-band-64k"));
+        assert!(caps.contains("agent"));
+        assert!(!caps.contains("side-band"));
+        assert_eq!(caps.get("agent"), Some("git/2.39.5"));
+        assert_eq!(caps.get("ofs-delta"), None);
+        assert_eq!(
+            caps.to_string(),
+            "multi_ack side-band-64k agent=git/2.39.5 ofs-delta"
+        );
+    }
+
+    #[test]
+    fn insert_symref() {
+        let mut caps = CapabilitySet::parse(b"side-band-64k");
+        caps.insert_symref("HEAD", "refs/heads/main");
+
+        assert_eq!(caps.get("symref"), Some("HEAD:refs/heads/main"));
+        assert_eq!(
+            caps.to_string(),
+            "side-band-64k symref=HEAD:refs/heads/main"
+        );
+    }
+
+    #[test]
+    fn capability_advertisement() {
+        use super::CapabilityAdvertisement;
+        use crate::PktLine;
+        use bytes::BytesMut;
+
+        let advertisement = CapabilityAdvertisement::default()
+            .with_capability("x-custom", Some("1".to_string()))
+            .with_session_id("abc")
+            .with_object_format("sha1")
+            .with_server_option()
+            .with_fetch(["shallow", "wait-for-done"])
+            .with_ls_refs(["unborn"])
+            .with_agent("packfile/0.1");
+
+        let mut buf = BytesMut::new();
+        advertisement.encode_to(&mut buf).unwrap();
+        assert_eq!(
+            buf.as_ref(),
+            b"000eversion 2\n\
+              0017agent=packfile/0.1\n\
+              0013ls-refs=unborn\n\
+              0020fetch=shallow wait-for-done\n\
+              0012server-option\n\
+              0017object-format=sha1\n\
+              0013session-id=abc\n\
+              000fx-custom=1\n\
+              0000"
+        );
+
+        // a server answering the client's ls-refs without waiting to read it, as it
+        // only ever has one answer
+        PktLine::ls_refs_line([1; 20], "HEAD", Some("refs/heads/main"))
+            .encode_to(&mut buf)
+            .unwrap();
+        PktLine::ls_refs_line([1; 20], "refs/heads/main", None)
+            .encode_to(&mut buf)
+            .unwrap();
+        PktLine::Flush.encode_to(&mut buf).unwrap();
+
+        let server = crate::test::ScriptServer::new(&buf);
+        let out = server
+            .git(2, "ls-remote")
+            .arg("--symref")
+            .arg(server.url())
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+        assert!(out.status.success(), "{stderr}");
+
+        let main = "01".repeat(20);
+        assert_eq!(
+            String::from_utf8(out.stdout).unwrap(),
+            format!("ref: refs/heads/main\tHEAD\n{main}\tHEAD\n{main}\trefs/heads/main\n")
+        );
+    }
+
+    #[test]
+    fn sideband_from_capabilities() {
+        let sideband = |caps: &[u8]| Sideband::from_capabilities(&CapabilitySet::parse(caps));
+
+        assert_eq!(sideband(b"side-band ofs-delta"), Some(Sideband::SideBand));
+        assert_eq!(sideband(b"side-band-64k"), Some(Sideband::SideBand64k));
+        assert_eq!(
+            sideband(b"side-band side-band-64k"),
+            Some(Sideband::SideBand64k)
+        );
+        assert_eq!(sideband(b"ofs-delta"), None);
+
+        assert_eq!(Sideband::SideBand.chunk_size(), 995);
+        assert_eq!(Sideband::SideBand64k.chunk_size(), 65515);
+    }
+
+    #[test]
+    fn sideband_chunks_fit_packet_len() {
+        use bytes::{Bytes, BytesMut};
+
+        let data = Bytes::from(vec![b'a'; 200_000]);
+
+        for sideband in [Sideband::SideBand, Sideband::SideBand64k] {
+            let mut reassembled = Vec::new();
+
+            for line in crate::PktLine::sideband_chunks(&data, sideband.chunk_size()) {
+                let mut buf = BytesMut::new();
+                line.encode_to(&mut buf).unwrap();
+
+                let len = usize::from_str_radix(std::str::from_utf8(&buf[..4]).unwrap(), 16);
+                assert_eq!(len.unwrap(), buf.len());
+                assert!(buf.len() <= sideband.max_packet_len());
+                reassembled.extend_from_slice(&buf[5..]);
+            }
+
+            assert_eq!(reassembled, data);
+        }
+    }
+}
@@ -4,14 +4,17 @@
 
 #![allow(clippy::module_name_repetitions)]
 
-use std::ops::RangeInclusive;
-
 use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
 use tokio_util::codec;
 
-use crate::{packet_line::PktLine, Error};
-
-const ALLOWED_PACKET_LENGTH: RangeInclusive<usize> = 4..=65520;
+use crate::{
+    capabilities::{CapabilitySet, Sideband},
+    low_level::{HashOutput, IncrementalPackEncoder, PackFile},
+    packet_line::{encode_sideband, Parsed, PktLine, PktLineOwned, MAX_DATA_LEN},
+    push::RefUpdateReport,
+    Error, SIDEBAND_DATA, SIDEBAND_ERROR, SIDEBAND_PROGRESS,
+};
 
 pub struct Encoder;
 
@@ -24,15 +27,130 @@ impl codec::Encoder<PktLine<'_>> for Encoder {
     }
 }
 
+impl codec::Encoder<PktLineOwned> for Encoder {
+    type Error = Error;
+
+    fn encode(&mut self, item: PktLineOwned, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encode_to(dst)?;
+        Ok(())
+    }
+}
+
+impl codec::Encoder<RefUpdateReport> for Encoder {
+    type Error = Error;
+
+    fn encode(&mut self, item: RefUpdateReport, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        item.encode_to(dst)
+    }
+}
+
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct GitCommand {
     pub command: Bytes,
     pub metadata: Vec<Bytes>,
 }
 
-#[derive(Default)]
+impl GitCommand {
+    /// Splits `command` at its first NUL, returning the part before it and the
+    /// capabilities after it, or `None` if there's no NUL.
+    ///
+    /// This is the format of the first line of a protocol v0/v1 ref advertisement,
+    /// `<hash> <refname>\0<capabilities>`, including the `capabilities^{}` line sent
+    /// in place of a ref by a repository without any.
+    #[must_use]
+    pub fn split_capabilities(&self) -> (Bytes, Option<CapabilitySet>) {
+        match self.command.iter().position(|&c| c == b'\0') {
+            Some(nul) => (
+                self.command.slice(..nul),
+                Some(CapabilitySet::parse(&self.command[nul + 1..])),
+            ),
+            None => (self.command.clone(), None),
+        }
+    }
+
+    /// Returns the value of the first metadata line for `key`, or `None` if there
+    /// isn't one. See [`GitCommand::metadata_values`].
+    #[must_use]
+    pub fn metadata_value<'a>(&'a self, key: &'a str) -> Option<&'a [u8]> {
+        self.metadata_values(key).next()
+    }
+
+    /// Returns the value of every metadata line for `key`, for arguments that can be
+    /// sent more than once such as `want`. Lines are matched as either `<key> <value>`
+    /// arguments, or `<key>=<value>` capabilities such as `agent`.
+    pub fn metadata_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.metadata.iter().filter_map(move |line| {
+            match line.strip_prefix(key.as_bytes())?.split_first() {
+                Some((b' ' | b'=', value)) => Some(value),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// The number of metadata lines a [`GitCodec`] accepts in a single command by
+/// default.
+pub const DEFAULT_MAX_METADATA_LINES: usize = 1024;
+
+/// A suggested limit for [`GitCodec::with_max_command_bytes`], which comfortably fits
+/// any command Git sends.
+pub const DEFAULT_MAX_COMMAND_BYTES: usize = 1024;
+
+/// A suggested limit for [`GitCodec::with_max_metadata_bytes`], which comfortably
+/// fits any argument Git sends, including the capabilities after the first `want`.
+pub const DEFAULT_MAX_METADATA_BYTES: usize = 4096;
+
 pub struct GitCodec {
     command: GitCommand,
+    /// The most metadata lines a command can have before it's rejected, this stops a
+    /// client from using up all of the server's memory with a single command.
+    max_metadata_lines: usize,
+    /// The longest the command line of a command can be, if limited.
+    max_command_bytes: Option<usize>,
+    /// The longest each metadata line of a command can be, if limited.
+    max_metadata_bytes: Option<usize>,
+}
+
+impl GitCodec {
+    /// Sets the most metadata lines a single command can have, beyond which `decode`
+    /// returns [`Error::TooManyMetadataLines`]. Defaults to
+    /// [`DEFAULT_MAX_METADATA_LINES`].
+    #[must_use]
+    pub fn with_max_metadata_lines(mut self, max: usize) -> Self {
+        self.max_metadata_lines = max;
+        self
+    }
+
+    /// Limits the command line of each command to `max` bytes, not including the
+    /// length prefix, beyond which `decode` returns [`Error::CommandTooLarge`]. By
+    /// default any length the pkt-line format allows is accepted, see
+    /// [`DEFAULT_MAX_COMMAND_BYTES`] for a suggested limit.
+    #[must_use]
+    pub fn with_max_command_bytes(mut self, max: usize) -> Self {
+        self.max_command_bytes = Some(max);
+        self
+    }
+
+    /// Limits each metadata line of a command to `max` bytes, not including the
+    /// length prefix, beyond which `decode` returns [`Error::CommandTooLarge`]. By
+    /// default any length the pkt-line format allows is accepted, see
+    /// [`DEFAULT_MAX_METADATA_BYTES`] for a suggested limit.
+    #[must_use]
+    pub fn with_max_metadata_bytes(mut self, max: usize) -> Self {
+        self.max_metadata_bytes = Some(max);
+        self
+    }
+}
+
+impl Default for GitCodec {
+    fn default() -> Self {
+        Self {
+            command: GitCommand::default(),
+            max_metadata_lines: DEFAULT_MAX_METADATA_LINES,
+            max_command_bytes: None,
+            max_metadata_bytes: None,
+        }
+    }
 }
 
 impl codec::Decoder for GitCodec {
@@ -42,41 +160,42 @@ impl codec::Decoder for GitCodec {
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, src), err))]
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
         loop {
-            if src.len() < 4 {
-                return Ok(None);
-            }
-
-            let mut length_bytes = [0_u8; 4];
-            length_bytes.copy_from_slice(&src[..4]);
-            let length = u16::from_str_radix(
-                std::str::from_utf8(&length_bytes).map_err(Error::ParseLengthBytes)?,
-                16,
-            )
-            .map_err(Error::ParseLengthAsHex)? as usize;
-
-            if length == 0 {
-                // flush
-                src.advance(4);
-                return Ok(Some(std::mem::take(&mut self.command)));
-            } else if length == 1 || length == 2 {
-                src.advance(4);
-                continue;
-            } else if !ALLOWED_PACKET_LENGTH.contains(&length) {
-                return Err(Error::PacketLengthExceedsSpec(
-                    ALLOWED_PACKET_LENGTH,
-                    length,
-                ));
-            }
+            let (data_len, consumed) = match PktLine::parse(src)? {
+                (Parsed::Incomplete { needed }, _) => {
+                    // not enough bytes in the buffer yet, ask for more
+                    src.reserve(needed);
+                    return Ok(None);
+                }
+                (Parsed::Flush, consumed) => {
+                    src.advance(consumed);
+                    return Ok(Some(std::mem::take(&mut self.command)));
+                }
+                (Parsed::Delimiter | Parsed::ResponseEnd, consumed) => {
+                    src.advance(consumed);
+                    continue;
+                }
+                (Parsed::Data(data), consumed) => (data.len(), consumed),
+            };
 
-            // not enough bytes in the buffer yet, ask for more
-            if src.len() < length {
-                src.reserve(length - src.len());
-                return Ok(None);
+            let max_bytes = if self.command.command.is_empty() {
+                self.max_command_bytes
+            } else {
+                self.max_metadata_bytes
+            };
+            if let Some(max) = max_bytes.filter(|&max| data_len > max) {
+                // skip the packet so a caller that carries on decoding doesn't see
+                // the same error again
+                src.advance(consumed);
+                self.command = GitCommand::default();
+                return Err(Error::CommandTooLarge {
+                    size: data_len,
+                    max,
+                });
             }
 
-            // length is inclusive of the 4 bytes that makes up itself
-            let mut data = src.split_to(length).freeze();
-            data.advance(4);
+            // the data runs up until the end of the packet
+            let mut data = src.split_to(consumed).freeze();
+            data.advance(consumed - data_len);
 
             // strip newlines for conformity
             if data.ends_with(b"\n") {
@@ -85,6 +204,9 @@ impl codec::Decoder for GitCodec {
 
             if self.command.command.is_empty() {
                 self.command.command = data;
+            } else if self.command.metadata.len() >= self.max_metadata_lines {
+                self.command = GitCommand::default();
+                return Err(Error::TooManyMetadataLines(self.max_metadata_lines));
             } else {
                 self.command.metadata.push(data);
             }
@@ -92,9 +214,234 @@ impl codec::Decoder for GitCodec {
     }
 }
 
+/// Decodes the [`RefUpdateReport`] a server sends in response to a push.
+///
+/// By default a report can have up to [`DEFAULT_MAX_METADATA_LINES`] ref updates,
+/// each at most [`DEFAULT_MAX_METADATA_BYTES`] long, which callers pushing more refs
+/// than that can raise.
+pub struct RefUpdateReportDecoder {
+    codec: GitCodec,
+}
+
+impl RefUpdateReportDecoder {
+    /// Sets the most ref updates a single report can have, beyond which `decode`
+    /// returns [`Error::TooManyMetadataLines`]. The report has a line for every ref
+    /// pushed, so this should be at least the number of refs in the push.
+    #[must_use]
+    pub fn with_max_updates(mut self, max: usize) -> Self {
+        self.codec = self.codec.with_max_metadata_lines(max);
+        self
+    }
+
+    /// Limits each line of the report to `max` bytes, not including the length
+    /// prefix, beyond which `decode` returns [`Error::CommandTooLarge`].
+    #[must_use]
+    pub fn with_max_line_bytes(mut self, max: usize) -> Self {
+        self.codec = self
+            .codec
+            .with_max_command_bytes(max)
+            .with_max_metadata_bytes(max);
+        self
+    }
+}
+
+impl Default for RefUpdateReportDecoder {
+    fn default() -> Self {
+        Self {
+            codec: GitCodec::default()
+                .with_max_command_bytes(DEFAULT_MAX_METADATA_BYTES)
+                .with_max_metadata_bytes(DEFAULT_MAX_METADATA_BYTES),
+        }
+    }
+}
+
+impl codec::Decoder for RefUpdateReportDecoder {
+    type Item = RefUpdateReport;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let Some(command) = self.codec.decode(src)? else {
+            return Ok(None);
+        };
+
+        RefUpdateReport::parse(
+            std::iter::once(&command.command)
+                .chain(&command.metadata)
+                .map(AsRef::as_ref),
+        )
+        .map(Some)
+    }
+}
+
+/// Writes pkt-lines straight to an [`AsyncWrite`], for servers that aren't using a
+/// [`tokio_util::codec::Framed`].
+///
+/// Each packet is written out before the next is encoded, so only a single packet is
+/// buffered at a time and a slow client applies backpressure to the writer. Call
+/// [`AsyncPktLineWriter::flush`] once finished to flush the inner writer.
+pub struct AsyncPktLineWriter<W> {
+    inner: W,
+    sideband: Sideband,
+    /// The packet currently being written.
+    buf: BytesMut,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncPktLineWriter<W> {
+    /// Wraps `inner`, writing side-band packets as large as `side-band-64k` allows.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            sideband: Sideband::SideBand64k,
+            buf: BytesMut::new(),
+        }
+    }
+
+    /// Sets the side-band capability the client negotiated, which decides how large
+    /// side-band packets can be. Defaults to [`Sideband::SideBand64k`].
+    #[must_use]
+    pub fn with_sideband(mut self, sideband: Sideband) -> Self {
+        self.sideband = sideband;
+        self
+    }
+
+    /// Writes `data` as plain data packets, split into as many as are required.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        for chunk in data.chunks(MAX_DATA_LEN) {
+            self.buf.clear();
+            PktLine::Data(chunk).encode_to(&mut self.buf)?;
+            self.inner.write_all(&self.buf).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `data` to side-band channel 1, which carries the packfile.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_sideband_data(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.write_sideband(SIDEBAND_DATA, data).await
+    }
+
+    /// Writes a progress message to side-band channel 2.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_sideband_msg(&mut self, msg: &[u8]) -> Result<(), Error> {
+        self.write_sideband(SIDEBAND_PROGRESS, msg).await
+    }
+
+    /// Writes a fatal error to side-band channel 3, after which the client aborts.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_sideband_err(&mut self, msg: &[u8]) -> Result<(), Error> {
+        self.write_sideband(SIDEBAND_ERROR, msg).await
+    }
+
+    /// Encodes `packfile` to side-band channel 1 an entry at a time, writing out each
+    /// packet as soon as enough of the packfile has been encoded to fill it. The bytes
+    /// written are the same as for [`PktLine::SidebandData`]. Returns the checksum
+    /// written to the packfile's footer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packfile fails to encode, under the same conditions as
+    /// [`PackFile::encode_to`], or [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_sideband_pack(
+        &mut self,
+        packfile: &PackFile<'_>,
+    ) -> Result<HashOutput, Error> {
+        packfile.validate_delta_chains()?;
+
+        let entries = packfile.entries();
+        let count = u32::try_from(entries.len()).map_err(Error::EntriesExceedsU32)?;
+        let chunk_size = self.sideband.chunk_size();
+
+        let mut encoder = IncrementalPackEncoder::default();
+        let mut pending = BytesMut::from(encoder.header(count));
+
+        for entry in entries {
+            pending.extend_from_slice(encoder.entry(entry)?);
+
+            while pending.len() >= chunk_size {
+                let packet = pending.split_to(chunk_size);
+                self.write_sideband(SIDEBAND_DATA, &packet).await?;
+            }
+        }
+
+        let hash = encoder.footer();
+        pending.extend_from_slice(&hash);
+        self.write_sideband(SIDEBAND_DATA, &pending).await?;
+
+        Ok(hash)
+    }
+
+    /// Writes a flush packet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_flush(&mut self) -> Result<(), Error> {
+        Ok(self.inner.write_all(b"0000").await?)
+    }
+
+    /// Writes a delimiter packet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_delimiter(&mut self) -> Result<(), Error> {
+        Ok(self.inner.write_all(b"0001").await?)
+    }
+
+    /// Writes a response end packet.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub async fn write_response_end(&mut self) -> Result<(), Error> {
+        Ok(self.inner.write_all(b"0002").await?)
+    }
+
+    /// Flushes the inner writer.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if flushing the inner writer fails.
+    pub async fn flush(&mut self) -> Result<(), Error> {
+        Ok(self.inner.flush().await?)
+    }
+
+    /// Returns the inner writer, without flushing it.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    /// Writes `data` to the side-band `channel`, a packet at a time.
+    async fn write_sideband(&mut self, channel: u8, data: &[u8]) -> Result<(), Error> {
+        let chunk_size = self.sideband.chunk_size();
+
+        for chunk in data.chunks(chunk_size) {
+            self.buf.clear();
+            encode_sideband(&mut self.buf, channel, chunk, chunk_size)?;
+            self.inner.write_all(&self.buf).await?;
+        }
+
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::PktLine;
+    use crate::{capabilities::CapabilitySet, PktLine};
     use bytes::{Bytes, BytesMut};
     use std::fmt::Write;
     use tokio_util::codec::{Decoder, Encoder};
@@ -158,4 +505,469 @@ mod test {
             })
         );
     }
+
+    #[test]
+    fn decode_ref_advertisement_capabilities() {
+        let caps: CapabilitySet = ["multi_ack", "side-band-64k", "symref=HEAD:refs/heads/main"]
+            .into_iter()
+            .collect();
+
+        let mut codec = super::GitCodec::default();
+        let mut bytes = BytesMut::new();
+        PktLine::ref_line_with_capabilities([1; 20], "HEAD", &caps)
+            .encode_to(&mut bytes)
+            .unwrap();
+        PktLine::ref_line([2; 20], "refs/heads/main")
+            .encode_to(&mut bytes)
+            .unwrap();
+        PktLine::Flush.encode_to(&mut bytes).unwrap();
+
+        let res = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(
+            res.metadata,
+            vec![Bytes::from_static(
+                b"0202020202020202020202020202020202020202 refs/heads/main"
+            )]
+        );
+
+        let (ref_line, caps) = res.split_capabilities();
+        assert_eq!(
+            ref_line,
+            Bytes::from_static(b"0101010101010101010101010101010101010101 HEAD")
+        );
+        let caps = caps.unwrap();
+        assert!(caps.contains("multi_ack"));
+        assert!(caps.contains("side-band-64k"));
+        assert_eq!(caps.get("symref"), Some("HEAD:refs/heads/main"));
+
+        // an empty repository advertises its capabilities on a placeholder ref
+        let mut bytes = BytesMut::new();
+        PktLine::ref_line_with_capabilities(
+            [0; 20],
+            "capabilities^{}",
+            &["ofs-delta"].into_iter().collect(),
+        )
+        .encode_to(&mut bytes)
+        .unwrap();
+        PktLine::Flush.encode_to(&mut bytes).unwrap();
+        let res = codec.decode(&mut bytes).unwrap().unwrap();
+        let (ref_line, caps) = res.split_capabilities();
+        assert_eq!(
+            ref_line,
+            Bytes::from_static(b"0000000000000000000000000000000000000000 capabilities^{}")
+        );
+        assert!(caps.unwrap().contains("ofs-delta"));
+
+        // lines without a NUL have no capabilities
+        let command = super::GitCommand {
+            command: Bytes::from_static(b"command=ls-refs"),
+            metadata: vec![],
+        };
+        assert_eq!(
+            command.split_capabilities(),
+            (Bytes::from_static(b"command=ls-refs"), None)
+        );
+    }
+
+    #[test]
+    fn metadata_value() {
+        let want = |oid: u8| Bytes::from(format!("want {}", hex::encode([oid; 20])));
+        let command = super::GitCommand {
+            command: Bytes::from_static(b"command=fetch"),
+            metadata: vec![
+                Bytes::from_static(b"agent=git/2.39.5"),
+                Bytes::from_static(b"thin-pack"),
+                want(1),
+                Bytes::from_static(b"want-ref refs/heads/main"),
+                want(2),
+                Bytes::from_static(b"done"),
+            ],
+        };
+
+        assert_eq!(command.metadata_value("agent"), Some(&b"git/2.39.5"[..]));
+        assert_eq!(
+            command.metadata_values("want").collect::<Vec<_>>(),
+            [&want(1)[5..], &want(2)[5..]]
+        );
+        assert_eq!(
+            command.metadata_value("want-ref"),
+            Some(&b"refs/heads/main"[..])
+        );
+
+        // arguments without a value, and keys that aren't sent, have no value
+        assert_eq!(command.metadata_value("thin-pack"), None);
+        assert_eq!(command.metadata_value("have"), None);
+        assert_eq!(command.metadata_value("ag"), None);
+    }
+
+    #[tokio::test]
+    async fn owned_lines_across_tasks() {
+        use crate::PktLineOwned;
+        use futures_util::SinkExt;
+        use tokio_util::codec::FramedWrite;
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(4);
+
+        let producer = tokio::spawn(async move {
+            for line in ["hello\n", "world\n"] {
+                tx.send(PktLineOwned::Data(Bytes::from(line)))
+                    .await
+                    .unwrap();
+            }
+            tx.send(PktLineOwned::SidebandMsg(Bytes::from("done")))
+                .await
+                .unwrap();
+            tx.send(PktLineOwned::Flush).await.unwrap();
+        });
+
+        let mut sink = FramedWrite::new(Vec::new(), super::Encoder);
+        while let Some(line) = rx.recv().await {
+            sink.send(line).await.unwrap();
+        }
+        producer.await.unwrap();
+
+        assert_eq!(
+            sink.get_ref().as_slice(),
+            b"000ahello\n000aworld\n0009\x02done0000"
+        );
+    }
+
+    #[tokio::test]
+    async fn async_pkt_line_writer() {
+        use super::{AsyncPktLineWriter, GitCodec};
+        use crate::{
+            capabilities::Sideband,
+            low_level::{PackFile, PackFileEntry},
+            Parsed,
+        };
+        use futures_util::StreamExt;
+        use tokio_util::codec::FramedRead;
+
+        // incompressible data, so the pack spans several packets
+        let mut state = 0x9e37_79b9_u32;
+        let noise = std::iter::repeat_with(|| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state.to_le_bytes()[0]
+        })
+        .take(150_000)
+        .collect::<Vec<_>>();
+        let entries = [
+            PackFileEntry::Blob(Bytes::from(noise)),
+            PackFileEntry::Blob(Bytes::from("hello world")),
+        ];
+        let big = vec![b'a'; 150_000];
+
+        for sideband in [Sideband::SideBand, Sideband::SideBand64k] {
+            // a small buffer so the writer has to wait for the reader
+            let (client, server) = tokio::io::duplex(4096);
+
+            let write = async {
+                let mut writer = AsyncPktLineWriter::new(server).with_sideband(sideband);
+                writer.write_data(b"command=fetch").await.unwrap();
+                writer.write_data(&big).await.unwrap();
+                writer.write_delimiter().await.unwrap();
+                writer.write_flush().await.unwrap();
+                writer.write_sideband_msg(b"progress").await.unwrap();
+                let hash = writer
+                    .write_sideband_pack(&PackFile::new(&entries))
+                    .await
+                    .unwrap();
+                writer.write_flush().await.unwrap();
+                writer.flush().await.unwrap();
+                hash
+            };
+
+            let read = async {
+                FramedRead::new(client, GitCodec::default())
+                    .map(Result::unwrap)
+                    .collect::<Vec<_>>()
+                    .await
+            };
+
+            let (hash, commands) = tokio::join!(write, read);
+            assert_eq!(commands.len(), 2);
+
+            // the large data is split over several packets
+            assert_eq!(commands[0].command, "command=fetch");
+            assert!(commands[0].metadata.len() > 1);
+            assert_eq!(commands[0].metadata.concat(), big);
+
+            assert_eq!(commands[1].command, "\x02progress");
+
+            // the pack is written in the same packets as encoding it upfront gives,
+            // which the codec strips the trailing newline from like any other packet
+            let mut expected = BytesMut::new();
+            PktLine::SidebandData(PackFile::new(&entries))
+                .encode_to_with_sideband(&mut expected, sideband)
+                .unwrap();
+            let mut expected_packets = Vec::new();
+            let mut rest = &expected[..];
+            while !rest.is_empty() {
+                let (Parsed::Data(packet), consumed) = PktLine::parse(rest).unwrap() else {
+                    panic!("the pack should only be data packets");
+                };
+                assert!(consumed <= sideband.max_packet_len());
+                expected_packets.push(packet.strip_suffix(b"\n").unwrap_or(packet));
+                rest = &rest[consumed..];
+            }
+            assert_eq!(commands[1].metadata, expected_packets);
+
+            let mut pack = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut pack).unwrap();
+            assert_eq!(&hash[..], &pack[pack.len() - 20..]);
+        }
+    }
+
+    #[test]
+    fn decode_max_command_bytes() {
+        let command = |command: &[u8], metadata: &[u8]| {
+            let mut bytes = BytesMut::new();
+            PktLine::Data(command).encode_to(&mut bytes).unwrap();
+            PktLine::Data(metadata).encode_to(&mut bytes).unwrap();
+            PktLine::Flush.encode_to(&mut bytes).unwrap();
+            bytes
+        };
+
+        // unlimited by default
+        let mut codec = super::GitCodec::default();
+        let res = codec
+            .decode(&mut command(&vec![b'a'; 65516], &vec![b'b'; 65516]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command.len(), 65516);
+
+        let mut codec = super::GitCodec::default()
+            .with_max_command_bytes(super::DEFAULT_MAX_COMMAND_BYTES)
+            .with_max_metadata_bytes(super::DEFAULT_MAX_METADATA_BYTES);
+
+        // exactly at the limits, the newline counts towards the size
+        let mut at_limit = vec![b'a'; 1023];
+        at_limit.push(b'\n');
+        let res = codec
+            .decode(&mut command(&at_limit, &[b'b'; 4096]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(res.command.len(), 1023);
+        assert_eq!(res.metadata[0].len(), 4096);
+
+        // the oversized packet is consumed, so decoding carries on after it
+        let mut bytes = command(&[b'a'; 1025], b"b");
+        assert!(matches!(
+            codec.decode(&mut bytes),
+            Err(crate::Error::CommandTooLarge {
+                size: 1025,
+                max: 1024
+            })
+        ));
+        assert_eq!(&bytes[..], b"0005b0000");
+
+        let mut codec = super::GitCodec::default()
+            .with_max_command_bytes(super::DEFAULT_MAX_COMMAND_BYTES)
+            .with_max_metadata_bytes(super::DEFAULT_MAX_METADATA_BYTES);
+        assert!(matches!(
+            codec.decode(&mut command(b"command=fetch", &[b'b'; 4097])),
+            Err(crate::Error::CommandTooLarge {
+                size: 4097,
+                max: 4096
+            })
+        ));
+    }
+
+    #[test]
+    fn decode_max_metadata_lines() {
+        let command = |lines: usize| {
+            let mut bytes = BytesMut::new();
+            PktLine::Data(b"command=fetch\n")
+                .encode_to(&mut bytes)
+                .unwrap();
+            for _ in 0..lines {
+                PktLine::Data(b"have 0101010101010101010101010101010101010101\n")
+                    .encode_to(&mut bytes)
+                    .unwrap();
+            }
+            PktLine::Flush.encode_to(&mut bytes).unwrap();
+            bytes
+        };
+
+        let mut codec = super::GitCodec::default();
+        let res = codec.decode(&mut command(1024)).unwrap().unwrap();
+        assert_eq!(res.metadata.len(), super::DEFAULT_MAX_METADATA_LINES);
+
+        let mut codec = super::GitCodec::default().with_max_metadata_lines(3);
+
+        let res = codec.decode(&mut command(3)).unwrap().unwrap();
+        assert_eq!(res.metadata.len(), 3);
+
+        // the flush resets the count, so the next command can use the limit again
+        let res = codec.decode(&mut command(3)).unwrap().unwrap();
+        assert_eq!(res.metadata.len(), 3);
+
+        assert!(matches!(
+            codec.decode(&mut command(4)),
+            Err(crate::Error::TooManyMetadataLines(3))
+        ));
+
+        // delimiters don't count towards the limit
+        let mut bytes = BytesMut::from(&b"0009fetch00010009have10009have200010009have30000"[..]);
+        let res = codec.decode(&mut bytes).unwrap().unwrap();
+        assert_eq!(res.metadata.len(), 3);
+    }
+
+    #[test]
+    fn ref_update_report() {
+        use super::RefUpdateReportDecoder;
+        use crate::push::RefUpdateReport;
+
+        let report = RefUpdateReport {
+            unpack_result: Ok(()),
+            updates: vec![
+                ("refs/heads/main".into(), Ok(())),
+                (
+                    "refs/heads/locked".into(),
+                    Err("pre-receive hook declined".into()),
+                ),
+                ("refs/tags/v1".into(), Ok(())),
+            ],
+        };
+
+        let mut bytes = BytesMut::new();
+        super::Encoder.encode(report.clone(), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.as_ref(),
+            b"000eunpack ok\n\
+              0017ok refs/heads/main\n\
+              0033ng refs/heads/locked pre-receive hook declined\n\
+              0014ok refs/tags/v1\n\
+              0000"
+        );
+
+        let mut decoder = RefUpdateReportDecoder::default();
+        let mut partial = bytes.split_to(20);
+        assert_eq!(decoder.decode(&mut partial).unwrap(), None);
+        partial.unsplit(bytes);
+        assert_eq!(decoder.decode(&mut partial).unwrap(), Some(report));
+        assert!(partial.is_empty());
+
+        let failed = RefUpdateReport {
+            unpack_result: Err("index-pack abnormal exit".to_string()),
+            updates: vec![("refs/heads/main".into(), Err("unpacker error".into()))],
+        };
+        let mut bytes = BytesMut::new();
+        super::Encoder.encode(failed.clone(), &mut bytes).unwrap();
+        assert_eq!(
+            bytes.as_ref(),
+            b"0024unpack index-pack abnormal exit\n\
+              0026ng refs/heads/main unpacker error\n\
+              0000"
+        );
+        assert_eq!(decoder.decode(&mut bytes).unwrap(), Some(failed));
+    }
+
+    #[test]
+    fn ref_update_report_limits() {
+        use super::RefUpdateReportDecoder;
+        use crate::push::RefUpdateReport;
+
+        let report = RefUpdateReport {
+            unpack_result: Ok(()),
+            updates: (0..=super::DEFAULT_MAX_METADATA_LINES)
+                .map(|i| (format!("refs/heads/{i}").into(), Ok(())))
+                .collect(),
+        };
+        let mut bytes = BytesMut::new();
+        super::Encoder.encode(report.clone(), &mut bytes).unwrap();
+
+        assert!(matches!(
+            RefUpdateReportDecoder::default().decode(&mut bytes.clone()),
+            Err(crate::Error::TooManyMetadataLines(_))
+        ));
+        assert!(matches!(
+            RefUpdateReportDecoder::default()
+                .with_max_line_bytes(8)
+                .decode(&mut bytes.clone()),
+            Err(crate::Error::CommandTooLarge { .. })
+        ));
+        assert_eq!(
+            RefUpdateReportDecoder::default()
+                .with_max_updates(2048)
+                .decode(&mut bytes)
+                .unwrap(),
+            Some(report)
+        );
+    }
+
+    #[test]
+    fn decode_invalid_length_prefix() {
+        for prefix in [&b"xxxx"[..], b"00g0", b"+001", b"00\xff0"] {
+            let mut codec = super::GitCodec::default();
+            let mut bytes = BytesMut::from(prefix);
+
+            assert!(
+                matches!(
+                    codec.decode(&mut bytes),
+                    Err(crate::Error::InvalidLengthPrefix(actual)) if actual == prefix
+                ),
+                "{prefix:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn decode_length_too_small() {
+        let mut codec = super::GitCodec::default();
+        let mut bytes = BytesMut::from(&b"0003"[..]);
+
+        assert!(matches!(
+            codec.decode(&mut bytes),
+            Err(crate::Error::PacketLengthTooSmall(3))
+        ));
+    }
+
+    #[test]
+    fn decode_partial_header() {
+        for len in 1..4 {
+            let mut codec = super::GitCodec::default();
+
+            let mut bytes = BytesMut::from(&b"0009hello0000"[..len]);
+            assert_eq!(codec.decode(&mut bytes).unwrap(), None);
+            assert_eq!(bytes.as_ref(), &b"0009hello0000"[..len], "consumed bytes");
+
+            // the rest of the header arriving should carry on where it left off
+            bytes.extend_from_slice(&b"0009hello0000"[len..]);
+            assert_eq!(
+                codec.decode(&mut bytes).unwrap(),
+                Some(super::GitCommand {
+                    command: Bytes::from_static(b"hello"),
+                    metadata: vec![],
+                })
+            );
+            assert!(bytes.is_empty());
+        }
+    }
+
+    #[test]
+    fn decode_byte_at_a_time() {
+        let input = b"0014command=ls-refs\n00010009peel\n000bsymrefs0000";
+        let mut codec = super::GitCodec::default();
+        let mut bytes = BytesMut::new();
+
+        for (i, byte) in input.iter().enumerate() {
+            bytes.extend_from_slice(&[*byte]);
+            let res = codec.decode(&mut bytes).unwrap();
+
+            if i == input.len() - 1 {
+                assert_eq!(
+                    res,
+                    Some(super::GitCommand {
+                        command: Bytes::from_static(b"command=ls-refs"),
+                        metadata: vec![Bytes::from_static(b"peel"), Bytes::from_static(b"symrefs"),],
+                    })
+                );
+            } else {
+                assert_eq!(res, None, "decoded early at byte {i}");
+            }
+        }
+    }
 }
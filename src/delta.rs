@@ -0,0 +1,251 @@
+//! Encoding and decoding of the delta format Git uses to store an object as a set of
+//! instructions for rebuilding it from another object.
+//!
+//! <https://git-scm.com/docs/pack-format#_deltified_representation>
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::Error;
+
+/// The largest amount of data a single insert instruction can carry.
+const MAX_INSERT_LEN: usize = 0x7f;
+
+/// The largest amount of data a single copy instruction can carry, the size is stored
+/// in at most 3 bytes.
+const MAX_COPY_LEN: usize = 0xff_ffff;
+
+/// Builds a delta that rebuilds `target` from `base`.
+///
+/// The delta copies the prefix and suffix `target` shares with `base`, and inserts
+/// everything in between. This is cheap to compute and works well for objects that
+/// have been appended to or edited in a single place, but won't find any data that has
+/// moved around.
+#[must_use]
+pub fn encode(base: &[u8], target: &[u8]) -> Bytes {
+    let prefix = base.iter().zip(target).take_while(|(a, b)| a == b).count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(target[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut out = BytesMut::new();
+    write_size(&mut out, base.len());
+    write_size(&mut out, target.len());
+
+    write_copy(&mut out, 0, prefix);
+
+    for chunk in target[prefix..target.len() - suffix].chunks(MAX_INSERT_LEN) {
+        #[allow(clippy::cast_possible_truncation)] // bounded by MAX_INSERT_LEN
+        out.put_u8(chunk.len() as u8);
+        out.extend_from_slice(chunk);
+    }
+
+    write_copy(&mut out, base.len() - suffix, suffix);
+
+    out.freeze()
+}
+
+/// Rebuilds the target object by applying the instructions in `delta` to `base`.
+///
+/// # Errors
+///
+/// Returns [`Error::MalformedDelta`] if the delta is truncated, wasn't built from an
+/// object the size of `base`, refers to data outside of `base` or doesn't build an
+/// object of the size it claims.
+pub fn apply(base: &[u8], delta: &[u8]) -> Result<Bytes, Error> {
+    let mut delta = delta;
+
+    let base_size = read_size(&mut delta)?;
+    if base_size != base.len() {
+        return Err(Error::MalformedDelta("base size doesn't match"));
+    }
+
+    let target_size = read_size(&mut delta)?;
+    let mut out = BytesMut::with_capacity(target_size);
+
+    while let Some((&instruction, rest)) = delta.split_first() {
+        delta = rest;
+
+        if instruction & 0b1000_0000 != 0 {
+            // the lower 7 bits say which bytes of the offset (4 bytes) and size
+            // (3 bytes) follow, any that are omitted are zero
+            let mut args = [0_u8; 7];
+            for (i, arg) in args.iter_mut().enumerate() {
+                if instruction & (1 << i) != 0 {
+                    let (&byte, rest) = delta
+                        .split_first()
+                        .ok_or(Error::MalformedDelta("truncated copy instruction"))?;
+                    *arg = byte;
+                    delta = rest;
+                }
+            }
+
+            let offset = u32::from_le_bytes([args[0], args[1], args[2], args[3]]) as usize;
+            let size = match u32::from_le_bytes([args[4], args[5], args[6], 0]) as usize {
+                0 => 0x10000,
+                size => size,
+            };
+
+            let data = offset
+                .checked_add(size)
+                .and_then(|end| base.get(offset..end))
+                .ok_or(Error::MalformedDelta("copy outside of base"))?;
+            out.extend_from_slice(data);
+        } else if instruction != 0 {
+            let len = usize::from(instruction);
+            if delta.len() < len {
+                return Err(Error::MalformedDelta("truncated insert instruction"));
+            }
+
+            out.extend_from_slice(&delta[..len]);
+            delta = &delta[len..];
+        } else {
+            return Err(Error::MalformedDelta("reserved instruction"));
+        }
+    }
+
+    if out.len() != target_size {
+        return Err(Error::MalformedDelta("target size doesn't match"));
+    }
+
+    Ok(out.freeze())
+}
+
+/// Reads a size from the delta header, advancing `delta` past it.
+fn read_size(delta: &mut &[u8]) -> Result<usize, Error> {
+    let mut size = 0_usize;
+    let mut shift = 0;
+
+    loop {
+        let (&byte, rest) = delta
+            .split_first()
+            .ok_or(Error::MalformedDelta("truncated header"))?;
+        *delta = rest;
+
+        if shift >= usize::BITS {
+            return Err(Error::MalformedDelta("size overflows"));
+        }
+        size |= usize::from(byte & 0b111_1111) << shift;
+        shift += 7;
+
+        if byte & 0b1000_0000 == 0 {
+            return Ok(size);
+        }
+    }
+}
+
+/// Writes a size to the delta header, as a little-endian base 128 varint.
+fn write_size(out: &mut BytesMut, mut size: usize) {
+    loop {
+        #[allow(clippy::cast_possible_truncation)] // value is masked
+        let mut val = (size & 0b111_1111) as u8;
+        size >>= 7;
+
+        if size != 0 {
+            val |= 1 << 7;
+        }
+
+        out.put_u8(val);
+
+        if size == 0 {
+            break;
+        }
+    }
+}
+
+/// Writes instructions copying `len` bytes from `offset` in the base object.
+fn write_copy(out: &mut BytesMut, mut offset: usize, mut len: usize) {
+    while len > 0 {
+        let size = len.min(MAX_COPY_LEN);
+
+        let mut instruction = 0b1000_0000_u8;
+        let mut args = [0_u8; 7];
+        let mut args_len = 0;
+
+        // each of the lower 7 bits of the instruction says whether the corresponding
+        // byte of the offset (4 bytes) or size (3 bytes) is present, zero bytes are
+        // omitted
+        for (i, byte) in offset
+            .to_le_bytes()
+            .into_iter()
+            .take(4)
+            .chain(size.to_le_bytes().into_iter().take(3))
+            .enumerate()
+        {
+            if byte != 0 {
+                instruction |= 1 << i;
+                args[args_len] = byte;
+                args_len += 1;
+            }
+        }
+
+        out.put_u8(instruction);
+        out.extend_from_slice(&args[..args_len]);
+
+        offset += size;
+        len -= size;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::delta::{apply, encode};
+
+    #[test]
+    fn apply_round_trip() {
+        for (base, target) in [
+            (&b"hello world"[..], &b"hello there world"[..]),
+            (b"abc", b"xyz"),
+            (b"", b"new"),
+            (b"old", b""),
+            (&vec![0xaa; 100_000], &vec![0xaa; 200_000]),
+        ] {
+            let delta = encode(base, target);
+            assert_eq!(apply(base, &delta).unwrap().as_ref(), target);
+        }
+    }
+
+    #[test]
+    fn apply_malformed() {
+        let delta = encode(b"hello world", b"hello there world");
+
+        assert!(apply(b"hello", &delta).is_err());
+        assert!(apply(b"hello world", &delta[..delta.len() - 1]).is_err());
+        // a copy past the end of the base
+        assert!(apply(b"abc", &[3, 4, 0b1001_0000, 4]).is_err());
+        assert!(apply(b"abc", &[3, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn prefix_and_suffix() {
+        let actual = encode(b"hello world", b"hello there world");
+
+        assert_eq!(
+            actual.as_ref(),
+            &[
+                11, // base size
+                17, // target size
+                0b1001_0000,
+                6, // copy 6 bytes from offset 0
+                6,
+                b't',
+                b'h',
+                b'e',
+                b'r',
+                b'e',
+                b' ', // insert "there "
+                0b1001_0001,
+                6,
+                5, // copy 5 bytes from offset 6
+            ]
+        );
+    }
+
+    #[test]
+    fn nothing_in_common() {
+        let actual = encode(b"abc", b"xyz");
+        assert_eq!(actual.as_ref(), &[3, 3, 3, b'x', b'y', b'z']);
+    }
+}
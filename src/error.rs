@@ -1,4 +1,7 @@
-use std::ops::RangeInclusive;
+use std::{
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,23 +9,132 @@ pub enum Error {
     #[error("Failed to write formatted string to buffer: {0}")]
     BufferWrite(#[from] std::fmt::Error),
     #[error("{0} is not a directory")]
-    NotDirectory(&'static str),
+    NotDirectory(String),
+    #[error("Both repositories contain a different file or directory at {0}")]
+    MergeConflict(String),
     #[cfg(feature = "tokio-util")]
+    #[deprecated(note = "invalid length prefixes are reported as `Error::InvalidLengthPrefix`")]
     #[error("Failed to parse utf-8 encoded prefix: {0}")]
     ParseLengthBytes(std::str::Utf8Error),
     #[cfg(feature = "tokio-util")]
+    #[deprecated(note = "invalid length prefixes are reported as `Error::InvalidLengthPrefix`")]
     #[error("Failed to parse length from hex string: {0}")]
     ParseLengthAsHex(std::num::ParseIntError),
+    #[cfg(feature = "tokio-util")]
+    #[error("Command has more than the maximum of {0} metadata lines")]
+    TooManyMetadataLines(usize),
+    #[cfg(feature = "tokio-util")]
+    #[error("Line of {size} bytes in command exceeds the maximum of {max}")]
+    CommandTooLarge { size: usize, max: usize },
+    #[error("Packet length prefix {0:?} isn't 4 hex digits")]
+    InvalidLengthPrefix([u8; 4]),
+    #[error("Input ends part way through a pkt-line, leaving {0} bytes unread")]
+    TruncatedPktLine(usize),
+    #[deprecated(note = "compression failures are reported as `Error::Compress`")]
     #[error("Failed to write bytes to compress to zlib: {0}")]
     CompressWrite(std::io::Error),
     #[error("Failed to compress packfile with zlib: {0}")]
     Compress(std::io::Error),
     #[error("Failed to encode tree hash to hex: {0}")]
     EncodeTreeHash(hex::FromHexError),
+    #[error("Failed to decode hash from hex: {0}")]
+    DecodeHash(#[from] hex::FromHexError),
     #[error("Entries in packfile exceeds a u32: {0}")]
     EntriesExceedsU32(std::num::TryFromIntError),
     #[error("Packet length is not in the range {0:?} as defined by the spec, got {1}")]
     PacketLengthExceedsSpec(RangeInclusive<usize>, usize),
+    #[error(
+        "Packet length {0} is too small to hold its own length prefix, and isn't a special packet"
+    )]
+    PacketLengthTooSmall(usize),
+    #[error("{0} is not a valid mode for a file")]
+    InvalidFileMode(crate::low_level::TreeItemKind),
+    #[error("Submodules must be inserted with the 20 byte hash of their commit, got {0} bytes")]
+    InvalidSubmoduleHash(usize),
+    #[error("{0:?} isn't a valid file or directory name, names must be non-empty and can't contain `/` or NUL")]
+    InvalidName(String),
+    #[error("Malformed user info in commit: {0}")]
+    MalformedUserInfo(String),
+    #[error("Malformed commit: {0}")]
+    MalformedCommit(String),
+    #[error("Commit message isn't valid UTF-8 and no encoding was given: {0}")]
+    NonUtf8MessageWithoutEncoding(std::str::Utf8Error),
+    #[error("Malformed tag: {0}")]
+    MalformedTag(String),
+    #[error("Malformed line in fetch request: {0}")]
+    MalformedFetchRequest(String),
+    #[error("deepen and deepen-since (or deepen-not) cannot be used together")]
+    ConflictingDeepen,
+    #[error("Invalid fetch request: {0}")]
+    InvalidFetchRequest(&'static str),
+    #[error("Invalid fetch response: {0}")]
+    InvalidFetchResponse(&'static str),
+    #[error("Malformed line in ref update report: {0}")]
+    MalformedRefUpdateReport(String),
+    #[error("Malformed ref advertisement: {0}")]
+    MalformedRefAdvertisement(String),
+    #[error("Unknown mode in tree: {0:o}")]
+    UnknownTreeMode(u32),
+    #[error("Unknown tree item kind: {0}")]
+    UnknownTreeKind(String),
+    #[error("Unknown object kind: {0}")]
+    UnknownObjectKind(String),
+    #[error("Malformed tree record at byte {offset}: {reason}")]
+    MalformedTree { offset: usize, reason: &'static str },
+    #[error("Tree contains more than one entry named {0:?}")]
+    DuplicateTreeEntry(String),
+    #[error("Tree entry hash is {actual} bytes but the object format uses {expected}")]
+    HashWidthMismatch { expected: usize, actual: usize },
+    #[error("Malformed packfile: {0}")]
+    MalformedPackFile(String),
+    #[error("Malformed packfile entry header: {0}")]
+    MalformedEntryHeader(&'static str),
+    #[error("Failed to decompress packfile entry with zlib: {0}")]
+    Decompress(std::io::Error),
+    #[error("Delta chain for {} exceeds the maximum length of {max}", hex::encode(.hash))]
+    DeltaChainTooLong { hash: [u8; 20], max: usize },
+    #[error("Raw and compressed entries must be a commit, tree, blob or tag, got {0:?}")]
+    InvalidRawKind(crate::low_level::ObjectKind),
+    #[error("Compressed entry {} doesn't inflate to the object it claims to be", hex::encode(.0))]
+    CompressedEntryMismatch([u8; 20]),
+    #[error("Malformed delta: {0}")]
+    MalformedDelta(&'static str),
+    #[error("Deltas must be built between two full objects of the same type")]
+    InvalidDeltaBase,
+    #[error("Base {} of delta {} isn't in the packfile, and the packfile isn't thin", hex::encode(.base), hex::encode(.hash))]
+    MissingDeltaBase { hash: [u8; 20], base: [u8; 20] },
+    #[error("Object {} refers to {}, which isn't in the packfile", hex::encode(.referencing), hex::encode(.target))]
+    DanglingReference {
+        referencing: [u8; 20],
+        target: [u8; 20],
+    },
+    #[error("Packfile can't be split into packs of at most {max} bytes, an entry and its deltas require {required}")]
+    SplitSizeTooSmall { required: usize, max: usize },
+    #[error("Packfile can't be split into packs of at most {max} entries, a delta chain requires {required}")]
+    SplitEntriesTooSmall { required: usize, max: usize },
+    #[error("Invalid refname: {0:?}")]
+    InvalidRefName(String),
+    #[error("Timestamp {0} with a UTC offset of {1} minutes is out of range")]
+    TimestampOutOfRange(i64, i16),
+    #[cfg(feature = "tokio")]
+    #[error("Unsupported entry in tar archive: {0}")]
+    UnsupportedTarEntry(String),
+    #[cfg(feature = "serde")]
+    #[error("Failed to serialise or deserialise repository: {0}")]
+    Json(serde_json::Error),
+    #[error("I/O error at {}: {source}", .path.display())]
+    IoWithPath {
+        path: PathBuf,
+        source: std::io::Error,
+    },
     #[error("I/O error: {0}")]
     Io(#[from] std::io::Error),
 }
+
+/// Wraps an error from an I/O operation on `path`, so it can be told which file failed.
+pub(crate) fn io_error_at(path: &Path, err: std::io::Error) -> Error {
+    Error::IoWithPath {
+        path: path.to_path_buf(),
+        source: err,
+    }
+}
@@ -0,0 +1,1781 @@
+//! Types for serving `fetch` requests from clients, and for building them to send to
+//! servers.
+//!
+//! <https://git-scm.com/docs/protocol-v2#_fetch>
+
+use bytes::{Bytes, BytesMut};
+
+use crate::{
+    capabilities::CapabilitySet,
+    low_level::{HashOutput, PackFile},
+    Error, PktLine,
+};
+#[cfg(feature = "tokio-util")]
+use crate::{
+    capabilities::Sideband,
+    low_level::{IncrementalPackEncoder, PackFileEntry},
+    packet_line::encode_sideband,
+    SIDEBAND_DATA,
+};
+
+/// A `fetch` request sent by a client, parsed from either the v2 argument lines or the
+/// v1 `want`/`have` lines.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FetchRequest {
+    /// Objects the client wants the server to send.
+    pub wants: Vec<HashOutput>,
+    /// Objects the client already has.
+    pub haves: Vec<HashOutput>,
+    /// Whether the client has finished negotiation and is waiting for the packfile.
+    pub done: bool,
+    /// Whether the client can understand `OFS_DELTA` entries in the packfile, callers
+    /// must only send delta entries based on offsets when this is set.
+    pub ofs_delta: bool,
+    /// Whether the client can complete a thin pack, callers may only send deltas
+    /// against bases that aren't in the packfile when this is set, and only against
+    /// objects the client is known to have.
+    pub thin_pack: bool,
+    /// How far back the client wants history to be cut off for a shallow fetch, along
+    /// with the shallow boundaries it already has.
+    pub depth: DepthSpec,
+    /// All the capabilities and features the client sent with the request, including
+    /// v1 capabilities sent after the first `want` and v2 arguments such as `thin-pack`.
+    pub capabilities: CapabilitySet,
+}
+
+/// The `deepen`, `deepen-since`, `deepen-not` and `shallow` lines of a fetch request,
+/// from which the server works out the new shallow boundary to send back with
+/// [`FetchResponse::with_shallow`] and [`FetchResponse::with_unshallow`].
+///
+/// Only one of `depth`, or `since` and `not`, is ever set, as Git won't deepen by both
+/// a depth and a ref or time.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct DepthSpec {
+    /// `deepen <n>`, the number of commits to fetch from the tip of each `want`.
+    pub depth: Option<u32>,
+    /// `deepen-relative`, where `depth` counts from the client's current shallow
+    /// boundary rather than the tips.
+    pub relative: bool,
+    /// `deepen-since <timestamp>`, cutting history off at commits made before this
+    /// Unix timestamp.
+    pub since: Option<u64>,
+    /// `deepen-not <ref>`, cutting history off at commits reachable from these refs.
+    pub not: Vec<String>,
+    /// `shallow <oid>`, the commits the client's history is already cut off at.
+    pub shallows: Vec<HashOutput>,
+}
+
+impl DepthSpec {
+    /// Whether the client asked for its history to be cut off or deepened, rather than
+    /// just telling the server about its existing shallow boundary.
+    #[must_use]
+    pub fn is_deepening(&self) -> bool {
+        self.depth.is_some() || self.since.is_some() || !self.not.is_empty()
+    }
+}
+
+impl FetchRequest {
+    /// Parses the lines of a `fetch` request, such as the metadata of a
+    /// `GitCommand`, with any trailing newlines already stripped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedFetchRequest`] if a `want`, `have` or `shallow` line
+    /// doesn't contain a valid object id, a `deepen` line doesn't contain a positive
+    /// number or a `deepen-since` line doesn't contain a number, and
+    /// [`Error::ConflictingDeepen`] if the client asked
+    /// for both a depth and a `deepen-since` or `deepen-not`, as `git upload-pack`
+    /// does.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(lines), err))]
+    pub fn parse<'a>(lines: impl IntoIterator<Item = &'a [u8]>) -> Result<Self, Error> {
+        let mut request = Self::default();
+
+        for line in lines {
+            if let Some(want) = line.strip_prefix(b"want ") {
+                // the first want line in a v1 request carries the client's
+                // capabilities after the object id
+                let (oid, capabilities) = match want.iter().position(|&c| c == b' ') {
+                    Some(i) => (&want[..i], &want[i + 1..]),
+                    None => (want, &[][..]),
+                };
+
+                request.wants.push(parse_oid(line, oid)?);
+
+                request
+                    .capabilities
+                    .extend(String::from_utf8_lossy(capabilities).split_whitespace());
+            } else if let Some(have) = line.strip_prefix(b"have ") {
+                request.haves.push(parse_oid(line, have)?);
+            } else if line == b"done" {
+                request.done = true;
+            } else if let Some(shallow) = line.strip_prefix(b"shallow ") {
+                request.depth.shallows.push(parse_oid(line, shallow)?);
+            } else if let Some(depth) = line.strip_prefix(b"deepen ") {
+                request.depth.depth = Some(
+                    parse_number(depth)
+                        .filter(|&depth| depth != 0)
+                        .and_then(|depth| u32::try_from(depth).ok())
+                        .ok_or_else(|| malformed(line))?,
+                );
+            } else if let Some(since) = line.strip_prefix(b"deepen-since ") {
+                request.depth.since = Some(parse_number(since).ok_or_else(|| malformed(line))?);
+            } else if let Some(not) = line.strip_prefix(b"deepen-not ") {
+                request
+                    .depth
+                    .not
+                    .push(String::from_utf8_lossy(not).into_owned());
+            } else {
+                request
+                    .capabilities
+                    .insert(String::from_utf8_lossy(line).into_owned());
+            }
+        }
+
+        request.ofs_delta = request.capabilities.contains("ofs-delta");
+        request.thin_pack = request.capabilities.contains("thin-pack");
+        request.depth.relative = request.capabilities.contains("deepen-relative");
+
+        if request.depth.depth.is_some()
+            && (request.depth.since.is_some() || !request.depth.not.is_empty())
+        {
+            return Err(Error::ConflictingDeepen);
+        }
+
+        Ok(request)
+    }
+}
+
+/// Builds a `fetch` request to send to a server, as either a protocol v2 `fetch`
+/// command or the v0/v1 `want`/`have` lines.
+///
+/// Arguments are written in the order `git fetch-pack` writes them: features such as
+/// `thin-pack`, `ofs-delta` and `no-progress`, then the shallow and deepen lines, the
+/// filter, `want`s, `have`s and finally `done`.
+///
+/// <https://git-scm.com/docs/protocol-v2#_fetch>
+#[derive(Debug, Default, Clone)]
+pub struct FetchRequestBuilder {
+    /// Capabilities sent before the arguments, such as `agent` or `object-format`.
+    capabilities: CapabilitySet,
+    features: CapabilitySet,
+    wants: Vec<HashOutput>,
+    haves: Vec<HashOutput>,
+    done: bool,
+    filter: Option<String>,
+    depth: DepthSpec,
+}
+
+impl FetchRequestBuilder {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sends a capability with the request, in the form `name` or `name=value`, such
+    /// as `agent=packfile/0.1`. In a v0/v1 request these are sent after the first
+    /// `want` along with the features.
+    #[must_use]
+    pub fn with_capability(mut self, capability: impl Into<String>) -> Self {
+        self.capabilities.insert(capability);
+        self
+    }
+
+    /// Asks for a feature of the packfile the server sends back, such as `thin-pack`,
+    /// `ofs-delta`, `no-progress` or `include-tag`.
+    #[must_use]
+    pub fn with_feature(mut self, feature: impl Into<String>) -> Self {
+        self.features.insert(feature);
+        self
+    }
+
+    /// Asks the server for `oid` and everything reachable from it.
+    #[must_use]
+    pub fn with_want(mut self, oid: HashOutput) -> Self {
+        self.wants.push(oid);
+        self
+    }
+
+    /// Tells the server the client already has `oid`, so it can be left out of the
+    /// packfile along with everything reachable from it.
+    #[must_use]
+    pub fn with_have(mut self, oid: HashOutput) -> Self {
+        self.haves.push(oid);
+        self
+    }
+
+    /// Ends negotiation, so the server sends the packfile straight away rather than
+    /// acknowledging the `have`s.
+    #[must_use]
+    pub fn with_done(mut self) -> Self {
+        self.done = true;
+        self
+    }
+
+    /// Asks for a partial clone with the given filter spec, such as `blob:none`. The
+    /// server must have advertised `filter`.
+    #[must_use]
+    pub fn with_filter(mut self, filter: impl Into<String>) -> Self {
+        self.filter = Some(filter.into());
+        self
+    }
+
+    /// Asks for history to be cut off, and tells the server where the client's
+    /// history is already cut off.
+    #[must_use]
+    pub fn with_depth(mut self, depth: DepthSpec) -> Self {
+        self.depth = depth;
+        self
+    }
+
+    /// Writes the request out to `buf` as a protocol v2 `fetch` command, ending with a
+    /// flush.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`FetchRequestBuilder::validate`],
+    /// or if the request fails to serialise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(buf), err))]
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.validate()?;
+
+        PktLine::text("command=fetch").encode_to(buf)?;
+        for (name, value) in self.capabilities.iter() {
+            write_capability(name, value, buf)?;
+        }
+        PktLine::Delimiter.encode_to(buf)?;
+
+        for (name, value) in self.features.iter() {
+            write_capability(name, value, buf)?;
+        }
+        self.encode_shallow_to(buf)?;
+        if self.depth.relative {
+            PktLine::text("deepen-relative").encode_to(buf)?;
+        }
+        if let Some(filter) = &self.filter {
+            PktLine::text(&format!("filter {filter}")).encode_to(buf)?;
+        }
+        for want in &self.wants {
+            oid_line("want", *want).encode_to(buf)?;
+        }
+        for have in &self.haves {
+            oid_line("have", *have).encode_to(buf)?;
+        }
+        if self.done {
+            PktLine::text("done").encode_to(buf)?;
+        }
+
+        PktLine::Flush.encode_to(buf)?;
+
+        Ok(())
+    }
+
+    /// Writes the request out to `buf` as the lines a v0/v1 client sends: the `want`s,
+    /// with the capabilities and features after the first of them, then the shallow
+    /// and deepen lines and the filter, followed by a flush. The `have`s come after
+    /// that, followed by `done` or another flush.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`FetchRequestBuilder::validate`],
+    /// or if the request fails to serialise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(buf), err))]
+    pub fn encode_v0_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        self.validate()?;
+
+        let mut capabilities = self.features.clone();
+        if self.depth.relative {
+            capabilities.insert("deepen-relative");
+        }
+        capabilities.extend(self.capabilities.iter().map(|(name, value)| match value {
+            Some(value) => format!("{name}={value}"),
+            None => name.to_string(),
+        }));
+
+        for (i, want) in self.wants.iter().enumerate() {
+            if i == 0 && !capabilities.is_empty() {
+                PktLine::text(&format!("want {} {capabilities}", hex::encode(want)))
+                    .encode_to(buf)?;
+            } else {
+                oid_line("want", *want).encode_to(buf)?;
+            }
+        }
+        self.encode_shallow_to(buf)?;
+        if let Some(filter) = &self.filter {
+            PktLine::text(&format!("filter {filter}")).encode_to(buf)?;
+        }
+        PktLine::Flush.encode_to(buf)?;
+
+        for have in &self.haves {
+            oid_line("have", *have).encode_to(buf)?;
+        }
+        if self.done {
+            PktLine::text("done").encode_to(buf)?;
+        } else {
+            PktLine::Flush.encode_to(buf)?;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the request makes sense before it's sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidFetchRequest`] if nothing is wanted, the depth is 0 or
+    /// any of the lines would contain a newline, and [`Error::ConflictingDeepen`] if
+    /// both a depth and a `deepen-since` or `deepen-not` were given.
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.wants.is_empty() {
+            return Err(Error::InvalidFetchRequest(
+                "a fetch request must want at least one object",
+            ));
+        }
+
+        if self.depth.depth == Some(0) {
+            return Err(Error::InvalidFetchRequest("depth must be positive"));
+        }
+
+        if self.depth.depth.is_some() && (self.depth.since.is_some() || !self.depth.not.is_empty())
+        {
+            return Err(Error::ConflictingDeepen);
+        }
+
+        let mut lines = self
+            .capabilities
+            .iter()
+            .chain(self.features.iter())
+            .flat_map(|(name, value)| [Some(name), value])
+            .flatten()
+            .chain(self.filter.as_deref())
+            .chain(self.depth.not.iter().map(String::as_str));
+        if lines.any(|line| line.contains('\n')) {
+            return Err(Error::InvalidFetchRequest(
+                "arguments can't contain a newline",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the `shallow`, `deepen`, `deepen-since` and `deepen-not` lines, which are
+    /// the same in every version of the protocol.
+    fn encode_shallow_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        for shallow in &self.depth.shallows {
+            oid_line("shallow", *shallow).encode_to(buf)?;
+        }
+        if let Some(depth) = self.depth.depth {
+            PktLine::text(&format!("deepen {depth}")).encode_to(buf)?;
+        }
+        if let Some(since) = self.depth.since {
+            PktLine::text(&format!("deepen-since {since}")).encode_to(buf)?;
+        }
+        for not in &self.depth.not {
+            PktLine::text(&format!("deepen-not {not}")).encode_to(buf)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes a `name` or `name=value` line.
+fn write_capability(name: &str, value: Option<&str>, buf: &mut BytesMut) -> Result<(), Error> {
+    match value {
+        Some(value) => PktLine::text(&format!("{name}={value}")).encode_to(buf)?,
+        None => PktLine::text(name).encode_to(buf)?,
+    };
+
+    Ok(())
+}
+
+/// Builds a `<name> <oid>` line.
+fn oid_line(name: &str, oid: HashOutput) -> PktLine<'static> {
+    PktLine::OwnedData(Bytes::from(format!("{name} {}\n", hex::encode(oid))))
+}
+
+/// How a v1 server acknowledges the `have`s a client sends while negotiating, picked
+/// from the client's capabilities.
+///
+/// <https://git-scm.com/docs/pack-protocol#_packfile_negotiation>
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AckMode {
+    /// Only the first common object is acknowledged, with `ACK <oid>`.
+    Single,
+    /// `multi_ack`, every common object is acknowledged with `ACK <oid> continue`.
+    MultiAck,
+    /// `multi_ack_detailed`, common objects are acknowledged with `ACK <oid> common`
+    /// and the server tells the client it can stop with `ACK <oid> ready`.
+    MultiAckDetailed,
+}
+
+/// The server's side of a protocol v1 `have` negotiation, producing the `ACK` and
+/// `NAK` lines to send back to the client in the mode it asked for. This follows
+/// the same rules as `git upload-pack`.
+///
+/// The caller decides which objects are common, and when it has found enough of
+/// them to be able to build a packfile the client can use, which is when the
+/// server is "ready".
+#[derive(Debug, Clone)]
+pub struct Negotiation {
+    mode: AckMode,
+    no_done: bool,
+    /// The number of common objects found over the whole negotiation.
+    common_count: usize,
+    /// The last common object found, which is acknowledged at the end.
+    last_common: Option<HashOutput>,
+    /// The `have`s seen since the last flush.
+    round: Round,
+    sent_ready: bool,
+    finished: bool,
+}
+
+/// What the server has seen in a single round of `have`s, which is reset by each
+/// flush.
+#[derive(Debug, Default, Clone)]
+struct Round {
+    /// Whether a common object has been found.
+    got_common: bool,
+    /// Whether an object the server doesn't have was sent.
+    got_other: bool,
+    /// Whether the server is ready, as of the last `have`.
+    ready: bool,
+}
+
+impl Negotiation {
+    /// Starts a negotiation in the mode picked from the client's capabilities,
+    /// preferring `multi_ack_detailed` when both it and `multi_ack` are present.
+    /// `no-done` is only honoured alongside `multi_ack_detailed`.
+    #[must_use]
+    pub fn new(capabilities: &CapabilitySet) -> Self {
+        let mode = if capabilities.contains("multi_ack_detailed") {
+            AckMode::MultiAckDetailed
+        } else if capabilities.contains("multi_ack") {
+            AckMode::MultiAck
+        } else {
+            AckMode::Single
+        };
+
+        Self {
+            mode,
+            no_done: mode == AckMode::MultiAckDetailed && capabilities.contains("no-done"),
+            common_count: 0,
+            last_common: None,
+            round: Round::default(),
+            sent_ready: false,
+            finished: false,
+        }
+    }
+
+    #[must_use]
+    pub fn mode(&self) -> AckMode {
+        self.mode
+    }
+
+    /// Whether the client is waiting for the packfile, either because it sent
+    /// `done` or because `no-done` let the server end the negotiation itself.
+    #[must_use]
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Handles a `have <oid>` line from the client, returning the line to respond
+    /// with, if any. `common` is whether the server has the object, and `ready`
+    /// whether the server now has enough common objects to send a packfile.
+    pub fn have(&mut self, oid: HashOutput, common: bool, ready: bool) -> Option<PktLine<'static>> {
+        self.round.ready = ready;
+
+        if !common {
+            self.round.got_other = true;
+
+            return match self.mode {
+                AckMode::MultiAckDetailed if ready => {
+                    self.sent_ready = true;
+                    Some(ack(oid, " ready"))
+                }
+                AckMode::MultiAck if ready => Some(ack(oid, " continue")),
+                _ => None,
+            };
+        }
+
+        self.round.got_common = true;
+        self.common_count += 1;
+        self.last_common = Some(oid);
+
+        match self.mode {
+            AckMode::MultiAckDetailed => Some(ack(oid, " common")),
+            AckMode::MultiAck => Some(ack(oid, " continue")),
+            AckMode::Single if self.common_count == 1 => Some(ack(oid, "")),
+            AckMode::Single => None,
+        }
+    }
+
+    /// Handles the flush the client sends after each round of `have`s, returning
+    /// the lines to respond with. With `no-done`, this can finish the negotiation, in
+    /// which case the packfile should be sent straight after these lines.
+    pub fn flush(&mut self) -> Vec<PktLine<'static>> {
+        let mut lines = Vec::new();
+
+        if let Some(last_common) = self.last_common {
+            let round = &self.round;
+
+            if self.mode == AckMode::MultiAckDetailed
+                && round.got_common
+                && !round.got_other
+                && round.ready
+            {
+                self.sent_ready = true;
+                lines.push(ack(last_common, " ready"));
+            }
+        }
+
+        if self.common_count == 0 || self.mode != AckMode::Single {
+            lines.push(PktLine::text("NAK"));
+        }
+
+        if self.no_done && self.sent_ready {
+            if let Some(last_common) = self.last_common {
+                lines.push(ack(last_common, ""));
+            }
+            self.finished = true;
+        }
+
+        self.round = Round::default();
+
+        lines
+    }
+
+    /// Handles the client's `done`, returning the final `ACK` or `NAK` to send before
+    /// the packfile.
+    pub fn done(&mut self) -> Option<PktLine<'static>> {
+        self.finished = true;
+
+        match self.last_common {
+            Some(last_common) if self.mode != AckMode::Single => Some(ack(last_common, "")),
+            // a single ack was already sent for the first common object
+            Some(_) => None,
+            None => Some(PktLine::text("NAK")),
+        }
+    }
+}
+
+/// Builds an `ACK <oid><status>` line.
+fn ack(oid: HashOutput, status: &str) -> PktLine<'static> {
+    PktLine::OwnedData(Bytes::from(format!("ACK {}{status}\n", hex::encode(oid))))
+}
+
+/// Encodes `packfile` as it's sent to a client in response to a fetch, a single
+/// entry at a time so the packfile is never held in memory as a whole. Each item is
+/// ready to be written straight to the client, such as into a
+/// [`tokio_util::codec::FramedWrite`] using [`tokio_util::codec::BytesCodec`].
+///
+/// With a `sideband`, the packfile is sent on side-band channel 1 in packets as large
+/// as it allows followed by a flush, which is the form protocol v2 and clients
+/// advertising `side-band` or `side-band-64k` expect. Without one, the packfile is
+/// sent as-is with no framing and nothing following it.
+///
+/// If the packfile fails to encode, the stream yields the error and ends.
+#[cfg(feature = "tokio-util")]
+pub fn pack_stream<'a>(
+    packfile: &'a PackFile<'_>,
+    sideband: Option<Sideband>,
+) -> impl futures_util::Stream<Item = Result<Bytes, Error>> + 'a {
+    let count = packfile
+        .validate_delta_chains()
+        .and_then(|()| u32::try_from(packfile.entries().len()).map_err(Error::EntriesExceedsU32));
+
+    futures_util::stream::iter(PackPieces {
+        encoder: Some(IncrementalPackEncoder::default()),
+        header: Some(count),
+        entries: packfile.entries().iter(),
+        sideband,
+    })
+}
+
+/// The pieces of an encoded packfile, see [`pack_stream`].
+#[cfg(feature = "tokio-util")]
+struct PackPieces<'a> {
+    /// Taken once the footer is written or encoding fails, ending the iterator.
+    encoder: Option<IncrementalPackEncoder>,
+    /// The number of entries to write in the header, taken once it's written.
+    header: Option<Result<u32, Error>>,
+    entries: std::slice::Iter<'a, PackFileEntry>,
+    sideband: Option<Sideband>,
+}
+
+#[cfg(feature = "tokio-util")]
+impl Iterator for PackPieces<'_> {
+    type Item = Result<Bytes, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let encoder = self.encoder.as_mut()?;
+
+        let piece = match self.header.take() {
+            Some(Ok(count)) => Ok(encoder.header(count)),
+            Some(Err(e)) => Err(e),
+            None => {
+                let Some(entry) = self.entries.next() else {
+                    let hash = self.encoder.take()?.footer();
+                    return Some(frame(&hash, self.sideband, true));
+                };
+                encoder.entry(entry)
+            }
+        };
+
+        match piece {
+            Ok(piece) => Some(frame(piece, self.sideband, false)),
+            Err(e) => {
+                self.encoder = None;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Frames a piece of a packfile to be sent on side-band channel 1, followed by a
+/// flush if it's the `last` piece. Without a side-band, the piece is sent as-is.
+#[cfg(feature = "tokio-util")]
+fn frame(piece: &[u8], sideband: Option<Sideband>, last: bool) -> Result<Bytes, Error> {
+    let Some(sideband) = sideband else {
+        return Ok(Bytes::copy_from_slice(piece));
+    };
+
+    let mut buf = BytesMut::new();
+    encode_sideband(&mut buf, SIDEBAND_DATA, piece, sideband.chunk_size())?;
+    if last {
+        PktLine::Flush.encode_to(&mut buf)?;
+    }
+
+    Ok(buf.freeze())
+}
+
+/// Encodes `packfile` as the whole body of a protocol v2 smart HTTP response to a
+/// `fetch` request the client sent `done` with, for `git-upload-pack` to reply with.
+///
+/// As the client has finished negotiating, the response is only the `packfile` section:
+/// the `packfile\n` section header, the packfile on side-band channel 1 split into
+/// packets as large as `side-band-64k` allows, then a flush. The result is a complete,
+/// self-contained body for `git-upload-pack --stateless-rpc` to send.
+///
+/// # Errors
+///
+/// Returns an error if the packfile fails to encode.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip(packfile), err))]
+pub fn http_pack_response(packfile: &PackFile<'_>) -> Result<Bytes, Error> {
+    let mut buf = BytesMut::new();
+    FetchResponse::new()
+        .with_packfile(packfile)
+        .encode_to(&mut buf)?;
+
+    Ok(buf.freeze())
+}
+
+/// Builds the response to a protocol v2 `fetch` command, writing its sections in the
+/// order the spec requires with delimiters between them:
+///
+/// - `acknowledgments`, unless the client sent `done`: `ACK`s for the client's
+///   `have`s the server has in common, or `NAK` if there are none, then `ready` if
+///   the packfile follows. Otherwise the response ends here, with a flush, and the
+///   client sends another round of `have`s.
+/// - `shallow-info`, if the client asked for a shallow fetch.
+/// - `wanted-refs`, if the client asked for refs with `want-ref`.
+/// - `packfile`, on side-band channel 1, then a flush.
+///
+/// <https://git-scm.com/docs/protocol-v2#_fetch>
+#[derive(Default, Clone)]
+pub struct FetchResponse<'a> {
+    /// The common objects to acknowledge and whether the server is ready, or `None`
+    /// if the client has sent `done`.
+    acknowledgments: Option<(Vec<HashOutput>, bool)>,
+    /// `shallow <oid>` and `unshallow <oid>` lines.
+    shallow_info: Vec<String>,
+    wanted_refs: Vec<(HashOutput, String)>,
+    pack: Option<Pack<'a>>,
+}
+
+/// The packfile sent in a [`FetchResponse`].
+#[derive(Clone)]
+enum Pack<'a> {
+    PackFile(&'a PackFile<'a>),
+    Encoded(Bytes),
+}
+
+impl<'a> FetchResponse<'a> {
+    /// Starts a response to a client that has sent `done`, so has no
+    /// `acknowledgments` section.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts the response with an `acknowledgments` section, for a client that
+    /// hasn't sent `done`. `common` are the client's `have`s the server also has, and
+    /// `ready` is whether the server has found enough of them to send the packfile in
+    /// this response.
+    #[must_use]
+    pub fn with_acknowledgments(
+        mut self,
+        common: impl IntoIterator<Item = HashOutput>,
+        ready: bool,
+    ) -> Self {
+        self.acknowledgments = Some((common.into_iter().collect(), ready));
+        self
+    }
+
+    /// Tells a shallow client that `oid` is now a shallow boundary, as its parents
+    /// aren't being sent.
+    #[must_use]
+    pub fn with_shallow(mut self, oid: HashOutput) -> Self {
+        self.shallow_info
+            .push(format!("shallow {}", hex::encode(oid)));
+        self
+    }
+
+    /// Tells a shallow client that `oid` is no longer a shallow boundary, as its
+    /// parents are being sent.
+    #[must_use]
+    pub fn with_unshallow(mut self, oid: HashOutput) -> Self {
+        self.shallow_info
+            .push(format!("unshallow {}", hex::encode(oid)));
+        self
+    }
+
+    /// Tells the client the ref `name` it asked for with `want-ref` points to `oid`.
+    #[must_use]
+    pub fn with_wanted_ref(mut self, oid: HashOutput, name: impl Into<String>) -> Self {
+        self.wanted_refs.push((oid, name.into()));
+        self
+    }
+
+    /// Sends `packfile` in the `packfile` section, encoding it when the response is.
+    #[must_use]
+    pub fn with_packfile(mut self, packfile: &'a PackFile<'a>) -> Self {
+        self.pack = Some(Pack::PackFile(packfile));
+        self
+    }
+
+    /// Sends an already encoded packfile in the `packfile` section.
+    #[must_use]
+    pub fn with_pack_data(mut self, data: Bytes) -> Self {
+        self.pack = Some(Pack::Encoded(data));
+        self
+    }
+
+    /// Writes the response out to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidFetchResponse`] if a packfile, `shallow-info` or
+    /// `wanted-refs` is given while negotiation isn't ready, or no packfile is given
+    /// once it is, [`Error::InvalidRefName`] if a wanted ref's name is invalid, or an
+    /// error if the packfile fails to encode.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf), err))]
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        let ready = self
+            .acknowledgments
+            .as_ref()
+            .is_none_or(|(_, ready)| *ready);
+
+        if !ready
+            && (self.pack.is_some()
+                || !self.shallow_info.is_empty()
+                || !self.wanted_refs.is_empty())
+        {
+            return Err(Error::InvalidFetchResponse(
+                "the packfile can only be sent once negotiation is ready or done",
+            ));
+        } else if ready && self.pack.is_none() {
+            return Err(Error::InvalidFetchResponse(
+                "a packfile must be sent once negotiation is ready or done",
+            ));
+        }
+
+        for (_, name) in &self.wanted_refs {
+            crate::refs::validate_refname(name)?;
+        }
+
+        if let Some((common, ready)) = &self.acknowledgments {
+            PktLine::text("acknowledgments").encode_to(buf)?;
+
+            if common.is_empty() {
+                PktLine::text("NAK").encode_to(buf)?;
+            }
+
+            for oid in common {
+                PktLine::from(format!("ACK {}\n", hex::encode(oid))).encode_to(buf)?;
+            }
+
+            if !ready {
+                PktLine::Flush.encode_to(buf)?;
+                return Ok(());
+            }
+
+            PktLine::text("ready").encode_to(buf)?;
+            PktLine::Delimiter.encode_to(buf)?;
+        }
+
+        if !self.shallow_info.is_empty() {
+            PktLine::text("shallow-info").encode_to(buf)?;
+            for line in &self.shallow_info {
+                PktLine::text(line).encode_to(buf)?;
+            }
+            PktLine::Delimiter.encode_to(buf)?;
+        }
+
+        if !self.wanted_refs.is_empty() {
+            PktLine::text("wanted-refs").encode_to(buf)?;
+            for (oid, name) in &self.wanted_refs {
+                PktLine::from(format!("{} {name}\n", hex::encode(oid))).encode_to(buf)?;
+            }
+            PktLine::Delimiter.encode_to(buf)?;
+        }
+
+        let data = match &self.pack {
+            Some(Pack::PackFile(packfile)) => {
+                let mut data = BytesMut::new();
+                packfile.encode_to(&mut data)?;
+                data.freeze()
+            }
+            Some(Pack::Encoded(data)) => data.clone(),
+            None => unreachable!("checked above"),
+        };
+
+        PktLine::text("packfile").encode_to(buf)?;
+        PktLine::SidebandRaw(data).encode_to(buf)?;
+        PktLine::Flush.encode_to(buf)?;
+
+        Ok(())
+    }
+}
+
+/// Parses the positive decimal number from a `deepen` or `deepen-since` line.
+/// Parses a number the way `git upload-pack` does for `deepen` and `deepen-since`,
+/// using `strtol` with a base of 0: after any leading whitespace and `+`, a `0x`
+/// prefix means the number is in hex and a leading `0` means it's in octal.
+fn parse_number(value: &[u8]) -> Option<u64> {
+    let value = std::str::from_utf8(value).ok()?.trim_start();
+    let value = value.strip_prefix('+').unwrap_or(value);
+
+    let (digits, radix) = if let Some(hex) = value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if let Some(octal) = value.strip_prefix('0').filter(|rest| !rest.is_empty()) {
+        (octal, 8)
+    } else {
+        (value, 10)
+    };
+
+    // `from_str_radix` would otherwise accept a second sign
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    u64::from_str_radix(digits, radix).ok()
+}
+
+fn parse_oid(line: &[u8], oid: &[u8]) -> Result<HashOutput, Error> {
+    let mut out = HashOutput::default();
+    hex::decode_to_slice(oid, &mut out).map_err(|_| malformed(line))?;
+    Ok(out)
+}
+
+fn malformed(line: &[u8]) -> Error {
+    Error::MalformedFetchRequest(String::from_utf8_lossy(line).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fetch::FetchRequest;
+
+    #[test]
+    fn parse_v1_capabilities() {
+        let with = FetchRequest::parse([
+            &b"want 0101010101010101010101010101010101010101 multi_ack side-band-64k ofs-delta"[..],
+            b"want 0202020202020202020202020202020202020202",
+            b"have 0303030303030303030303030303030303030303",
+            b"done",
+        ])
+        .unwrap();
+        assert!(with.ofs_delta);
+        assert!(with.done);
+        assert_eq!(with.wants, vec![[1; 20], [2; 20]]);
+        assert_eq!(with.haves, vec![[3; 20]]);
+        assert!(with.capabilities.contains("side-band-64k"));
+
+        let without = FetchRequest::parse([
+            &b"want 0101010101010101010101010101010101010101 multi_ack side-band-64k"[..],
+        ])
+        .unwrap();
+        assert!(!without.ofs_delta);
+        assert!(!without.thin_pack);
+        assert!(!without.done);
+    }
+
+    #[test]
+    fn parse_v2_arguments() {
+        let with = FetchRequest::parse([
+            &b"agent=git/2.39.5"[..],
+            b"thin-pack",
+            b"ofs-delta",
+            b"want 0101010101010101010101010101010101010101",
+        ])
+        .unwrap();
+        assert!(with.ofs_delta);
+        assert!(with.thin_pack);
+        assert_eq!(with.capabilities.get("agent"), Some("git/2.39.5"));
+
+        let thin_pack_only = FetchRequest::parse([
+            &b"thin-pack"[..],
+            b"want 0101010101010101010101010101010101010101",
+        ])
+        .unwrap();
+        assert!(!thin_pack_only.ofs_delta);
+        assert!(thin_pack_only.thin_pack);
+
+        let ofs_delta_only = FetchRequest::parse([
+            &b"ofs-delta"[..],
+            b"want 0101010101010101010101010101010101010101",
+        ])
+        .unwrap();
+        assert!(ofs_delta_only.ofs_delta);
+        assert!(!ofs_delta_only.thin_pack);
+    }
+
+    #[test]
+    fn fetch_request_builder() {
+        use super::{DepthSpec, FetchRequestBuilder};
+        use bytes::BytesMut;
+
+        let ones = "01".repeat(20);
+        let twos = "02".repeat(20);
+        let builder = FetchRequestBuilder::new()
+            .with_capability("agent=packfile/0.1")
+            .with_feature("thin-pack")
+            .with_feature("ofs-delta")
+            .with_depth(DepthSpec {
+                depth: Some(1),
+                ..DepthSpec::default()
+            })
+            .with_want([1; 20])
+            .with_have([2; 20])
+            .with_done();
+
+        let mut buf = BytesMut::new();
+        builder.encode_to(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            format!(
+                "0012command=fetch\n0017agent=packfile/0.1\n0001000ethin-pack\n\
+                 000eofs-delta\n000ddeepen 1\n0032want {ones}\n0032have {twos}\n\
+                 0009done\n0000"
+            )
+        );
+
+        // the request reads back the same as a client's
+        let lines = crate::PktLineIter::new(&buf)
+            .skip_while(|frame| !matches!(frame, Ok(crate::Frame::Delimiter)))
+            .filter_map(|frame| match frame.unwrap() {
+                crate::Frame::Data(line) => Some(line.strip_suffix(b"\n").unwrap()),
+                _ => None,
+            });
+        let request = FetchRequest::parse(lines).unwrap();
+        assert_eq!(
+            (request.wants, request.haves, request.depth.depth),
+            (vec![[1; 20]], vec![[2; 20]], Some(1))
+        );
+        assert!(request.done && request.thin_pack && request.ofs_delta);
+
+        let mut buf = BytesMut::new();
+        builder.encode_v0_to(&mut buf).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            format!(
+                "0059want {ones} thin-pack ofs-delta agent=packfile/0.1\n000ddeepen 1\n\
+                 00000032have {twos}\n0009done\n"
+            )
+        );
+
+        // nonsense requests are refused before they're sent
+        for (builder, err) in [
+            (FetchRequestBuilder::new().with_done(), "want"),
+            (
+                FetchRequestBuilder::new()
+                    .with_want([1; 20])
+                    .with_depth(DepthSpec {
+                        depth: Some(0),
+                        ..DepthSpec::default()
+                    }),
+                "depth",
+            ),
+            (
+                FetchRequestBuilder::new()
+                    .with_want([1; 20])
+                    .with_filter("blob:none\ndone"),
+                "newline",
+            ),
+        ] {
+            assert!(matches!(
+                builder.encode_to(&mut BytesMut::new()),
+                Err(crate::Error::InvalidFetchRequest(msg)) if msg.contains(err)
+            ));
+        }
+        assert!(matches!(
+            FetchRequestBuilder::new()
+                .with_want([1; 20])
+                .with_depth(DepthSpec {
+                    depth: Some(1),
+                    since: Some(1),
+                    ..DepthSpec::default()
+                })
+                .encode_v0_to(&mut BytesMut::new()),
+            Err(crate::Error::ConflictingDeepen)
+        ));
+    }
+
+    #[test]
+    fn fetch_request_builder_sent_to_upload_pack() {
+        use super::FetchRequestBuilder;
+        use crate::{high_level::GitRepository, test::git, Frame, PktLineIter, SIDEBAND_DATA};
+        use bytes::{Bytes, BytesMut};
+        use std::process::{Command, Stdio};
+
+        let mut repo = GitRepository::default();
+        repo.insert(&[], "a.txt", Bytes::from("a\n")).unwrap();
+        let (first, entries) = repo
+            .clone()
+            .commit("me", "me@example.com", "first")
+            .unwrap();
+        let mut packed = BytesMut::new();
+        crate::low_level::PackFile::new(&entries)
+            .encode_to(&mut packed)
+            .unwrap();
+        let server = crate::test::bare_repo_with_pack(&packed);
+        git(
+            server.path(),
+            &["update-ref", "HEAD", &hex::encode(first)],
+            &[],
+        );
+
+        // sends the request to `git upload-pack`, returning the packfile it answers
+        // with on side-band channel 1 after the section header
+        let fetch = |protocol: &str, request: &[u8], header: &[u8]| {
+            let mut child = Command::new("git")
+                .args(["upload-pack", "--stateless-rpc"])
+                .arg(server.path())
+                .env("GIT_PROTOCOL", protocol)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap();
+            std::io::Write::write_all(&mut child.stdin.take().unwrap(), request).unwrap();
+            let out = child.wait_with_output().unwrap();
+            assert!(out.status.success());
+
+            let mut frames = PktLineIter::new(&out.stdout);
+            assert_eq!(frames.next().unwrap().unwrap(), Frame::Data(header));
+            let mut pack = Vec::new();
+            for frame in frames {
+                match frame.unwrap() {
+                    Frame::Data([SIDEBAND_DATA, data @ ..]) => pack.extend_from_slice(data),
+                    Frame::Flush => break,
+                    frame => panic!("unexpected frame {frame:?}"),
+                }
+            }
+            pack
+        };
+
+        let request = FetchRequestBuilder::new()
+            .with_feature("ofs-delta")
+            .with_feature("no-progress")
+            .with_want(first)
+            .with_done();
+
+        let mut v2 = BytesMut::new();
+        request.encode_to(&mut v2).unwrap();
+        let pack = fetch("version=2", &v2, b"packfile\n");
+        let unpacked = crate::test::bare_repo_with_pack(&pack);
+        assert_eq!(
+            git(
+                unpacked.path(),
+                &["show", &format!("{}:a.txt", hex::encode(first))],
+                &[]
+            ),
+            "a\n"
+        );
+
+        let mut v0 = BytesMut::new();
+        request
+            .with_feature("side-band-64k")
+            .encode_v0_to(&mut v0)
+            .unwrap();
+        assert_eq!(fetch("", &v0, b"NAK\n"), pack);
+    }
+
+    /// Parses the arguments of a request as traced by `GIT_TRACE_PACKET`.
+    fn parse_trace(trace: &str) -> Result<FetchRequest, crate::Error> {
+        FetchRequest::parse(trace.lines().map(str::as_bytes))
+    }
+
+    #[test]
+    fn parse_depth() {
+        use super::DepthSpec;
+
+        const WANT: &str = "b551e21d4b638e317d88d7709ee8cade6e5401c7";
+        const SHALLOW: &str = "7ac43a6b2d779954bffc746a0b7761a3ef3aef1a";
+
+        // git clone --depth 2
+        let request = parse_trace(&format!(
+            "thin-pack\nno-progress\ninclude-tag\nofs-delta\ndeepen 2\n\
+             want {WANT}\nwant {WANT}\ndone"
+        ))
+        .unwrap();
+        assert_eq!(
+            request.depth,
+            DepthSpec {
+                depth: Some(2),
+                ..DepthSpec::default()
+            }
+        );
+        assert!(request.depth.is_deepening());
+        assert!(!request.capabilities.contains("deepen"));
+
+        // git clone --shallow-since=1700000002
+        let request = parse_trace(&format!(
+            "thin-pack\nno-progress\ninclude-tag\nofs-delta\ndeepen-since 1700000002\n\
+             want {WANT}\nwant {WANT}\ndone"
+        ))
+        .unwrap();
+        assert_eq!(request.depth.since, Some(1_700_000_002));
+        assert_eq!(request.depth.depth, None);
+
+        // git fetch --deepen=1, from a shallow clone
+        let request = parse_trace(&format!(
+            "thin-pack\nno-progress\ninclude-tag\nofs-delta\nshallow {SHALLOW}\ndeepen 1\n\
+             deepen-relative\nwant {WANT}\nhave {WANT}\nhave {SHALLOW}"
+        ))
+        .unwrap();
+        assert_eq!(
+            request.depth,
+            DepthSpec {
+                depth: Some(1),
+                relative: true,
+                shallows: vec![crate::decode_hash(SHALLOW).unwrap()],
+                ..DepthSpec::default()
+            }
+        );
+
+        // an unshallow fetch only tells the server where its history is cut off
+        let request = parse_trace(&format!("shallow {SHALLOW}\nwant {WANT}")).unwrap();
+        assert!(!request.depth.is_deepening());
+        assert_eq!(
+            request.depth.shallows,
+            [crate::decode_hash(SHALLOW).unwrap()]
+        );
+
+        // git clone --depth 2 --shallow-exclude=v1, which git upload-pack rejects
+        let err = parse_trace(&format!(
+            "want {WANT} multi_ack_detailed side-band-64k thin-pack no-progress include-tag \
+             ofs-delta deepen-since deepen-not agent=git/2.39.5\n\
+             want {WANT}\ndeepen 2\ndeepen-not v1"
+        ))
+        .unwrap_err();
+        assert!(matches!(err, crate::Error::ConflictingDeepen));
+        assert_eq!(
+            err.to_string(),
+            "deepen and deepen-since (or deepen-not) cannot be used together"
+        );
+
+        let request = parse_trace("deepen-not v1\ndeepen-not refs/tags/v2").unwrap();
+        assert_eq!(request.depth.not, ["v1", "refs/tags/v2"]);
+
+        // numbers are read as `git upload-pack` reads them, with `strtol`
+        for (line, depth) in [
+            ("deepen +1", 1),
+            ("deepen 0x10", 16),
+            ("deepen 010", 8),
+            ("deepen  5", 5),
+        ] {
+            assert_eq!(
+                parse_trace(line).unwrap().depth.depth,
+                Some(depth),
+                "{line}"
+            );
+        }
+        assert_eq!(parse_trace("deepen-since 0").unwrap().depth.since, Some(0));
+
+        for invalid in [
+            "deepen 0",
+            "deepen -1",
+            "deepen 08",
+            "deepen 0x",
+            "deepen +-1",
+            "deepen ",
+            "deepen 1 ",
+            "deepen 99999999999",
+            "deepen-since yesterday",
+            "shallow 7ac4",
+        ] {
+            assert!(
+                matches!(
+                    parse_trace(invalid),
+                    Err(crate::Error::MalformedFetchRequest(line)) if line == invalid
+                ),
+                "{invalid} should be rejected"
+            );
+        }
+    }
+
+    mod negotiation {
+        use crate::{
+            capabilities::CapabilitySet,
+            fetch::{AckMode, Negotiation},
+            PktLine,
+        };
+        use bytes::BytesMut;
+
+        /// The text of each line, with the object ids shortened to their first byte.
+        fn text(lines: impl IntoIterator<Item = PktLine<'static>>) -> Vec<String> {
+            lines
+                .into_iter()
+                .map(|line| {
+                    let mut buf = BytesMut::new();
+                    line.encode_to(&mut buf).unwrap();
+                    String::from_utf8(buf[4..].to_vec())
+                        .unwrap()
+                        .replace(&"01".repeat(20), "A")
+                        .replace(&"02".repeat(20), "B")
+                        .replace(&"03".repeat(20), "C")
+                        .replace(&"04".repeat(20), "D")
+                })
+                .collect()
+        }
+
+        fn start(caps: &[u8]) -> Negotiation {
+            Negotiation::new(&CapabilitySet::parse(caps))
+        }
+
+        #[test]
+        fn mode() {
+            assert_eq!(start(b"side-band-64k").mode(), AckMode::Single);
+            assert_eq!(start(b"multi_ack").mode(), AckMode::MultiAck);
+            assert_eq!(
+                start(b"multi_ack multi_ack_detailed").mode(),
+                AckMode::MultiAckDetailed
+            );
+        }
+
+        #[test]
+        fn multi_ack_detailed() {
+            let mut negotiation = start(b"multi_ack_detailed side-band-64k");
+
+            // the first round finds a common object, but not enough to be ready
+            assert!(negotiation.have([1; 20], false, false).is_none());
+            assert_eq!(
+                text(negotiation.have([2; 20], true, false)),
+                ["ACK B common\n"]
+            );
+            assert!(negotiation.have([3; 20], false, false).is_none());
+            assert_eq!(text(negotiation.flush()), ["NAK\n"]);
+            assert!(!negotiation.is_finished());
+
+            // the second round only has common objects, so the server tells the
+            // client it's ready when it flushes
+            assert_eq!(
+                text(negotiation.have([4; 20], true, true)),
+                ["ACK D common\n"]
+            );
+            assert_eq!(text(negotiation.flush()), ["ACK D ready\n", "NAK\n"]);
+            assert!(!negotiation.is_finished());
+
+            assert_eq!(text(negotiation.done()), ["ACK D\n"]);
+            assert!(negotiation.is_finished());
+        }
+
+        #[test]
+        fn multi_ack_detailed_ready_on_other() {
+            let mut negotiation = start(b"multi_ack_detailed");
+
+            assert_eq!(
+                text(negotiation.have([1; 20], true, true)),
+                ["ACK A common\n"]
+            );
+            // once ready, objects the server doesn't have get a ready ack
+            assert_eq!(
+                text(negotiation.have([2; 20], false, true)),
+                ["ACK B ready\n"]
+            );
+            assert_eq!(text(negotiation.flush()), ["NAK\n"]);
+            assert_eq!(text(negotiation.done()), ["ACK A\n"]);
+        }
+
+        #[test]
+        fn no_done() {
+            let mut negotiation = start(b"multi_ack_detailed no-done");
+
+            assert!(negotiation.have([1; 20], false, false).is_none());
+            assert_eq!(text(negotiation.flush()), ["NAK\n"]);
+            assert!(!negotiation.is_finished());
+
+            assert_eq!(
+                text(negotiation.have([2; 20], true, true)),
+                ["ACK B common\n"]
+            );
+            // the client won't send done, so the server finishes the negotiation
+            assert_eq!(
+                text(negotiation.flush()),
+                ["ACK B ready\n", "NAK\n", "ACK B\n"]
+            );
+            assert!(negotiation.is_finished());
+
+            // no-done requires multi_ack_detailed
+            let mut negotiation = start(b"multi_ack no-done");
+            negotiation.have([2; 20], true, true);
+            assert_eq!(text(negotiation.flush()), ["NAK\n"]);
+            assert!(!negotiation.is_finished());
+        }
+
+        #[test]
+        fn multi_ack() {
+            let mut negotiation = start(b"multi_ack");
+
+            assert!(negotiation.have([1; 20], false, false).is_none());
+            assert_eq!(
+                text(negotiation.have([2; 20], true, false)),
+                ["ACK B continue\n"]
+            );
+            assert_eq!(
+                text(negotiation.have([3; 20], true, true)),
+                ["ACK C continue\n"]
+            );
+            assert_eq!(
+                text(negotiation.have([4; 20], false, true)),
+                ["ACK D continue\n"]
+            );
+            assert_eq!(text(negotiation.flush()), ["NAK\n"]);
+            assert_eq!(text(negotiation.done()), ["ACK C\n"]);
+        }
+
+        #[test]
+        fn single() {
+            let mut negotiation = start(b"side-band-64k");
+
+            assert!(negotiation.have([1; 20], false, false).is_none());
+            assert_eq!(text(negotiation.flush()), ["NAK\n"]);
+
+            // only the first common object is acknowledged, after which flushes get
+            // no response at all
+            assert_eq!(text(negotiation.have([2; 20], true, false)), ["ACK B\n"]);
+            assert!(negotiation.have([3; 20], true, false).is_none());
+            assert!(text(negotiation.flush()).is_empty());
+            assert!(negotiation.done().is_none());
+
+            let mut negotiation = start(b"");
+            assert_eq!(text(negotiation.done()), ["NAK\n"]);
+        }
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[tokio::test]
+    async fn pack_stream() {
+        use crate::{
+            capabilities::Sideband,
+            low_level::{PackFile, PackFileEntry},
+        };
+        use bytes::{Bytes, BytesMut};
+        use futures_util::StreamExt;
+
+        // incompressible data so the pack spans multiple packets
+        let mut state = 0x2545_f491_u32;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state.to_le_bytes()[0]
+            })
+            .collect();
+        let entries = vec![
+            PackFileEntry::Blob(Bytes::from(data)),
+            PackFileEntry::Blob(Bytes::from_static(b"hello world")),
+        ];
+        let packfile = PackFile::new(&entries);
+
+        let mut expected = BytesMut::new();
+        packfile.encode_to(&mut expected).unwrap();
+
+        for sideband in [Sideband::SideBand, Sideband::SideBand64k] {
+            // the header, each entry and the footer are yielded separately
+            let pieces: Vec<_> = super::pack_stream(&packfile, Some(sideband))
+                .map(Result::unwrap)
+                .collect()
+                .await;
+            assert_eq!(pieces.len(), entries.len() + 2);
+            let encoded = pieces.concat();
+
+            // reassemble the packfile from the data packets
+            let mut actual = Vec::new();
+            let mut rest = &encoded[..];
+            loop {
+                let len =
+                    usize::from_str_radix(std::str::from_utf8(&rest[..4]).unwrap(), 16).unwrap();
+                if len == 0 {
+                    assert_eq!(rest, b"0000", "flush should come last");
+                    break;
+                }
+
+                assert!(len <= sideband.max_packet_len());
+                assert_eq!(
+                    rest[4],
+                    crate::SIDEBAND_DATA,
+                    "data should be on side-band channel 1"
+                );
+                actual.extend_from_slice(&rest[5..len]);
+                rest = &rest[len..];
+            }
+            assert_eq!(actual, expected);
+        }
+
+        let pieces: Vec<_> = super::pack_stream(&packfile, None)
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        assert_eq!(pieces.concat(), expected);
+
+        // a pack that fails to encode only yields the error
+        let dangling = [PackFileEntry::RefDelta {
+            base: [1; 20],
+            hash: [2; 20],
+            delta: Bytes::new(),
+        }];
+        let results: Vec<_> = super::pack_stream(&PackFile::new(&dangling), None)
+            .collect()
+            .await;
+        assert!(matches!(results.as_slice(), [Err(_)]));
+    }
+
+    #[test]
+    fn http_pack_response() {
+        use crate::{high_level::GitRepository, low_level::PackFile, test::git, Parsed, PktLine};
+        use bytes::{Bytes, BytesMut};
+        use std::{
+            io::Write,
+            process::{Command, Stdio},
+        };
+
+        /// Splits a response into its packets, concatenating the packfile rather than
+        /// keeping the packets it was split across.
+        fn packets(mut response: &[u8]) -> (Vec<Parsed<'_>>, Vec<u8>) {
+            let mut packets = Vec::new();
+            let mut pack = Vec::new();
+
+            while !response.is_empty() {
+                let (packet, consumed) = PktLine::parse(response).unwrap();
+                match packet {
+                    Parsed::Data([crate::SIDEBAND_DATA, data @ ..]) => {
+                        if pack.is_empty() {
+                            packets.push(Parsed::Data(&[crate::SIDEBAND_DATA]));
+                        }
+                        pack.extend_from_slice(data);
+                    }
+                    packet => packets.push(packet),
+                }
+                response = &response[consumed..];
+            }
+
+            (packets, pack)
+        }
+
+        let mut repo = GitRepository::default();
+        repo.insert(&["src"], "main.rs", Bytes::from("fn main() {}\n"))
+            .unwrap();
+        repo.insert(&[], "big.bin", Bytes::from(vec![7; 100_000]))
+            .unwrap();
+        let (commit, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        let commit = hex::encode(commit);
+
+        let ours = super::http_pack_response(&PackFile::new(&entries)).unwrap();
+
+        // capture the response git itself gives to the same request over smart HTTP
+        let mut packed = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut packed).unwrap();
+        let dir = crate::test::bare_repo_with_pack(&packed);
+        git(
+            dir.path(),
+            &["update-ref", "refs/heads/master", &commit],
+            &[],
+        );
+
+        let want = format!("want {commit}\n");
+        let mut request = BytesMut::new();
+        for line in [
+            PktLine::Data(b"command=fetch\n"),
+            PktLine::Delimiter,
+            PktLine::Data(b"no-progress\n"),
+            PktLine::Data(want.as_bytes()),
+            PktLine::Data(b"done\n"),
+            PktLine::Flush,
+        ] {
+            line.encode_to(&mut request).unwrap();
+        }
+
+        let mut child = Command::new("git")
+            .args(["upload-pack", "--stateless-rpc"])
+            .arg(dir.path())
+            .env("GIT_PROTOCOL", "version=2")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(&request).unwrap();
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success());
+
+        let (our_packets, our_pack) = packets(&ours);
+        let (git_packets, git_pack) = packets(&out.stdout);
+        assert_eq!(
+            our_packets,
+            [
+                Parsed::Data(b"packfile\n"),
+                Parsed::Data(&[crate::SIDEBAND_DATA]),
+                Parsed::Flush
+            ]
+        );
+        assert_eq!(our_packets, git_packets);
+
+        // git packs the objects differently, but both packs must hold the same objects
+        let list = |pack: &[u8]| {
+            let listing = crate::test::verify_pack_file(Bytes::copy_from_slice(pack));
+            let mut objects = listing
+                .lines()
+                .filter_map(|line| line.split_once(' '))
+                .filter(|(hash, _)| hash.len() == 40)
+                .map(|(hash, _)| hash.to_string())
+                .collect::<Vec<_>>();
+            objects.sort();
+            objects
+        };
+        assert_eq!(list(&our_pack), list(&git_pack));
+        assert_eq!(list(&our_pack).len(), entries.len());
+    }
+
+    #[test]
+    fn fetch_response() {
+        use super::FetchResponse;
+        use bytes::{Bytes, BytesMut};
+
+        let encode = |response: FetchResponse<'_>| {
+            let mut buf = BytesMut::new();
+            response.encode_to(&mut buf).map(|()| buf)
+        };
+        let pack = Bytes::from_static(b"PACK");
+
+        assert_eq!(
+            encode(
+                FetchResponse::new()
+                    .with_acknowledgments([[1; 20], [2; 20]], true)
+                    .with_shallow([3; 20])
+                    .with_unshallow([4; 20])
+                    .with_wanted_ref([5; 20], "refs/heads/main")
+                    .with_pack_data(pack.clone())
+            )
+            .unwrap(),
+            format!(
+                "0014acknowledgments\n\
+                 0031ACK {}\n\
+                 0031ACK {}\n\
+                 000aready\n\
+                 0001\
+                 0011shallow-info\n\
+                 0035shallow {}\n\
+                 0037unshallow {}\n\
+                 0001\
+                 0010wanted-refs\n\
+                 003d{} refs/heads/main\n\
+                 0001\
+                 000dpackfile\n\
+                 0009\x01PACK\
+                 0000",
+                "01".repeat(20),
+                "02".repeat(20),
+                "03".repeat(20),
+                "04".repeat(20),
+                "05".repeat(20),
+            )
+            .as_bytes()
+        );
+
+        // still negotiating, so only the acknowledgments are sent
+        assert_eq!(
+            encode(FetchResponse::new().with_acknowledgments([], false)).unwrap(),
+            &b"0014acknowledgments\n0008NAK\n0000"[..]
+        );
+
+        for response in [
+            FetchResponse::new()
+                .with_acknowledgments([[1; 20]], false)
+                .with_pack_data(pack.clone()),
+            FetchResponse::new()
+                .with_acknowledgments([], false)
+                .with_shallow([1; 20]),
+            FetchResponse::new().with_acknowledgments([[1; 20]], true),
+            FetchResponse::new(),
+        ] {
+            assert!(matches!(
+                encode(response),
+                Err(crate::Error::InvalidFetchResponse(_))
+            ));
+        }
+
+        assert!(matches!(
+            encode(
+                FetchResponse::new()
+                    .with_wanted_ref([1; 20], "refs/heads/a..b")
+                    .with_pack_data(pack)
+            ),
+            Err(crate::Error::InvalidRefName(_))
+        ));
+    }
+
+    /// Advertises `refs/heads/master` at `head` over protocol v2, and answers the next
+    /// fetch with `response`, without reading the client's `ls-refs` or `fetch` as
+    /// each test step knows the requests it expects.
+    fn serve(
+        server: &crate::test::ScriptServer,
+        head: crate::low_level::HashOutput,
+        response: &super::FetchResponse<'_>,
+    ) {
+        use crate::{capabilities::CapabilityAdvertisement, PktLine};
+
+        let mut buf = bytes::BytesMut::new();
+        CapabilityAdvertisement::default()
+            .with_ls_refs([] as [&str; 0])
+            .with_fetch(["shallow"])
+            .encode_to(&mut buf)
+            .unwrap();
+        PktLine::ls_refs_line(head, "HEAD", Some("refs/heads/master"))
+            .encode_to(&mut buf)
+            .unwrap();
+        PktLine::ls_refs_line(head, "refs/heads/master", None)
+            .encode_to(&mut buf)
+            .unwrap();
+        PktLine::Flush.encode_to(&mut buf).unwrap();
+        response.encode_to(&mut buf).unwrap();
+        server.write("response", &buf);
+    }
+
+    /// Runs a git command in `dir` talking to `server`.
+    fn run(server: &crate::test::ScriptServer, dir: &std::path::Path, args: &[&str]) {
+        let (subcommand, args) = args.split_first().unwrap();
+        let out = server
+            .git(2, subcommand)
+            .current_dir(dir)
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+
+    #[test]
+    fn fetch_response_served_to_git() {
+        use super::FetchResponse;
+        use crate::{
+            high_level::GitRepository,
+            low_level::{CommitBuilder, CommitUserInfo, HashOutput, PackFile, PackFileEntry},
+        };
+        use bytes::Bytes;
+        use std::path::Path;
+
+        // commits the repository's tree on top of `parent`, returning the commit's hash
+        // and every object needed to check it out
+        let commit = |repo: &GitRepository, parent: Option<HashOutput>| {
+            let user = CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap();
+            let (tree, mut entries) = repo.write_tree().unwrap();
+            let builder = CommitBuilder::new(tree, user.clone(), user).with_message("commit\n");
+            let commit = match parent {
+                Some(parent) => builder.with_parent(parent),
+                None => builder,
+            };
+            entries.push(PackFileEntry::Commit(commit.build().unwrap()));
+            (entries.last().unwrap().hash().unwrap(), entries)
+        };
+        let log = |dir: &Path, rev: &str| {
+            crate::test::git(dir, &["log", "--format=%H", rev], &[])
+                .lines()
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        };
+
+        let mut repo = GitRepository::default();
+        repo.insert(&[], "a.txt", Bytes::from("a\n")).unwrap();
+        let (first_hash, first) = commit(&repo, None);
+        repo.insert(&["src"], "b.txt", Bytes::from("b\n")).unwrap();
+        let (second_hash, second) = commit(&repo, Some(first_hash));
+        let (first_hex, second_hex) = (hex::encode(first_hash), hex::encode(second_hash));
+
+        let server = crate::test::ScriptServer::new(&[]);
+        let client = tempfile::TempDir::new().unwrap();
+        let clone = client.path().join("clone");
+
+        // a clone has nothing to negotiate with, so sends done straight away
+        let packfile = PackFile::owned(first);
+        serve(
+            &server,
+            first_hash,
+            &FetchResponse::new().with_packfile(&packfile),
+        );
+        run(
+            &server,
+            client.path(),
+            &["clone", "--quiet", &server.url(), "clone"],
+        );
+        assert_eq!(log(&clone, "HEAD"), [first_hex.as_str()]);
+
+        // fetching on top of it has the client's commit to negotiate with
+        let packfile = PackFile::owned(second);
+        serve(
+            &server,
+            second_hash,
+            &FetchResponse::new()
+                .with_acknowledgments([first_hash], true)
+                .with_packfile(&packfile),
+        );
+        run(&server, &clone, &["fetch", "--quiet", "origin"]);
+        assert_eq!(
+            log(&clone, "origin/master"),
+            [second_hex.as_str(), first_hex.as_str()]
+        );
+
+        // a shallow clone has to be told where history has been cut off
+        serve(
+            &server,
+            second_hash,
+            &FetchResponse::new()
+                .with_shallow(second_hash)
+                .with_packfile(&packfile),
+        );
+        run(
+            &server,
+            client.path(),
+            &["clone", "--quiet", "--depth=1", &server.url(), "shallow"],
+        );
+        let shallow = client.path().join("shallow");
+        assert_eq!(log(&shallow, "HEAD"), [second_hex.as_str()]);
+        assert_eq!(
+            std::fs::read_to_string(shallow.join(".git/shallow")).unwrap(),
+            format!("{second_hex}\n")
+        );
+        crate::test::git(&shallow, &["fsck", "--no-progress"], &[]);
+    }
+
+    #[test]
+    fn parse_invalid_oid() {
+        assert!(FetchRequest::parse([&b"want abc"[..]]).is_err());
+        assert!(FetchRequest::parse([&b"have 0101"[..]]).is_err());
+    }
+}
@@ -6,23 +6,35 @@
 //! for our purposes because `cargo` will `git pull --force` from our Git
 //! server, allowing us to ignore any history the client may have.
 
-use bytes::Bytes;
+#[cfg(feature = "serde")]
+mod persist;
+#[cfg(feature = "tokio")]
+mod tar;
+
+use std::collections::HashSet;
+
+use bytes::{Bytes, BytesMut};
 use indexmap::IndexMap;
 
 use crate::{
+    capabilities::Sideband,
     low_level::{
-        Commit, CommitUserInfo, HashOutput, PackFileEntry, TreeItem as LowLevelTreeItem,
-        TreeItemKind,
+        Commit, CommitUserInfo, HashOutput, ObjectKind, PackFile, PackFileEntry, Tag,
+        TreeItem as LowLevelTreeItem, TreeItemKind, EMPTY_TREE_HASH,
     },
-    util::ArcOrCowStr,
-    Error,
+    util::{ArcOrCowBytes, ArcOrCowStr},
+    Error, PktLine,
 };
 
 /// The main way of interacting with the high level Packfile builder
 ///
 /// Builds a whole packfile containing files, directories and commits - essentially
 /// building out a full Git repository in memory.
-#[derive(Default, Debug)]
+///
+/// Cloning a repository is cheap as file contents are reference counted, so a
+/// clone can be taken as a snapshot before applying a batch of changes to roll
+/// back to if any of them fail.
+#[derive(Default, Debug, Clone)]
 pub struct GitRepository {
     /// A map containing all the blobs and their corresponding hashes so they're
     /// not inserted more than once for any files in the whole tree with the same
@@ -31,22 +43,238 @@ pub struct GitRepository {
     /// An in-progress `Tree` currently being built out, the tree refers to items
     /// in `file_entries` by hash.
     tree: Tree,
+    /// The hashes of every commit this repository has written, which are kept after
+    /// the commits themselves have been drained so they can be tagged.
+    commits: HashSet<HashOutput>,
+    /// The number of entries a single tree can have before a warning is logged for
+    /// it on commit, see [`GitRepository::with_large_tree_threshold`].
+    #[cfg(feature = "tracing")]
+    large_tree_threshold: Option<usize>,
 }
 
 impl GitRepository {
+    /// Logs a warning for every tree with more than `threshold` entries when the
+    /// repository is committed. Git has no limit on the size of a tree, but trees
+    /// with hundreds of thousands of entries are slow to build and for clients to
+    /// check out, so this helps track down where they're coming from.
+    ///
+    /// See [`GitRepository::large_trees`] to find them without `tracing`.
+    #[cfg(feature = "tracing")]
+    #[must_use]
+    pub fn with_large_tree_threshold(mut self, threshold: usize) -> Self {
+        self.large_tree_threshold = Some(threshold);
+        self
+    }
+
     /// Inserts a file into the repository, writing a file to the path
     /// `path/to/my-file` would require a `path` of `["path", "to"]`
     /// and a `file` of `"my-file"`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotDirectory`] if one of the parts of `path` has already
+    /// been inserted as a file, or [`Error::InvalidName`] if `file` or one of the
+    /// parts of `path` is empty or contains `/` or NUL.
+    pub fn insert(
+        &mut self,
+        path: &[&'static str],
+        file: impl Into<ArcOrCowStr>,
+        content: Bytes,
+    ) -> Result<(), Error> {
+        self.insert_with_mode(path, file, content, TreeItemKind::File)
+    }
+
+    /// Inserts an executable file into the repository, as [`GitRepository::insert`]
+    /// does.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotDirectory`] if one of the parts of `path` has already
+    /// been inserted as a file, or [`Error::InvalidName`] if `file` or one of the
+    /// parts of `path` is empty or contains `/` or NUL.
+    pub fn insert_executable(
+        &mut self,
+        path: &[&'static str],
+        file: impl Into<ArcOrCowStr>,
+        content: Bytes,
+    ) -> Result<(), Error> {
+        self.insert_with_mode(path, file, content, TreeItemKind::Executable)
+    }
+
+    /// Inserts a file into the repository as [`GitRepository::insert`] does, but
+    /// only if nothing exists at that path yet. Returns `false` without modifying
+    /// the repository if something does.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotDirectory`] if one of the parts of `path` has already
+    /// been inserted as a file, or [`Error::InvalidName`] if `file` or one of the
+    /// parts of `path` is empty or contains `/` or NUL.
+    pub fn insert_if_absent(
+        &mut self,
+        path: &[&'static str],
+        file: impl Into<ArcOrCowStr>,
+        content: Bytes,
+    ) -> Result<bool, Error> {
+        let file = file.into();
+
+        if self.tree.get(path, &file).is_some() {
+            return Ok(false);
+        }
+
+        self.insert(path, file, content)?;
+        Ok(true)
+    }
+
+    /// Replaces a file in the repository as [`GitRepository::insert`] does, but
+    /// only if it currently has the content `expected_hash`. Returns `false`
+    /// without modifying the repository if the file has changed, doesn't exist, or
+    /// is a directory.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotDirectory`] if one of the parts of `path` has already
+    /// been inserted as a file, or [`Error::InvalidName`] if `file` or one of the
+    /// parts of `path` is empty or contains `/` or NUL.
+    pub fn insert_if_unchanged(
+        &mut self,
+        path: &[&'static str],
+        file: impl Into<ArcOrCowStr>,
+        expected_hash: HashOutput,
+        content: Bytes,
+    ) -> Result<bool, Error> {
+        let file = file.into();
+
+        match self.tree.get(path, &file) {
+            Some(TreeItem::File { hash, .. }) if *hash == expected_hash => {}
+            _ => return Ok(false),
+        }
+
+        self.insert(path, file, content)?;
+        Ok(true)
+    }
+
+    /// Returns the hash, size and mode of the file `name` within the directory at
+    /// `path`, without reading its content. Returns `None` if there's no file there,
+    /// including if `name` is a directory.
+    #[must_use]
+    pub fn stat(&self, path: &[&str], name: &str) -> Option<FileStat> {
+        let TreeItem::File { kind, hash } = self.tree.get(path, name)? else {
+            return None;
+        };
+
+        // submodules point at a commit in another repository, so have no blob here
+        let size = match kind {
+            TreeItemKind::Submodule => 0,
+            _ => self
+                .packfile_entries
+                .get(hash)
+                .map_or(0, PackFileEntry::uncompressed_size),
+        };
+
+        Some(FileStat {
+            hash: *hash,
+            size,
+            mode: *kind,
+        })
+    }
+
+    /// Inserts a file with the given `mode` into the repository, as
+    /// [`GitRepository::insert`] does.
+    ///
+    /// The `content` of a [`TreeItemKind::Symlink`] is the path it links to, and
+    /// the `content` of a [`TreeItemKind::Submodule`] is the 20 byte hash of the
+    /// commit it points to, which isn't written to the packfile.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotDirectory`] if one of the parts of `path` has already
+    /// been inserted as a file, [`Error::InvalidName`] if `file` or one of the parts
+    /// of `path` is empty or contains `/` or NUL, [`Error::InvalidFileMode`] if
+    /// `mode` is [`TreeItemKind::Directory`], or [`Error::InvalidSubmoduleHash`] if
+    /// a submodule's `content` isn't a hash.
     #[cfg_attr(
         feature = "tracing",
         tracing::instrument(skip(self, file, content), err)
     )]
-    pub fn insert(
+    pub fn insert_with_mode(
         &mut self,
         path: &[&'static str],
         file: impl Into<ArcOrCowStr>,
         content: Bytes,
+        mode: TreeItemKind,
+    ) -> Result<(), Error> {
+        self.insert_at(
+            path.iter().map(|part| (*part).into()),
+            file.into().into(),
+            content,
+            mode,
+        )
+    }
+
+    /// Inserts a file into the repository as [`GitRepository::insert`] does, but with
+    /// the names of the directories in `path` and of the file itself given as bytes, as
+    /// Git allows them to be any bytes other than `/` and NUL rather than only UTF-8.
+    /// This allows names such as Latin-1 encoded ones from another repository to be
+    /// written out verbatim.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotDirectory`] if one of the parts of `path` has already
+    /// been inserted as a file, or [`Error::InvalidName`] if `name` or one of the
+    /// parts of `path` is empty or contains `/` or NUL.
+    pub fn insert_bytes(
+        &mut self,
+        path: &[&[u8]],
+        name: &[u8],
+        content: Bytes,
+    ) -> Result<(), Error> {
+        self.insert_at(
+            path.iter().map(|part| part.to_vec().into()),
+            name.to_vec().into(),
+            content,
+            TreeItemKind::File,
+        )
+    }
+
+    /// Inserts a file into the directory at `path`, as [`GitRepository::insert_with_mode`]
+    /// does.
+    fn insert_at(
+        &mut self,
+        path: impl IntoIterator<Item = ArcOrCowBytes>,
+        file: ArcOrCowBytes,
+        content: Bytes,
+        kind: TreeItemKind,
     ) -> Result<(), Error> {
+        // check every name up front so a bad one doesn't leave behind the empty
+        // directories leading up to it
+        let path: Vec<_> = path.into_iter().collect();
+        if let Some(name) = path
+            .iter()
+            .chain(std::iter::once(&file))
+            .find(|name| name.is_empty() || name.contains(&b'/') || name.contains(&0))
+        {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+
+        // a submodule refers to a commit in another repository, so there's nothing
+        // to write to the packfile for it, and directories are built from the paths
+        // of the files within them
+        let (hash, entry) = match kind {
+            TreeItemKind::Directory => return Err(Error::InvalidFileMode(kind)),
+            TreeItemKind::Submodule => {
+                let hash = HashOutput::try_from(content.as_ref())
+                    .map_err(|_| Error::InvalidSubmoduleHash(content.len()))?;
+                (hash, None)
+            }
+            TreeItemKind::File | TreeItemKind::Executable | TreeItemKind::Symlink => {
+                // wrap the file in a Blob so it's ready for writing into the packfile,
+                // and also allows us to grab the hash of the file for use in the tree
+                let entry = PackFileEntry::Blob(content);
+                (entry.hash()?, Some(entry))
+            }
+        };
+
         // we'll initialise the directory to the root of the tree, this means
         // if a path isn't specified we'll just write it to the root directory
         let mut directory = &mut self.tree;
@@ -57,34 +285,51 @@ impl GitRepository {
         for part in path {
             let tree_item = directory
                 .0
-                .entry((*part).into())
+                .entry(part.clone())
                 .or_insert_with(|| Box::new(TreeItem::Tree(Tree::default())));
 
             if let TreeItem::Tree(d) = tree_item.as_mut() {
                 directory = d;
             } else {
-                return Err(Error::NotDirectory(part));
+                return Err(Error::NotDirectory(part.to_string()));
             }
         }
 
-        // wrap the file in a Blob so it's ready for writing into the packfile, and also
-        // allows us to grab the hash of the file for use in the tree
-        let entry = PackFileEntry::Blob(content);
-        let file_hash = entry.hash()?;
-
         // todo: what should we do on overwrite?
         directory
             .0
-            .insert(file.into(), Box::new(TreeItem::Blob(file_hash)));
+            .insert(file, Box::new(TreeItem::File { kind, hash }));
 
-        self.packfile_entries.insert(file_hash, entry);
+        if let Some(entry) = entry {
+            self.packfile_entries.insert(hash, entry);
+        }
 
         Ok(())
     }
 
+    /// Builds the trees for all the files currently in the repository without
+    /// committing them, returning the hash of the root tree along with every blob
+    /// and tree in the repository, as `git write-tree` does. The repository is left
+    /// as-is, so it can still be committed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the trees fail to serialise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn write_tree(&self) -> Result<(HashOutput, Vec<PackFileEntry>), Error> {
+        let mut entries = self.packfile_entries.clone();
+        let tree_hash = self.tree.clone().into_packfile_entries(&mut entries)?;
+
+        Ok((tree_hash, entries.into_values().collect()))
+    }
+
     /// Finalises this `GitRepository` by writing a commit to the `packfile_entries`,
     /// all the files currently in the `tree`, returning all the packfile entries
     /// and also the commit hash so it can be referred to by `ls-ref`s.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the trees or the commit fail to serialise.
     #[cfg_attr(
         feature = "tracing",
         tracing::instrument(skip(self, name, email, message), err)
@@ -95,39 +340,362 @@ impl GitRepository {
         email: &'static str,
         message: &'static str,
     ) -> Result<(HashOutput, Vec<PackFileEntry>), Error> {
-        // gets the hash of the entire tree from the root
-        let tree_hash = self
-            .tree
-            .into_packfile_entries(&mut self.packfile_entries)?;
-
-        // build the commit using the given inputs
-        let commit_user = CommitUserInfo {
-            name,
-            email,
+        let commit_hash = self.write_commit(name, email, message)?;
+
+        Ok((
+            commit_hash,
+            self.packfile_entries.into_iter().map(|(_, v)| v).collect(),
+        ))
+    }
+
+    /// Commits the repository as [`GitRepository::commit`] does, then writes an
+    /// annotated tag named `tag_name` pointing to the commit, tagged by the author of
+    /// the commit. Returns the hashes of the commit and the tag, along with all the
+    /// packfile entries, draining the repository.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the trees, the commit or the tag fail to serialise.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, name, email, commit_message, tag_message), err)
+    )]
+    pub fn commit_and_tag(
+        &mut self,
+        name: &'static str,
+        email: &'static str,
+        commit_message: &'static str,
+        tag_name: &str,
+        tag_message: &str,
+    ) -> Result<(HashOutput, HashOutput, Vec<PackFileEntry>), Error> {
+        let commit_hash = self.write_commit(name, email, commit_message)?;
+
+        let tagger = CommitUserInfo {
+            name: name.into(),
+            email: email.into(),
             time: time::OffsetDateTime::UNIX_EPOCH,
+            negative_utc: false,
         };
+        let tag = build_tag(
+            tag_name.to_string(),
+            commit_hash,
+            tagger,
+            tag_message.to_string(),
+            None,
+        );
+        let tag_hash = tag.hash()?;
+        self.packfile_entries.insert(tag_hash, tag);
 
-        let commit = PackFileEntry::Commit(Commit {
-            tree: tree_hash,
-            author: commit_user,
-            committer: commit_user,
-            message,
-        });
+        Ok((
+            commit_hash,
+            tag_hash,
+            self.packfile_entries.drain(..).map(|(_, v)| v).collect(),
+        ))
+    }
+
+    /// Builds the whole response to a protocol v0/v1 clone of this repository, for the
+    /// common case of serving a fresh clone of it: a `NAK`, as a client cloning has
+    /// nothing in common with us, followed by a packfile of every object in the
+    /// repository along with a commit of its files. With a `sideband`, such as one
+    /// picked by [`Sideband::from_capabilities`], the packfile is sent on side-band
+    /// channel 1 in packets as large as it allows then a flush, otherwise the
+    /// packfile is written out as-is.
+    ///
+    /// The commit is written to a clone of the repository, so it's left as-is and
+    /// can serve any number of clones. Commits are deterministic, so the returned
+    /// hash is the same each time and can be advertised to clients as `HEAD`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the trees or the commit fail to serialise, or the
+    /// packfile fails to encode.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, name, email, message), err)
+    )]
+    pub fn clone_response(
+        &self,
+        name: &'static str,
+        email: &'static str,
+        message: &'static str,
+        sideband: Option<Sideband>,
+    ) -> Result<(HashOutput, BytesMut), Error> {
+        let (commit_hash, entries) = self.clone().commit(name, email, message)?;
+        let packfile = PackFile::new(&entries);
+
+        let mut buf = BytesMut::new();
+        PktLine::text("NAK").encode_to(&mut buf)?;
+        if let Some(sideband) = sideband {
+            PktLine::SidebandData(packfile).encode_to_with_sideband(&mut buf, sideband)?;
+            PktLine::Flush.encode_to(&mut buf)?;
+        } else {
+            packfile.encode_to(&mut buf)?;
+        }
+
+        Ok((commit_hash, buf))
+    }
+
+    /// Writes the trees and a commit of them to the `packfile_entries`, leaving the
+    /// `tree` empty, and returns the hash of the commit.
+    fn write_commit(
+        &mut self,
+        name: &'static str,
+        email: &'static str,
+        message: &'static str,
+    ) -> Result<HashOutput, Error> {
+        #[cfg(feature = "tracing")]
+        if let Some(threshold) = self.large_tree_threshold {
+            for (path, entries) in self.large_trees(threshold) {
+                tracing::warn!(
+                    path = display_path(&path),
+                    entries,
+                    threshold,
+                    "tree has more entries than the large tree threshold"
+                );
+            }
+        }
+
+        // gets the hash of the entire tree from the root
+        let tree_hash =
+            std::mem::take(&mut self.tree).into_packfile_entries(&mut self.packfile_entries)?;
 
         // write the commit out to the packfile_entries
+        let commit = build_commit(tree_hash, name, email, message);
         let commit_hash = commit.hash()?;
         self.packfile_entries.insert(commit_hash, commit);
+        self.commits.insert(commit_hash);
+
+        Ok(commit_hash)
+    }
+
+    /// Writes a commit of an empty tree, which Git supports for repositories with
+    /// no files. Any files inserted into the repository aren't referenced by the
+    /// commit, but are still drained along with the empty tree and the commit, and
+    /// the repository's tree is cleared so later commits don't refer to them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the tree or the commit fail to serialise.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, name, email, message), err)
+    )]
+    pub fn commit_empty(
+        &mut self,
+        name: &'static str,
+        email: &'static str,
+        message: &'static str,
+    ) -> Result<(HashOutput, Vec<PackFileEntry>), Error> {
+        self.tree = Tree::default();
+        let tree = PackFileEntry::Tree(Vec::new());
+        self.packfile_entries.insert(EMPTY_TREE_HASH, tree);
+
+        let commit = build_commit(EMPTY_TREE_HASH, name, email, message);
+        let commit_hash = commit.hash()?;
+        self.packfile_entries.insert(commit_hash, commit);
+        self.commits.insert(commit_hash);
 
         Ok((
             commit_hash,
-            self.packfile_entries.into_iter().map(|(_, v)| v).collect(),
+            self.packfile_entries.drain(..).map(|(_, v)| v).collect(),
         ))
     }
+
+    /// Writes an annotated tag named `name` pointing to the commit `target`, which
+    /// must have been written by this repository, such as one returned by
+    /// [`GitRepository::commit_empty`], optionally signed with the armoured
+    /// `signature` over the rest of the tag. Returns the tag, anything held by the
+    /// repository is left in place to be committed.
+    ///
+    /// The signature is appended to the message as Git expects, so `message` should
+    /// end with a newline if it's given.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::DanglingReference`] if this repository hasn't written the
+    /// commit `target`, or an error if the tag fails to serialise.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, name, tagger, message, signature), err)
+    )]
+    pub fn tag(
+        &self,
+        name: impl Into<ArcOrCowStr>,
+        target: HashOutput,
+        tagger: CommitUserInfo,
+        message: impl Into<Bytes>,
+        signature: Option<Bytes>,
+    ) -> Result<(HashOutput, Vec<PackFileEntry>), Error> {
+        let tag = build_tag(name, target, tagger, message, signature);
+        let tag_hash = tag.hash()?;
+
+        if !self.commits.contains(&target) {
+            return Err(Error::DanglingReference {
+                referencing: tag_hash,
+                target,
+            });
+        }
+
+        Ok((tag_hash, vec![tag]))
+    }
+
+    /// Overlays the files in `other` onto this repository, such as user files on top
+    /// of a base template. Paths are compared file by file, the contents of files are
+    /// never merged.
+    ///
+    /// A path conflicts if the two repositories hold different files at it, or a file
+    /// in one where the other has a directory, which is resolved by `strategy`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MergeConflict`] with the first conflicting path if `strategy`
+    /// is [`MergeStrategy::Error`], in which case this repository is left unchanged.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, other), err))]
+    pub fn merge(&mut self, other: &GitRepository, strategy: MergeStrategy) -> Result<(), Error> {
+        if strategy == MergeStrategy::Error {
+            if let Some(path) = self.tree.find_conflict(&other.tree, &mut Vec::new()) {
+                return Err(Error::MergeConflict(display_path(&path)));
+            }
+        }
+
+        let mut taken = Vec::new();
+        self.tree.merge(
+            &other.tree,
+            strategy == MergeStrategy::TheirsWins,
+            &mut taken,
+        );
+
+        // bring along the blobs for any of their files we've taken, submodules don't
+        // have one
+        for hash in taken {
+            if let Some(PackFileEntry::Blob(content)) = other.packfile_entries.get(&hash) {
+                self.packfile_entries
+                    .entry(hash)
+                    .or_insert_with(|| PackFileEntry::Blob(content.clone()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Compares the files in this repository (the old state) to the files in `other`
+    /// (the new state), returning every file that was added, modified or deleted.
+    ///
+    /// Files are compared by hash and mode, so this never needs to look at their
+    /// content.
+    #[must_use]
+    pub fn diff<'a>(&'a self, other: &'a GitRepository) -> Vec<FileDiff<'a>> {
+        let mut out = Vec::new();
+        self.tree.diff(&other.tree, &mut Vec::new(), &mut out);
+        out
+    }
+
+    /// Returns the path to every tree with more than `threshold` entries, along with
+    /// the number of entries in it. The root tree has an empty path.
+    #[must_use]
+    pub fn large_trees(&self, threshold: usize) -> Vec<(Vec<&[u8]>, usize)> {
+        let mut out = Vec::new();
+        self.tree.large_trees(threshold, &mut Vec::new(), &mut out);
+        out
+    }
+}
+
+/// Builds a commit of `tree`, authored and committed by the given user.
+fn build_commit(
+    tree: HashOutput,
+    name: &'static str,
+    email: &'static str,
+    message: &'static str,
+) -> PackFileEntry {
+    let commit_user = CommitUserInfo {
+        name: name.into(),
+        email: email.into(),
+        time: time::OffsetDateTime::UNIX_EPOCH,
+        negative_utc: false,
+    };
+
+    PackFileEntry::Commit(Commit {
+        tree,
+        parents: Vec::new(),
+        author: commit_user.clone(),
+        committer: commit_user,
+        encoding: None,
+        extra_headers: Vec::new(),
+        message: message.into(),
+    })
+}
+
+/// Joins the parts of a path with `/` to show to a user, replacing any invalid UTF-8 in
+/// the names.
+fn display_path(path: &[&[u8]]) -> String {
+    String::from_utf8_lossy(&path.join(&b'/')).into_owned()
+}
+
+/// Builds an annotated tag of the commit `target`, see [`GitRepository::tag`].
+fn build_tag(
+    name: impl Into<ArcOrCowStr>,
+    target: HashOutput,
+    tagger: CommitUserInfo,
+    message: impl Into<Bytes>,
+    signature: Option<Bytes>,
+) -> PackFileEntry {
+    PackFileEntry::Tag(Tag {
+        object: target,
+        kind: ObjectKind::Commit,
+        name: name.into(),
+        tagger,
+        extra_headers: Vec::new(),
+        message: message.into(),
+        signature,
+    })
+}
+
+/// Metadata about a file within a [`GitRepository`], see [`GitRepository::stat`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileStat {
+    /// The hash of the file's blob, or of the commit a submodule points to.
+    pub hash: HashOutput,
+    /// The size of the file's content in bytes, which is always `0` for submodules.
+    pub size: usize,
+    /// The mode of the file's entry in its tree.
+    pub mode: TreeItemKind,
+}
+
+/// How [`GitRepository::merge`] resolves a path that conflicts between the two
+/// repositories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MergeStrategy {
+    /// The file or directory from the repository being merged in is kept.
+    TheirsWins,
+    /// The file or directory already in the repository is kept.
+    OursWins,
+    /// The merge fails with [`Error::MergeConflict`].
+    Error,
+}
+
+/// A change to a file between two states of a [`GitRepository`], as returned by
+/// [`GitRepository::diff`]. `path` is the full path to the file, including its name,
+/// with each part given as bytes as names aren't necessarily UTF-8.
+#[derive(Debug, PartialEq, Eq)]
+pub enum FileDiff<'a> {
+    Added {
+        path: Vec<&'a [u8]>,
+        hash: HashOutput,
+    },
+    Modified {
+        path: Vec<&'a [u8]>,
+        old_hash: HashOutput,
+        new_hash: HashOutput,
+    },
+    Deleted {
+        path: Vec<&'a [u8]>,
+        old_hash: HashOutput,
+    },
 }
 
 /// An in-progress tree builder, containing file hashes along with their names or nested trees
-#[derive(Default, Debug)]
-struct Tree(IndexMap<ArcOrCowStr, Box<TreeItem>>);
+#[derive(Default, Debug, Clone)]
+struct Tree(IndexMap<ArcOrCowBytes, Box<TreeItem>>);
 
 impl Tree {
     /// Recursively writes the the whole tree out to the given `pack_file`,
@@ -142,15 +710,9 @@ impl Tree {
 
         for (name, item) in self.0 {
             tree.push(match *item {
-                TreeItem::Blob(hash) => LowLevelTreeItem {
-                    kind: TreeItemKind::File,
-                    sort_name: name.to_string(),
-                    name,
-                    hash,
-                },
+                TreeItem::File { kind, hash } => LowLevelTreeItem { kind, name, hash },
                 TreeItem::Tree(tree) => LowLevelTreeItem {
                     kind: TreeItemKind::Directory,
-                    sort_name: format!("{name}/"),
                     name,
                     // we're essentially working through our tree from the bottom up,
                     // so we can grab the hash of each directory along the way and
@@ -163,7 +725,7 @@ impl Tree {
         // we need to sort our tree alphabetically, otherwise Git will silently
         // stop parsing the rest of the tree once it comes across a non-sorted
         // tree entry.
-        tree.sort_unstable_by(|a, b| a.sort_name.cmp(&b.sort_name));
+        tree.sort_unstable_by(LowLevelTreeItem::cmp_tree_order);
 
         // gets the hash of the tree we've just worked on, and
         // pushes it to the packfile
@@ -173,22 +735,1015 @@ impl Tree {
 
         Ok(hash)
     }
+
+    /// Returns the item named `name` within the directory at `path`, or `None` if
+    /// there isn't one or part of `path` isn't a directory.
+    fn get(&self, path: &[&str], name: &str) -> Option<&TreeItem> {
+        let mut directory = self;
+
+        for part in path {
+            match directory.0.get(part.as_bytes())?.as_ref() {
+                TreeItem::Tree(tree) => directory = tree,
+                TreeItem::File { .. } => return None,
+            }
+        }
+
+        directory.0.get(name.as_bytes()).map(AsRef::as_ref)
+    }
+
+    /// Calls `f` with the full path, kind and hash of every file within this tree,
+    /// `path` being the path to the tree itself.
+    fn for_each_file<'a>(
+        &'a self,
+        path: &mut Vec<&'a [u8]>,
+        f: &mut impl FnMut(Vec<&'a [u8]>, TreeItemKind, HashOutput),
+    ) {
+        for (name, item) in &self.0 {
+            path.push(name);
+            item.for_each_file(path, f);
+            path.pop();
+        }
+    }
+
+    /// Appends the path and size of every tree at or below this one with more than
+    /// `threshold` entries to `out`, `path` being the path to this tree.
+    fn large_trees<'a>(
+        &'a self,
+        threshold: usize,
+        path: &mut Vec<&'a [u8]>,
+        out: &mut Vec<(Vec<&'a [u8]>, usize)>,
+    ) {
+        if self.0.len() > threshold {
+            out.push((path.clone(), self.0.len()));
+        }
+
+        for (name, item) in &self.0 {
+            if let TreeItem::Tree(tree) = item.as_ref() {
+                path.push(name);
+                tree.large_trees(threshold, path, out);
+                path.pop();
+            }
+        }
+    }
+
+    /// Returns the path of the first item in `theirs` that conflicts with one in
+    /// `self`, as [`GitRepository::merge`] defines it.
+    fn find_conflict<'a>(
+        &'a self,
+        theirs: &'a Tree,
+        path: &mut Vec<&'a [u8]>,
+    ) -> Option<Vec<&'a [u8]>> {
+        for (name, their_item) in &theirs.0 {
+            let Some(our_item) = self.0.get(name) else {
+                continue;
+            };
+
+            path.push(name);
+
+            match (our_item.as_ref(), their_item.as_ref()) {
+                (TreeItem::Tree(ours), TreeItem::Tree(theirs)) => {
+                    if let Some(conflict) = ours.find_conflict(theirs, path) {
+                        return Some(conflict);
+                    }
+                }
+                (ours, theirs) if ours.is_same_file(theirs) => {}
+                _ => return Some(path.clone()),
+            }
+
+            path.pop();
+        }
+
+        None
+    }
+
+    /// Overlays `theirs` onto `self`, replacing conflicting items if `theirs_wins`,
+    /// and appending the hashes of every file taken from `theirs` to `taken`.
+    fn merge(&mut self, theirs: &Tree, theirs_wins: bool, taken: &mut Vec<HashOutput>) {
+        for (name, their_item) in &theirs.0 {
+            let Some(our_item) = self.0.get_mut(name) else {
+                their_item.for_each_file(&mut Vec::new(), &mut |_, _, hash| taken.push(hash));
+                self.0.insert(name.clone(), their_item.clone());
+                continue;
+            };
+
+            match (our_item.as_mut(), their_item.as_ref()) {
+                (TreeItem::Tree(ours), TreeItem::Tree(theirs)) => {
+                    ours.merge(theirs, theirs_wins, taken);
+                }
+                (ours, theirs) if ours.is_same_file(theirs) => {}
+                _ if theirs_wins => {
+                    their_item.for_each_file(&mut Vec::new(), &mut |_, _, hash| taken.push(hash));
+                    our_item.clone_from(their_item);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Appends the differences between `self` and `new` to `out`, recursing into
+    /// any directories present in both.
+    fn diff<'a>(&'a self, new: &'a Tree, path: &mut Vec<&'a [u8]>, out: &mut Vec<FileDiff<'a>>) {
+        for (name, old_item) in &self.0 {
+            path.push(name);
+
+            match (old_item.as_ref(), new.0.get(name).map(AsRef::as_ref)) {
+                (
+                    TreeItem::File {
+                        kind: old_kind,
+                        hash: old_hash,
+                    },
+                    Some(TreeItem::File {
+                        kind: new_kind,
+                        hash: new_hash,
+                    }),
+                ) => {
+                    if old_hash != new_hash || old_kind != new_kind {
+                        out.push(FileDiff::Modified {
+                            path: path.clone(),
+                            old_hash: *old_hash,
+                            new_hash: *new_hash,
+                        });
+                    }
+                }
+                (TreeItem::Tree(old_tree), Some(TreeItem::Tree(new_tree))) => {
+                    old_tree.diff(new_tree, path, out);
+                }
+                (old_item, new_item) => {
+                    // the path was either removed, or changed between a file and a
+                    // directory, so everything under the old path has been deleted
+                    old_item.for_each_file(path, &mut |path, _, old_hash| {
+                        out.push(FileDiff::Deleted { path, old_hash });
+                    });
+
+                    if let Some(new_item) = new_item {
+                        new_item.for_each_file(path, &mut |path, _, hash| {
+                            out.push(FileDiff::Added { path, hash });
+                        });
+                    }
+                }
+            }
+
+            path.pop();
+        }
+
+        for (name, new_item) in &new.0 {
+            if self.0.contains_key(name) {
+                continue;
+            }
+
+            path.push(name);
+            new_item.for_each_file(path, &mut |path, _, hash| {
+                out.push(FileDiff::Added { path, hash });
+            });
+            path.pop();
+        }
+    }
 }
 
-/// An item within a `Tree`, this could be a file blob or another directory.
-#[derive(Debug)]
+/// An item within a `Tree`, this could be a file or another directory.
+#[derive(Debug, Clone)]
 enum TreeItem {
-    /// Refers to a file by hash
-    Blob(HashOutput),
+    /// Refers to a file by hash, or a submodule by the hash of its commit
+    File {
+        kind: TreeItemKind,
+        hash: HashOutput,
+    },
     /// Refers to a nested directory
     Tree(Tree),
 }
 
+impl TreeItem {
+    /// Whether both items are files with the same content and kind.
+    fn is_same_file(&self, other: &TreeItem) -> bool {
+        matches!(
+            (self, other),
+            (Self::File { kind, hash }, Self::File { kind: other_kind, hash: other_hash })
+                if kind == other_kind && hash == other_hash
+        )
+    }
+
+    /// Calls `f` with the full path, kind and hash of every file at or below this item.
+    fn for_each_file<'a>(
+        &'a self,
+        path: &mut Vec<&'a [u8]>,
+        f: &mut impl FnMut(Vec<&'a [u8]>, TreeItemKind, HashOutput),
+    ) {
+        match self {
+            Self::File { kind, hash } => f(path.clone(), *kind, *hash),
+            Self::Tree(tree) => tree.for_each_file(path, f),
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::{high_level::GitRepository, low_level::PackFile};
+    use crate::{
+        capabilities::Sideband,
+        high_level::{FileDiff, GitRepository, MergeStrategy},
+        low_level::{CommitUserInfo, PackFile, PackFileEntry, TreeItemKind, EMPTY_TREE_HASH},
+        Error,
+    };
     use bytes::{Bytes, BytesMut};
 
+    #[test]
+    fn empty_tree_hash() {
+        assert_eq!(
+            PackFileEntry::Tree(Vec::new()).hash().unwrap(),
+            EMPTY_TREE_HASH
+        );
+        assert_eq!(
+            hex::encode(EMPTY_TREE_HASH),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+    }
+
+    #[test]
+    fn commit_empty() {
+        let mut repo = GitRepository::default();
+        let (hash, entries) = repo
+            .commit_empty("me", "me@example.com", "initial commit")
+            .unwrap();
+        assert_eq!(entries.len(), 2);
+
+        // committing an empty repository normally should give the same commit
+        let (normal_hash, _entries) = GitRepository::default()
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        assert_eq!(hash, normal_hash);
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+
+        let repo = crate::test::bare_repo_with_pack(&output);
+        let hash = hex::encode(hash);
+        crate::test::git(repo.path(), &["update-ref", "HEAD", &hash], &[]);
+
+        assert_eq!(crate::test::git(repo.path(), &["ls-tree", "HEAD"], &[]), "");
+        assert_eq!(
+            crate::test::git(repo.path(), &["rev-parse", "HEAD^{tree}"], &[]).trim(),
+            "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+        );
+    }
+
+    #[test]
+    fn commit_empty_then_commit() {
+        let mut repo = GitRepository::default();
+        repo.insert(&[], "README.md", Bytes::from("hello world!"))
+            .unwrap();
+        repo.commit_empty("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        // the README was drained by the empty commit, so must not be referenced by
+        // the next one
+        let (hash, entries) = repo
+            .commit("me", "me@example.com", "second commit")
+            .unwrap();
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+        crate::test::verify_pack_file(output.clone().freeze());
+
+        let repo = crate::test::bare_repo_with_pack(&output);
+        let hash = hex::encode(hash);
+        assert_eq!(crate::test::git(repo.path(), &["ls-tree", &hash], &[]), "");
+    }
+
+    #[test]
+    fn tag() {
+        let mut repo = GitRepository::default();
+        let (commit, mut entries) = repo
+            .commit_empty("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let tagger = CommitUserInfo::from_unix("me", "me@example.com", 1_688_494_158, 60).unwrap();
+        let signature = "-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----\n";
+        let (tag, tag_entries) = repo
+            .tag(
+                "v1.0.0",
+                commit,
+                tagger,
+                "release v1.0.0\n",
+                Some(Bytes::from_static(signature.as_bytes())),
+            )
+            .unwrap();
+        assert_eq!(tag_entries.len(), 1);
+        entries.extend(tag_entries);
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+
+        let repo = crate::test::bare_repo_with_pack(&output);
+        let tag = hex::encode(tag);
+
+        assert_eq!(
+            crate::test::git(repo.path(), &["cat-file", "-p", &tag], &[]),
+            format!(
+                "object {}\n\
+                 type commit\n\
+                 tag v1.0.0\n\
+                 tagger me <me@example.com> 1688494158 +0100\n\
+                 \n\
+                 release v1.0.0\n\
+                 {signature}",
+                hex::encode(commit)
+            )
+        );
+        assert_eq!(
+            crate::test::git(
+                repo.path(),
+                &["rev-parse", &format!("{tag}^{{commit}}")],
+                &[]
+            )
+            .trim(),
+            hex::encode(commit)
+        );
+    }
+
+    #[test]
+    fn tag_unknown_target() {
+        let tagger = CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap();
+        let err = GitRepository::default()
+            .tag("v1.0.0", [1; 20], tagger, "release v1.0.0\n", None)
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::DanglingReference { target, .. } if target == [1; 20]),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn tag_leaves_pending_entries() {
+        let mut repo = GitRepository::default();
+        repo.insert(&[], "README.md", Bytes::from("hello world!"))
+            .unwrap();
+        let (first, _) = repo
+            .commit_empty("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        repo.insert(&[], "LICENSE", Bytes::from("license")).unwrap();
+        let tagger = CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap();
+        let (_, tag_entries) = repo
+            .tag("v1.0.0", first, tagger, "release v1.0.0\n", None)
+            .unwrap();
+        assert_eq!(tag_entries.len(), 1);
+        assert!(matches!(tag_entries[0], PackFileEntry::Tag(_)));
+
+        let (_, entries) = repo
+            .commit("me", "me@example.com", "second commit")
+            .unwrap();
+        assert!(entries.iter().any(
+            |entry| matches!(entry, PackFileEntry::Blob(blob) if blob.as_ref() == b"license")
+        ));
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+        crate::test::verify_pack_file(output.freeze());
+    }
+
+    #[test]
+    fn merge() {
+        let base = || {
+            let mut repo = GitRepository::default();
+            repo.insert(&[], "README.md", Bytes::from("template readme"))
+                .unwrap();
+            repo.insert(&["src"], "lib.rs", Bytes::from("// template"))
+                .unwrap();
+            repo.insert(&["docs"], "guide", Bytes::from("template guide"))
+                .unwrap();
+            repo.insert(&[], "LICENSE", Bytes::from("license")).unwrap();
+            repo
+        };
+
+        let mut user = GitRepository::default();
+        user.insert(&[], "README.md", Bytes::from("user readme"))
+            .unwrap();
+        user.insert(&["src"], "main.rs", Bytes::from("fn main() {}"))
+            .unwrap();
+        user.insert(&["docs", "guide"], "index.md", Bytes::from("user guide"))
+            .unwrap();
+        user.insert(&[], "LICENSE", Bytes::from("license")).unwrap();
+
+        let files = |repo: &GitRepository| {
+            let mut files = Vec::new();
+            repo.tree
+                .for_each_file(&mut Vec::new(), &mut |path, _, hash| {
+                    let PackFileEntry::Blob(content) = &repo.packfile_entries[&hash] else {
+                        unreachable!()
+                    };
+                    files.push((
+                        String::from_utf8(path.join(&b'/')).unwrap(),
+                        String::from_utf8(content.to_vec()).unwrap(),
+                    ));
+                });
+            files.sort();
+            files
+        };
+        let expected = |files: &[(&str, &str)]| {
+            files
+                .iter()
+                .map(|(path, content)| ((*path).to_string(), (*content).to_string()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut repo = base();
+        repo.merge(&user, MergeStrategy::TheirsWins).unwrap();
+        assert_eq!(
+            files(&repo),
+            expected(&[
+                ("LICENSE", "license"),
+                ("README.md", "user readme"),
+                ("docs/guide/index.md", "user guide"),
+                ("src/lib.rs", "// template"),
+                ("src/main.rs", "fn main() {}"),
+            ])
+        );
+
+        let mut repo = base();
+        repo.merge(&user, MergeStrategy::OursWins).unwrap();
+        assert_eq!(
+            files(&repo),
+            expected(&[
+                ("LICENSE", "license"),
+                ("README.md", "template readme"),
+                ("docs/guide", "template guide"),
+                ("src/lib.rs", "// template"),
+                ("src/main.rs", "fn main() {}"),
+            ])
+        );
+
+        let mut repo = base();
+        assert!(matches!(
+            repo.merge(&user, MergeStrategy::Error),
+            Err(Error::MergeConflict(path)) if path == "README.md"
+        ));
+        assert!(
+            repo.diff(&base()).is_empty(),
+            "failed merge changed the repo"
+        );
+
+        // identical files at the same path aren't a conflict
+        let mut repo = base();
+        let mut other = GitRepository::default();
+        other
+            .insert(&[], "LICENSE", Bytes::from("license"))
+            .unwrap();
+        other
+            .insert(&["src"], "main.rs", Bytes::from("fn main() {}"))
+            .unwrap();
+        repo.merge(&other, MergeStrategy::Error).unwrap();
+        assert_eq!(files(&repo).len(), 5);
+
+        let mut directory_conflict = GitRepository::default();
+        directory_conflict
+            .insert(&["docs", "guide"], "index.md", Bytes::from("user guide"))
+            .unwrap();
+        assert!(matches!(
+            base().merge(&directory_conflict, MergeStrategy::Error),
+            Err(Error::MergeConflict(path)) if path == "docs/guide"
+        ));
+
+        // the merged repository should commit cleanly with all of its blobs
+        let mut repo = base();
+        repo.merge(&user, MergeStrategy::TheirsWins).unwrap();
+        let (_hash, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        PackFile::rehash_verify(&entries).unwrap();
+    }
+
+    #[test]
+    fn insert_with_mode() {
+        let mut repo = GitRepository::default();
+        repo.insert(&["dir"], "file.txt", Bytes::from("file"))
+            .unwrap();
+        repo.insert_executable(&[], "run.sh", Bytes::from("#!/bin/sh\n"))
+            .unwrap();
+        repo.insert_with_mode(&[], "link", Bytes::from("run.sh"), TreeItemKind::Symlink)
+            .unwrap();
+        repo.insert_with_mode(
+            &[],
+            "sub",
+            Bytes::from(vec![1; 20]),
+            TreeItemKind::Submodule,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            repo.insert_with_mode(&[], "d", Bytes::new(), TreeItemKind::Directory),
+            Err(Error::InvalidFileMode(TreeItemKind::Directory))
+        ));
+        assert!(matches!(
+            repo.insert_with_mode(&[], "s", Bytes::from("abc"), TreeItemKind::Submodule),
+            Err(Error::InvalidSubmoduleHash(3))
+        ));
+
+        let (commit, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+        let dir = crate::test::bare_repo_with_pack(&output);
+
+        let hash = |content: &'static str| {
+            hex::encode(PackFileEntry::Blob(Bytes::from(content)).hash().unwrap())
+        };
+        assert_eq!(
+            crate::test::git(dir.path(), &["ls-tree", "-r", &hex::encode(commit)], &[]),
+            format!(
+                "100644 blob {}\tdir/file.txt\n\
+                120000 blob {}\tlink\n\
+                100755 blob {}\trun.sh\n\
+                160000 commit {}\tsub\n",
+                hash("file"),
+                hash("run.sh"),
+                hash("#!/bin/sh\n"),
+                hex::encode([1; 20]),
+            )
+        );
+    }
+
+    #[test]
+    fn clone() {
+        let mut repo = GitRepository::default();
+        repo.insert(&["dir"], "file.txt", Bytes::from("original"))
+            .unwrap();
+
+        let mut snapshot = repo.clone();
+        snapshot
+            .insert(&["dir"], "file.txt", Bytes::from("modified"))
+            .unwrap();
+        snapshot.insert(&[], "new.txt", Bytes::from("new")).unwrap();
+        assert_eq!(repo.diff(&snapshot).len(), 2);
+
+        let mut expected = GitRepository::default();
+        expected
+            .insert(&["dir"], "file.txt", Bytes::from("original"))
+            .unwrap();
+        assert!(expected.diff(&repo).is_empty());
+
+        let (_, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        let (_, expected_entries) = expected
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        assert_eq!(entries.len(), expected_entries.len());
+        for (entry, expected) in entries.iter().zip(&expected_entries) {
+            assert_eq!(entry.hash().unwrap(), expected.hash().unwrap());
+        }
+    }
+
+    #[test]
+    fn commit_and_tag() {
+        use crate::test::git;
+        use tempfile::TempDir;
+
+        let mut repo = GitRepository::default();
+        repo.insert(&[], "README.md", Bytes::from("hello world!"))
+            .unwrap();
+        let (commit, tag, entries) = repo
+            .commit_and_tag(
+                "me",
+                "me@example.com",
+                "initial commit",
+                "v1.0.0",
+                "release v1.0.0\n",
+            )
+            .unwrap();
+        assert!(entries
+            .iter()
+            .any(|entry| matches!(entry, PackFileEntry::Tag(_))));
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+
+        let remote = crate::test::bare_repo_with_pack(&output);
+        git(
+            remote.path(),
+            &["update-ref", "refs/heads/main", &hex::encode(commit)],
+            &[],
+        );
+        git(
+            remote.path(),
+            &["update-ref", "refs/tags/v1.0.0", &hex::encode(tag)],
+            &[],
+        );
+        git(
+            remote.path(),
+            &["symbolic-ref", "HEAD", "refs/heads/main"],
+            &[],
+        );
+
+        let clone = TempDir::new().unwrap();
+        git(
+            clone.path(),
+            &[
+                "clone",
+                "--quiet",
+                &remote.path().display().to_string(),
+                ".",
+            ],
+            &[],
+        );
+        assert_eq!(git(clone.path(), &["describe"], &[]), "v1.0.0\n");
+    }
+
+    #[test]
+    fn insert_bytes() {
+        use crate::low_level::parse_tree;
+
+        // `déjà/café.txt`, encoded as Latin-1
+        let mut repo = GitRepository::default();
+        repo.insert_bytes(&[b"d\xe9j\xe0"], b"caf\xe9.txt", Bytes::from("latte"))
+            .unwrap();
+        repo.insert(&[], "README.md", Bytes::from("hello")).unwrap();
+        let (commit, entries) = repo.commit("me", "me@example.com", "initial").unwrap();
+
+        let mut output = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut output).unwrap();
+        let remote = crate::test::bare_repo_with_pack(&output);
+        let git = |args: &[&str]| crate::test::git_raw(remote.path(), args, &[]);
+        assert_eq!(
+            git(&["ls-tree", "-r", "-z", "--name-only", &hex::encode(commit)]),
+            b"README.md\0d\xe9j\xe0/caf\xe9.txt\0"
+        );
+
+        // the name is written out verbatim, and reads back the same
+        let directory = entries
+            .iter()
+            .find(|entry| matches!(entry, PackFileEntry::Tree(items) if items.len() == 1))
+            .unwrap();
+        let tree = git(&["cat-file", "tree", &hex::encode(directory.hash().unwrap())]);
+        assert!(tree.starts_with(b"100644 caf\xe9.txt\0"));
+        assert_eq!(&*parse_tree(&tree).unwrap()[0].name, b"caf\xe9.txt");
+    }
+
+    #[test]
+    fn insert_invalid_names() {
+        let mut repo = GitRepository::default();
+
+        for name in [&b""[..], b"a/b", b"a\0b"] {
+            assert!(matches!(
+                repo.insert_bytes(&[], name, Bytes::from("content")),
+                Err(Error::InvalidName(_))
+            ));
+            assert!(matches!(
+                repo.insert_bytes(&[b"dir", name], b"file", Bytes::from("content")),
+                Err(Error::InvalidName(_))
+            ));
+        }
+        assert!(matches!(
+            repo.insert(&["dir"], "", Bytes::from("content")),
+            Err(Error::InvalidName(_))
+        ));
+
+        // nothing was inserted, not even the directories leading up to a bad name
+        let (tree, entries) = repo.write_tree().unwrap();
+        assert_eq!(tree, EMPTY_TREE_HASH);
+        assert_eq!(entries.len(), 1);
+    }
+
+    #[test]
+    fn clone_response() {
+        use crate::{test::git, Frame, PktLine, PktLineIter, SIDEBAND_DATA};
+
+        let mut repo = GitRepository::default();
+        repo.insert(&["src"], "lib.rs", Bytes::from("fn main() {}"))
+            .unwrap();
+        repo.insert(&[], "large", Bytes::from(vec![b'a'; 100_000]))
+            .unwrap();
+        let (commit, response) = repo
+            .clone_response(
+                "me",
+                "me@example.com",
+                "initial commit",
+                Some(Sideband::SideBand),
+            )
+            .unwrap();
+
+        // the repository is left as-is, so the commit can be served again
+        let (again, _) = repo
+            .clone_response(
+                "me",
+                "me@example.com",
+                "initial commit",
+                Some(Sideband::SideBand64k),
+            )
+            .unwrap();
+        assert_eq!(commit, again);
+
+        let mut frames = PktLineIter::new(&response);
+        assert_eq!(frames.next().unwrap().unwrap(), Frame::Data(b"NAK\n"));
+        let mut packed = Vec::new();
+        for frame in frames {
+            match frame.unwrap() {
+                Frame::Data([SIDEBAND_DATA, data @ ..]) => {
+                    assert!(data.len() <= Sideband::SideBand.chunk_size());
+                    packed.extend_from_slice(data);
+                }
+                Frame::Flush => break,
+                frame => panic!("unexpected frame {frame:?}"),
+            }
+        }
+
+        let remote = crate::test::bare_repo_with_pack(&packed);
+        assert_eq!(
+            git(
+                remote.path(),
+                &["show", &format!("{}:src/lib.rs", hex::encode(commit))],
+                &[]
+            ),
+            "fn main() {}"
+        );
+
+        // without side-band, the packfile follows the NAK as-is
+        let (_, response) = repo
+            .clone_response("me", "me@example.com", "initial commit", None)
+            .unwrap();
+        let (_, consumed) = PktLine::parse(&response).unwrap();
+        assert_eq!(&response[..consumed], b"0008NAK\n");
+        assert_eq!(&response[consumed..], packed.as_slice());
+    }
+
+    #[test]
+    fn clone_response_served_to_git() {
+        use crate::{capabilities::CapabilitySet, test::ScriptServer, PktLine};
+        use tempfile::TempDir;
+
+        let mut repo = GitRepository::default();
+        repo.insert(&[], "README.md", Bytes::from("hello world!"))
+            .unwrap();
+
+        for sideband in [Some(Sideband::SideBand64k), Some(Sideband::SideBand), None] {
+            let (commit, response) = repo
+                .clone_response("me", "me@example.com", "initial commit", sideband)
+                .unwrap();
+
+            let caps: CapabilitySet = match sideband {
+                Some(Sideband::SideBand64k) => ["side-band-64k"].into_iter().collect(),
+                Some(Sideband::SideBand) => ["side-band"].into_iter().collect(),
+                None => CapabilitySet::default(),
+            };
+            let mut advertisement = BytesMut::new();
+            PktLine::ref_line_with_capabilities(commit, "refs/heads/master", &caps)
+                .encode_to(&mut advertisement)
+                .unwrap();
+            PktLine::Flush.encode_to(&mut advertisement).unwrap();
+
+            let server = ScriptServer::after_done(&advertisement, &response);
+            let clone = TempDir::new().unwrap();
+            let out = server
+                .git(0, "clone")
+                .current_dir(clone.path())
+                .args(["--quiet", &server.url(), "."])
+                .output()
+                .unwrap();
+            assert!(
+                out.status.success(),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+            assert_eq!(
+                std::fs::read_to_string(clone.path().join("README.md")).unwrap(),
+                "hello world!"
+            );
+        }
+    }
+
+    #[test]
+    fn write_tree() {
+        let mut repo = GitRepository::default();
+        repo.insert(&["dir"], "file.txt", Bytes::from("file"))
+            .unwrap();
+        repo.insert(&[], "README.md", Bytes::from("hello world!"))
+            .unwrap();
+
+        let (tree_hash, entries) = repo.write_tree().unwrap();
+        // two blobs, the root tree and `dir`
+        assert_eq!(entries.len(), 4);
+        assert!(entries
+            .iter()
+            .all(|entry| !matches!(entry, PackFileEntry::Commit(_))));
+
+        let (commit_hash, commit_entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        let commit = commit_entries
+            .iter()
+            .find_map(|entry| match entry {
+                PackFileEntry::Commit(commit) => Some(commit),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(commit.tree, tree_hash);
+
+        let mut output = BytesMut::new();
+        PackFile::new(&commit_entries)
+            .encode_to(&mut output)
+            .unwrap();
+        let dir = crate::test::bare_repo_with_pack(&output);
+        assert_eq!(
+            crate::test::git(
+                dir.path(),
+                &[
+                    "rev-parse",
+                    &format!("{}^{{tree}}", hex::encode(commit_hash))
+                ],
+                &[]
+            )
+            .trim(),
+            hex::encode(tree_hash)
+        );
+    }
+
+    #[test]
+    fn insert_if_absent() {
+        let mut repo = GitRepository::default();
+        assert!(repo
+            .insert_if_absent(&["dir"], "file.txt", Bytes::from("first"))
+            .unwrap());
+        assert!(!repo
+            .insert_if_absent(&["dir"], "file.txt", Bytes::from("second"))
+            .unwrap());
+        // directories exist too
+        assert!(!repo
+            .insert_if_absent(&[], "dir", Bytes::from("second"))
+            .unwrap());
+        assert!(matches!(
+            repo.insert_if_absent(&["dir", "file.txt"], "nested", Bytes::new()),
+            Err(Error::NotDirectory(_))
+        ));
+
+        let mut expected = GitRepository::default();
+        expected
+            .insert(&["dir"], "file.txt", Bytes::from("first"))
+            .unwrap();
+
+        assert!(expected.diff(&repo).is_empty());
+    }
+
+    #[test]
+    fn insert_if_unchanged() {
+        let hash =
+            |content: &'static str| PackFileEntry::Blob(Bytes::from(content)).hash().unwrap();
+
+        let mut repo = GitRepository::default();
+        repo.insert(&["dir"], "file.txt", Bytes::from("first"))
+            .unwrap();
+
+        // another writer got there first
+        assert!(!repo
+            .insert_if_unchanged(&["dir"], "file.txt", hash("other"), Bytes::from("second"))
+            .unwrap());
+        assert!(repo
+            .insert_if_unchanged(&["dir"], "file.txt", hash("first"), Bytes::from("second"))
+            .unwrap());
+        assert!(!repo
+            .insert_if_unchanged(&["dir"], "file.txt", hash("first"), Bytes::from("third"))
+            .unwrap());
+
+        // missing files and directories never match
+        assert!(!repo
+            .insert_if_unchanged(&["dir"], "missing", hash("first"), Bytes::new())
+            .unwrap());
+        assert!(!repo
+            .insert_if_unchanged(&[], "dir", hash("first"), Bytes::new())
+            .unwrap());
+
+        let mut expected = GitRepository::default();
+        expected
+            .insert(&["dir"], "file.txt", Bytes::from("second"))
+            .unwrap();
+
+        assert!(expected.diff(&repo).is_empty());
+    }
+
+    #[test]
+    fn stat() {
+        use crate::high_level::FileStat;
+
+        let mut repo = GitRepository::default();
+        repo.insert(&["src", "bin"], "main.rs", Bytes::from("fn main() {}\n"))
+            .unwrap();
+        repo.insert_executable(&[], "run.sh", Bytes::from("#!/bin/sh\n"))
+            .unwrap();
+        repo.insert_with_mode(
+            &[],
+            "vendor",
+            Bytes::from(vec![1; 20]),
+            TreeItemKind::Submodule,
+        )
+        .unwrap();
+
+        // the path can be built at runtime, unlike when inserting
+        let dir = String::from("bin");
+        assert_eq!(
+            repo.stat(&["src", &dir], "main.rs"),
+            Some(FileStat {
+                hash: PackFileEntry::Blob(Bytes::from("fn main() {}\n"))
+                    .hash()
+                    .unwrap(),
+                size: 13,
+                mode: TreeItemKind::File,
+            })
+        );
+        assert_eq!(
+            repo.stat(&[], "run.sh").map(|stat| (stat.size, stat.mode)),
+            Some((10, TreeItemKind::Executable))
+        );
+        assert_eq!(
+            repo.stat(&[], "vendor"),
+            Some(FileStat {
+                hash: [1; 20],
+                size: 0,
+                mode: TreeItemKind::Submodule,
+            })
+        );
+
+        // missing files, and paths going through a missing directory or a file
+        assert_eq!(repo.stat(&["src", "bin"], "lib.rs"), None);
+        assert_eq!(repo.stat(&["lib"], "main.rs"), None);
+        assert_eq!(repo.stat(&["run.sh"], "main.rs"), None);
+
+        // directories aren't files
+        assert_eq!(repo.stat(&[], "src"), None);
+        assert_eq!(repo.stat(&["src"], "bin"), None);
+    }
+
+    #[test]
+    fn diff() {
+        let path = |parts: &[&'static str]| -> Vec<&'static [u8]> {
+            parts.iter().map(|part| part.as_bytes()).collect()
+        };
+        let hash =
+            |content: &'static str| PackFileEntry::Blob(Bytes::from(content)).hash().unwrap();
+
+        let mut old = GitRepository::default();
+        old.insert(&["a"], "unchanged.txt", Bytes::from("same"))
+            .unwrap();
+        old.insert(&["a"], "modified.txt", Bytes::from("before"))
+            .unwrap();
+        old.insert(&["a", "b"], "deleted.txt", Bytes::from("gone"))
+            .unwrap();
+        old.insert(&[], "becomes-dir", Bytes::from("file")).unwrap();
+
+        let mut new = GitRepository::default();
+        new.insert(&["a"], "unchanged.txt", Bytes::from("same"))
+            .unwrap();
+        new.insert(&["a"], "modified.txt", Bytes::from("after"))
+            .unwrap();
+        new.insert(&["a"], "added.txt", Bytes::from("new")).unwrap();
+        new.insert(&["becomes-dir"], "nested.txt", Bytes::from("file"))
+            .unwrap();
+
+        assert_eq!(
+            old.diff(&new),
+            vec![
+                FileDiff::Modified {
+                    path: path(&["a", "modified.txt"]),
+                    old_hash: hash("before"),
+                    new_hash: hash("after"),
+                },
+                FileDiff::Deleted {
+                    path: path(&["a", "b", "deleted.txt"]),
+                    old_hash: hash("gone"),
+                },
+                FileDiff::Added {
+                    path: path(&["a", "added.txt"]),
+                    hash: hash("new"),
+                },
+                FileDiff::Deleted {
+                    path: path(&["becomes-dir"]),
+                    old_hash: hash("file"),
+                },
+                FileDiff::Added {
+                    path: path(&["becomes-dir", "nested.txt"]),
+                    hash: hash("file"),
+                },
+            ]
+        );
+
+        assert!(old.diff(&old).is_empty());
+
+        // a change in mode is a modification, even with the same content
+        let mut executable = GitRepository::default();
+        executable
+            .insert_executable(&[], "becomes-dir", Bytes::from("file"))
+            .unwrap();
+        let mut file = GitRepository::default();
+        file.insert(&[], "becomes-dir", Bytes::from("file"))
+            .unwrap();
+        assert_eq!(
+            file.diff(&executable),
+            vec![FileDiff::Modified {
+                path: path(&["becomes-dir"]),
+                old_hash: hash("file"),
+                new_hash: hash("file"),
+            }]
+        );
+    }
+
     #[test]
     fn deterministic() {
         let mut repo = GitRepository::default();
@@ -231,4 +1786,80 @@ mod test {
             insta::assert_snapshot!(stdout);
         });
     }
+
+    fn repo_with_large_trees() -> GitRepository {
+        let mut repo = GitRepository::default();
+        for name in ["a", "b", "c"] {
+            repo.insert(&["big"], name, Bytes::from(name)).unwrap();
+            repo.insert(&["nested", "big"], name, Bytes::from(name))
+                .unwrap();
+        }
+        repo.insert(&["small"], "d", Bytes::from("d")).unwrap();
+        repo
+    }
+
+    #[test]
+    fn large_trees() {
+        let repo = repo_with_large_trees();
+
+        assert_eq!(
+            repo.large_trees(2),
+            vec![
+                (vec![], 3),
+                (vec![&b"big"[..]], 3),
+                (vec![&b"nested"[..], b"big"], 3)
+            ]
+        );
+        assert_eq!(repo.large_trees(3), vec![]);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn large_tree_threshold_warns() {
+        use std::{
+            io,
+            sync::{Arc, Mutex},
+        };
+
+        /// Collects everything the fmt subscriber writes.
+        #[derive(Clone, Default)]
+        struct Logs(Arc<Mutex<Vec<u8>>>);
+
+        impl io::Write for Logs {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        /// Returns the `path` of every warning logged while committing `repo`.
+        fn warned_paths(repo: GitRepository) -> Vec<String> {
+            let logs = Logs::default();
+            let writer = logs.clone();
+            let subscriber = tracing_subscriber::fmt()
+                .with_writer(move || writer.clone())
+                .with_max_level(tracing::Level::WARN)
+                .with_ansi(false)
+                .finish();
+            tracing::subscriber::with_default(subscriber, || {
+                repo.commit("me", "me@example.com", "initial commit")
+                    .unwrap();
+            });
+
+            let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+            logs.lines()
+                .filter_map(|line| line.split_once("path=\"")?.1.split_once('"'))
+                .map(|(path, _)| path.to_string())
+                .collect()
+        }
+
+        assert_eq!(
+            warned_paths(repo_with_large_trees().with_large_tree_threshold(2)),
+            ["", "big", "nested/big"]
+        );
+        assert!(warned_paths(repo_with_large_trees()).is_empty());
+    }
 }
@@ -0,0 +1,248 @@
+//! Persistence of a [`GitRepository`] as JSON, so an in-progress repository can
+//! survive a restart.
+//!
+//! Each file in the repository is written as an entry in a map from its full path
+//! (separated by `/`) to a tuple of its mode, hex-encoded hash and base64-encoded
+//! content. Submodules have no content, as their hash is the commit they point to.
+//! Paths that aren't valid UTF-8 can't be represented, so fail to serialise.
+
+use std::{fmt::Formatter, path::Path};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use bytes::Bytes;
+use serde::{
+    de::{Error as _, MapAccess, Visitor},
+    ser::{Error as _, SerializeMap},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{
+    error::io_error_at,
+    high_level::GitRepository,
+    low_level::{PackFileEntry, TreeItemKind},
+    util::ArcOrCowBytes,
+    Error,
+};
+
+impl GitRepository {
+    /// Writes the repository out to `path` as JSON, replacing the file if it already
+    /// exists.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the repository fails to serialise, or
+    /// [`Error::IoWithPath`] if the file fails to write.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn save_to_file(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_vec(self).map_err(Error::Json)?;
+        std::fs::write(path, json).map_err(|e| io_error_at(path, e))?;
+        Ok(())
+    }
+
+    /// Reads a repository previously written by [`GitRepository::save_to_file`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::IoWithPath`] if the file fails to read, or an error if it
+    /// isn't a valid repository.
+    #[cfg_attr(feature = "tracing", tracing::instrument(err))]
+    pub fn load_from_file(path: &Path) -> Result<Self, Error> {
+        let json = std::fs::read(path).map_err(|e| io_error_at(path, e))?;
+        serde_json::from_slice(&json).map_err(Error::Json)
+    }
+}
+
+impl Serialize for GitRepository {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut files = Vec::new();
+        self.tree
+            .for_each_file(&mut Vec::new(), &mut |path, kind, hash| {
+                files.push((path.join(&b'/'), kind, hash));
+            });
+
+        let mut map = serializer.serialize_map(Some(files.len()))?;
+
+        for (path, kind, hash) in files {
+            // JSON strings can't hold names that aren't UTF-8 without mangling them
+            let path = String::from_utf8(path).map_err(|e| {
+                S::Error::custom(format!(
+                    "path {} isn't valid UTF-8",
+                    String::from_utf8_lossy(e.as_bytes())
+                ))
+            })?;
+
+            let content = match self.packfile_entries.get(&hash) {
+                _ if kind == TreeItemKind::Submodule => String::new(),
+                Some(PackFileEntry::Blob(content)) => BASE64.encode(content),
+                _ => return Err(S::Error::custom(format!("missing content for {path}"))),
+            };
+
+            map.serialize_entry(&path, &(kind.mode(), hex::encode(hash), content))?;
+        }
+
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for GitRepository {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_map(RepositoryVisitor)
+    }
+}
+
+struct RepositoryVisitor;
+
+impl<'de> Visitor<'de> for RepositoryVisitor {
+    type Value = GitRepository;
+
+    fn expecting(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a map of paths to (mode, hash, content) tuples")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+        let mut repo = GitRepository::default();
+
+        while let Some((path, (mode, hash, content))) =
+            access.next_entry::<String, (String, String, String)>()?
+        {
+            let kind: TreeItemKind = mode.parse().map_err(A::Error::custom)?;
+
+            let content = if kind == TreeItemKind::Submodule {
+                if !content.is_empty() {
+                    return Err(A::Error::custom(format!(
+                        "submodule {path} can't have content"
+                    )));
+                }
+
+                Bytes::from(hex::decode(&hash).map_err(A::Error::custom)?)
+            } else {
+                let content = Bytes::from(BASE64.decode(content).map_err(A::Error::custom)?);
+
+                // the hash is redundant, but catches any corruption of the content
+                let actual = PackFileEntry::Blob(content.clone())
+                    .hash()
+                    .map_err(A::Error::custom)?;
+                if hex::encode(actual) != hash {
+                    return Err(A::Error::custom(format!(
+                        "content of {path} doesn't match its hash {hash}"
+                    )));
+                }
+
+                content
+            };
+
+            if path.split('/').any(str::is_empty) {
+                return Err(A::Error::custom(format!("invalid path {path}")));
+            }
+
+            let (directory, file) = path.rsplit_once('/').unwrap_or(("", &path));
+            let parts = directory
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .map(|part| ArcOrCowBytes::from(part.to_string()));
+
+            repo.insert_at(parts, file.to_string().into(), content, kind)
+                .map_err(A::Error::custom)?;
+        }
+
+        Ok(repo)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{high_level::GitRepository, low_level::TreeItemKind};
+    use bytes::Bytes;
+
+    fn example() -> GitRepository {
+        let mut repo = GitRepository::default();
+        repo.insert_executable(&["bin"], "run.sh", Bytes::from("#!/bin/sh\n"))
+            .unwrap();
+        repo.insert_with_mode(&[], "link", Bytes::from("d.txt"), TreeItemKind::Symlink)
+            .unwrap();
+        repo.insert_with_mode(
+            &[],
+            "sub",
+            Bytes::from(vec![1; 20]),
+            TreeItemKind::Submodule,
+        )
+        .unwrap();
+        repo.insert(&["a", "b"], "c.txt", Bytes::from("hello world!"))
+            .unwrap();
+        repo.insert(&["a"], "binary", Bytes::from(vec![0, 159, 146, 150]))
+            .unwrap();
+        repo.insert(&[], "d.txt", Bytes::from("test")).unwrap();
+        repo
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("repo.json");
+
+        example().save_to_file(&path).unwrap();
+
+        let json: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(
+            json["a/b/c.txt"],
+            serde_json::json!([
+                "100644",
+                "bc7774a7b18deb1d7bd0212d34246a9b1260ae17",
+                "aGVsbG8gd29ybGQh"
+            ])
+        );
+
+        assert_eq!(
+            json["sub"],
+            serde_json::json!(["160000", "0101010101010101010101010101010101010101", ""])
+        );
+        assert_eq!(json["bin/run.sh"][0], "100755");
+        assert_eq!(json["link"][0], "120000");
+
+        let restored = GitRepository::load_from_file(&path).unwrap();
+        assert!(example().diff(&restored).is_empty());
+
+        let (expected, _) = example()
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        let (actual, _) = restored
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+        assert_eq!(actual, expected);
+
+        let missing = dir.path().join("missing").join("repo.json");
+        let err = GitRepository::load_from_file(&missing).unwrap_err();
+        assert!(matches!(&err, crate::Error::IoWithPath { path, .. } if *path == missing));
+        let err = example().save_to_file(&missing).unwrap_err();
+        assert!(matches!(&err, crate::Error::IoWithPath { path, .. } if *path == missing));
+    }
+
+    #[test]
+    fn invalid() {
+        for json in [
+            r#"{"a.txt": ["100644", "0000000000000000000000000000000000000000", "aGk="]}"#,
+            r#"{"a.txt": ["100644", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "!!!"]}"#,
+            r#"{"a.txt": ["999999", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]}"#,
+            r#"{"a//b.txt": ["100644", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]}"#,
+            r#"{"a/": ["100644", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]}"#,
+            r#"{"a": ["040000", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]}"#,
+            r#"{"a": ["160000", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]}"#,
+            r#"{"a": ["160000", "32f95c", ""]}"#,
+            r#"{
+                "a": ["100644", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="],
+                "a/b.txt": ["100644", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]
+            }"#,
+        ] {
+            assert!(
+                serde_json::from_str::<GitRepository>(json).is_err(),
+                "{json} should be rejected"
+            );
+        }
+
+        serde_json::from_str::<GitRepository>(
+            r#"{"a/b.txt": ["100644", "32f95c0d1244a78b2be1bab8de17906fabb2c4a8", "aGk="]}"#,
+        )
+        .unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! Building a [`GitRepository`] from a tar archive, for when a tree of files
+//! arrives as a single stream rather than one file at a time.
+
+use std::path::Component;
+
+use bytes::Bytes;
+use futures_util::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio_tar::Archive;
+
+use crate::{high_level::GitRepository, low_level::TreeItemKind, util::ArcOrCowBytes, Error};
+
+impl GitRepository {
+    /// Reads a tar archive from `reader`, inserting each file in it into a new
+    /// repository.
+    ///
+    /// Files with any of their executable bits set in the tar header are inserted
+    /// as [`TreeItemKind::Executable`] and all other files as [`TreeItemKind::File`],
+    /// symlinks are inserted as [`TreeItemKind::Symlink`]. Directories are created
+    /// from the paths of the files within them, so empty directories are dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if the archive fails to read, or
+    /// [`Error::UnsupportedTarEntry`] if it contains an entry that can't be
+    /// represented in a Git tree, such as a hard link, a device or a path that isn't
+    /// valid UTF-8 or escapes the root of the archive.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(reader), err))]
+    pub async fn from_tar_reader<R: AsyncRead + Unpin>(reader: R) -> Result<Self, Error> {
+        let mut repo = Self::default();
+
+        let mut archive = Archive::new(reader);
+        let mut entries = archive.entries()?;
+
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry?;
+            let header = entry.header();
+            let entry_type = header.entry_type();
+
+            let (kind, content) = if entry_type.is_file() {
+                let kind = if header.mode()? & 0o111 == 0 {
+                    TreeItemKind::File
+                } else {
+                    TreeItemKind::Executable
+                };
+
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content).await?;
+                (kind, Bytes::from(content))
+            } else if entry_type.is_symlink() {
+                let target = entry.link_name_bytes().ok_or_else(|| {
+                    Error::UnsupportedTarEntry("symlink without a target".to_string())
+                })?;
+                (TreeItemKind::Symlink, Bytes::from(target.into_owned()))
+            } else if entry_type.is_dir() || entry_type.is_pax_global_extensions() {
+                continue;
+            } else {
+                return Err(Error::UnsupportedTarEntry(format!(
+                    "{entry_type:?} at {}",
+                    String::from_utf8_lossy(&entry.path_bytes())
+                )));
+            };
+
+            let mut parts = entry
+                .path()?
+                .components()
+                .filter(|component| *component != Component::CurDir)
+                .map(|component| match component {
+                    Component::Normal(part) => part
+                        .to_str()
+                        .map(|part| ArcOrCowBytes::from(part.to_string()))
+                        .ok_or(()),
+                    _ => Err(()),
+                })
+                .collect::<Result<Vec<_>, ()>>()
+                .map_err(|()| {
+                    Error::UnsupportedTarEntry(format!(
+                        "invalid path {}",
+                        String::from_utf8_lossy(&entry.path_bytes())
+                    ))
+                })?;
+
+            let Some(file) = parts.pop() else {
+                return Err(Error::UnsupportedTarEntry("empty path".to_string()));
+            };
+
+            repo.insert_at(parts, file, content, kind)?;
+        }
+
+        Ok(repo)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        high_level::GitRepository,
+        low_level::{PackFile, TreeItemKind},
+        test::{bare_repo_with_pack, git},
+        Error,
+    };
+    use bytes::{Bytes, BytesMut};
+    use tokio_tar::{Builder, EntryType, Header};
+
+    /// Appends an entry to `builder`, writing the path straight into the header so
+    /// paths the builder would otherwise refuse can be tested.
+    async fn append(
+        builder: &mut Builder<Vec<u8>>,
+        entry_type: EntryType,
+        path: &str,
+        mode: u32,
+        content: &[u8],
+    ) {
+        let mut header = Header::new_gnu();
+        header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+
+        if entry_type.is_file() {
+            header.set_size(content.len() as u64);
+            header.set_cksum();
+            builder.append(&header, content).await.unwrap();
+        } else {
+            header.set_size(0);
+            if !content.is_empty() {
+                header
+                    .set_link_name(std::str::from_utf8(content).unwrap())
+                    .unwrap();
+            }
+            header.set_cksum();
+            builder.append(&header, &[][..]).await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn from_tar_reader() {
+        let mut builder = Builder::new(Vec::new());
+        append(
+            &mut builder,
+            EntryType::Regular,
+            "./README.md",
+            0o644,
+            b"hello world!",
+        )
+        .await;
+        append(&mut builder, EntryType::Directory, "bin/", 0o755, b"").await;
+        append(
+            &mut builder,
+            EntryType::Regular,
+            "bin/run.sh",
+            0o755,
+            b"#!/bin/sh\n",
+        )
+        .await;
+        append(
+            &mut builder,
+            EntryType::Regular,
+            "a/b/c.txt",
+            0o600,
+            b"test",
+        )
+        .await;
+        append(&mut builder, EntryType::Directory, "empty/", 0o755, b"").await;
+        append(
+            &mut builder,
+            EntryType::Symlink,
+            "link",
+            0o777,
+            b"README.md",
+        )
+        .await;
+        let tar = builder.into_inner().await.unwrap();
+
+        let repo = GitRepository::from_tar_reader(&tar[..]).await.unwrap();
+
+        let mut expected = GitRepository::default();
+        expected
+            .insert(&[], "README.md", Bytes::from("hello world!"))
+            .unwrap();
+        expected
+            .insert_executable(&["bin"], "run.sh", Bytes::from("#!/bin/sh\n"))
+            .unwrap();
+        expected
+            .insert(&["a", "b"], "c.txt", Bytes::from("test"))
+            .unwrap();
+        expected
+            .insert_with_mode(&[], "link", Bytes::from("README.md"), TreeItemKind::Symlink)
+            .unwrap();
+        assert!(repo.diff(&expected).is_empty());
+
+        let (commit, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let mut packed = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut packed).unwrap();
+        let dir = bare_repo_with_pack(&packed);
+
+        let listing = git(
+            dir.path(),
+            &[
+                "ls-tree",
+                "-r",
+                "--format=%(objectmode) %(path)",
+                &hex::encode(commit),
+            ],
+            &[],
+        );
+        assert_eq!(
+            listing,
+            "100644 README.md\n100644 a/b/c.txt\n100755 bin/run.sh\n120000 link\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn from_tar_reader_unsupported() {
+        for (entry_type, path, content) in [
+            (EntryType::Regular, "../escape.txt", &b"hello"[..]),
+            (EntryType::Regular, "/absolute.txt", b"hello"),
+            (EntryType::Link, "hard", b"README.md"),
+            (EntryType::Fifo, "fifo", b""),
+        ] {
+            let mut builder = Builder::new(Vec::new());
+            append(&mut builder, entry_type, path, 0o644, content).await;
+            let tar = builder.into_inner().await.unwrap();
+
+            assert!(
+                matches!(
+                    GitRepository::from_tar_reader(&tar[..]).await,
+                    Err(Error::UnsupportedTarEntry(_))
+                ),
+                "{path} should be rejected"
+            );
+        }
+    }
+}
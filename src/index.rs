@@ -0,0 +1,95 @@
+//! Generation of pack index (`.idx`) files, which allow Git to find objects within
+//! a packfile without reading the whole thing.
+//!
+//! <https://git-scm.com/docs/pack-format#_version_2_pack_idx_files_support_packs_larger_than_4_gib_and>
+
+use bytes::{BufMut, BytesMut};
+use sha1::Digest;
+
+use crate::{low_level::HashOutput, Error};
+
+/// The magic bytes at the start of every v2 index, chosen so they can't be mistaken
+/// for the fan-out table at the start of a v1 index.
+const V2_MAGIC: &[u8; 4] = b"\xfftOc";
+
+/// The location of an object within a packfile.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IndexEntry {
+    pub hash: HashOutput,
+    /// The offset of the object's header from the start of the packfile.
+    pub offset: u64,
+    /// The CRC-32 of the object as it's stored in the packfile, including its header.
+    pub crc32: u32,
+}
+
+/// Writes a v2 pack index for a packfile containing `entries` to `buf`, `pack_hash` is
+/// the checksum from the end of the packfile.
+///
+/// # Errors
+///
+/// Returns [`Error::EntriesExceedsU32`] if there are too many entries to fit in the index.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip(entries, buf), err))]
+pub fn encode_v2(
+    entries: &mut [IndexEntry],
+    pack_hash: &HashOutput,
+    buf: &mut BytesMut,
+) -> Result<(), Error> {
+    entries.sort_unstable_by_key(|entry| entry.hash);
+
+    let start = buf.len();
+
+    buf.extend_from_slice(V2_MAGIC);
+    buf.put_u32(2); // version
+
+    write_fan_out(entries, buf)?;
+
+    for entry in entries.iter() {
+        buf.extend_from_slice(&entry.hash);
+    }
+
+    for entry in entries.iter() {
+        buf.put_u32(entry.crc32);
+    }
+
+    // offsets that don't fit in 31 bits are written to a separate table of 64-bit
+    // offsets, with the MSB set and the index into that table in the 31-bit table
+    let mut large_offsets = Vec::new();
+    for entry in entries.iter() {
+        match u32::try_from(entry.offset) {
+            Ok(offset) if offset & 0x8000_0000 == 0 => buf.put_u32(offset),
+            _ => {
+                let index = u32::try_from(large_offsets.len()).map_err(Error::EntriesExceedsU32)?;
+                buf.put_u32(index | 0x8000_0000);
+                large_offsets.push(entry.offset);
+            }
+        }
+    }
+
+    for offset in large_offsets {
+        buf.put_u64(offset);
+    }
+
+    buf.extend_from_slice(pack_hash);
+
+    let checksum = sha1::Sha1::digest(&buf[start..]);
+    buf.extend_from_slice(&checksum);
+
+    Ok(())
+}
+
+/// Writes the table of 256 counts, where the nth entry is the number of objects with a
+/// hash whose first byte is less than or equal to n. `entries` must be sorted.
+fn write_fan_out(entries: &[IndexEntry], buf: &mut BytesMut) -> Result<(), Error> {
+    let mut count = 0;
+
+    for first_byte in 0..=u8::MAX {
+        count += entries[count..]
+            .iter()
+            .take_while(|entry| entry.hash[0] == first_byte)
+            .count();
+
+        buf.put_u32(u32::try_from(count).map_err(Error::EntriesExceedsU32)?);
+    }
+
+    Ok(())
+}
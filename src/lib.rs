@@ -20,23 +20,109 @@
 //! [Git Packfiles]: https://git-scm.com/book/en/v2/Git-Internals-Packfiles
 //! [`SidebandData`]: crate::codec::Codec::SidebandData
 
+pub mod capabilities;
 #[cfg(feature = "tokio-util")]
 pub mod codec;
+pub mod delta;
 mod error;
+pub mod fetch;
 pub mod high_level;
+pub mod index;
 pub mod low_level;
 mod packet_line;
+pub mod push;
+pub mod reader;
+pub mod refs;
 mod util;
+pub mod writer;
 
 pub use error::Error;
-pub use packet_line::PktLine;
+pub use packet_line::{
+    Frame, Parsed, PktLine, PktLineIter, PktLineOwned, PktLineWriter, RefAdvertisement,
+    RefAdvertisementParsed, SIDEBAND_DATA, SIDEBAND_ERROR, SIDEBAND_PROGRESS,
+};
+pub use util::{decode_hash, ArcOrCowBytes, ArcOrCowStr};
 
 #[cfg(test)]
 mod test {
     use bytes::Bytes;
-    use std::process::{Command, Stdio};
+    use std::{
+        alloc::{GlobalAlloc, Layout, System},
+        cell::Cell,
+        io::Write,
+        path::Path,
+        process::{Command, Stdio},
+    };
     use tempfile::TempDir;
 
+    /// Wraps the system allocator, counting the allocations made by each thread so
+    /// tests can assert on them without interference from other tests.
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _res = ALLOCATIONS.try_with(|v| v.set(v.get() + 1));
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _res = ALLOCATIONS.try_with(|v| v.set(v.get() + 1));
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Returns the number of allocations made on the current thread while running `f`.
+    pub fn count_allocations(f: impl FnOnce()) -> usize {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    }
+
+    /// Runs `git` in `dir` with the given arguments, feeding `stdin` to it and
+    /// returning its stdout.
+    pub fn git(dir: &Path, args: &[&str], stdin: &[u8]) -> String {
+        String::from_utf8(git_raw(dir, args, stdin)).unwrap()
+    }
+
+    /// Like [`git`], but for commands writing binary data to stdout.
+    pub fn git_raw(dir: &Path, args: &[&str], stdin: &[u8]) -> Vec<u8> {
+        let mut child = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+
+        let out = child.wait_with_output().unwrap();
+        assert!(out.status.success(), "git exited non-0");
+
+        out.stdout
+    }
+
+    /// Creates a bare repository containing all the objects within `packed`.
+    pub fn bare_repo_with_pack(packed: &[u8]) -> TempDir {
+        let scratch_dir = TempDir::new().unwrap();
+
+        git(scratch_dir.path(), &["init", "--bare", "--quiet"], &[]);
+        git(scratch_dir.path(), &["unpack-objects", "-q"], packed);
+
+        scratch_dir
+    }
+
     pub fn verify_pack_file(packed: Bytes) -> String {
         let scratch_dir = TempDir::new().unwrap();
         let packfile_path = scratch_dir.path().join("example.pack");
@@ -66,4 +152,69 @@ mod test {
 
         String::from_utf8(out.stdout).unwrap()
     }
+
+    /// A server Git can be pointed at with `--upload-pack`, which replies with canned
+    /// responses rather than running `git upload-pack`, so tests can check how Git
+    /// handles what we'd send it.
+    pub struct ScriptServer(TempDir);
+
+    impl ScriptServer {
+        /// A server that sends `response` as soon as the client connects, without
+        /// reading the client's request. This suits a ref advertisement, or a protocol
+        /// v2 exchange where the test knows every request the client will make.
+        pub fn new(response: &[u8]) -> Self {
+            Self::with_script("cat response; cat >/dev/null", &[("response", response)])
+        }
+
+        /// A protocol v0 server that sends `advertisement`, waits for the client to
+        /// finish negotiating with `done`, then sends `response`.
+        pub fn after_done(advertisement: &[u8], response: &[u8]) -> Self {
+            Self::with_script(
+                "cat advertisement
+                 while read -r line; do case \"$line\" in *done) break;; esac; done
+                 cat response",
+                &[("advertisement", advertisement), ("response", response)],
+            )
+        }
+
+        fn with_script(script: &str, files: &[(&str, &[u8])]) -> Self {
+            let dir = TempDir::new().unwrap();
+            std::fs::write(
+                dir.path().join("server.sh"),
+                format!("cd \"$(dirname \"$0\")\"\n{script}"),
+            )
+            .unwrap();
+            let server = Self(dir);
+            for (name, content) in files {
+                server.write(name, content);
+            }
+            server
+        }
+
+        /// Replaces the file `name` the server sends from, such as `response`.
+        pub fn write(&self, name: &str, content: &[u8]) {
+            std::fs::write(self.0.path().join(name), content).unwrap();
+        }
+
+        pub fn path(&self) -> &Path {
+            self.0.path()
+        }
+
+        pub fn url(&self) -> String {
+            format!("file://{}", self.path().display())
+        }
+
+        /// Builds `git -c protocol.version=<protocol> <subcommand> --upload-pack ...`
+        /// talking to this server, run from the server's directory unless the caller
+        /// changes it. The caller adds the rest of the arguments, such as the URL.
+        pub fn git(&self, protocol: u8, subcommand: &str) -> Command {
+            let mut command = Command::new("git");
+            command
+                .current_dir(self.path())
+                .args(["-c", &format!("protocol.version={protocol}"), subcommand])
+                .arg("--upload-pack")
+                .arg(format!("sh {}", self.path().join("server.sh").display()));
+            command
+        }
+    }
 }
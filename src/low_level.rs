@@ -4,33 +4,286 @@
 //! order that Git expects.
 
 use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     convert::TryInto,
     fmt::{Display, Formatter, Write},
-    io::Write as IoWrite,
+    str::FromStr,
 };
 
 use bytes::{BufMut, Bytes, BytesMut};
-use flate2::{write::ZlibEncoder, Compression};
+use flate2::{Compress, Compression, FlushCompress, Status};
 use sha1::Digest;
 
-use crate::{util::ArcOrCowStr, Error};
+use crate::{
+    util::{ArcOrCowBytes, ArcOrCowStr},
+    Error,
+};
 
 pub type HashOutput = [u8; 20];
 
+/// The maximum length of a chain of deltas, where each delta's base is another delta,
+/// allowed in a packfile by default. This matches the default `pack.depth` Git uses.
+pub const DEFAULT_MAX_DELTA_CHAIN_LENGTH: usize = 50;
+
+/// The hash of a tree with no entries, `4b825dc642cb6eb9a060e54bf8d69288fbee4904`.
+pub const EMPTY_TREE_HASH: HashOutput = [
+    0x4b, 0x82, 0x5d, 0xc6, 0x42, 0xcb, 0x6e, 0xb9, 0xa0, 0x60, 0xe5, 0x4b, 0xf8, 0xd6, 0x92, 0x88,
+    0xfb, 0xee, 0x49, 0x04,
+];
+
 // The packfile itself is a very simple format. There is a header, a
 // series of packed objects (each with it's own header and body) and
 // then a checksum trailer. The first four bytes is the string 'PACK',
 // which is sort of used to make sure you're getting the start of the
 // packfile correctly. This is followed by a 4-byte packfile version
 // number and then a 4-byte number of entries in that file.
+//
+// A thin pack is one containing deltas against bases that aren't in the packfile
+// itself, which is only valid to send to a client that advertised the `thin-pack`
+// capability and is known to have those bases already (such as from its `have`s).
+// The client is responsible for completing the pack by appending the missing bases,
+// like `git index-pack --fix-thin` does.
 pub struct PackFile<'a> {
-    entries: &'a [PackFileEntry],
+    entries: Cow<'a, [PackFileEntry]>,
+    max_delta_chain_length: usize,
+    thin: bool,
 }
 
 impl<'a> PackFile<'a> {
     #[must_use]
     pub fn new(entries: &'a [PackFileEntry]) -> Self {
-        Self { entries }
+        Self {
+            entries: Cow::Borrowed(entries),
+            max_delta_chain_length: DEFAULT_MAX_DELTA_CHAIN_LENGTH,
+            thin: false,
+        }
+    }
+
+    /// Builds a packfile that owns its entries, so it can be returned or stored
+    /// without keeping the entries alive separately.
+    #[must_use]
+    pub fn owned(entries: Vec<PackFileEntry>) -> PackFile<'static> {
+        PackFile {
+            entries: Cow::Owned(entries),
+            max_delta_chain_length: DEFAULT_MAX_DELTA_CHAIN_LENGTH,
+            thin: false,
+        }
+    }
+
+    /// Allows deltas in the packfile to be based on objects that aren't in the
+    /// packfile, building a thin pack. This should only be set if the client
+    /// advertised the `thin-pack` capability, see [`FetchRequest::thin_pack`].
+    ///
+    /// [`FetchRequest::thin_pack`]: crate::fetch::FetchRequest::thin_pack
+    #[must_use]
+    pub fn thin(mut self, thin: bool) -> Self {
+        self.thin = thin;
+        self
+    }
+
+    /// Sets the maximum length of a chain of deltas within the packfile, defaults to
+    /// [`DEFAULT_MAX_DELTA_CHAIN_LENGTH`].
+    #[must_use]
+    pub fn with_max_delta_chain_length(mut self, max_delta_chain_length: usize) -> Self {
+        self.max_delta_chain_length = max_delta_chain_length;
+        self
+    }
+
+    /// Walks the base of every delta in the packfile, ensuring none of the chains
+    /// of deltas are longer than the configured maximum. In a thin pack, bases that
+    /// aren't in the packfile end the chain.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::DeltaChainTooLong`] if any of the chains are too long, or
+    /// [`Error::MissingDeltaBase`] if a delta's base isn't in a packfile that isn't
+    /// thin.
+    pub fn validate_delta_chains(&self) -> Result<(), Error> {
+        let deltas: HashMap<HashOutput, HashOutput> = self
+            .entries
+            .iter()
+            .filter_map(|entry| match entry {
+                PackFileEntry::RefDelta { base, hash, .. } => Some((*hash, *base)),
+                _ => None,
+            })
+            .collect();
+
+        if deltas.is_empty() {
+            return Ok(());
+        }
+
+        if !self.thin {
+            self.check_delta_bases(&deltas)?;
+        }
+
+        let mut lengths: HashMap<HashOutput, usize> = HashMap::with_capacity(deltas.len());
+
+        for (&hash, &base) in &deltas {
+            // walk down the chain until we get to a full object, or a delta we've
+            // already calculated the length for
+            let mut chain = vec![hash];
+            let mut next = Some(base);
+            let mut length = 0;
+
+            while let Some(base) = next {
+                if let Some(known) = lengths.get(&base) {
+                    length = *known;
+                    break;
+                }
+
+                chain.push(base);
+
+                // guards against cycles, as well as overly long chains
+                if chain.len() > self.max_delta_chain_length + 1 {
+                    return Err(Error::DeltaChainTooLong {
+                        hash,
+                        max: self.max_delta_chain_length,
+                    });
+                }
+
+                next = deltas.get(&base).copied();
+            }
+
+            for hash in chain.into_iter().rev() {
+                if deltas.contains_key(&hash) {
+                    length += 1;
+                }
+
+                if length > self.max_delta_chain_length {
+                    return Err(Error::DeltaChainTooLong {
+                        hash,
+                        max: self.max_delta_chain_length,
+                    });
+                }
+
+                lengths.insert(hash, length);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Ensures the base of every delta in `deltas`, keyed by the delta's hash, is
+    /// within the packfile. Only the bases that aren't deltas themselves need
+    /// hashing to find, so full objects are hashed until they've all been found.
+    fn check_delta_bases(&self, deltas: &HashMap<HashOutput, HashOutput>) -> Result<(), Error> {
+        let mut missing: HashSet<HashOutput> = deltas
+            .values()
+            .filter(|base| !deltas.contains_key(*base))
+            .copied()
+            .collect();
+
+        for entry in self.entries.iter() {
+            if missing.is_empty() {
+                break;
+            }
+
+            if !matches!(entry, PackFileEntry::RefDelta { .. }) {
+                missing.remove(&entry.hash()?);
+            }
+        }
+
+        match deltas.iter().find(|(_, base)| missing.contains(*base)) {
+            Some((&hash, &base)) => Err(Error::MissingDeltaBase { hash, base }),
+            None => Ok(()),
+        }
+    }
+
+    /// Ensures every object referred to by the trees and commits in `entries` is
+    /// also within `entries`, so the packfile is complete on its own. Submodules
+    /// refer to commits in another repository, so aren't checked, and neither are
+    /// the contents of deltas.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::DanglingReference`] on the first reference to an object
+    /// that isn't in `entries`, or an error if any of the entries fail to serialise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(entries), err))]
+    pub fn rehash_verify(entries: &[PackFileEntry]) -> Result<(), Error> {
+        let hashes = entries
+            .iter()
+            .map(PackFileEntry::hash)
+            .collect::<Result<HashSet<_>, _>>()?;
+
+        for entry in entries {
+            let verify = |target: &HashOutput| {
+                if hashes.contains(target) {
+                    Ok(())
+                } else {
+                    Err(Error::DanglingReference {
+                        referencing: entry.hash()?,
+                        target: *target,
+                    })
+                }
+            };
+
+            match entry {
+                PackFileEntry::Commit(commit) => verify(&commit.tree)?,
+                PackFileEntry::Tag(tag) => verify(&tag.object)?,
+                PackFileEntry::Tree(items) => {
+                    for item in items {
+                        if item.kind != TreeItemKind::Submodule {
+                            verify(&item.hash)?;
+                        }
+                    }
+                }
+                // raw objects aren't parsed, so their references can't be checked
+                PackFileEntry::Blob(_)
+                | PackFileEntry::RefDelta { .. }
+                | PackFileEntry::Raw { .. }
+                | PackFileEntry::Compressed { .. } => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "tokio-util")]
+    pub(crate) fn entries(&self) -> &[PackFileEntry] {
+        &self.entries
+    }
+
+    /// Builds a packfile from entries that are produced on demand, rather than all
+    /// being held in a slice, so only the entry currently being encoded needs to be in
+    /// memory. `count` is the number of entries `entries` will yield, which is needed
+    /// upfront as it's written in the packfile's header.
+    ///
+    /// Deltas can't be checked against the rest of the packfile without holding onto
+    /// every entry, so unlike [`PackFile::encode_to`] the length of delta chains and
+    /// the presence of their bases aren't validated.
+    pub fn from_iter<I>(count: u32, entries: I) -> PackFileFromIter<I::IntoIter>
+    where
+        I: IntoIterator<Item = Result<PackFileEntry, Error>>,
+    {
+        PackFileFromIter {
+            count,
+            entries: entries.into_iter(),
+        }
+    }
+
+    /// Counts the entries in the packfile by type, along with their total size before
+    /// compression. This is a single pass over the entries, without encoding them.
+    ///
+    /// The type of a delta is the type of the object it builds, which isn't known
+    /// without resolving its base, so deltas are counted separately.
+    #[must_use]
+    pub fn statistics(&self) -> PackFileStats {
+        let mut stats = PackFileStats::default();
+
+        for entry in self.entries.iter() {
+            match entry.kind() {
+                ObjectKind::Commit => stats.commit_count += 1,
+                ObjectKind::Tree => stats.tree_count += 1,
+                ObjectKind::Blob => stats.blob_count += 1,
+                ObjectKind::Tag => stats.tag_count += 1,
+                ObjectKind::OfsDelta | ObjectKind::RefDelta => stats.delta_count += 1,
+            }
+
+            stats.total_uncompressed_bytes += entry.uncompressed_size();
+        }
+
+        stats
     }
 
     #[must_use]
@@ -43,17 +296,187 @@ impl<'a> PackFile<'a> {
         20
     }
 
+    /// Returns an upper bound on the number of bytes [`PackFile::encode_to`] will write,
+    /// assuming every entry is incompressible. This only needs the uncompressed size of
+    /// each entry so is cheap to calculate, making it useful for pre-allocating buffers.
+    #[must_use]
+    pub fn encoded_size_bound(&self) -> usize {
+        Self::header_size()
+            + self
+                .entries
+                .iter()
+                .map(PackFileEntry::encoded_size_bound)
+                .sum::<usize>()
+            + Self::footer_size()
+    }
+
+    /// Returns the exact number of bytes [`PackFile::encode_to`] will write. This
+    /// compresses every entry to find out, so costs as much as encoding the packfile
+    /// does (without keeping the output), prefer [`PackFile::encoded_size_bound`] where
+    /// an upper bound will do.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the entries fail to serialise or compress.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn encoded_size_exact(&self) -> Result<usize, Error> {
+        let mut encoder = PackEncoder::default();
+        let mut scratch = BytesMut::new();
+        let mut size = Self::header_size() + Self::footer_size();
+
+        for entry in self.entries.iter() {
+            scratch.clear();
+            entry.encode_with(&mut encoder, &mut scratch)?;
+            size += scratch.len();
+        }
+
+        Ok(size)
+    }
+
+    /// Encodes the packfile, appending it to `original_buf` and returning the number
+    /// of bytes appended.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there are more than [`u32::MAX`] entries, if any of the
+    /// delta chains are too long, or if any of the entries fail to serialise or compress.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, original_buf), err)
+    )]
+    pub fn encode_to(&self, original_buf: &mut BytesMut) -> Result<usize, Error> {
+        let start = original_buf.len();
+        self.encode_recording(original_buf, |_, _, _| Ok(()))?;
+        Ok(original_buf.len() - start)
+    }
+
+    /// Clears `pool` and encodes the packfile into it, as [`PackFile::encode_to`] does,
+    /// returning the length of the encoded pack. The capacity `pool` already has is
+    /// reused, so a server encoding many packs can keep a single buffer around
+    /// rather than growing a new one from empty for every pack.
+    ///
+    /// The pack should be written out of `pool` before it's reused for the next one.
+    /// If it's frozen or split off instead, the capacity can only be reclaimed once
+    /// every handle to it has been dropped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`PackFile::encode_to`].
+    pub fn encode_into_pooled(&self, pool: &mut BytesMut) -> Result<usize, Error> {
+        pool.clear();
+        self.encode_to(pool)
+    }
+
+    /// Encodes the packfile as [`PackFile::encode_to`] does, returning the checksum
+    /// written to the pack's footer. This is the hash Git names the pack and its index
+    /// after, as in `pack-<hash>.pack`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`PackFile::encode_to`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, original_buf), err)
+    )]
+    pub fn encode_to_with_hash(&self, original_buf: &mut BytesMut) -> Result<HashOutput, Error> {
+        self.encode_recording(original_buf, |_, _, _| Ok(()))
+    }
+
+    /// Encodes the packfile as [`PackFile::encode_to`] does, returning the hash of each
+    /// entry along with the offset it was written at, relative to the start of the
+    /// pack header, in the same order as the entries. These can be sorted by hash to
+    /// build an index for the pack.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`PackFile::encode_to`].
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, original_buf), err)
+    )]
+    pub fn encode_to_indexed(
+        &self,
+        original_buf: &mut BytesMut,
+    ) -> Result<Vec<(HashOutput, u64)>, Error> {
+        let mut offsets = Vec::with_capacity(self.entries.len());
+
+        self.encode_recording(original_buf, |entry, offset, _| {
+            offsets.push((entry.hash()?, offset));
+            Ok(())
+        })?;
+
+        Ok(offsets)
+    }
+
+    /// Encodes the packfile as [`PackFile::encode_to`] does, returning the hash of each
+    /// entry along with its uncompressed size and the number of bytes it took up in
+    /// the pack, including its header, in the same order as the entries. This is
+    /// useful for seeing how well each entry compressed when tuning the compression.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`PackFile::encode_to`].
     #[cfg_attr(
         feature = "tracing",
         tracing::instrument(skip(self, original_buf), err)
     )]
-    pub fn encode_to(&self, original_buf: &mut BytesMut) -> Result<(), Error> {
+    pub fn encode_to_with_sizes(
+        &self,
+        original_buf: &mut BytesMut,
+    ) -> Result<Vec<(HashOutput, usize, usize)>, Error> {
+        let mut sizes = Vec::with_capacity(self.entries.len());
+
+        self.encode_recording(original_buf, |entry, _, encoded| {
+            sizes.push((entry.hash()?, entry.uncompressed_size(), encoded.len()));
+            Ok(())
+        })?;
+
+        Ok(sizes)
+    }
+
+    /// Encodes the packfile, appending it to `original_buf` and calling `record` with
+    /// each entry, the offset it was written at relative to the start of the pack and
+    /// the bytes it was encoded to. Returns the checksum written to the pack's footer.
+    pub(crate) fn encode_recording(
+        &self,
+        original_buf: &mut BytesMut,
+        record: impl FnMut(&PackFileEntry, u64, &[u8]) -> Result<(), Error>,
+    ) -> Result<HashOutput, Error> {
+        self.encode_recording_with_header(original_buf, *b"PACK", 2, record)
+    }
+
+    /// Encodes the packfile as [`PackFile::encode_to`] does, but with the given
+    /// `magic` and `version` in its header rather than `PACK` and `2`. This is only
+    /// useful for building malformed packs to test readers against.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`PackFile::encode_to`].
+    pub fn encode_to_with_header(
+        &self,
+        original_buf: &mut BytesMut,
+        magic: [u8; 4],
+        version: u32,
+    ) -> Result<(), Error> {
+        self.encode_recording_with_header(original_buf, magic, version, |_, _, _| Ok(()))?;
+        Ok(())
+    }
+
+    fn encode_recording_with_header(
+        &self,
+        original_buf: &mut BytesMut,
+        magic: [u8; 4],
+        version: u32,
+        mut record: impl FnMut(&PackFileEntry, u64, &[u8]) -> Result<(), Error>,
+    ) -> Result<HashOutput, Error> {
+        self.validate_delta_chains()?;
+
         let mut buf = original_buf.split_off(original_buf.len());
         buf.reserve(Self::header_size() + Self::footer_size());
 
         // header
-        buf.extend_from_slice(b"PACK"); // magic header
-        buf.put_u32(2); // version
+        buf.extend_from_slice(&magic); // magic header
+        buf.put_u32(version); // version
         buf.put_u32(
             self.entries
                 .len()
@@ -62,365 +485,3400 @@ impl<'a> PackFile<'a> {
         ); // number of entries in the packfile
 
         // body
-        for entry in self.entries {
-            entry.encode_to(&mut buf)?;
+        let mut encoder = PackEncoder::default();
+        for entry in self.entries.iter() {
+            let offset = buf.len();
+            entry.encode_with(&mut encoder, &mut buf)?;
+            record(entry, offset as u64, &buf[offset..])?;
         }
 
         // footer
-        buf.extend_from_slice(&sha1::Sha1::digest(&buf[..]));
+        let hash: HashOutput = sha1::Sha1::digest(&buf[..]).into();
+        buf.extend_from_slice(&hash);
 
         original_buf.unsplit(buf);
 
-        Ok(())
+        Ok(hash)
     }
-}
 
-#[derive(Debug, Clone)]
-pub struct Commit {
-    pub tree: HashOutput,
-    // pub parent: [u8; 20],
-    pub author: CommitUserInfo,
-    pub committer: CommitUserInfo,
-    // pub gpgsig: &str,
-    pub message: &'static str,
-}
+    /// Partitions `entries` into several packfiles, each with at most `max_entries`
+    /// entries and an [`encoded_size_bound`] of at most `max_bytes`. Packs are cut
+    /// between entries, keeping their order, and never between a delta and its base
+    /// so every pack is valid on its own.
+    ///
+    /// As this only has the bound to go by, the encoded packs will usually be smaller
+    /// than `max_bytes`. Use [`PackFile::encode_split`] to split by exact size instead.
+    ///
+    /// [`encoded_size_bound`]: PackFile::encoded_size_bound
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SplitSizeTooSmall`] or [`Error::SplitEntriesTooSmall`] if a run
+    /// of entries that can't be split up, such as a delta chain, doesn't fit within
+    /// the limits.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(entries), err))]
+    pub fn split(
+        entries: &'a [PackFileEntry],
+        max_bytes: usize,
+        max_entries: u32,
+    ) -> Result<Vec<PackFile<'a>>, Error> {
+        let mut index_by_hash = HashMap::with_capacity(entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            index_by_hash.entry(entry.hash()?).or_insert(i);
+        }
 
-impl Commit {
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, out), err))]
-    fn encode_to(&self, out: &mut BytesMut) -> Result<(), Error> {
-        let mut tree_hex = [0_u8; 20 * 2];
-        hex::encode_to_slice(self.tree, &mut tree_hex).map_err(Error::EncodeTreeHash)?;
+        // a pack can start at `i` as long as no delta from `i` onwards has its base
+        // before `i`, so walk backwards keeping track of the earliest base referred to
+        let mut can_start_at = vec![true; entries.len()];
+        let mut earliest_base = usize::MAX;
+        for (i, entry) in entries.iter().enumerate().rev() {
+            if let PackFileEntry::RefDelta { base, .. } = entry {
+                if let Some(&base) = index_by_hash.get(base) {
+                    earliest_base = earliest_base.min(base);
+                }
+            }
 
-        out.write_str("tree ")?;
-        out.extend_from_slice(&tree_hex);
-        out.write_char('\n')?;
+            can_start_at[i] = earliest_base >= i;
+        }
 
-        writeln!(out, "author {}", self.author)?;
-        writeln!(out, "committer {}", self.committer)?;
-        write!(out, "\n{}", self.message)?;
+        let overhead = Self::header_size() + Self::footer_size();
+        let max_entries = usize::try_from(max_entries).unwrap_or(usize::MAX);
 
-        Ok(())
+        let mut packs = Vec::new();
+        let mut pack_start = 0;
+        let mut pack_size = overhead;
+        let mut run_start = 0;
+        let mut run_size = 0;
+
+        for (i, entry) in entries.iter().enumerate() {
+            if i != 0 && can_start_at[i] {
+                // the run of entries before `i` can't be split up any further, so
+                // either add it to the current pack or start a new pack with it
+                Self::check_split_run(run_size, i - run_start, max_bytes, max_entries)?;
+
+                if pack_size + run_size > max_bytes || i - pack_start > max_entries {
+                    packs.push(PackFile::new(&entries[pack_start..run_start]));
+                    pack_start = run_start;
+                    pack_size = overhead;
+                }
+
+                pack_size += run_size;
+                run_start = i;
+                run_size = 0;
+            }
+
+            run_size += entry.encoded_size_bound();
+        }
+
+        if !entries.is_empty() {
+            Self::check_split_run(run_size, entries.len() - run_start, max_bytes, max_entries)?;
+
+            if pack_size + run_size > max_bytes || entries.len() - pack_start > max_entries {
+                packs.push(PackFile::new(&entries[pack_start..run_start]));
+                pack_start = run_start;
+            }
+
+            packs.push(PackFile::new(&entries[pack_start..]));
+        }
+
+        Ok(packs)
     }
 
-    #[must_use]
-    pub fn size(&self) -> usize {
-        let mut len = 0;
-        len += "tree ".len() + (self.tree.len() * 2) + "\n".len();
-        len += "author ".len() + self.author.size() + "\n".len();
-        len += "committer ".len() + self.committer.size() + "\n".len();
-        len += "\n".len() + self.message.len();
-        len
+    /// Ensures a run of entries that can't be split up fits within a single pack.
+    fn check_split_run(
+        size: usize,
+        count: usize,
+        max_bytes: usize,
+        max_entries: usize,
+    ) -> Result<(), Error> {
+        let required = Self::header_size() + size + Self::footer_size();
+
+        if required > max_bytes {
+            Err(Error::SplitSizeTooSmall {
+                required,
+                max: max_bytes,
+            })
+        } else if count > max_entries {
+            Err(Error::SplitEntriesTooSmall {
+                required: count,
+                max: max_entries,
+            })
+        } else {
+            Ok(())
+        }
     }
-}
 
-#[derive(Clone, Copy, Debug)]
-pub struct CommitUserInfo {
-    pub name: &'static str,
-    pub email: &'static str,
-    pub time: time::OffsetDateTime,
-}
+    /// Encodes the packfile as several standalone packfiles, none of which are larger
+    /// than `max_bytes`. Entries are written in order until the next would exceed the
+    /// limit, at which point a new packfile is started. Deltas are always kept in the
+    /// same packfile as their base.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::SplitSizeTooSmall`] if a single entry, along with any deltas
+    /// based on it, can't fit within `max_bytes`, or any of the errors from
+    /// [`PackFile::encode_to`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn encode_split(&self, max_bytes: usize) -> Result<Vec<Bytes>, Error> {
+        self.validate_delta_chains()?;
 
-impl Display for CommitUserInfo {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{} <{}> {} +0000",
-            self.name,
-            self.email,
-            self.time.unix_timestamp()
-        )
+        // group each delta with its base, keeping the groups in the order their first
+        // entry appears in the packfile
+        let mut groups: Vec<(usize, Vec<Bytes>)> = Vec::new();
+        let mut group_by_hash: HashMap<HashOutput, usize> = HashMap::new();
+
+        let mut encoder = PackEncoder::default();
+        let mut buf = BytesMut::new();
+
+        for entry in self.entries.iter() {
+            entry.encode_with(&mut encoder, &mut buf)?;
+            let entry_bytes = buf.split().freeze();
+
+            let group = match entry {
+                PackFileEntry::RefDelta { base, .. } => group_by_hash.get(base).copied(),
+                _ => None,
+            }
+            .unwrap_or_else(|| {
+                groups.push((0, Vec::new()));
+                groups.len() - 1
+            });
+
+            group_by_hash.insert(entry.hash()?, group);
+
+            let (size, group) = &mut groups[group];
+            *size += entry_bytes.len();
+            group.push(entry_bytes);
+        }
+
+        let overhead = Self::header_size() + Self::footer_size();
+        let mut packs = Vec::new();
+        let mut current: Vec<Bytes> = Vec::new();
+        let mut current_size = overhead;
+
+        for (size, group) in groups {
+            if overhead + size > max_bytes {
+                return Err(Error::SplitSizeTooSmall {
+                    required: overhead + size,
+                    max: max_bytes,
+                });
+            }
+
+            if current_size + size > max_bytes {
+                packs.push(Self::encode_raw_entries(&current)?);
+                current.clear();
+                current_size = overhead;
+            }
+
+            current_size += size;
+            current.extend(group);
+        }
+
+        if !current.is_empty() {
+            packs.push(Self::encode_raw_entries(&current)?);
+        }
+
+        Ok(packs)
+    }
+
+    /// Writes the header of a packfile containing `count` entries to `buf`, for
+    /// encoding a pack a piece at a time. Each entry can then be written with
+    /// [`PackFileEntry::encode_with`], and the pack finished with
+    /// [`PackFile::write_trailer`], feeding everything written into the
+    /// [`PackHasher`] given to it along the way.
+    pub fn write_header(count: u32, buf: &mut BytesMut) {
+        buf.extend_from_slice(b"PACK");
+        buf.put_u32(2);
+        buf.put_u32(count);
+    }
+
+    /// Finishes a packfile written a piece at a time, appending the checksum of
+    /// everything fed into `hasher` to `buf` as the pack's trailer and returning it.
+    ///
+    /// `hasher` must have been fed the header and every entry exactly as they were
+    /// written, the trailer is otherwise wrong and Git will reject the pack.
+    pub fn write_trailer(hasher: PackHasher, buf: &mut BytesMut) -> HashOutput {
+        let hash: HashOutput = hasher.0.finalize().into();
+        buf.extend_from_slice(&hash);
+        hash
+    }
+
+    /// Wraps already encoded entries with a packfile header and footer.
+    fn encode_raw_entries(entries: &[Bytes]) -> Result<Bytes, Error> {
+        let mut buf = BytesMut::with_capacity(
+            Self::header_size()
+                + entries.iter().map(Bytes::len).sum::<usize>()
+                + Self::footer_size(),
+        );
+
+        Self::write_header(
+            entries.len().try_into().map_err(Error::EntriesExceedsU32)?,
+            &mut buf,
+        );
+
+        for entry in entries {
+            buf.extend_from_slice(entry);
+        }
+
+        let hash = sha1::Sha1::digest(&buf[..]);
+        buf.extend_from_slice(&hash);
+
+        Ok(buf.freeze())
     }
 }
 
-impl CommitUserInfo {
+/// The running checksum of a packfile written a piece at a time, which becomes its
+/// trailer, see [`PackFile::write_trailer`].
+#[derive(Clone, Default)]
+pub struct PackHasher(sha1::Sha1);
+
+impl PackHasher {
     #[must_use]
-    pub fn size(&self) -> usize {
-        let timestamp_len = itoa::Buffer::new().format(self.time.unix_timestamp()).len();
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        self.name.len()
-            + "< ".len()
-            + self.email.len()
-            + "> ".len()
-            + timestamp_len
-            + " +0000".len()
+    /// Adds the next piece of the packfile to the checksum, exactly as it was written.
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum TreeItemKind {
-    File,
-    Directory,
+/// Builds a packfile an object at a time, checking each object only refers to objects
+/// that were pushed before it. This catches the most common cause of a corrupt
+/// packfile, an object referring to one that was never added, as soon as it happens
+/// rather than when the client tries to unpack it. The whole packfile can also be
+/// checked after the fact with [`PackFile::rehash_verify`].
+///
+/// Objects pushed more than once are only written to the packfile once.
+#[derive(Debug, Default)]
+pub struct PackFileBuilder {
+    entries: Vec<PackFileEntry>,
+    hashes: HashSet<HashOutput>,
 }
 
-impl TreeItemKind {
+impl PackFileBuilder {
     #[must_use]
-    pub const fn mode(&self) -> &'static str {
-        match self {
-            Self::File => "100644",
-            Self::Directory => "40000",
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a blob to the packfile, returning its hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the blob fails to serialise.
+    pub fn push_blob(&mut self, data: Bytes) -> Result<HashOutput, Error> {
+        self.push(PackFileEntry::Blob(data), &[])
+    }
+
+    /// Adds a tree to the packfile, returning its hash. Submodules refer to commits
+    /// in another repository, so are the only items that don't need to have been
+    /// pushed already.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::DanglingReference`] if any of the items haven't been pushed,
+    /// or an error if the tree fails to serialise.
+    pub fn push_tree(&mut self, items: Vec<TreeItem>) -> Result<HashOutput, Error> {
+        let references: Vec<_> = items
+            .iter()
+            .filter(|item| item.kind != TreeItemKind::Submodule)
+            .map(|item| item.hash)
+            .collect();
+
+        self.push(PackFileEntry::Tree(items), &references)
+    }
+
+    /// Adds a commit to the packfile, returning its hash. Only the commit's tree needs
+    /// to have been pushed, as its parents may already be held by the client, such as
+    /// when sending only new commits in response to a fetch.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::DanglingReference`] if the commit's tree hasn't been pushed,
+    /// or an error if the commit fails to serialise.
+    pub fn push_commit(&mut self, commit: Commit) -> Result<HashOutput, Error> {
+        let tree = commit.tree;
+        self.push(PackFileEntry::Commit(commit), &[tree])
+    }
+
+    /// Adds an annotated tag to the packfile, returning its hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::DanglingReference`] if the tagged object hasn't been pushed,
+    /// or an error if the tag fails to serialise.
+    pub fn push_tag(&mut self, tag: Tag) -> Result<HashOutput, Error> {
+        let object = tag.object;
+        self.push(PackFileEntry::Tag(tag), &[object])
+    }
+
+    fn push(
+        &mut self,
+        entry: PackFileEntry,
+        references: &[HashOutput],
+    ) -> Result<HashOutput, Error> {
+        let hash = entry.hash()?;
+
+        if let Some(target) = references
+            .iter()
+            .find(|target| !self.hashes.contains(*target))
+        {
+            return Err(Error::DanglingReference {
+                referencing: hash,
+                target: *target,
+            });
+        }
+
+        if self.hashes.insert(hash) {
+            self.entries.push(entry);
         }
+
+        Ok(hash)
+    }
+
+    /// Finishes the packfile, with its entries in the order they were pushed.
+    #[must_use]
+    pub fn build(self) -> PackFile<'static> {
+        PackFile::owned(self.entries)
     }
 }
 
-#[derive(Debug)]
-pub struct TreeItem {
-    pub kind: TreeItemKind,
-    pub name: ArcOrCowStr,
-    pub hash: HashOutput,
-    pub sort_name: String,
+/// A packfile built from an iterator of entries, see [`PackFile::from_iter`].
+pub struct PackFileFromIter<I> {
+    count: u32,
+    entries: I,
 }
 
-// `[mode] [name]\0[hash]`
-impl TreeItem {
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, out), err))]
-    fn encode_to(&self, out: &mut BytesMut) -> Result<(), Error> {
-        out.write_str(self.kind.mode())?;
-        write!(out, " {}\0", self.name)?;
-        out.extend_from_slice(&self.hash);
-        Ok(())
+impl<I: Iterator<Item = Result<PackFileEntry, Error>>> PackFileFromIter<I> {
+    /// Encodes the packfile, appending it to `buf`. Returns the checksum written to
+    /// the pack's footer.
+    ///
+    /// # Errors
+    ///
+    /// Returns the first error yielded by the iterator, an error if any of the entries
+    /// fail to serialise or compress, or [`Error::MalformedPackFile`] if the iterator
+    /// doesn't yield `count` entries.
+    pub fn encode_to(self, buf: &mut BytesMut) -> Result<HashOutput, Error> {
+        self.encode_with(|data| {
+            buf.extend_from_slice(data);
+            Ok(())
+        })
     }
 
-    #[must_use]
-    pub fn size(&self) -> usize {
-        self.kind.mode().len() + " ".len() + self.name.len() + "\0".len() + self.hash.len()
+    /// Encodes the packfile, writing it to `out` an entry at a time so the packfile
+    /// is never held in memory as a whole. Returns the checksum written to the pack's
+    /// footer.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error under the same conditions as [`PackFileFromIter::encode_to`],
+    /// or [`Error::Io`] if writing to `out` fails.
+    pub fn write_to(self, mut out: impl std::io::Write) -> Result<HashOutput, Error> {
+        self.encode_with(|data| Ok(out.write_all(data)?))
     }
-}
 
-#[derive(Debug)] // could be copy but Vec<TreeItem<'a>>
-pub enum PackFileEntry {
-    // jordan@Jordans-MacBook-Pro-2 0d % printf "\x1f\x8b\x08\x00\x00\x00\x00\x00" | cat - f5/473259d9674ed66239766a013f96a3550374e3 | gzip -dc
-    // commit 1068tree 0d586b48bc42e8591773d3d8a7223551c39d453c
-    // parent c2a862612a14346ae95234f26efae1ee69b5b7a9
-    // author Jordan Doyle <jordan@doyle.la> 1630244577 +0100
-    // committer Jordan Doyle <jordan@doyle.la> 1630244577 +0100
-    // gpgsig -----BEGIN PGP SIGNATURE-----
-    //
-    // iQIzBAABCAAdFiEEMn1zof7yzaURQBGDHqa65vZtxJoFAmErjuEACgkQHqa65vZt
-    // xJqhvhAAieKXnGRjT926qzozcvarC8D3TlA+Z1wVXueTAWqfusNIP0zCun/crOb2
-    // tOULO+/DXVBmwu5eInAf+t/wvlnIsrzJonhVr1ZT0f0vDX6fs2vflWg4UCVEuTsZ
-    // tg+aTjcibwnmViIM9XVOzhU8Au2OIqMQLyQOMWSt8NhY0W2WhBCdQvhktvK1V8W6
-    // omPs04SrR39xWBDQaxsXYxq/1ZKUYXDwudvEfv14EvrxG1vWumpUVJd7Ib5w4gXX
-    // fYa95DxYL720ZaiWPIYEG8FMBzSOpo6lUzY9g2/o/wKwSQZJNvpaMGCuouy8Fb+E
-    // UaqC0XPxqpKG9duXPgCldUr+P7++48CF5zc358RBGz5OCNeTREsIQQo5PUO1k+wO
-    // FnGOQTT8vvNOrxBgb3QgKu67RVwWDc6JnQCNpUrhUJrXMDWnYLBqo4Y+CdKGSQ4G
-    // hW8V/hVTOlJZNi8bbU4v53cxh4nXiMM6NKUblUKs65ar3/2dkojwunz7r7GVZ6mG
-    // QUpr9+ybG61XDqd1ad1A/B/i3WdWixTmJS3K/4uXjFjFX1f3RAk7O0gHc9I8HYOE
-    // Vd8UsHzLOWAUHeaqbsd6xx3GCXF4D5D++kh9OY9Ov7CXlqbYbHd6Atg+PQ7VnqNf
-    // bDqWN0Q2qcKX3k4ggtucmkkA6gP+K3+F5ANQj3AsGMQeddowC0Y=
-    // =fXoH
-    // -----END PGP SIGNATURE-----
-    //
-    // test
-    Commit(Commit),
-    // jordan@Jordans-MacBook-Pro-2 0d % printf "\x1f\x8b\x08\x00\x00\x00\x00\x00" | cat - 0d/586b48bc42e8591773d3d8a7223551c39d453c | gzip -dc
-    // tree 20940000 .cargo���CYy��Ve�������100644 .gitignore�K��_ow�]����4�n�ݺ100644 Cargo.lock�7�3-�?/��
-    // kt��c0C�100644 Cargo.toml�6�&(��]\8@�SHA�]f40000 src0QW��ƅ���b[�!�S&N�100644 test�G2Y�gN�b9vj?��Ut�
-    Tree(Vec<TreeItem>),
-    // jordan@Jordans-MacBook-Pro-2 objects % printf "\x1f\x8b\x08\x00\x00\x00\x00\x00" | cat - f5/473259d9674ed66239766a013f96a3550374e3| gzip -dc
-    // blob 23try and find me in .git
-    Blob(Bytes),
-    // Tag,
-    // OfsDelta,
-    // RefDelta,
+    fn encode_with(
+        self,
+        mut write: impl FnMut(&[u8]) -> Result<(), Error>,
+    ) -> Result<HashOutput, Error> {
+        let mut encoder = IncrementalPackEncoder::default();
+        write(encoder.header(self.count))?;
+
+        let mut written = 0_u32;
+
+        for entry in self.entries {
+            if written == self.count {
+                return Err(Error::MalformedPackFile(format!(
+                    "iterator yielded more than the {} entries expected",
+                    self.count
+                )));
+            }
+
+            write(encoder.entry(&entry?)?)?;
+            written += 1;
+        }
+
+        if written != self.count {
+            return Err(Error::MalformedPackFile(format!(
+                "iterator yielded {written} entries, expected {}",
+                self.count
+            )));
+        }
+
+        let hash = encoder.footer();
+        write(&hash)?;
+
+        Ok(hash)
+    }
 }
 
-impl PackFileEntry {
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf)))]
-    fn write_header(&self, buf: &mut BytesMut) {
-        let mut size = self.uncompressed_size();
+/// Encodes a packfile a piece at a time, returning each piece to be written out before
+/// the next is encoded, so the packfile never has to be held in memory as a whole.
+#[derive(Default)]
+pub(crate) struct IncrementalPackEncoder {
+    hasher: sha1::Sha1,
+    encoder: PackEncoder,
+    buf: BytesMut,
+}
 
-        // write header
-        {
-            let mut val = 0b1000_0000_u8;
-
-            val |= match self {
-                Self::Commit(_) => 0b001,
-                Self::Tree(_) => 0b010,
-                Self::Blob(_) => 0b011,
-                // Self::Tag => 0b100,
-                // Self::OfsDelta => 0b110,
-                // Self::RefDelta => 0b111,
-            } << 4;
-
-            // pack the 4 LSBs of the size into the header
-            #[allow(clippy::cast_possible_truncation)] // value is masked
+impl IncrementalPackEncoder {
+    /// Encodes the header of a packfile containing `count` entries.
+    pub(crate) fn header(&mut self, count: u32) -> &[u8] {
+        self.buf.clear();
+        PackFile::write_header(count, &mut self.buf);
+        self.hasher.update(&self.buf);
+        &self.buf
+    }
+
+    /// Encodes the next entry in the packfile.
+    pub(crate) fn entry(&mut self, entry: &PackFileEntry) -> Result<&[u8], Error> {
+        self.buf.clear();
+        entry.encode_with(&mut self.encoder, &mut self.buf)?;
+        self.hasher.update(&self.buf);
+        Ok(&self.buf)
+    }
+
+    /// Finishes the packfile, returning the checksum that's written as its footer.
+    pub(crate) fn footer(self) -> HashOutput {
+        self.hasher.finalize().into()
+    }
+}
+
+/// The number of entries of each type in a packfile, see [`PackFile::statistics`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PackFileStats {
+    pub blob_count: usize,
+    pub tree_count: usize,
+    pub commit_count: usize,
+    pub tag_count: usize,
+    /// Entries stored as deltas, which aren't included in the counts above.
+    pub delta_count: usize,
+    /// The size of every entry before compression, for deltas this is the size of the
+    /// delta rather than the object it builds.
+    pub total_uncompressed_bytes: usize,
+}
+
+impl PackFileStats {
+    /// The total number of entries in the packfile.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.blob_count + self.tree_count + self.commit_count + self.tag_count + self.delta_count
+    }
+}
+
+/// Formats the statistics as `key: value` lines, with sizes given in KiB. The layout
+/// is modelled on `git count-objects -v`, but the keys are this crate's own and the
+/// output isn't meant to be parsed like git's.
+impl Display for PackFileStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "count: {}", self.count())?;
+        writeln!(f, "commits: {}", self.commit_count)?;
+        writeln!(f, "trees: {}", self.tree_count)?;
+        writeln!(f, "blobs: {}", self.blob_count)?;
+        writeln!(f, "tags: {}", self.tag_count)?;
+        writeln!(f, "deltas: {}", self.delta_count)?;
+        writeln!(f, "size: {}", self.total_uncompressed_bytes / 1024)
+    }
+}
+
+/// Reusable state for compressing entries, allowing a single zlib stream and scratch
+/// buffer to be shared between every entry in a packfile rather than allocating them
+/// again for each one.
+pub struct PackEncoder {
+    compress: Compress,
+    /// The uncompressed entry currently being encoded.
+    scratch: BytesMut,
+    /// Compressed output is written here a chunk at a time before being appended to
+    /// the destination, so the destination never needs zero-filling ahead of time.
+    chunk: Box<[u8]>,
+}
+
+impl PackEncoder {
+    #[must_use]
+    pub fn new(level: Compression) -> Self {
+        Self {
+            compress: Compress::new(level, true),
+            scratch: BytesMut::new(),
+            chunk: vec![0; COMPRESS_CHUNK_LEN].into_boxed_slice(),
+        }
+    }
+
+    /// Compresses the whole of `scratch` as a single zlib stream, appending the
+    /// compressed bytes onto the end of `out`.
+    fn compress_into(&mut self, out: &mut BytesMut) -> Result<(), Error> {
+        self.compress.reset();
+
+        // reserve for the worst case upfront so `out` grows at most once
+        out.reserve(zlib_compress_bound(self.scratch.len()));
+
+        let mut input = &self.scratch[..];
+
+        loop {
+            let (before_in, before_out) = (self.compress.total_in(), self.compress.total_out());
+            let status = self
+                .compress
+                .compress(input, &mut self.chunk, FlushCompress::Finish)
+                .map_err(|e| Error::Compress(std::io::Error::other(e)))?;
+
+            // both are bounded by the length of the buffers we passed in
+            #[allow(clippy::cast_possible_truncation)]
             {
-                val |= (size & 0b1111) as u8;
+                input = &input[(self.compress.total_in() - before_in) as usize..];
+                let written = (self.compress.total_out() - before_out) as usize;
+                out.extend_from_slice(&self.chunk[..written]);
+            }
+
+            if status == Status::StreamEnd {
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// The size of [`PackEncoder`]'s buffer for compressed output.
+const COMPRESS_CHUNK_LEN: usize = 32 * 1024;
+
+/// The maximum size of a zlib stream compressing `len` bytes, as calculated by zlib's
+/// `compressBound`. Incompressible data is written as stored blocks, so compressing can
+/// never grow the data past this.
+const fn zlib_compress_bound(len: usize) -> usize {
+    len + (len >> 12) + (len >> 14) + (len >> 25) + 13
+}
+
+impl Default for PackEncoder {
+    fn default() -> Self {
+        Self::new(Compression::default())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Commit {
+    pub tree: HashOutput,
+    pub parents: Vec<HashOutput>,
+    pub author: CommitUserInfo,
+    pub committer: CommitUserInfo,
+    /// The character set `message` is encoded in, such as `ISO-8859-1`, written as the
+    /// `encoding` header. Git assumes UTF-8 when it's missing.
+    pub encoding: Option<ArcOrCowStr>,
+    /// Any other headers, such as `mergetag` or `gpgsig`, which are written after the
+    /// committer and encoding in the order given. Values spanning multiple lines are
+    /// held without the space Git prefixes each continuation line with.
+    pub extra_headers: Vec<(Bytes, Bytes)>,
+    pub message: Bytes,
+}
+
+impl Commit {
+    /// Parses a raw commit object, as returned by `git cat-file commit`.
+    ///
+    /// An `encoding` header straight after the committer is read into `encoding`.
+    /// Headers other than that and `tree`, `parent`, `author` and `committer` (such as
+    /// `gpgsig`) are kept in `extra_headers`, so a parsed commit encodes back to the
+    /// same object, as long as they came after the committer as Git writes them.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedCommit`] if any of the required headers are missing
+    /// or malformed, or [`Error::MalformedUserInfo`] if the author or committer is.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(data), err))]
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let malformed = |reason: &str| Error::MalformedCommit(reason.to_string());
+
+        // the headers are separated from the message by the first blank line
+        let (headers, message) = match data.windows(2).position(|w| w == b"\n\n") {
+            Some(i) => (&data[..=i], &data[i + 2..]),
+            None => (data, &[][..]),
+        };
+
+        let mut tree = None;
+        let mut parents = Vec::new();
+        let mut author = None;
+        let mut committer = None;
+        let mut encoding = None;
+        let mut extra_headers: Vec<(Bytes, BytesMut)> = Vec::new();
+        let mut in_extra_header = false;
+
+        for line in headers.split(|&c| c == b'\n') {
+            // continuations of multi-line headers, such as signatures, start with a
+            // space. none of the headers we read span multiple lines.
+            if let Some(continuation) = line.strip_prefix(b" ") {
+                if let (true, Some((_, value))) = (in_extra_header, extra_headers.last_mut()) {
+                    value.put_u8(b'\n');
+                    value.extend_from_slice(continuation);
+                }
+                continue;
+            }
+
+            in_extra_header = false;
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some(space) = line.iter().position(|&c| c == b' ') else {
+                return Err(malformed("header without a value"));
+            };
+            let (key, value) = (&line[..space], &line[space + 1..]);
+
+            match key {
+                b"tree" if tree.is_none() => tree = Some(parse_hex_hash(value, "tree")?),
+                b"parent" => parents.push(parse_hex_hash(value, "parent")?),
+                b"author" if author.is_none() => {
+                    author = Some(CommitUserInfo::try_parse_git_format(value)?);
+                }
+                b"committer" if committer.is_none() => {
+                    committer = Some(CommitUserInfo::try_parse_git_format(value)?);
+                }
+                // anywhere else, it's kept with the extra headers so the commit encodes
+                // back the same
+                b"encoding" if encoding.is_none() && extra_headers.is_empty() => {
+                    if let Ok(value) = std::str::from_utf8(value) {
+                        encoding = Some(ArcOrCowStr::from(value.to_string()));
+                    } else {
+                        extra_headers.push((Bytes::copy_from_slice(key), BytesMut::from(value)));
+                        in_extra_header = true;
+                    }
+                }
+                b"tree" | b"author" | b"committer" => {
+                    return Err(Error::MalformedCommit(format!(
+                        "duplicate {} header",
+                        String::from_utf8_lossy(key)
+                    )));
+                }
+                _ => {
+                    extra_headers.push((Bytes::copy_from_slice(key), BytesMut::from(value)));
+                    in_extra_header = true;
+                }
+            }
+        }
+
+        Ok(Self {
+            tree: tree.ok_or_else(|| malformed("missing tree header"))?,
+            parents,
+            author: author.ok_or_else(|| malformed("missing author header"))?,
+            committer: committer.ok_or_else(|| malformed("missing committer header"))?,
+            encoding,
+            extra_headers: extra_headers
+                .into_iter()
+                .map(|(key, value)| (key, value.freeze()))
+                .collect(),
+            message: Bytes::copy_from_slice(message),
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, out), err))]
+    fn encode_to(&self, out: &mut BytesMut) -> Result<(), Error> {
+        let mut hash_hex = [0_u8; 20 * 2];
+        hex::encode_to_slice(self.tree, &mut hash_hex).map_err(Error::EncodeTreeHash)?;
+
+        out.write_str("tree ")?;
+        out.extend_from_slice(&hash_hex);
+        out.write_char('\n')?;
+
+        for parent in &self.parents {
+            hex::encode_to_slice(parent, &mut hash_hex).map_err(Error::EncodeTreeHash)?;
+
+            out.write_str("parent ")?;
+            out.extend_from_slice(&hash_hex);
+            out.write_char('\n')?;
+        }
+
+        writeln!(out, "author {}", self.author)?;
+        writeln!(out, "committer {}", self.committer)?;
+
+        if let Some(encoding) = &self.encoding {
+            writeln!(out, "encoding {encoding}")?;
+        }
+
+        write_extra_headers(&self.extra_headers, out)?;
+
+        out.write_char('\n')?;
+        out.extend_from_slice(&self.message);
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn size(&self) -> usize {
+        let mut len = 0;
+        len += "tree ".len() + (self.tree.len() * 2) + "\n".len();
+        len += self.parents.len() * ("parent ".len() + (20 * 2) + "\n".len());
+        len += "author ".len() + self.author.size() + "\n".len();
+        len += "committer ".len() + self.committer.size() + "\n".len();
+        if let Some(encoding) = &self.encoding {
+            len += "encoding ".len() + encoding.len() + "\n".len();
+        }
+        len += extra_headers_size(&self.extra_headers);
+        len += "\n".len() + self.message.len();
+        len
+    }
+}
+
+/// Writes the extra headers of a commit or tag, prefixing each continuation line of a
+/// multi-line value with a space.
+fn write_extra_headers(headers: &[(Bytes, Bytes)], out: &mut BytesMut) -> Result<(), Error> {
+    for (key, value) in headers {
+        out.extend_from_slice(key);
+        out.write_char(' ')?;
+        for (i, line) in value.split(|&c| c == b'\n').enumerate() {
+            if i != 0 {
+                out.write_str("\n ")?;
+            }
+            out.extend_from_slice(line);
+        }
+        out.write_char('\n')?;
+    }
+
+    Ok(())
+}
+
+/// The size of the headers written by [`write_extra_headers`].
+fn extra_headers_size(headers: &[(Bytes, Bytes)]) -> usize {
+    headers
+        .iter()
+        .map(|(key, value)| {
+            // each continuation line is prefixed with a space
+            let continuations = value.split(|&c| c == b'\n').count() - 1;
+            key.len() + " ".len() + value.len() + continuations + "\n".len()
+        })
+        .sum()
+}
+
+/// Builds a [`Commit`], checking that its message can be read back.
+#[derive(Debug, Clone)]
+#[must_use]
+pub struct CommitBuilder {
+    commit: Commit,
+}
+
+impl CommitBuilder {
+    /// Starts a commit of `tree` with no parents and an empty message.
+    pub fn new(tree: HashOutput, author: CommitUserInfo, committer: CommitUserInfo) -> Self {
+        Self {
+            commit: Commit {
+                tree,
+                parents: Vec::new(),
+                author,
+                committer,
+                encoding: None,
+                extra_headers: Vec::new(),
+                message: Bytes::new(),
+            },
+        }
+    }
+
+    /// Adds `parent` after any parents already added.
+    pub fn with_parent(mut self, parent: HashOutput) -> Self {
+        self.commit.parents.push(parent);
+        self
+    }
+
+    /// Sets the character set the message is encoded in, such as `ISO-8859-1`.
+    pub fn with_encoding(mut self, encoding: impl Into<ArcOrCowStr>) -> Self {
+        self.commit.encoding = Some(encoding.into());
+        self
+    }
+
+    /// Adds a header to be written after the committer and encoding, such as
+    /// `gpgsig`.
+    pub fn with_extra_header(mut self, key: impl Into<Bytes>, value: impl Into<Bytes>) -> Self {
+        self.commit.extra_headers.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn with_message(mut self, message: impl Into<Bytes>) -> Self {
+        self.commit.message = message.into();
+        self
+    }
+
+    /// Finishes the commit.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NonUtf8MessageWithoutEncoding`] if no encoding was given and
+    /// the message isn't valid UTF-8, as Git would then assume it is.
+    pub fn build(self) -> Result<Commit, Error> {
+        if self.commit.encoding.is_none() {
+            std::str::from_utf8(&self.commit.message)
+                .map_err(Error::NonUtf8MessageWithoutEncoding)?;
+        }
+
+        Ok(self.commit)
+    }
+}
+
+/// Parses the hex-encoded hash from the `name` header of a commit.
+fn parse_hex_hash(value: &[u8], name: &str) -> Result<HashOutput, Error> {
+    let mut out = HashOutput::default();
+    hex::decode_to_slice(value, &mut out)
+        .map_err(|_| Error::MalformedCommit(format!("invalid hash in {name} header")))?;
+    Ok(out)
+}
+
+/// The lines that start the signature of a signed tag, which is appended to the end
+/// of its message.
+const TAG_SIGNATURE_PREFIXES: [&[u8]; 4] = [
+    b"-----BEGIN PGP SIGNATURE-----",
+    b"-----BEGIN PGP MESSAGE-----",
+    b"-----BEGIN SSH SIGNATURE-----",
+    b"-----BEGIN SIGNED MESSAGE-----",
+];
+
+/// An annotated tag, pointing to another object (usually a commit) and giving it a
+/// name along with a message.
+#[derive(Debug, Clone)]
+pub struct Tag {
+    /// The object being tagged.
+    pub object: HashOutput,
+    /// The type of `object`.
+    pub kind: ObjectKind,
+    /// The name of the tag, without the `refs/tags/` prefix.
+    pub name: ArcOrCowStr,
+    pub tagger: CommitUserInfo,
+    /// Any other headers, which are written after the tagger in the order given, held
+    /// in the same way as [`Commit::extra_headers`].
+    pub extra_headers: Vec<(Bytes, Bytes)>,
+    pub message: Bytes,
+    /// The armoured signature over the rest of the tag, such as the output of
+    /// `gpg --detach-sign --armor`. Unlike commits, Git stores the signature of a tag
+    /// straight after its message, so `message` should end with a newline.
+    pub signature: Option<Bytes>,
+}
+
+impl Tag {
+    /// Parses a raw tag object, as returned by `git cat-file tag`.
+    ///
+    /// Headers other than `object`, `type`, `tag` and `tagger` are kept in
+    /// `extra_headers`, so a parsed tag encodes back to the same object, as long as
+    /// they came after the tagger as Git writes them.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedTag`] if any of the headers are missing or malformed,
+    /// or [`Error::MalformedUserInfo`] if the tagger is.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(data), err))]
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let malformed = |reason: &str| Error::MalformedTag(reason.to_string());
+
+        let (headers, body) = match data.windows(2).position(|w| w == b"\n\n") {
+            Some(i) => (&data[..=i], &data[i + 2..]),
+            None => (data, &[][..]),
+        };
+
+        let mut object = None;
+        let mut kind = None;
+        let mut name = None;
+        let mut tagger = None;
+        let mut extra_headers: Vec<(Bytes, BytesMut)> = Vec::new();
+        let mut in_extra_header = false;
+
+        for line in headers.split(|&c| c == b'\n') {
+            if let Some(continuation) = line.strip_prefix(b" ") {
+                if let (true, Some((_, value))) = (in_extra_header, extra_headers.last_mut()) {
+                    value.put_u8(b'\n');
+                    value.extend_from_slice(continuation);
+                }
+                continue;
+            }
+
+            in_extra_header = false;
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let Some(space) = line.iter().position(|&c| c == b' ') else {
+                return Err(malformed("header without a value"));
+            };
+            let (key, value) = (&line[..space], &line[space + 1..]);
+
+            match key {
+                b"object" if object.is_none() => {
+                    let mut hash = HashOutput::default();
+                    hex::decode_to_slice(value, &mut hash)
+                        .map_err(|_| malformed("invalid hash in object header"))?;
+                    object = Some(hash);
+                }
+                b"type" if kind.is_none() => {
+                    let value = std::str::from_utf8(value)
+                        .ok()
+                        .and_then(|value| value.parse::<ObjectKind>().ok())
+                        .filter(|kind| !kind.is_delta())
+                        .ok_or_else(|| malformed("invalid type header"))?;
+                    kind = Some(value);
+                }
+                b"tag" if name.is_none() => {
+                    let value = std::str::from_utf8(value)
+                        .map_err(|_| malformed("tag name isn't valid UTF-8"))?;
+                    name = Some(ArcOrCowStr::from(value.to_string()));
+                }
+                b"tagger" if tagger.is_none() => {
+                    tagger = Some(CommitUserInfo::try_parse_git_format(value)?);
+                }
+                b"object" | b"type" | b"tag" | b"tagger" => {
+                    return Err(Error::MalformedTag(format!(
+                        "duplicate {} header",
+                        String::from_utf8_lossy(key)
+                    )));
+                }
+                _ => {
+                    extra_headers.push((Bytes::copy_from_slice(key), BytesMut::from(value)));
+                    in_extra_header = true;
+                }
+            }
+        }
+
+        // the signature starts on the first line that looks like the start of one
+        let signature_start = std::iter::once(0)
+            .chain(
+                body.iter()
+                    .enumerate()
+                    .filter(|(_, &c)| c == b'\n')
+                    .map(|(i, _)| i + 1),
+            )
+            .find(|&i| {
+                TAG_SIGNATURE_PREFIXES
+                    .iter()
+                    .any(|prefix| body[i..].starts_with(prefix))
+            });
+        let (message, signature) = match signature_start {
+            Some(i) => (&body[..i], Some(Bytes::copy_from_slice(&body[i..]))),
+            None => (body, None),
+        };
+
+        Ok(Self {
+            object: object.ok_or_else(|| malformed("missing object header"))?,
+            kind: kind.ok_or_else(|| malformed("missing type header"))?,
+            name: name.ok_or_else(|| malformed("missing tag header"))?,
+            tagger: tagger.ok_or_else(|| malformed("missing tagger header"))?,
+            extra_headers: extra_headers
+                .into_iter()
+                .map(|(key, value)| (key, value.freeze()))
+                .collect(),
+            message: Bytes::copy_from_slice(message),
+            signature,
+        })
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, out), err))]
+    fn encode_to(&self, out: &mut BytesMut) -> Result<(), Error> {
+        let mut hash_hex = [0_u8; 20 * 2];
+        hex::encode_to_slice(self.object, &mut hash_hex).map_err(Error::EncodeTreeHash)?;
+
+        out.write_str("object ")?;
+        out.extend_from_slice(&hash_hex);
+        out.write_char('\n')?;
+
+        writeln!(out, "type {}", raw_object_type(self.kind)?)?;
+        writeln!(out, "tag {}", self.name)?;
+        writeln!(out, "tagger {}", self.tagger)?;
+        write_extra_headers(&self.extra_headers, out)?;
+        out.write_char('\n')?;
+        out.extend_from_slice(&self.message);
+
+        if let Some(signature) = &self.signature {
+            out.extend_from_slice(signature);
+        }
+
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn size(&self) -> usize {
+        let mut len = 0;
+        len += "object ".len() + (self.object.len() * 2) + "\n".len();
+        len += "type ".len() + self.kind.as_str().len() + "\n".len();
+        len += "tag ".len() + self.name.len() + "\n".len();
+        len += "tagger ".len() + self.tagger.size() + "\n".len();
+        len += extra_headers_size(&self.extra_headers);
+        len += "\n".len() + self.message.len();
+        len += self.signature.as_ref().map_or(0, Bytes::len);
+        len
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommitUserInfo {
+    pub name: ArcOrCowStr,
+    pub email: ArcOrCowStr,
+    /// The time of the action, the offset of the `OffsetDateTime` is written out as
+    /// the timezone of the user.
+    ///
+    /// Git only stores whole seconds, so any sub-second part of the time is dropped
+    /// when it's written out, rounding down rather than to the nearest second. Times
+    /// before 1970 are written as negative timestamps with a leading `-`, as Git does.
+    pub time: time::OffsetDateTime,
+    /// Whether a UTC offset is written as `-0000` rather than `+0000`, which Git uses
+    /// to mark a time whose timezone is unknown. Ignored if the offset of `time`
+    /// isn't zero.
+    pub negative_utc: bool,
+}
+
+impl Display for CommitUserInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let offset = self.time.offset();
+
+        // `unix_timestamp` floors to the second, so sub-second precision is truncated
+        // as Git expects rather than rounded up into the next second
+        write!(
+            f,
+            "{} <{}> {} {}{:02}{:02}",
+            self.name,
+            self.email,
+            self.time.unix_timestamp(),
+            if offset.is_negative() || (self.negative_utc && offset.is_utc()) {
+                '-'
+            } else {
+                '+'
+            },
+            offset.whole_hours().unsigned_abs(),
+            offset.minutes_past_hour().unsigned_abs(),
+        )
+    }
+}
+
+impl CommitUserInfo {
+    /// Builds the user info for an action at `time`, with the user's timezone taken
+    /// from the offset of `time`.
+    ///
+    /// The timestamp Git stores is always relative to UTC, so the offset doesn't
+    /// change the instant recorded, only the timezone it's displayed in. An
+    /// `OffsetDateTime` of 12:00 at `+01:00` and one of 11:00 at `+00:00` are written
+    /// with the same timestamp, followed by `+0100` and `+0000` respectively.
+    #[must_use]
+    pub fn new(
+        name: impl Into<ArcOrCowStr>,
+        email: impl Into<ArcOrCowStr>,
+        time: time::OffsetDateTime,
+    ) -> Self {
+        Self {
+            name: name.into(),
+            email: email.into(),
+            time,
+            negative_utc: false,
+        }
+    }
+
+    /// Builds the user info from a Unix timestamp in seconds, and the user's timezone
+    /// as an offset from UTC in minutes (for example, `-330` for `-0530`).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::TimestampOutOfRange`] if the timestamp or offset can't be
+    /// represented.
+    pub fn from_unix(
+        name: impl Into<ArcOrCowStr>,
+        email: impl Into<ArcOrCowStr>,
+        secs: i64,
+        offset_minutes: i16,
+    ) -> Result<Self, Error> {
+        let out_of_range = || Error::TimestampOutOfRange(secs, offset_minutes);
+
+        // the remainder has the same sign as the hours, as `from_hms` expects
+        let hours = i8::try_from(offset_minutes / 60).map_err(|_| out_of_range())?;
+        #[allow(clippy::cast_possible_truncation)] // remainder is within ±59
+        let minutes = (offset_minutes % 60) as i8;
+        let offset = time::UtcOffset::from_hms(hours, minutes, 0).map_err(|_| out_of_range())?;
+
+        let time = time::OffsetDateTime::from_unix_timestamp(secs)
+            .map_err(|_| out_of_range())?
+            .to_offset(offset);
+
+        Ok(Self::new(name, email, time))
+    }
+
+    /// Parses the user info from an `author`/`committer` line of a commit object, in the
+    /// format `Name <email> unix_timestamp ±HHMM`. A timezone of `-0000` is kept as
+    /// [`CommitUserInfo::negative_utc`], so it's written back out the same.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedUserInfo`] if the line isn't in the expected format.
+    pub fn try_parse_git_format(line: &[u8]) -> Result<Self, Error> {
+        let malformed = || Error::MalformedUserInfo(String::from_utf8_lossy(line).into_owned());
+
+        // the name runs up until the start of the email, and the email runs until the
+        // last `>` so any `>` within the email itself doesn't throw us off
+        let email_start = line.iter().position(|&c| c == b'<').ok_or_else(malformed)?;
+        let email_end = line
+            .iter()
+            .rposition(|&c| c == b'>')
+            .ok_or_else(malformed)?;
+        if email_end < email_start {
+            return Err(malformed());
+        }
+
+        let name = std::str::from_utf8(&line[..email_start]).map_err(|_| malformed())?;
+        let email =
+            std::str::from_utf8(&line[email_start + 1..email_end]).map_err(|_| malformed())?;
+
+        let date = std::str::from_utf8(&line[email_end + 1..]).map_err(|_| malformed())?;
+        let (timestamp, timezone) = date
+            .trim_start_matches(' ')
+            .split_once(' ')
+            .ok_or_else(malformed)?;
+
+        let timestamp: i64 = timestamp.parse().map_err(|_| malformed())?;
+
+        // timezones are always in the format `±HHMM`
+        let (sign, timezone) = match timezone.as_bytes() {
+            [b'+', rest @ ..] => (1, rest),
+            [b'-', rest @ ..] => (-1, rest),
+            _ => return Err(malformed()),
+        };
+        let [h1, h2, m1, m2] = *timezone else {
+            return Err(malformed());
+        };
+        if ![h1, h2, m1, m2].iter().all(u8::is_ascii_digit) {
+            return Err(malformed());
+        }
+
+        #[allow(clippy::cast_possible_wrap)] // values are ascii digits
+        let offset = time::UtcOffset::from_hms(
+            sign * ((h1 - b'0') * 10 + (h2 - b'0')) as i8,
+            sign * ((m1 - b'0') * 10 + (m2 - b'0')) as i8,
+            0,
+        )
+        .map_err(|_| malformed())?;
+
+        let time = time::OffsetDateTime::from_unix_timestamp(timestamp)
+            .map_err(|_| malformed())?
+            .to_offset(offset);
+
+        Ok(Self {
+            name: name.trim_end_matches(' ').to_string().into(),
+            email: email.to_string().into(),
+            time,
+            negative_utc: sign == -1 && offset.is_utc(),
+        })
+    }
+
+    #[must_use]
+    pub fn size(&self) -> usize {
+        // includes the `-` of a negative timestamp, matching `Display`
+        let timestamp_len = itoa::Buffer::new().format(self.time.unix_timestamp()).len();
+
+        self.name.len()
+            + " <".len()
+            + self.email.len()
+            + "> ".len()
+            + timestamp_len
+            + " +0000".len()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TreeItemKind {
+    File,
+    Executable,
+    Symlink,
+    Directory,
+    /// A gitlink, referring to a commit within a submodule.
+    Submodule,
+}
+
+impl TreeItemKind {
+    #[must_use]
+    pub const fn mode(&self) -> &'static str {
+        match self {
+            Self::File => "100644",
+            Self::Executable => "100755",
+            Self::Symlink => "120000",
+            Self::Directory => "40000",
+            Self::Submodule => "160000",
+        }
+    }
+
+    /// Converts a raw mode, as stored in a tree object, to a `TreeItemKind`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::UnknownTreeMode`] if the mode isn't one Git defines.
+    pub const fn from_mode_u32(mode: u32) -> Result<Self, Error> {
+        Ok(match mode {
+            0o100_644 => Self::File,
+            0o100_755 => Self::Executable,
+            0o120_000 => Self::Symlink,
+            0o040_000 => Self::Directory,
+            0o160_000 => Self::Submodule,
+            _ => return Err(Error::UnknownTreeMode(mode)),
+        })
+    }
+
+    #[must_use]
+    pub const fn to_mode_u32(&self) -> u32 {
+        match self {
+            Self::File => 0o100_644,
+            Self::Executable => 0o100_755,
+            Self::Symlink => 0o120_000,
+            Self::Directory => 0o040_000,
+            Self::Submodule => 0o160_000,
+        }
+    }
+}
+
+impl Display for TreeItemKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.mode())
+    }
+}
+
+impl std::fmt::Octal for TreeItemKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Octal::fmt(&self.to_mode_u32(), f)
+    }
+}
+
+impl FromStr for TreeItemKind {
+    type Err = Error;
+
+    /// Parses either a numeric mode, such as `100644`, or one of the symbolic names
+    /// `blob`, `exec`, `symlink`, `tree` or `commit`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "blob" => Self::File,
+            "exec" => Self::Executable,
+            "symlink" => Self::Symlink,
+            "tree" => Self::Directory,
+            "commit" => Self::Submodule,
+            _ => {
+                let mode =
+                    u32::from_str_radix(s, 8).map_err(|_| Error::UnknownTreeKind(s.to_string()))?;
+                Self::from_mode_u32(mode)?
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TreeItem {
+    pub kind: TreeItemKind,
+    /// The name of the item, which is written out verbatim as Git doesn't require
+    /// names to be UTF-8.
+    pub name: ArcOrCowBytes,
+    pub hash: HashOutput,
+}
+
+// `[mode] [name]\0[hash]`
+impl TreeItem {
+    /// Compares two items by the order Git requires them to be in within a tree,
+    /// which is byte-wise by name except that directories sort as if their name had
+    /// a trailing `/`, so `foo.bar` comes before the directory `foo` but after the
+    /// file `foo`.
+    ///
+    /// Git will stop reading a tree at the first item that's out of order, so trees
+    /// must be sorted with this before they're encoded.
+    #[must_use]
+    pub fn cmp_tree_order(&self, other: &Self) -> std::cmp::Ordering {
+        self.tree_order_key().cmp(other.tree_order_key())
+    }
+
+    /// The name of the item as Git sorts it, without having to allocate a copy of it
+    /// with the trailing `/` of a directory.
+    fn tree_order_key(&self) -> impl Iterator<Item = u8> + '_ {
+        let suffix = (self.kind == TreeItemKind::Directory).then_some(b'/');
+        self.name.iter().copied().chain(suffix)
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, out), err))]
+    fn encode_to(&self, out: &mut BytesMut) -> Result<(), Error> {
+        check_hash_width(&self.hash)?;
+
+        out.write_str(self.kind.mode())?;
+        out.put_u8(b' ');
+        out.extend_from_slice(&self.name);
+        out.put_u8(0);
+        out.extend_from_slice(&self.hash);
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn size(&self) -> usize {
+        self.kind.mode().len() + " ".len() + self.name.len() + "\0".len() + self.hash.len()
+    }
+}
+
+/// Parses the records of a raw tree object, in the format `<mode> <name>\0<hash>`,
+/// such as the body of a tree read from a packfile or loose object.
+///
+/// # Errors
+///
+/// Returns [`Error::MalformedTree`] with the offset of the offending record if a
+/// record is truncated, and [`Error::UnknownTreeKind`] with the mode as written if it
+/// doesn't map to a [`TreeItemKind`]. Names are kept as they're written, whether or
+/// not they're valid UTF-8.
+#[cfg_attr(feature = "tracing", tracing::instrument(skip(data), err))]
+pub fn parse_tree(data: &[u8]) -> Result<Vec<TreeItem>, Error> {
+    let mut items = Vec::new();
+    let mut offset = 0;
+
+    while offset < data.len() {
+        let malformed = |reason| Error::MalformedTree { offset, reason };
+        let record = &data[offset..];
+
+        let space = record
+            .iter()
+            .position(|&c| c == b' ')
+            .ok_or_else(|| malformed("missing space after mode"))?;
+        let nul = record
+            .iter()
+            .position(|&c| c == 0)
+            .filter(|&nul| nul > space)
+            .ok_or_else(|| malformed("missing NUL after name"))?;
+        let hash_end = nul + 1 + std::mem::size_of::<HashOutput>();
+        if record.len() < hash_end {
+            return Err(malformed("truncated hash"));
+        }
+
+        let mode = std::str::from_utf8(&record[..space]).map_err(|_| malformed("invalid mode"))?;
+        let kind = u32::from_str_radix(mode, 8)
+            .ok()
+            .and_then(|mode| TreeItemKind::from_mode_u32(mode).ok())
+            .ok_or_else(|| Error::UnknownTreeKind(mode.to_string()))?;
+
+        let mut hash = HashOutput::default();
+        hash.copy_from_slice(&record[nul + 1..hash_end]);
+
+        items.push(TreeItem {
+            kind,
+            name: record[space + 1..nul].to_vec().into(),
+            hash,
+        });
+
+        offset += hash_end;
+    }
+
+    Ok(items)
+}
+
+/// The type Git prefixes an object of `kind` with when hashing it, failing for deltas
+/// since they aren't objects themselves.
+fn raw_object_type(kind: ObjectKind) -> Result<&'static str, Error> {
+    if kind.is_delta() {
+        Err(Error::InvalidRawKind(kind))
+    } else {
+        Ok(kind.as_str())
+    }
+}
+
+/// Ensures a tree item's hash is as wide as the hashes of the pack's object format,
+/// which is always SHA-1 for now.
+fn check_hash_width(hash: &[u8]) -> Result<(), Error> {
+    let expected = std::mem::size_of::<HashOutput>();
+
+    if hash.len() == expected {
+        Ok(())
+    } else {
+        Err(Error::HashWidthMismatch {
+            expected,
+            actual: hash.len(),
+        })
+    }
+}
+
+/// Ensures no two items in a tree share a name, Git treats such trees as corrupt.
+fn check_duplicate_tree_entries(items: &[TreeItem]) -> Result<(), Error> {
+    let mut seen = std::collections::HashSet::with_capacity(items.len());
+
+    for item in items {
+        if !seen.insert(&*item.name) {
+            return Err(Error::DuplicateTreeEntry(item.name.to_string()));
+        }
+    }
+
+    Ok(())
+}
+
+/// The type of an entry within a packfile, as stored in the 3 type bits of the
+/// entry's header.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ObjectKind {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+    /// A delta against a base elsewhere in the packfile, referred to by its offset.
+    OfsDelta,
+    /// A delta against a base referred to by its hash.
+    RefDelta,
+}
+
+impl ObjectKind {
+    /// The name Git gives this kind, as used in the header of a loose object and
+    /// when hashing one. Deltas aren't objects in their own right, so their names
+    /// are only used for display.
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Commit => "commit",
+            Self::Tree => "tree",
+            Self::Blob => "blob",
+            Self::Tag => "tag",
+            Self::OfsDelta => "ofs-delta",
+            Self::RefDelta => "ref-delta",
+        }
+    }
+
+    /// Whether this kind is a delta against another object, rather than an object
+    /// in its own right.
+    #[must_use]
+    pub const fn is_delta(self) -> bool {
+        matches!(self, Self::OfsDelta | Self::RefDelta)
+    }
+
+    /// The value of the type bits in an entry header for this kind.
+    #[must_use]
+    pub const fn pack_type_bits(self) -> u8 {
+        match self {
+            Self::Commit => 0b001,
+            Self::Tree => 0b010,
+            Self::Blob => 0b011,
+            Self::Tag => 0b100,
+            Self::OfsDelta => 0b110,
+            Self::RefDelta => 0b111,
+        }
+    }
+
+    /// Maps the type bits of an entry header back to an `ObjectKind`, returning `None`
+    /// for the values the pack format reserves.
+    #[must_use]
+    pub const fn from_pack_type_bits(bits: u8) -> Option<Self> {
+        Some(match bits {
+            0b001 => Self::Commit,
+            0b010 => Self::Tree,
+            0b011 => Self::Blob,
+            0b100 => Self::Tag,
+            0b110 => Self::OfsDelta,
+            0b111 => Self::RefDelta,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for ObjectKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ObjectKind {
+    type Err = Error;
+
+    /// Parses the name of a kind, as returned by [`ObjectKind::as_str`].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "commit" => Self::Commit,
+            "tree" => Self::Tree,
+            "blob" => Self::Blob,
+            "tag" => Self::Tag,
+            "ofs-delta" => Self::OfsDelta,
+            "ref-delta" => Self::RefDelta,
+            _ => return Err(Error::UnknownObjectKind(s.to_string())),
+        })
+    }
+}
+
+/// Writes the header of a packfile entry to `buf`, the type and the 4 LSBs of the
+/// uncompressed `size` go in the first byte and the rest of the size follows in
+/// 7 bit chunks, with the MSB of each byte set if another follows.
+///
+/// The first byte is always followed by at least one size byte, even if it's zero,
+/// so headers are never shorter than 2 bytes.
+pub fn write_entry_header(kind: ObjectKind, mut size: u64, buf: &mut BytesMut) {
+    // write header
+    {
+        let mut val = 0b1000_0000_u8;
+
+        val |= kind.pack_type_bits() << 4;
+
+        // pack the 4 LSBs of the size into the header
+        #[allow(clippy::cast_possible_truncation)] // value is masked
+        {
+            val |= (size & 0b1111) as u8;
+        }
+        size >>= 4;
+
+        buf.put_u8(val);
+    }
+
+    // write size bytes
+    loop {
+        // read 7 LSBs from the `size` and push them off for the next iteration
+        #[allow(clippy::cast_possible_truncation)] // value is masked
+        let mut val = (size & 0b111_1111) as u8;
+        size >>= 7;
+
+        if size != 0 {
+            // MSB set to 1 implies there's more size bytes to come, otherwise
+            // the data starts after this byte
+            val |= 1 << 7;
+        }
+
+        buf.put_u8(val);
+
+        if size == 0 {
+            break;
+        }
+    }
+}
+
+/// Reads a packfile entry header, as written by [`write_entry_header`], from the
+/// start of `buf`, returning the kind of the entry, its uncompressed size and the
+/// length of the header.
+///
+/// # Errors
+///
+/// Returns [`Error::MalformedEntryHeader`] if the header is truncated, uses a
+/// reserved type or its size doesn't fit in a `u64`.
+pub fn read_entry_header(buf: &[u8]) -> Result<(ObjectKind, u64, usize), Error> {
+    let (&first, _) = buf
+        .split_first()
+        .ok_or(Error::MalformedEntryHeader("truncated header"))?;
+
+    let kind = ObjectKind::from_pack_type_bits((first >> 4) & 0b111)
+        .ok_or(Error::MalformedEntryHeader("reserved type"))?;
+
+    let mut size = u64::from(first & 0b1111);
+    let mut shift = 4;
+    let mut len = 1;
+    let mut byte = first;
+
+    while byte & 0b1000_0000 != 0 {
+        byte = *buf
+            .get(len)
+            .ok_or(Error::MalformedEntryHeader("truncated header"))?;
+        len += 1;
+
+        let bits = u64::from(byte & 0b111_1111);
+        if shift >= u64::BITS || (bits << shift) >> shift != bits {
+            return Err(Error::MalformedEntryHeader("size overflows"));
+        }
+        size |= bits << shift;
+        shift += 7;
+    }
+
+    Ok((kind, size, len))
+}
+
+#[derive(Debug, Clone)] // could be copy but Vec<TreeItem<'a>>
+pub enum PackFileEntry {
+    // jordan@Jordans-MacBook-Pro-2 0d % printf "\x1f\x8b\x08\x00\x00\x00\x00\x00" | cat - f5/473259d9674ed66239766a013f96a3550374e3 | gzip -dc
+    // commit 1068tree 0d586b48bc42e8591773d3d8a7223551c39d453c
+    // parent c2a862612a14346ae95234f26efae1ee69b5b7a9
+    // author Jordan Doyle <jordan@doyle.la> 1630244577 +0100
+    // committer Jordan Doyle <jordan@doyle.la> 1630244577 +0100
+    // gpgsig -----BEGIN PGP SIGNATURE-----
+    //
+    // iQIzBAABCAAdFiEEMn1zof7yzaURQBGDHqa65vZtxJoFAmErjuEACgkQHqa65vZt
+    // xJqhvhAAieKXnGRjT926qzozcvarC8D3TlA+Z1wVXueTAWqfusNIP0zCun/crOb2
+    // tOULO+/DXVBmwu5eInAf+t/wvlnIsrzJonhVr1ZT0f0vDX6fs2vflWg4UCVEuTsZ
+    // tg+aTjcibwnmViIM9XVOzhU8Au2OIqMQLyQOMWSt8NhY0W2WhBCdQvhktvK1V8W6
+    // omPs04SrR39xWBDQaxsXYxq/1ZKUYXDwudvEfv14EvrxG1vWumpUVJd7Ib5w4gXX
+    // fYa95DxYL720ZaiWPIYEG8FMBzSOpo6lUzY9g2/o/wKwSQZJNvpaMGCuouy8Fb+E
+    // UaqC0XPxqpKG9duXPgCldUr+P7++48CF5zc358RBGz5OCNeTREsIQQo5PUO1k+wO
+    // FnGOQTT8vvNOrxBgb3QgKu67RVwWDc6JnQCNpUrhUJrXMDWnYLBqo4Y+CdKGSQ4G
+    // hW8V/hVTOlJZNi8bbU4v53cxh4nXiMM6NKUblUKs65ar3/2dkojwunz7r7GVZ6mG
+    // QUpr9+ybG61XDqd1ad1A/B/i3WdWixTmJS3K/4uXjFjFX1f3RAk7O0gHc9I8HYOE
+    // Vd8UsHzLOWAUHeaqbsd6xx3GCXF4D5D++kh9OY9Ov7CXlqbYbHd6Atg+PQ7VnqNf
+    // bDqWN0Q2qcKX3k4ggtucmkkA6gP+K3+F5ANQj3AsGMQeddowC0Y=
+    // =fXoH
+    // -----END PGP SIGNATURE-----
+    //
+    // test
+    Commit(Commit),
+    // jordan@Jordans-MacBook-Pro-2 0d % printf "\x1f\x8b\x08\x00\x00\x00\x00\x00" | cat - 0d/586b48bc42e8591773d3d8a7223551c39d453c | gzip -dc
+    // tree 20940000 .cargo���CYy��Ve�������100644 .gitignore�K��_ow�]����4�n�ݺ100644 Cargo.lock�7�3-�?/��
+    // kt��c0C�100644 Cargo.toml�6�&(��]\8@�SHA�]f40000 src0QW��ƅ���b[�!�S&N�100644 test�G2Y�gN�b9vj?��Ut�
+    Tree(Vec<TreeItem>),
+    // jordan@Jordans-MacBook-Pro-2 objects % printf "\x1f\x8b\x08\x00\x00\x00\x00\x00" | cat - f5/473259d9674ed66239766a013f96a3550374e3| gzip -dc
+    // blob 23try and find me in .git
+    Blob(Bytes),
+    Tag(Tag),
+    // OfsDelta,
+    /// An object stored as a delta against another object, referred to by hash. See
+    /// [`PackFileEntry::ref_delta`] for building one.
+    RefDelta {
+        /// The hash of the object the delta is applied to.
+        base: HashOutput,
+        /// The hash of the object the delta produces, which is how this entry is
+        /// referred to.
+        hash: HashOutput,
+        /// The delta instructions, as built by [`crate::delta::encode`].
+        delta: Bytes,
+    },
+    /// An object that has already been serialised, such as one read from another
+    /// repository, which is written to the packfile as-is. `data` is the object
+    /// without the `<kind> <size>\0` prefix Git hashes it with.
+    ///
+    /// This allows objects to be sent without being modelled by this crate, at the
+    /// cost of not being able to verify them. `kind` can't be a delta.
+    Raw {
+        kind: ObjectKind,
+        data: Bytes,
+    },
+    /// An object that has already been compressed, such as one read out of another
+    /// packfile by [`crate::reader::PackFileReader::compressed_at`], which is copied
+    /// into the packfile verbatim rather than being compressed again.
+    ///
+    /// The fields aren't checked against each other when encoding, so the caller is
+    /// trusted to keep them consistent. [`PackFileEntry::verify`] can be used to check
+    /// them. `kind` can't be a delta.
+    Compressed {
+        kind: ObjectKind,
+        /// The size of the object once `zlib_data` is inflated.
+        uncompressed_size: u64,
+        /// The object's zlib stream, without the entry header.
+        zlib_data: Bytes,
+        /// The hash of the object, as Git would refer to it.
+        hash: HashOutput,
+    },
+}
+
+impl PackFileEntry {
+    /// Builds a `RefDelta` entry that stores `target` as a delta against `base`, the
+    /// returned entry can be sent in place of `target`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidDeltaBase`] if `base` and `target` aren't the same type of
+    /// object, or either of them is already a delta.
+    pub fn ref_delta(base: &PackFileEntry, target: &PackFileEntry) -> Result<Self, Error> {
+        if base.kind() != target.kind() || base.kind().is_delta() {
+            return Err(Error::InvalidDeltaBase);
+        }
+
+        let mut base_body = BytesMut::with_capacity(base.uncompressed_size());
+        base.encode_body(&mut base_body)?;
+
+        let mut target_body = BytesMut::with_capacity(target.uncompressed_size());
+        target.encode_body(&mut target_body)?;
+
+        Ok(Self::RefDelta {
+            base: base.hash()?,
+            hash: target.hash()?,
+            delta: crate::delta::encode(&base_body, &target_body),
+        })
+    }
+
+    /// Builds a `Blob` entry for each item in `iter`, in the same order.
+    pub fn blobs<B: Into<Bytes>>(iter: impl IntoIterator<Item = B>) -> Vec<Self> {
+        iter.into_iter()
+            .map(|data| Self::Blob(data.into()))
+            .collect()
+    }
+
+    /// Creates a blob of the file at `path` by memory-mapping it rather than reading
+    /// it onto the heap, so large files only need to be paged in as they're encoded.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or any other,
+    /// while the entry or any clone of its data is alive, as the contents are read
+    /// straight from the mapping. Doing so is undefined behaviour.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::IoWithPath`] if the file can't be opened or mapped.
+    #[cfg(feature = "memmap2")]
+    #[cfg_attr(feature = "tracing", tracing::instrument(err))]
+    pub unsafe fn blob_mmap(path: &std::path::Path) -> Result<Self, Error> {
+        let file = std::fs::File::open(path).map_err(|e| crate::error::io_error_at(path, e))?;
+
+        // SAFETY: the caller upholds that the file isn't modified while it's mapped
+        let map =
+            unsafe { memmap2::Mmap::map(&file) }.map_err(|e| crate::error::io_error_at(path, e))?;
+
+        Ok(Self::Blob(Bytes::from_owner(map)))
+    }
+
+    /// The type of this entry, as written to its header.
+    #[must_use]
+    pub fn kind(&self) -> ObjectKind {
+        match self {
+            Self::Commit(_) => ObjectKind::Commit,
+            Self::Tree(_) => ObjectKind::Tree,
+            Self::Blob(_) => ObjectKind::Blob,
+            Self::Tag(_) => ObjectKind::Tag,
+            Self::RefDelta { .. } => ObjectKind::RefDelta,
+            Self::Raw { kind, .. } | Self::Compressed { kind, .. } => *kind,
+        }
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf)))]
+    fn write_header(&self, buf: &mut BytesMut) {
+        let size = match self {
+            Self::Compressed {
+                uncompressed_size, ..
+            } => *uncompressed_size,
+            _ => self.uncompressed_size() as u64,
+        };
+
+        write_entry_header(self.kind(), size, buf);
+    }
+
+    /// The number of bytes [`PackFileEntry::write_header`] writes for this entry.
+    fn header_size(&self) -> usize {
+        // 4 bits of the size go in the first byte, and 7 bits in each byte after,
+        // with at least one byte after
+        let size_bits = usize::BITS - self.uncompressed_size().leading_zeros();
+        1 + size_bits.saturating_sub(4).div_ceil(7).max(1) as usize
+    }
+
+    /// Returns an upper bound on the number of bytes [`PackFileEntry::encode_to`] will
+    /// write for this entry, assuming its body is incompressible.
+    #[must_use]
+    pub fn encoded_size_bound(&self) -> usize {
+        let base_len = match self {
+            Self::RefDelta { base, .. } => base.len(),
+            Self::Compressed { zlib_data, .. } => return self.header_size() + zlib_data.len(),
+            _ => 0,
+        };
+
+        self.header_size() + base_len + zlib_compress_bound(self.uncompressed_size())
+    }
+
+    /// Encodes the entry header followed by the zlib compressed entry into `original_out`.
+    ///
+    /// When encoding many entries, prefer [`PackFileEntry::encode_with`] so the
+    /// compressor can be reused between them.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry fails to serialise or compress.
+    pub fn encode_to(&self, original_out: &mut BytesMut) -> Result<(), Error> {
+        self.encode_with(&mut PackEncoder::default(), original_out)
+    }
+
+    /// Encodes the entry header followed by the zlib compressed entry into `original_out`,
+    /// using the compressor and scratch buffers held by `encoder`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry fails to serialise or compress.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(skip(self, encoder, original_out), err)
+    )]
+    pub fn encode_with(
+        &self,
+        encoder: &mut PackEncoder,
+        original_out: &mut BytesMut,
+    ) -> Result<(), Error> {
+        // already compressed entries can be copied straight in
+        if let Self::Compressed {
+            kind, zlib_data, ..
+        } = self
+        {
+            raw_object_type(*kind)?;
+            self.write_header(original_out);
+            original_out.extend_from_slice(zlib_data);
+            return Ok(());
+        }
+
+        self.encode_compressing(encoder, original_out)
+    }
+
+    /// Encodes the entry header followed by the entry compressed by `encoder`, even
+    /// if the entry has already been compressed.
+    fn encode_compressing(
+        &self,
+        encoder: &mut PackEncoder,
+        original_out: &mut BytesMut,
+    ) -> Result<(), Error> {
+        if let Self::Raw { kind, .. } | Self::Compressed { kind, .. } = self {
+            raw_object_type(*kind)?;
+        }
+
+        self.write_header(original_out); // TODO: this needs space reserving for it
+
+        if let Self::RefDelta { base, .. } = self {
+            original_out.extend_from_slice(base);
+        }
+
+        let size = self.uncompressed_size();
+
+        // the data ends up getting compressed but we'll need at least this many bytes
+        encoder.scratch.clear();
+        encoder.scratch.reserve(size);
+        self.encode_body(&mut encoder.scratch)?;
+
+        debug_assert_eq!(encoder.scratch.len(), size);
+
+        encoder.compress_into(original_out)?;
+
+        Ok(())
+    }
+
+    /// Encodes the entry as [`PackFileEntry::encode_to`] does, but compressed at
+    /// `level`, returning just the entry's header and compressed body so it can be
+    /// spliced into another packfile.
+    ///
+    /// The hash of the entry doesn't depend on how it's compressed, so the result can
+    /// be used in place of any other encoding of the entry. `Compressed` entries are
+    /// inflated and compressed again.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry fails to serialise or compress.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn recompress(&self, level: Compression) -> Result<Bytes, Error> {
+        let mut out = BytesMut::with_capacity(self.encoded_size_bound());
+        self.encode_compressing(&mut PackEncoder::new(level), &mut out)?;
+        Ok(out.freeze())
+    }
+
+    /// Checks a `Compressed` entry's `zlib_data` inflates to an object of
+    /// `uncompressed_size` bytes with the given `hash`. Other entries are always
+    /// consistent, so are always valid.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::CompressedEntryMismatch`] if the entry is inconsistent, or
+    /// [`Error::Decompress`] if `zlib_data` isn't a valid zlib stream.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn verify(&self) -> Result<(), Error> {
+        let Self::Compressed {
+            kind,
+            uncompressed_size,
+            hash,
+            ..
+        } = self
+        else {
+            return Ok(());
+        };
+
+        let mut data = BytesMut::new();
+        self.encode_body(&mut data)?;
+
+        let actual = Self::Raw {
+            kind: *kind,
+            data: data.freeze(),
+        };
+        if actual.uncompressed_size() as u64 != *uncompressed_size || actual.hash()? != *hash {
+            return Err(Error::CompressedEntryMismatch(*hash));
+        }
+
+        Ok(())
+    }
+
+    /// Writes the uncompressed object, without any headers, to `out`.
+    fn encode_body(&self, out: &mut BytesMut) -> Result<(), Error> {
+        match self {
+            Self::Commit(commit) => {
+                commit.encode_to(out)?;
+            }
+            Self::Tag(tag) => {
+                tag.encode_to(out)?;
+            }
+            Self::Tree(items) => {
+                check_duplicate_tree_entries(items)?;
+
+                for item in items {
+                    item.encode_to(out)?;
+                }
+            }
+            Self::Blob(data) | Self::RefDelta { delta: data, .. } | Self::Raw { data, .. } => {
+                out.extend_from_slice(data);
+            }
+            Self::Compressed { zlib_data, .. } => {
+                let mut writer = out.writer();
+                std::io::copy(
+                    &mut flate2::read::ZlibDecoder::new(zlib_data.as_ref()),
+                    &mut writer,
+                )
+                .map_err(Error::Decompress)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
+    #[must_use]
+    pub fn uncompressed_size(&self) -> usize {
+        match self {
+            Self::Commit(commit) => commit.size(),
+            Self::Tag(tag) => tag.size(),
+            Self::Tree(items) => items.iter().map(TreeItem::size).sum(),
+            Self::Blob(data) | Self::RefDelta { delta: data, .. } | Self::Raw { data, .. } => {
+                data.len()
+            }
+            // only truncates for objects larger than the address space, which can't
+            // be inflated anyway
+            #[allow(clippy::cast_possible_truncation)]
+            Self::Compressed {
+                uncompressed_size, ..
+            } => *uncompressed_size as usize,
+        }
+    }
+
+    /// The hashes of the objects this entry refers to directly, the tree and parents of
+    /// a commit, the items of a tree or the object a tag points to. This is the
+    /// building block for walking the objects reachable from a commit.
+    ///
+    /// Submodules within a tree point to commits in other repositories, so they're
+    /// skipped. Raw and compressed entries aren't parsed, and the base of a delta is
+    /// only needed to build the object rather than being referred to by it, so these
+    /// return nothing.
+    #[must_use]
+    pub fn objects_referenced(&self) -> Vec<HashOutput> {
+        match self {
+            Self::Commit(commit) => std::iter::once(commit.tree)
+                .chain(commit.parents.iter().copied())
+                .collect(),
+            Self::Tree(items) => items
+                .iter()
+                .filter(|item| item.kind != TreeItemKind::Submodule)
+                .map(|item| item.hash)
+                .collect(),
+            Self::Tag(tag) => vec![tag.object],
+            Self::Blob(_) | Self::RefDelta { .. } | Self::Raw { .. } | Self::Compressed { .. } => {
+                Vec::new()
+            }
+        }
+    }
+
+    /// Calculates the SHA-1 hash of the entry, as Git would refer to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the entry fails to serialise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn hash(&self) -> Result<HashOutput, Error> {
+        let size = self.uncompressed_size();
+
+        let file_prefix = match self {
+            Self::RefDelta { hash, .. } | Self::Compressed { hash, .. } => return Ok(*hash),
+            _ => raw_object_type(self.kind())?,
+        };
+
+        let mut hasher = sha1::Sha1::new();
+        hasher.update(file_prefix);
+        hasher.update(b" ");
+        hasher.update(itoa::Buffer::new().format(size));
+        hasher.update(b"\0");
+
+        // feed the body through the hasher as it's encoded rather than building the
+        // whole object in memory first, blobs can be passed in as they are but trees
+        // are encoded an item at a time
+        match self {
+            Self::Blob(data) | Self::Raw { data, .. } => hasher.update(data),
+            Self::Tree(items) => {
+                check_duplicate_tree_entries(items)?;
+
+                let mut buf = BytesMut::new();
+
+                for item in items {
+                    buf.clear();
+                    item.encode_to(&mut buf)?;
+                    hasher.update(&buf);
+                }
+            }
+            Self::Commit(commit) => {
+                let mut buf = BytesMut::with_capacity(size);
+                commit.encode_to(&mut buf)?;
+                hasher.update(&buf);
+            }
+            Self::Tag(tag) => {
+                let mut buf = BytesMut::with_capacity(size);
+                tag.encode_to(&mut buf)?;
+                hasher.update(&buf);
+            }
+            Self::RefDelta { .. } | Self::Compressed { .. } => {
+                unreachable!("stored hashes are returned above")
+            }
+        }
+
+        Ok(hasher.finalize().into())
+    }
+}
+
+/// Builds a `packed-refs` file from a list of `(refname, hash)` pairs, calling `peeled`
+/// with each hash to find the object an annotated tag points to. The refs are sorted
+/// by name, as [`crate::refs::PackedRefs`] does.
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidRefName`] if any of the refnames are invalid.
+pub fn packed_refs(
+    refs: impl IntoIterator<Item = (String, HashOutput)>,
+    peeled: impl Fn(&HashOutput) -> Option<HashOutput>,
+) -> Result<Bytes, Error> {
+    let refs: Vec<_> = refs
+        .into_iter()
+        .map(|(name, hash)| {
+            let peeled = peeled(&hash);
+            (name, hash, peeled)
+        })
+        .collect();
+
+    let mut buf = BytesMut::new();
+    crate::refs::PackedRefs::new(&refs).encode_to(&mut buf)?;
+    Ok(buf.freeze())
+}
+
+#[cfg(test)]
+mod test {
+    mod packfile {
+        use crate::low_level::{
+            Commit, CommitUserInfo, PackEncoder, PackFile, PackFileEntry, PackHasher, TreeItem,
+            TreeItemKind,
+        };
+        use bytes::{Bytes, BytesMut};
+
+        fn example() -> Bytes {
+            let blob = PackFileEntry::Blob(Bytes::from("hello world"));
+
+            let tree = PackFileEntry::Tree(vec![TreeItem {
+                kind: TreeItemKind::File,
+                name: "helloworld.txt".into(),
+                hash: blob.hash().unwrap(),
+            }]);
+
+            let commit = PackFileEntry::Commit(Commit {
+                tree: tree.hash().unwrap(),
+                author: CommitUserInfo {
+                    name: "example".into(),
+                    email: "example@me.com".into(),
+                    time: time::OffsetDateTime::UNIX_EPOCH,
+                    negative_utc: false,
+                },
+                committer: CommitUserInfo {
+                    name: "example".into(),
+                    email: "example@me.com".into(),
+                    time: time::OffsetDateTime::UNIX_EPOCH,
+                    negative_utc: false,
+                },
+                parents: Vec::new(),
+                encoding: None,
+                extra_headers: Vec::new(),
+                message: "initial commit".into(),
+            });
+
+            let mut out = BytesMut::new();
+
+            PackFile::new(&[blob, tree, commit])
+                .encode_to(&mut out)
+                .unwrap();
+
+            out.freeze()
+        }
+
+        #[test]
+        fn snapshot() {
+            let actual = example();
+            insta::assert_debug_snapshot!(actual);
+        }
+
+        #[test]
+        fn statistics() {
+            use crate::low_level::PackFileStats;
+
+            let blob = PackFileEntry::Blob(Bytes::from(vec![b'a'; 2000]));
+            let tree = PackFileEntry::Tree(vec![TreeItem {
+                kind: TreeItemKind::File,
+                name: "a.txt".into(),
+                hash: blob.hash().unwrap(),
+            }]);
+            let commit = PackFileEntry::Commit(Commit {
+                tree: tree.hash().unwrap(),
+                author: CommitUserInfo {
+                    name: "example".into(),
+                    email: "example@me.com".into(),
+                    time: time::OffsetDateTime::UNIX_EPOCH,
+                    negative_utc: false,
+                },
+                committer: CommitUserInfo {
+                    name: "example".into(),
+                    email: "example@me.com".into(),
+                    time: time::OffsetDateTime::UNIX_EPOCH,
+                    negative_utc: false,
+                },
+                parents: Vec::new(),
+                encoding: None,
+                extra_headers: Vec::new(),
+                message: "initial commit".into(),
+            });
+            let mut entries = delta_chain(2);
+            entries.extend([blob, tree, commit]);
+
+            let stats = PackFile::new(&entries).statistics();
+            assert_eq!(
+                stats,
+                PackFileStats {
+                    blob_count: 2,
+                    tree_count: 1,
+                    commit_count: 1,
+                    tag_count: 0,
+                    delta_count: 2,
+                    total_uncompressed_bytes: entries
+                        .iter()
+                        .map(PackFileEntry::uncompressed_size)
+                        .sum(),
+                }
+            );
+            // 12 bytes for the base blob, 2000 for the other blob and 33 for the tree
+            // ("100644 a.txt\0" followed by the hash)
+            let commit_size = entries[5].uncompressed_size();
+            let delta_size = entries[1].uncompressed_size() + entries[2].uncompressed_size();
+            assert_eq!(
+                stats.total_uncompressed_bytes,
+                12 + 2000 + 33 + commit_size + delta_size
+            );
+
+            assert_eq!(
+                stats.to_string(),
+                "count: 6\ncommits: 1\ntrees: 1\nblobs: 2\ntags: 0\ndeltas: 2\nsize: 2\n"
+            );
+        }
+
+        #[test]
+        fn owned() {
+            fn build() -> PackFile<'static> {
+                PackFile::owned(vec![
+                    PackFileEntry::Blob(Bytes::from("hello world")),
+                    PackFileEntry::Blob(Bytes::from("another blob")),
+                ])
+                .with_max_delta_chain_length(1)
+            }
+
+            let entries = [
+                PackFileEntry::Blob(Bytes::from("hello world")),
+                PackFileEntry::Blob(Bytes::from("another blob")),
+            ];
+            let mut expected = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut expected).unwrap();
+
+            let mut actual = BytesMut::new();
+            build().encode_to(&mut actual).unwrap();
+            assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn encode_to_returns_length() {
+            let entries = [
+                PackFileEntry::Blob(Bytes::from("hello world")),
+                PackFileEntry::Blob(Bytes::from(vec![b'a'; 100_000])),
+            ];
+
+            let mut buf = BytesMut::from(&b"existing data"[..]);
+            let written = PackFile::new(&entries).encode_to(&mut buf).unwrap();
+            assert_eq!(written, buf.len() - b"existing data".len());
+
+            let written = PackFile::new(&[]).encode_to(&mut BytesMut::new()).unwrap();
+            assert_eq!(written, 32);
+        }
+
+        #[test]
+        fn encode_to_with_hash() {
+            let entries = delta_chain(2);
+
+            // the pack is appended to whatever is already in the buffer
+            let mut buf = BytesMut::from(&b"prefix"[..]);
+            let hash = PackFile::new(&entries)
+                .encode_to_with_hash(&mut buf)
+                .unwrap();
+            assert_eq!(&buf[buf.len() - 20..], &hash[..]);
+            assert_eq!(&buf[..6], b"prefix");
+
+            let mut plain = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut plain).unwrap();
+            assert_eq!(&buf[6..], &plain[..]);
+        }
+
+        #[test]
+        fn from_iter() {
+            use crate::Error;
+
+            let mut entries = delta_chain(3);
+            entries.push(PackFileEntry::Blob(Bytes::from("another blob")));
+
+            let mut expected = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut expected).unwrap();
+
+            let count = u32::try_from(entries.len()).unwrap();
+            let mut actual = BytesMut::new();
+            let hash =
+                PackFile::from_iter(
+                    count,
+                    delta_chain(3).into_iter().map(Ok).chain(std::iter::once(Ok(
+                        PackFileEntry::Blob(Bytes::from("another blob")),
+                    ))),
+                )
+                .encode_to(&mut actual)
+                .unwrap();
+            assert_eq!(actual, expected);
+            assert_eq!(&hash[..], &expected[expected.len() - 20..]);
+
+            let mut written = Vec::new();
+            PackFile::from_iter(
+                count,
+                delta_chain(3)
+                    .into_iter()
+                    .map(Ok)
+                    .chain([Ok(PackFileEntry::Blob(Bytes::from("another blob")))]),
+            )
+            .write_to(&mut written)
+            .unwrap();
+            assert_eq!(written, expected);
+
+            let mut empty = BytesMut::new();
+            PackFile::new(&[]).encode_to(&mut empty).unwrap();
+            let mut actual = BytesMut::new();
+            PackFile::from_iter(0, []).encode_to(&mut actual).unwrap();
+            assert_eq!(actual, empty);
+
+            for count in [3, 5] {
+                assert!(matches!(
+                    PackFile::from_iter(count, delta_chain(3).into_iter().map(Ok))
+                        .encode_to(&mut BytesMut::new()),
+                    Err(Error::MalformedPackFile(_))
+                ));
+            }
+
+            assert!(matches!(
+                PackFile::from_iter(
+                    2,
+                    [
+                        Ok(PackFileEntry::Blob(Bytes::from("hello"))),
+                        Err(Error::MalformedTree {
+                            offset: 0,
+                            reason: "test"
+                        }),
+                    ]
+                )
+                .encode_to(&mut BytesMut::new()),
+                Err(Error::MalformedTree { .. })
+            ));
+        }
+
+        fn delta_chain(length: usize) -> Vec<PackFileEntry> {
+            let blobs: Vec<_> = (0..=length)
+                .map(|i| PackFileEntry::Blob(Bytes::from("hello world\n".repeat(i + 1))))
+                .collect();
+
+            std::iter::once(PackFileEntry::Blob(Bytes::from("hello world\n")))
+                .chain(
+                    blobs
+                        .windows(2)
+                        .map(|w| PackFileEntry::ref_delta(&w[0], &w[1]).unwrap()),
+                )
+                .collect()
+        }
+
+        #[test]
+        fn encode_to_indexed() {
+            use crate::index::{encode_v2, IndexEntry};
+
+            let mut entries = delta_chain(3);
+            entries.push(PackFileEntry::Blob(Bytes::from("another blob")));
+
+            let mut pack = BytesMut::new();
+            let offsets = PackFile::new(&entries)
+                .encode_to_indexed(&mut pack)
+                .unwrap();
+
+            let mut plain = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut plain).unwrap();
+            assert_eq!(pack, plain);
+
+            assert_eq!(offsets.len(), entries.len());
+            assert_eq!(offsets[0].1, PackFile::header_size() as u64);
+            for ((hash, _), entry) in offsets.iter().zip(&entries) {
+                assert_eq!(*hash, entry.hash().unwrap());
+            }
+
+            // each entry runs until the next one starts, or the footer
+            let footer_start = (pack.len() - PackFile::footer_size()) as u64;
+            let ends = offsets.iter().skip(1).map(|(_, offset)| *offset);
+            let mut index_entries: Vec<_> = offsets
+                .iter()
+                .zip(ends.chain([footer_start]))
+                .map(|(&(hash, offset), end)| {
+                    let mut crc = flate2::Crc::new();
+                    crc.update(
+                        &pack[usize::try_from(offset).unwrap()..usize::try_from(end).unwrap()],
+                    );
+                    IndexEntry {
+                        hash,
+                        offset,
+                        crc32: crc.sum(),
+                    }
+                })
+                .collect();
+
+            let pack_hash: [u8; 20] = pack[pack.len() - 20..].try_into().unwrap();
+            let mut index = BytesMut::new();
+            encode_v2(&mut index_entries, &pack_hash, &mut index).unwrap();
+
+            let dir = tempfile::TempDir::new().unwrap();
+            std::fs::write(dir.path().join("test.pack"), &pack).unwrap();
+            std::fs::write(dir.path().join("test.idx"), &index).unwrap();
+
+            let stdout = crate::test::git(dir.path(), &["verify-pack", "-v", "test.idx"], &[]);
+            for (hash, offset) in &offsets {
+                let hash = hex::encode(hash);
+                assert!(
+                    stdout.lines().any(|line| line.starts_with(&hash)
+                        && line.split_whitespace().nth(4) == Some(&offset.to_string())),
+                    "{hash} at {offset} missing from {stdout}"
+                );
+            }
+        }
+
+        #[test]
+        fn encode_to_with_sizes() {
+            let mut entries = delta_chain(3);
+            entries.push(PackFileEntry::Blob(Bytes::from(vec![0; 10_000])));
+
+            let mut pack = BytesMut::new();
+            let sizes = PackFile::new(&entries)
+                .encode_to_with_sizes(&mut pack)
+                .unwrap();
+            assert_eq!(sizes.len(), entries.len());
+
+            for ((hash, uncompressed, _), entry) in sizes.iter().zip(&entries) {
+                assert_eq!(*hash, entry.hash().unwrap());
+                assert_eq!(*uncompressed, entry.uncompressed_size());
+            }
+
+            let compressed: usize = sizes.iter().map(|(_, _, compressed)| compressed).sum();
+            assert_eq!(
+                compressed + PackFile::header_size() + PackFile::footer_size(),
+                pack.len()
+            );
+
+            // the run of zeroes compresses far below its uncompressed size
+            let (_, uncompressed, compressed) = sizes.last().unwrap();
+            assert!(compressed * 10 < *uncompressed);
+        }
+
+        #[test]
+        fn ref_delta_is_readable_by_git() {
+            let entries = delta_chain(3);
+
+            let mut out = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut out).unwrap();
+
+            let stdout = crate::test::verify_pack_file(out.freeze());
+            assert!(stdout.contains("chain length = 3: 1 object"), "{stdout}");
+        }
+
+        #[test]
+        fn rehash_verify() {
+            let blob = PackFileEntry::Blob(Bytes::from("hello world"));
+            let tree = PackFileEntry::Tree(vec![
+                TreeItem {
+                    kind: TreeItemKind::File,
+                    name: "helloworld.txt".into(),
+                    hash: blob.hash().unwrap(),
+                },
+                TreeItem {
+                    kind: TreeItemKind::Submodule,
+                    name: "submodule".into(),
+                    hash: [1; 20],
+                },
+            ]);
+            let commit = PackFileEntry::Commit(Commit {
+                tree: tree.hash().unwrap(),
+                author: CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap(),
+                committer: CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap(),
+                parents: Vec::new(),
+                encoding: None,
+                extra_headers: Vec::new(),
+                message: "initial commit".into(),
+            });
+            let (blob_hash, tree_hash, commit_hash) = (
+                blob.hash().unwrap(),
+                tree.hash().unwrap(),
+                commit.hash().unwrap(),
+            );
+
+            let mut entries = vec![blob, tree, commit];
+            PackFile::rehash_verify(&entries).unwrap();
+
+            let commit = entries.pop().unwrap();
+            PackFile::rehash_verify(&entries).unwrap();
+
+            entries.remove(0);
+            assert!(matches!(
+                PackFile::rehash_verify(&entries),
+                Err(crate::Error::DanglingReference { referencing, target })
+                    if referencing == tree_hash && target == blob_hash
+            ));
+
+            assert!(matches!(
+                PackFile::rehash_verify(&[commit]),
+                Err(crate::Error::DanglingReference { referencing, target })
+                    if referencing == commit_hash && target == tree_hash
+            ));
+        }
+
+        #[test]
+        fn builder() {
+            use crate::low_level::PackFileBuilder;
+
+            let author = CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap();
+            let commit = |tree| Commit {
+                tree,
+                author: author.clone(),
+                committer: author.clone(),
+                parents: Vec::new(),
+                encoding: None,
+                extra_headers: Vec::new(),
+                message: "initial commit".into(),
+            };
+            let item = |hash| TreeItem {
+                kind: TreeItemKind::File,
+                name: "helloworld.txt".into(),
+                hash,
+            };
+
+            let mut builder = PackFileBuilder::new();
+
+            // nothing has been pushed yet, so the tree's blob is missing
+            let blob_hash = PackFileEntry::Blob(Bytes::from("hello world"))
+                .hash()
+                .unwrap();
+            assert!(matches!(
+                builder.push_tree(vec![item(blob_hash)]),
+                Err(crate::Error::DanglingReference { target, .. }) if target == blob_hash
+            ));
+
+            assert_eq!(
+                builder.push_blob(Bytes::from("hello world")).unwrap(),
+                blob_hash
+            );
+            assert_eq!(
+                builder.push_blob(Bytes::from("hello world")).unwrap(),
+                blob_hash
+            );
+            let tree_hash = builder.push_tree(vec![item(blob_hash)]).unwrap();
+
+            assert!(matches!(
+                builder.push_commit(commit([1; 20])),
+                Err(crate::Error::DanglingReference { target, .. }) if target == [1; 20]
+            ));
+            builder.push_commit(commit(tree_hash)).unwrap();
+
+            // the failed pushes and the duplicate blob were left out
+            let packfile = builder.build();
+            let stats = packfile.statistics();
+            assert_eq!(
+                (stats.blob_count, stats.tree_count, stats.commit_count),
+                (1, 1, 1)
+            );
+
+            let mut output = BytesMut::new();
+            packfile.encode_to(&mut output).unwrap();
+            crate::test::verify_pack_file(output.freeze());
+        }
+
+        #[test]
+        fn thin_pack() {
+            let base = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(10)));
+            let target = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(11)));
+            let entries = [PackFileEntry::ref_delta(&base, &target).unwrap()];
+
+            let err = PackFile::new(&entries)
+                .encode_to(&mut BytesMut::new())
+                .unwrap_err();
+            assert!(
+                matches!(err, crate::Error::MissingDeltaBase { base: b, .. } if b == base.hash().unwrap()),
+                "{err}"
+            );
+
+            let mut thin = BytesMut::new();
+            PackFile::new(&entries)
+                .thin(true)
+                .encode_to(&mut thin)
+                .unwrap();
+
+            // the client already has the base, and completes the pack using it
+            let mut base_pack = BytesMut::new();
+            PackFile::new(&[base]).encode_to(&mut base_pack).unwrap();
+            let repo = crate::test::bare_repo_with_pack(&base_pack);
+
+            crate::test::git(repo.path(), &["index-pack", "--stdin", "--fix-thin"], &thin);
+
+            let target_hash = hex::encode(target.hash().unwrap());
+            assert_eq!(
+                crate::test::git(repo.path(), &["cat-file", "-p", &target_hash], &[]),
+                "hello world\n".repeat(11)
+            );
+        }
+
+        #[test]
+        fn delta_bases_hashed_until_found() {
+            use crate::low_level::{TreeItem, TreeItemKind};
+
+            let base = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(10)));
+            let target = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(11)));
+            let delta = PackFileEntry::ref_delta(&base, &target).unwrap();
+
+            // fails to hash, so validation only passes if it stops once the base is found
+            let item = |kind| TreeItem {
+                kind,
+                name: "foo".into(),
+                hash: [0; 20],
+            };
+            let unhashable = PackFileEntry::Tree(vec![
+                item(TreeItemKind::File),
+                item(TreeItemKind::Directory),
+            ]);
+            assert!(unhashable.hash().is_err());
+
+            PackFile::new(&[base, delta, unhashable])
+                .validate_delta_chains()
+                .unwrap();
+        }
+
+        #[test]
+        fn delta_chain_too_long() {
+            let entries = delta_chain(60);
+
+            let err = PackFile::new(&entries)
+                .encode_to(&mut BytesMut::new())
+                .unwrap_err();
+            assert!(
+                matches!(err, crate::Error::DeltaChainTooLong { max: 50, .. }),
+                "{err}"
+            );
+
+            PackFile::new(&entries)
+                .with_max_delta_chain_length(60)
+                .encode_to(&mut BytesMut::new())
+                .unwrap();
+        }
+
+        #[test]
+        fn ref_delta_mismatched_kinds() {
+            let blob = PackFileEntry::Blob(Bytes::from("hello world"));
+            let tree = PackFileEntry::Tree(vec![]);
+
+            assert!(matches!(
+                PackFileEntry::ref_delta(&blob, &tree),
+                Err(crate::Error::InvalidDeltaBase)
+            ));
+        }
+
+        #[test]
+        fn reused_encoder_matches_zlib_encoder() {
+            use flate2::{write::ZlibEncoder, Compression};
+            use std::io::Write;
+
+            let entries: Vec<_> = (0..10_u8)
+                .map(|i| {
+                    let data: Vec<u8> = (0..100_000_u32)
+                        .map(|v| (v.wrapping_mul(u32::from(i) + 7) >> 3).to_le_bytes()[0])
+                        .collect();
+                    PackFileEntry::Blob(Bytes::from(data))
+                })
+                .collect();
+
+            let mut expected = BytesMut::new();
+            for entry in &entries {
+                entry.write_header(&mut expected);
+                let mut body = BytesMut::new();
+                entry.encode_body(&mut body).unwrap();
+
+                let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+                e.write_all(&body).unwrap();
+                expected.extend_from_slice(&e.finish().unwrap());
+            }
+
+            let mut encoder = PackEncoder::default();
+            let mut actual = BytesMut::new();
+            for entry in &entries {
+                entry.encode_with(&mut encoder, &mut actual).unwrap();
+            }
+
+            assert_eq!(actual, expected);
+        }
+
+        #[test]
+        fn large_blob_compresses_in_place() {
+            let entry = PackFileEntry::Blob(Bytes::from(
+                (0..10_000_000_u32)
+                    .map(|v| (v >> 5).to_le_bytes()[0])
+                    .collect::<Vec<_>>(),
+            ));
+
+            let mut encoder = PackEncoder::default();
+            let mut out = BytesMut::with_capacity(20_000_000);
+            entry.encode_with(&mut encoder, &mut out).unwrap();
+            let expected = out.split().freeze();
+
+            // now the scratch buffer has grown, compressing shouldn't need to allocate
+            // anything else
+            let allocations = crate::test::count_allocations(|| {
+                entry.encode_with(&mut encoder, &mut out).unwrap();
+            });
+
+            assert_eq!(allocations, 0);
+            assert_eq!(out.freeze(), expected);
+        }
+
+        #[test]
+        fn reused_encoder_allocations() {
+            let entries: Vec<_> = (0..1000_u32)
+                .map(|i| PackFileEntry::Blob(Bytes::from(i.to_string())))
+                .collect();
+            let packfile = PackFile::new(&entries);
+
+            let mut out = BytesMut::with_capacity(1024 * 1024);
+            let allocations = crate::test::count_allocations(|| {
+                packfile.encode_to(&mut out).unwrap();
+            });
+
+            // a fresh compressor for each entry would be several allocations per entry
+            assert!(
+                allocations < entries.len() / 10,
+                "{allocations} allocations for {} entries",
+                entries.len()
+            );
+        }
+
+        #[test]
+        fn encode_into_pooled() {
+            let entries: Vec<_> = (0..10_u32)
+                .map(|i| PackFileEntry::Blob(Bytes::from(i.to_string().repeat(500))))
+                .collect();
+            let packfile = PackFile::new(&entries);
+
+            let mut expected = BytesMut::new();
+            packfile.encode_to(&mut expected).unwrap();
+
+            let fresh = crate::test::count_allocations(|| {
+                for _ in 0..100 {
+                    let mut buf = BytesMut::new();
+                    packfile.encode_to(&mut buf).unwrap();
+                }
+            });
+
+            let mut pool = BytesMut::new();
+            let pooled = crate::test::count_allocations(|| {
+                for _ in 0..100 {
+                    let len = packfile.encode_into_pooled(&mut pool).unwrap();
+                    assert_eq!(len, pool.len());
+                }
+            });
+            assert_eq!(pool, expected);
+
+            // growing a fresh buffer for every pack reallocates several times, a pooled
+            // one only has to grow for the first
+            assert!(
+                pooled + 100 <= fresh,
+                "{pooled} pooled vs {fresh} fresh allocations"
+            );
+        }
+
+        #[test]
+        fn write_trailer() {
+            let mut repo = crate::high_level::GitRepository::default();
+            repo.insert(&["src"], "main.rs", Bytes::from("fn main() {}\n"))
+                .unwrap();
+            repo.insert(&[], "big.bin", Bytes::from(vec![7; 100_000]))
+                .unwrap();
+            let (_, entries) = repo.commit("me", "me@example.com", "commit").unwrap();
+
+            let mut expected = BytesMut::new();
+            let expected_hash = PackFile::new(&entries)
+                .encode_to_with_hash(&mut expected)
+                .unwrap();
+
+            // stream each piece out as soon as it's written, as a server would
+            let mut out = Vec::new();
+            let mut hasher = PackHasher::new();
+            let mut buf = BytesMut::new();
+            let mut encoder = PackEncoder::default();
+
+            PackFile::write_header(entries.len().try_into().unwrap(), &mut buf);
+            for entry in &entries {
+                hasher.update(&buf);
+                out.extend_from_slice(&buf.split());
+                entry.encode_with(&mut encoder, &mut buf).unwrap();
+            }
+            hasher.update(&buf);
+            out.extend_from_slice(&buf.split());
+
+            let hash = PackFile::write_trailer(hasher, &mut buf);
+            out.extend_from_slice(&buf);
+
+            assert_eq!(hash, expected_hash);
+            assert_eq!(out, expected);
+        }
+
+        #[test]
+        fn encode_split() {
+            // incompressible blobs, so they each take up a decent chunk of the limit
+            let mut entries: Vec<_> = (1..=20_u32)
+                .map(|i| {
+                    let mut state = i;
+                    let data: Vec<u8> = (0..1000)
+                        .map(|_| {
+                            state ^= state << 13;
+                            state ^= state >> 17;
+                            state ^= state << 5;
+                            state.to_le_bytes()[0]
+                        })
+                        .collect();
+                    PackFileEntry::Blob(Bytes::from(data))
+                })
+                .collect();
+            entries.extend(delta_chain(3));
+
+            let packs = PackFile::new(&entries).encode_split(4096).unwrap();
+            assert!(packs.len() > 1, "expected multiple packs");
+
+            let mut objects = 0;
+            for pack in packs {
+                assert!(pack.len() <= 4096, "pack is {} bytes", pack.len());
+                let stdout = crate::test::verify_pack_file(pack);
+                objects += stdout
+                    .lines()
+                    .filter(|line| line.contains(" blob "))
+                    .count();
+            }
+            assert_eq!(objects, entries.len());
+
+            // a single pack of everything if the limit allows it
+            let packs = PackFile::new(&entries).encode_split(usize::MAX).unwrap();
+            let mut expected = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut expected).unwrap();
+            assert_eq!(packs, vec![expected.freeze()]);
+        }
+
+        #[test]
+        fn encode_split_too_small() {
+            let entries = delta_chain(3);
+
+            let err = PackFile::new(&entries).encode_split(100).unwrap_err();
+            assert!(
+                matches!(err, crate::Error::SplitSizeTooSmall { max: 100, .. }),
+                "{err}"
+            );
+        }
+
+        #[test]
+        fn split() {
+            let mut entries: Vec<_> = (0..20_u32)
+                .map(|i| PackFileEntry::Blob(Bytes::from(i.to_string().repeat(100))))
+                .collect();
+            entries.splice(5..5, delta_chain(4));
+            // a delta near the end referring back to a base a few entries before it
+            let target = PackFileEntry::Blob(Bytes::from("21".repeat(100)));
+            entries.push(PackFileEntry::ref_delta(&entries[21], &target).unwrap());
+
+            let packs = PackFile::split(&entries, 2048, 5).unwrap();
+            assert!(packs.len() > 1, "expected multiple packs");
+
+            let mut hashes = Vec::new();
+            for pack in &packs {
+                assert!(pack.entries.len() <= 5);
+                assert!(pack.encoded_size_bound() <= 2048);
+
+                let mut out = BytesMut::new();
+                pack.encode_to(&mut out).unwrap();
+                crate::test::verify_pack_file(out.freeze());
+
+                hashes.extend(pack.entries.iter().map(|entry| entry.hash().unwrap()));
+            }
+
+            let expected: Vec<_> = entries.iter().map(|entry| entry.hash().unwrap()).collect();
+            assert_eq!(hashes, expected);
+        }
+
+        #[test]
+        fn split_too_small() {
+            let entries = delta_chain(4);
+
+            assert!(matches!(
+                PackFile::split(&entries, usize::MAX, 4),
+                Err(crate::Error::SplitEntriesTooSmall {
+                    required: 5,
+                    max: 4
+                })
+            ));
+            assert!(matches!(
+                PackFile::split(&entries, 100, u32::MAX),
+                Err(crate::Error::SplitSizeTooSmall { max: 100, .. })
+            ));
+
+            let packs = PackFile::split(&entries, usize::MAX, 5).unwrap();
+            assert_eq!(packs.len(), 1);
+            assert!(PackFile::split(&[], 0, 0).unwrap().is_empty());
+        }
+
+        #[test]
+        fn encoded_size() {
+            // xorshift, so the blobs are incompressible and have a variety of sizes
+            let mut state = 0x2545_f491_u32;
+            let mut next = move || {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state
+            };
+
+            for _ in 0..20 {
+                let mut entries: Vec<_> = (0..5)
+                    .map(|_| {
+                        let len = next() % 200_000;
+                        let data: Vec<u8> = (0..len).map(|_| next().to_le_bytes()[0]).collect();
+                        PackFileEntry::Blob(Bytes::from(data))
+                    })
+                    .collect();
+                entries.push(PackFileEntry::Blob(Bytes::new()));
+                entries.push(PackFileEntry::Blob(Bytes::from(vec![0; 100_000])));
+                entries.extend(delta_chain(2));
+
+                let packfile = PackFile::new(&entries);
+
+                let mut out = BytesMut::new();
+                packfile.encode_to(&mut out).unwrap();
+
+                let bound = packfile.encoded_size_bound();
+                assert!(out.len() <= bound, "{} > {bound}", out.len());
+                assert_eq!(packfile.encoded_size_exact().unwrap(), out.len());
+            }
+        }
+
+        #[test]
+        fn is_readable_by_git() {
+            let stdout = crate::test::verify_pack_file(example());
+
+            insta::with_settings!({filters => vec![
+                (r"/(.*)/example.pack", "/path/to/example.pack")
+            ]}, {
+                insta::assert_snapshot!(stdout);
+            });
+        }
+    }
+
+    mod packed_refs {
+        use crate::{
+            high_level::GitRepository,
+            low_level::{packed_refs, PackFile},
+        };
+        use bytes::{Bytes, BytesMut};
+
+        #[test]
+        fn listed_by_git() {
+            let mut repo = GitRepository::default();
+            repo.insert(&[], "a.txt", Bytes::from("a")).unwrap();
+            let (commit, entries) = repo.commit("me", "me@example.com", "initial").unwrap();
+
+            let mut pack = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut pack).unwrap();
+            let dir = crate::test::bare_repo_with_pack(&pack);
+
+            let tag = crate::test::git(
+                dir.path(),
+                &["mktag"],
+                format!(
+                    "object {}\ntype commit\ntag v1\ntagger me <me@example.com> 0 +0000\n\nv1\n",
+                    hex::encode(commit),
+                )
+                .as_bytes(),
+            );
+            let mut tag_hash = [0; 20];
+            hex::decode_to_slice(tag.trim(), &mut tag_hash).unwrap();
+
+            let out = packed_refs(
+                [
+                    ("refs/tags/v1".to_string(), tag_hash),
+                    ("refs/heads/main".to_string(), commit),
+                    ("refs/heads/dev".to_string(), commit),
+                ],
+                |hash| (*hash == tag_hash).then_some(commit),
+            )
+            .unwrap();
+
+            let commit = hex::encode(commit);
+            let tag = hex::encode(tag_hash);
+            assert_eq!(
+                std::str::from_utf8(&out).unwrap(),
+                format!(
+                    "# pack-refs with: peeled fully-peeled sorted \n\
+                    {commit} refs/heads/dev\n\
+                    {commit} refs/heads/main\n\
+                    {tag} refs/tags/v1\n\
+                    ^{commit}\n"
+                )
+            );
+
+            std::fs::write(dir.path().join("packed-refs"), out).unwrap();
+            assert_eq!(
+                crate::test::git(
+                    dir.path(),
+                    &[
+                        "for-each-ref",
+                        "--format=%(objectname) %(refname) %(*objectname)"
+                    ],
+                    &[],
+                ),
+                format!(
+                    "{commit} refs/heads/dev \n\
+                    {commit} refs/heads/main \n\
+                    {tag} refs/tags/v1 {commit}\n"
+                )
+            );
+        }
+
+        #[test]
+        fn invalid_refname() {
+            assert!(matches!(
+                packed_refs([("refs/heads/a b".to_string(), [1; 20])], |_| None),
+                Err(crate::Error::InvalidRefName(_))
+            ));
+        }
+    }
+
+    mod tree_item_kind {
+        use crate::{low_level::TreeItemKind, Error};
+
+        #[test]
+        fn mode_u32_round_trip() {
+            for (mode, kind) in [
+                (0o100_644, TreeItemKind::File),
+                (0o100_755, TreeItemKind::Executable),
+                (0o120_000, TreeItemKind::Symlink),
+                (0o040_000, TreeItemKind::Directory),
+                (0o160_000, TreeItemKind::Submodule),
+            ] {
+                let actual = TreeItemKind::from_mode_u32(mode).unwrap();
+                assert_eq!(actual, kind);
+                assert_eq!(actual.to_mode_u32(), mode);
+                assert_eq!(u32::from_str_radix(actual.mode(), 8).unwrap(), mode);
+            }
+        }
+
+        #[test]
+        fn display_and_parse() {
+            for (name, kind) in [
+                ("blob", TreeItemKind::File),
+                ("exec", TreeItemKind::Executable),
+                ("symlink", TreeItemKind::Symlink),
+                ("tree", TreeItemKind::Directory),
+                ("commit", TreeItemKind::Submodule),
+            ] {
+                assert_eq!(kind.to_string(), kind.mode());
+                assert_eq!(format!("{kind:o}"), kind.mode());
+                assert_eq!(kind.to_string().parse::<TreeItemKind>().unwrap(), kind);
+                assert_eq!(name.parse::<TreeItemKind>().unwrap(), kind);
+            }
+
+            assert_eq!(format!("{:06o}", TreeItemKind::Directory), "040000");
+            assert_eq!(format!("{:#o}", TreeItemKind::File), "0o100644");
+            assert_eq!(
+                "040000".parse::<TreeItemKind>().unwrap(),
+                TreeItemKind::Directory
+            );
+
+            assert!(matches!(
+                "100664".parse::<TreeItemKind>(),
+                Err(Error::UnknownTreeMode(0o100_664))
+            ));
+            assert!(matches!(
+                "file".parse::<TreeItemKind>(),
+                Err(Error::UnknownTreeKind(s)) if s == "file"
+            ));
+        }
+
+        #[test]
+        fn unknown_mode() {
+            assert!(matches!(
+                TreeItemKind::from_mode_u32(0o100_664),
+                Err(Error::UnknownTreeMode(0o100_664))
+            ));
+        }
+    }
+
+    mod entry_header {
+        use crate::{
+            low_level::{read_entry_header, write_entry_header, ObjectKind},
+            Error,
+        };
+        use bytes::BytesMut;
+
+        const KINDS: [ObjectKind; 6] = [
+            ObjectKind::Commit,
+            ObjectKind::Tree,
+            ObjectKind::Blob,
+            ObjectKind::Tag,
+            ObjectKind::OfsDelta,
+            ObjectKind::RefDelta,
+        ];
+
+        #[test]
+        fn size_boundaries() {
+            // 4 bits of the size fit in the first byte and 7 in each byte after it,
+            // with at least one size byte even when it isn't needed
+            for (size, expected_len) in [
+                (0, 2),
+                (15, 2),
+                (16, 2),
+                ((1 << 11) - 1, 2),
+                (1 << 11, 3),
+                ((1 << 18) - 1, 3),
+                (1 << 18, 4),
+                ((1 << 32) - 1, 5),
+                (1 << 32, 6),
+                (u64::MAX, 10),
+            ] {
+                for kind in KINDS {
+                    let mut buf = BytesMut::new();
+                    write_entry_header(kind, size, &mut buf);
+                    assert_eq!(buf.len(), expected_len, "header length for {size}");
+
+                    buf.extend_from_slice(b"trailing data");
+                    let actual = read_entry_header(&buf).unwrap();
+                    assert_eq!(actual, (kind, size, expected_len));
+                }
+            }
+        }
+
+        #[test]
+        fn known_bytes() {
+            let mut buf = BytesMut::new();
+            write_entry_header(ObjectKind::Blob, 15, &mut buf);
+            assert_eq!(buf.as_ref(), &[0b1011_1111, 0b0000_0000]);
+
+            // a header without the redundant zero byte can still be read
+            assert_eq!(
+                read_entry_header(&[0b0011_1111]).unwrap(),
+                (ObjectKind::Blob, 15, 1)
+            );
+
+            let mut buf = BytesMut::new();
+            write_entry_header(ObjectKind::Commit, 16, &mut buf);
+            assert_eq!(buf.as_ref(), &[0b1001_0000, 0b0000_0001]);
+
+            let mut buf = BytesMut::new();
+            write_entry_header(ObjectKind::Tree, 1 << 11, &mut buf);
+            assert_eq!(buf.as_ref(), &[0b1010_0000, 0b1000_0000, 0b0000_0001]);
+        }
+
+        #[test]
+        fn pack_type_bits() {
+            for (kind, bits) in KINDS.into_iter().zip([1, 2, 3, 4, 6, 7]) {
+                assert_eq!(kind.pack_type_bits(), bits);
+                assert_eq!(ObjectKind::from_pack_type_bits(bits), Some(kind));
+            }
+
+            assert_eq!(ObjectKind::from_pack_type_bits(0), None);
+            assert_eq!(ObjectKind::from_pack_type_bits(5), None);
+        }
+
+        #[test]
+        fn names() {
+            let names = ["commit", "tree", "blob", "tag", "ofs-delta", "ref-delta"];
+            for (kind, name) in KINDS.into_iter().zip(names) {
+                assert_eq!(kind.as_str(), name);
+                assert_eq!(kind.to_string(), name);
+                assert_eq!(name.parse::<ObjectKind>().unwrap(), kind);
+                assert_eq!(kind.is_delta(), name.ends_with("-delta"));
             }
-            size >>= 4;
 
-            buf.put_u8(val);
+            assert!("Blob".parse::<ObjectKind>().is_err());
+            assert!("".parse::<ObjectKind>().is_err());
         }
 
-        // write size bytes
-        loop {
-            // read 7 LSBs from the `size` and push them off for the next iteration
-            #[allow(clippy::cast_possible_truncation)] // value is masked
-            let mut val = (size & 0b111_1111) as u8;
-            size >>= 7;
-
-            if size != 0 {
-                // MSB set to 1 implies there's more size bytes to come, otherwise
-                // the data starts after this byte
-                val |= 1 << 7;
+        #[test]
+        fn malformed() {
+            for (buf, reason) in [
+                (&[][..], "truncated header"),
+                (&[0b1011_0000], "truncated header"),
+                (&[0b1011_0000, 0b1000_0000], "truncated header"),
+                (&[0b0000_0001], "reserved type"),
+                (&[0b0101_0001], "reserved type"),
+                (
+                    &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f],
+                    "size overflows",
+                ),
+                (&[0xff; 11], "size overflows"),
+            ] {
+                match read_entry_header(buf) {
+                    Err(Error::MalformedEntryHeader(actual)) => assert_eq!(actual, reason),
+                    other => panic!("expected MalformedEntryHeader for {buf:?}, got {other:?}"),
+                }
             }
+        }
+    }
 
-            buf.put_u8(val);
+    mod commit_user_info {
+        use crate::low_level::CommitUserInfo;
 
-            if size == 0 {
-                break;
+        #[test]
+        fn parse_round_trip() {
+            for line in [
+                "Jordan Doyle <jordan@doyle.la> 1630244577 +0100",
+                "Jöhn Dœ Smith <john@example.com> 1688494158 -0530",
+                "me <me@example.com> 0 +0000",
+                "me <me@example.com> 1688494158 -0000",
+            ] {
+                let actual = CommitUserInfo::try_parse_git_format(line.as_bytes()).unwrap();
+                assert_eq!(actual.to_string(), line);
+                assert_eq!(actual.size(), line.len());
             }
         }
-    }
 
-    #[cfg_attr(
-        feature = "tracing",
-        tracing::instrument(skip(self, original_out), err)
-    )]
-    pub fn encode_to(&self, original_out: &mut BytesMut) -> Result<(), Error> {
-        self.write_header(original_out); // TODO: this needs space reserving for it
+        #[test]
+        fn parse_git_output() {
+            use crate::low_level::EMPTY_TREE_HASH;
 
-        // todo is there a way to stream through the zlibencoder so we don't have to
-        // have this intermediate bytesmut and vec?
-        let mut out = BytesMut::new();
+            let dir = tempfile::TempDir::new().unwrap();
+            let git = |args: &[&str]| {
+                let user = [
+                    "-c",
+                    "user.name=Jöhn Dœ Smith",
+                    "-c",
+                    "user.email=john@example.com",
+                ];
+                crate::test::git(dir.path(), &[&user[..], args].concat(), &[])
+            };
 
-        let size = self.uncompressed_size();
-        original_out.reserve(size);
-        // the data ends up getting compressed but we'll need at least this many bytes
-        out.reserve(size);
+            git(&["init", "--bare", "--quiet"]);
+            let commit = git(&[
+                "commit-tree",
+                &hex::encode(EMPTY_TREE_HASH),
+                "-m",
+                "initial",
+            ]);
+            git(&["tag", "-a", "-m", "v1", "v1", commit.trim()]);
 
-        match self {
-            Self::Commit(commit) => {
-                commit.encode_to(&mut out)?;
-            }
-            Self::Tree(items) => {
-                for item in items {
-                    item.encode_to(&mut out)?;
-                }
-            }
-            Self::Blob(data) => {
-                out.extend_from_slice(data);
+            let commit = git(&["cat-file", "commit", commit.trim()]);
+            let tag = git(&["cat-file", "tag", "v1"]);
+            let lines = commit.lines().chain(tag.lines());
+
+            let mut parsed = 0;
+            for line in lines {
+                let Some(user) = ["author ", "committer ", "tagger "]
+                    .iter()
+                    .find_map(|header| line.strip_prefix(header))
+                else {
+                    continue;
+                };
+
+                let actual = CommitUserInfo::try_parse_git_format(user.as_bytes()).unwrap();
+                assert_eq!(&*actual.name, "Jöhn Dœ Smith");
+                assert_eq!(&*actual.email, "john@example.com");
+                assert_eq!(actual.to_string(), user);
+                parsed += 1;
             }
+            assert_eq!(parsed, 3);
         }
 
-        debug_assert_eq!(out.len(), size);
+        #[test]
+        fn negative_utc() {
+            let parse = |line: &str| CommitUserInfo::try_parse_git_format(line.as_bytes()).unwrap();
 
-        let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
-        e.write_all(&out).map_err(Error::CompressWrite)?;
-        let compressed_data = e.finish().map_err(Error::Compress)?;
+            assert!(parse("me <me@example.com> 0 -0000").negative_utc);
+            assert!(!parse("me <me@example.com> 0 +0000").negative_utc);
+            assert!(!parse("me <me@example.com> 0 -0100").negative_utc);
 
-        original_out.extend_from_slice(&compressed_data);
+            // only applies to a zero offset
+            let user = CommitUserInfo {
+                negative_utc: true,
+                ..parse("me <me@example.com> 0 +0100")
+            };
+            assert_eq!(user.to_string(), "me <me@example.com> 0 +0100");
+        }
 
-        Ok(())
-    }
+        #[test]
+        fn parse() {
+            let actual = CommitUserInfo::try_parse_git_format(
+                "Jöhn Dœ Smith <john@example.com> 1688494158 -0530".as_bytes(),
+            )
+            .unwrap();
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self)))]
-    #[must_use]
-    pub fn uncompressed_size(&self) -> usize {
-        match self {
-            Self::Commit(commit) => commit.size(),
-            Self::Tree(items) => items.iter().map(TreeItem::size).sum(),
-            Self::Blob(data) => data.len(),
+            assert_eq!(
+                actual,
+                CommitUserInfo {
+                    name: "Jöhn Dœ Smith".into(),
+                    email: "john@example.com".into(),
+                    time: time::OffsetDateTime::from_unix_timestamp(1_688_494_158)
+                        .unwrap()
+                        .to_offset(time::UtcOffset::from_hms(-5, -30, 0).unwrap()),
+                    negative_utc: false,
+                }
+            );
         }
-    }
 
-    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
-    pub fn hash(&self) -> Result<HashOutput, Error> {
-        let size = self.uncompressed_size();
+        #[test]
+        fn new() {
+            let time = time::OffsetDateTime::from_unix_timestamp(1_688_494_158)
+                .unwrap()
+                .to_offset(time::UtcOffset::from_hms(5, 30, 0).unwrap());
 
-        let file_prefix = match self {
-            Self::Commit(_) => "commit",
-            Self::Tree(_) => "tree",
-            Self::Blob(_) => "blob",
-        };
+            let actual = CommitUserInfo::new("me", String::from("me@example.com"), time);
+            assert_eq!(actual.to_string(), "me <me@example.com> 1688494158 +0530");
+            assert_eq!(
+                actual,
+                CommitUserInfo::from_unix("me", "me@example.com", 1_688_494_158, 330).unwrap()
+            );
+
+            // the same instant in UTC is written with the same timestamp
+            let utc =
+                CommitUserInfo::new("me", "me@example.com", time.to_offset(time::UtcOffset::UTC));
+            assert_eq!(utc.to_string(), "me <me@example.com> 1688494158 +0000");
+        }
 
-        let size_len = itoa::Buffer::new().format(size).len();
+        #[test]
+        fn sub_second_truncated() {
+            let time =
+                time::OffsetDateTime::from_unix_timestamp_nanos(1_688_494_158_999_999_999).unwrap();
+            let actual = CommitUserInfo {
+                name: "me".into(),
+                email: "me@example.com".into(),
+                time,
+                negative_utc: false,
+            };
+            let expected = CommitUserInfo {
+                time: time.replace_nanosecond(0).unwrap(),
+                ..actual.clone()
+            };
 
-        let mut out =
-            BytesMut::with_capacity(file_prefix.len() + " ".len() + size_len + "\n".len() + size);
+            assert_eq!(actual.to_string(), "me <me@example.com> 1688494158 +0000");
+            assert_eq!(actual.to_string(), expected.to_string());
+            assert_eq!(actual.size(), expected.size());
+        }
 
-        write!(out, "{file_prefix} {size}\0")?;
-        match self {
-            Self::Commit(commit) => {
-                commit.encode_to(&mut out)?;
-            }
-            Self::Tree(items) => {
-                for item in items {
-                    item.encode_to(&mut out)?;
-                }
-            }
-            Self::Blob(blob) => {
-                out.extend_from_slice(blob);
-            }
+        #[test]
+        fn from_unix() {
+            let actual =
+                CommitUserInfo::from_unix("Jöhn Dœ Smith", "john@example.com", 1_688_494_158, -330)
+                    .unwrap();
+            let expected = "Jöhn Dœ Smith <john@example.com> 1688494158 -0530";
+            assert_eq!(actual.to_string(), expected);
+            assert_eq!(actual.size(), expected.len());
+
+            let actual = CommitUserInfo::from_unix("me", "me@example.com", 5, 60).unwrap();
+            assert_eq!(actual.to_string(), "me <me@example.com> 5 +0100");
+            assert_eq!(actual.size(), "me <me@example.com> 5 +0100".len());
+
+            assert!(CommitUserInfo::from_unix("me", "me@example.com", 0, 60 * 26).is_err());
+            assert!(CommitUserInfo::from_unix("me", "me@example.com", i64::MAX, 0).is_err());
         }
 
-        Ok(sha1::Sha1::digest(&out).into())
+        #[test]
+        fn parse_malformed() {
+            for line in [
+                "",
+                "no email 1688494158 +0000",
+                "name <email> 1688494158",
+                "name <email> abc +0000",
+                "name <email> 1688494158 0000",
+                "name <email> 1688494158 +00",
+                "name <email> 1688494158 +9900",
+                "name >email< 1688494158 +0000",
+            ] {
+                assert!(
+                    CommitUserInfo::try_parse_git_format(line.as_bytes()).is_err(),
+                    "{line} should be rejected"
+                );
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod test {
-    mod packfile {
-        use crate::low_level::{
-            Commit, CommitUserInfo, PackFile, PackFileEntry, TreeItem, TreeItemKind,
-        };
+    mod packfile_entry {
+        use crate::low_level::PackFileEntry;
         use bytes::{Bytes, BytesMut};
 
-        fn example() -> Bytes {
-            let blob = PackFileEntry::Blob(Bytes::from("hello world"));
+        #[test]
+        fn objects_referenced() {
+            use crate::low_level::{
+                Commit, CommitUserInfo, ObjectKind, Tag, TreeItem, TreeItemKind,
+            };
 
-            let tree = PackFileEntry::Tree(vec![TreeItem {
-                kind: TreeItemKind::File,
-                name: "helloworld.txt".into(),
-                hash: blob.hash().unwrap(),
-                sort_name: "helloworld.txt".to_string(),
-            }]);
+            let user = CommitUserInfo::from_unix("me", "me@example.com", 0, 0).unwrap();
 
             let commit = PackFileEntry::Commit(Commit {
-                tree: tree.hash().unwrap(),
-                author: CommitUserInfo {
-                    name: "example",
-                    email: "example@me.com",
-                    time: time::OffsetDateTime::UNIX_EPOCH,
-                },
-                committer: CommitUserInfo {
-                    name: "example",
-                    email: "example@me.com",
-                    time: time::OffsetDateTime::UNIX_EPOCH,
-                },
-                message: "initial commit",
+                tree: [1; 20],
+                parents: vec![[2; 20], [3; 20]],
+                author: user.clone(),
+                committer: user.clone(),
+                encoding: None,
+                extra_headers: Vec::new(),
+                message: "message".into(),
             });
+            assert_eq!(commit.objects_referenced(), [[1; 20], [2; 20], [3; 20]]);
 
-            let mut out = BytesMut::new();
+            let item = |kind, hash| TreeItem {
+                kind,
+                name: format!("{kind:o}").into(),
+                hash,
+            };
+            let tree = PackFileEntry::Tree(vec![
+                item(TreeItemKind::File, [4; 20]),
+                item(TreeItemKind::Directory, [5; 20]),
+                item(TreeItemKind::Submodule, [6; 20]),
+                item(TreeItemKind::Symlink, [7; 20]),
+            ]);
+            assert_eq!(tree.objects_referenced(), [[4; 20], [5; 20], [7; 20]]);
 
-            PackFile::new(&[blob, tree, commit])
-                .encode_to(&mut out)
-                .unwrap();
+            let tag = PackFileEntry::Tag(Tag {
+                object: [8; 20],
+                kind: ObjectKind::Commit,
+                name: "v1".into(),
+                tagger: user,
+                extra_headers: Vec::new(),
+                message: "message".into(),
+                signature: None,
+            });
+            assert_eq!(tag.objects_referenced(), [[8; 20]]);
 
-            out.freeze()
+            let blob = PackFileEntry::Blob(Bytes::from("hello"));
+            assert!(blob.objects_referenced().is_empty());
+            assert!(PackFileEntry::Tree(Vec::new())
+                .objects_referenced()
+                .is_empty());
         }
 
         #[test]
-        fn snapshot() {
-            let actual = example();
-            insta::assert_debug_snapshot!(actual);
+        fn blobs() {
+            let blobs = PackFileEntry::blobs((0..1000).map(|i: u32| i.to_string()));
+
+            assert_eq!(blobs.len(), 1000);
+            assert!(matches!(&blobs[999], PackFileEntry::Blob(data) if data == "999"));
+
+            let dir = tempfile::TempDir::new().unwrap();
+            let expected = crate::test::git(dir.path(), &["hash-object", "--stdin"], b"0");
+            assert_eq!(hex::encode(blobs[0].hash().unwrap()), expected.trim_end());
         }
 
         #[test]
-        fn is_readable_by_git() {
-            let stdout = crate::test::verify_pack_file(example());
+        #[cfg(feature = "memmap2")]
+        fn blob_mmap() {
+            let dir = tempfile::TempDir::new().unwrap();
 
-            insta::with_settings!({filters => vec![
-                (r"/(.*)/example.pack", "/path/to/example.pack")
-            ]}, {
-                insta::assert_snapshot!(stdout);
-            });
-        }
-    }
+            for (name, content) in [
+                ("empty", Vec::new()),
+                ("large", b"hello world ".repeat(100_000)),
+            ] {
+                let path = dir.path().join(name);
+                std::fs::write(&path, &content).unwrap();
 
-    mod packfile_entry {
-        use crate::low_level::PackFileEntry;
-        use bytes::{Bytes, BytesMut};
+                // SAFETY: nothing else touches the file while it's mapped
+                let mapped = unsafe { PackFileEntry::blob_mmap(&path) }.unwrap();
+                let in_memory = PackFileEntry::Blob(Bytes::from(content));
+                assert_eq!(mapped.uncompressed_size(), in_memory.uncompressed_size());
+                assert_eq!(mapped.hash().unwrap(), in_memory.hash().unwrap());
+
+                let expected = crate::test::git(
+                    dir.path(),
+                    &["hash-object", "--no-filters", path.to_str().unwrap()],
+                    &[],
+                );
+                assert_eq!(hex::encode(mapped.hash().unwrap()), expected.trim_end());
+            }
+
+            let missing = dir.path().join("missing");
+            // SAFETY: the file doesn't exist, so is never mapped
+            let err = unsafe { PackFileEntry::blob_mmap(&missing) }.unwrap_err();
+            assert!(matches!(&err, crate::Error::IoWithPath { path, .. } if *path == missing));
+        }
 
         #[test]
         fn header_size_bytes_large() {
@@ -430,6 +3888,7 @@ mod test {
             entry.write_header(&mut header);
 
             assert_eq!(header.to_vec(), &[0xb0, 0x01]);
+            assert_eq!(entry.header_size(), header.len());
         }
 
         #[test]
@@ -440,29 +3899,346 @@ mod test {
             entry.write_header(&mut header);
 
             assert_eq!(header.to_vec(), &[0xbf, 0x00]);
+            assert_eq!(entry.header_size(), header.len());
+        }
+
+        #[test]
+        fn recompress() {
+            use crate::low_level::read_entry_header;
+            use flate2::Compression;
+            use std::io::Read;
+
+            let entry = PackFileEntry::Blob(Bytes::from("hello world ".repeat(1000)));
+
+            let mut decoded = Vec::new();
+            for level in [Compression::none(), Compression::best()] {
+                let encoded = entry.recompress(level).unwrap();
+
+                let (kind, size, header_len) = read_entry_header(&encoded).unwrap();
+                assert_eq!(kind, entry.kind());
+                assert_eq!(size, 12000);
+
+                let mut body = Vec::new();
+                flate2::read::ZlibDecoder::new(&encoded[header_len..])
+                    .read_to_end(&mut body)
+                    .unwrap();
+                decoded.push((encoded.len(), body));
+            }
+
+            let [(uncompressed_len, uncompressed), (compressed_len, compressed)] =
+                decoded.try_into().unwrap();
+            assert_eq!(uncompressed, compressed);
+            assert_eq!(uncompressed, "hello world ".repeat(1000).as_bytes());
+            assert!(compressed_len < uncompressed_len);
         }
 
         mod commit {
-            use crate::low_level::{Commit, CommitUserInfo, PackFileEntry};
+            use crate::{
+                low_level::{Commit, CommitBuilder, CommitUserInfo, PackFileEntry},
+                Error,
+            };
             use bytes::BytesMut;
 
             fn example() -> PackFileEntry {
                 PackFileEntry::Commit(Commit {
                     tree: [0; 20],
+                    parents: Vec::new(),
                     author: CommitUserInfo {
-                        name: "author",
-                        email: "author@example.com",
+                        name: "author".into(),
+                        email: "author@example.com".into(),
                         time: time::OffsetDateTime::from_unix_timestamp(1_688_494_158).unwrap(),
+                        negative_utc: false,
                     },
                     committer: CommitUserInfo {
-                        name: "committer",
-                        email: "committer@example.com",
+                        name: "committer".into(),
+                        email: "committer@example.com".into(),
                         time: time::OffsetDateTime::from_unix_timestamp(1_687_494_158).unwrap(),
+                        negative_utc: false,
                     },
-                    message: "hello world!",
+                    encoding: None,
+                    extra_headers: Vec::new(),
+                    message: "hello world!".into(),
                 })
             }
 
+            fn encode_body(commit: &Commit) -> BytesMut {
+                let mut out = BytesMut::new();
+                commit.encode_to(&mut out).unwrap();
+                out
+            }
+
+            #[test]
+            fn parse_round_trip() {
+                let PackFileEntry::Commit(mut commit) = example() else {
+                    unreachable!()
+                };
+                commit.parents = vec![[1; 20], [2; 20]];
+                commit.message = "multi\nline\n\nmessage\n".into();
+
+                let encoded = encode_body(&commit);
+                assert_eq!(encoded.len(), commit.size());
+
+                let parsed = Commit::parse(&encoded).unwrap();
+                assert_eq!(parsed.parents, commit.parents);
+                assert_eq!(parsed.author, commit.author);
+                assert_eq!(parsed.message, commit.message);
+                assert_eq!(encode_body(&parsed), encoded);
+            }
+
+            #[test]
+            fn parse_from_git() {
+                use std::process::Command;
+
+                let dir = tempfile::TempDir::new().unwrap();
+                crate::test::git(dir.path(), &["init", "--bare", "--quiet"], &[]);
+
+                let commit_tree = |parent: Option<&str>| {
+                    let mut command = Command::new("git");
+                    command
+                        .current_dir(dir.path())
+                        .args(["commit-tree", "4b825dc642cb6eb9a060e54bf8d69288fbee4904"])
+                        .args(["-m", "subject", "-m", "body"])
+                        .env("GIT_AUTHOR_NAME", "Jöhn Dœ")
+                        .env("GIT_AUTHOR_EMAIL", "john@example.com")
+                        .env("GIT_AUTHOR_DATE", "1688494158 -0530")
+                        .env("GIT_COMMITTER_NAME", "committer")
+                        .env("GIT_COMMITTER_EMAIL", "committer@example.com")
+                        .env("GIT_COMMITTER_DATE", "1688494159 +0100");
+                    if let Some(parent) = parent {
+                        command.args(["-p", parent]);
+                    }
+
+                    let out = command.output().unwrap();
+                    assert!(out.status.success(), "git exited non-0");
+                    String::from_utf8(out.stdout).unwrap().trim().to_string()
+                };
+
+                crate::test::git(
+                    dir.path(),
+                    &["hash-object", "-t", "tree", "-w", "--stdin"],
+                    &[],
+                );
+                let parent = commit_tree(None);
+                let hash = commit_tree(Some(&parent));
+
+                let raw = crate::test::git(dir.path(), &["cat-file", "commit", &hash], &[]);
+                let commit = Commit::parse(raw.as_bytes()).unwrap();
+
+                assert_eq!(
+                    hex::encode(commit.tree),
+                    "4b825dc642cb6eb9a060e54bf8d69288fbee4904"
+                );
+                assert_eq!(
+                    commit.parents.iter().map(hex::encode).collect::<Vec<_>>(),
+                    [parent]
+                );
+                assert_eq!(
+                    commit.author.to_string(),
+                    "Jöhn Dœ <john@example.com> 1688494158 -0530"
+                );
+                assert_eq!(
+                    commit.committer.to_string(),
+                    "committer <committer@example.com> 1688494159 +0100"
+                );
+                assert_eq!(commit.message, "subject\n\nbody\n");
+
+                assert_eq!(encode_body(&commit), raw.as_bytes());
+                assert_eq!(
+                    hex::encode(PackFileEntry::Commit(commit).hash().unwrap()),
+                    hash
+                );
+            }
+
+            #[test]
+            fn timestamps_match_git() {
+                let dir = tempfile::TempDir::new().unwrap();
+                crate::test::git(dir.path(), &["init", "--bare", "--quiet"], &[]);
+
+                for (secs, offset, rendered) in [
+                    (-1, 0, "-1 +0000"),
+                    // 1969-07-20, in the timezone it was committed in
+                    (-14_182_940, -300, "-14182940 -0500"),
+                    // 2106-02-07, just past the end of an unsigned 32-bit timestamp
+                    (4_294_967_296, 60, "4294967296 +0100"),
+                ] {
+                    let user =
+                        CommitUserInfo::from_unix("a", "a@example.com", secs, offset).unwrap();
+                    assert_eq!(user.to_string(), format!("a <a@example.com> {rendered}"));
+                    assert_eq!(user.size(), user.to_string().len());
+                    assert_eq!(
+                        CommitUserInfo::try_parse_git_format(user.to_string().as_bytes()).unwrap(),
+                        user
+                    );
+
+                    let commit = Commit {
+                        tree: crate::low_level::EMPTY_TREE_HASH,
+                        parents: Vec::new(),
+                        author: user.clone(),
+                        committer: user,
+                        encoding: None,
+                        extra_headers: Vec::new(),
+                        message: "message\n".into(),
+                    };
+                    let encoded = encode_body(&commit);
+                    assert_eq!(encoded.len(), commit.size());
+
+                    let expected = crate::test::git(
+                        dir.path(),
+                        &["hash-object", "-t", "commit", "--stdin"],
+                        &encoded,
+                    );
+                    assert_eq!(
+                        hex::encode(PackFileEntry::Commit(commit).hash().unwrap()),
+                        expected.trim(),
+                        "{rendered}"
+                    );
+                }
+            }
+
+            #[test]
+            fn parse_tolerates_unknown_headers() {
+                let raw = b"tree 0000000000000000000000000000000000000000\n\
+                    author a <a@example.com> 0 +0000\n\
+                    committer c <c@example.com> 0 +0000\n\
+                    encoding ISO-8859-1\n\
+                    gpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n\
+                    \n\
+                    message";
+
+                let commit = Commit::parse(raw).unwrap();
+                assert_eq!(commit.author.to_string(), "a <a@example.com> 0 +0000");
+                assert_eq!(commit.committer.to_string(), "c <c@example.com> 0 +0000");
+                assert_eq!(commit.message, "message");
+                assert_eq!(commit.encoding.as_deref(), Some("ISO-8859-1"));
+                assert_eq!(
+                    commit.extra_headers,
+                    [(
+                        "gpgsig".into(),
+                        "-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----".into()
+                    )]
+                );
+                assert_eq!(encode_body(&commit), &raw[..]);
+            }
+
+            #[test]
+            fn latin1_message() {
+                let user =
+                    CommitUserInfo::from_unix("a", "a@example.com", 1_700_000_000, 60).unwrap();
+                let builder =
+                    CommitBuilder::new(crate::low_level::EMPTY_TREE_HASH, user.clone(), user)
+                        .with_message(&b"caf\xe9\n"[..]);
+
+                assert!(matches!(
+                    builder.clone().build(),
+                    Err(Error::NonUtf8MessageWithoutEncoding(_))
+                ));
+
+                let commit = builder.with_encoding("ISO-8859-1").build().unwrap();
+                let encoded = encode_body(&commit);
+                assert_eq!(
+                    encoded,
+                    &b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                       author a <a@example.com> 1700000000 +0100\n\
+                       committer a <a@example.com> 1700000000 +0100\n\
+                       encoding ISO-8859-1\n\
+                       \n\
+                       caf\xe9\n"[..]
+                );
+                assert_eq!(encoded.len(), commit.size());
+
+                let parsed = Commit::parse(&encoded).unwrap();
+                assert_eq!(parsed.encoding.as_deref(), Some("ISO-8859-1"));
+                assert!(parsed.extra_headers.is_empty());
+
+                // git re-encodes the message to UTF-8 when showing it
+                let dir = tempfile::TempDir::new().unwrap();
+                crate::test::git(dir.path(), &["init", "--bare", "--quiet"], &[]);
+                crate::test::git(
+                    dir.path(),
+                    &["hash-object", "-t", "tree", "-w", "--stdin"],
+                    &[],
+                );
+                let hash = crate::test::git(
+                    dir.path(),
+                    &["hash-object", "-t", "commit", "-w", "--stdin"],
+                    &encoded,
+                );
+                assert_eq!(
+                    hex::encode(PackFileEntry::Commit(commit).hash().unwrap()),
+                    hash.trim()
+                );
+                assert_eq!(
+                    crate::test::git(dir.path(), &["log", "-1", "--format=%s", hash.trim()], &[]),
+                    "café\n"
+                );
+            }
+
+            #[test]
+            fn extra_headers_round_trip() {
+                let raw = b"tree 4b825dc642cb6eb9a060e54bf8d69288fbee4904\n\
+                    parent 0101010101010101010101010101010101010101\n\
+                    parent 0202020202020202020202020202020202020202\n\
+                    author a <a@example.com> 1700000000 +0100\n\
+                    committer c <c@example.com> 1700000000 -0230\n\
+                    mergetag object 0202020202020202020202020202020202020202\n \
+                    type commit\n \
+                    tag v1.0\n \
+                    tagger t <t@example.com> 1600000000 +0000\n \
+                    \n \
+                    release v1.0\n\
+                    HG:extra rebase_source:abc\n\
+                    gpgsig -----BEGIN PGP SIGNATURE-----\n \n abc\n -----END PGP SIGNATURE-----\n\
+                    \n\
+                    Merge tag 'v1.0'\n";
+
+                let commit = Commit::parse(raw).unwrap();
+                assert_eq!(
+                    commit
+                        .extra_headers
+                        .iter()
+                        .map(|(key, _)| key.as_ref())
+                        .collect::<Vec<_>>(),
+                    [&b"mergetag"[..], b"HG:extra", b"gpgsig"]
+                );
+                assert_eq!(
+                    commit.extra_headers[0].1,
+                    "object 0202020202020202020202020202020202020202\ntype commit\n\
+                     tag v1.0\ntagger t <t@example.com> 1600000000 +0000\n\nrelease v1.0"
+                );
+
+                let encoded = encode_body(&commit);
+                assert_eq!(encoded, &raw[..]);
+                assert_eq!(commit.size(), raw.len());
+
+                let dir = tempfile::TempDir::new().unwrap();
+                crate::test::git(dir.path(), &["init", "--bare", "--quiet"], &[]);
+                let expected =
+                    crate::test::git(dir.path(), &["hash-object", "-t", "commit", "--stdin"], raw);
+                assert_eq!(
+                    hex::encode(PackFileEntry::Commit(commit).hash().unwrap()),
+                    expected.trim()
+                );
+            }
+
+            #[test]
+            fn parse_malformed() {
+                for raw in [
+                    &b""[..],
+                    b"tree 00\nauthor a <a> 0 +0000\ncommitter c <c> 0 +0000\n\n",
+                    b"author a <a> 0 +0000\ncommitter c <c> 0 +0000\n\n",
+                    b"tree 0000000000000000000000000000000000000000\ncommitter c <c> 0 +0000\n\n",
+                    b"tree 0000000000000000000000000000000000000000\nauthor a <a> 0 +0000\n\n",
+                    b"tree 0000000000000000000000000000000000000000\nauthor a\ncommitter c <c> 0 +0000\n\n",
+                    b"tree 0000000000000000000000000000000000000000\ntree 0000000000000000000000000000000000000000\nauthor a <a> 0 +0000\ncommitter c <c> 0 +0000\n\n",
+                ] {
+                    assert!(
+                        Commit::parse(raw).is_err(),
+                        "{} should be rejected",
+                        String::from_utf8_lossy(raw)
+                    );
+                }
+            }
+
             #[test]
             fn hash() {
                 let commit = example();
@@ -504,8 +4280,140 @@ mod test {
             }
         }
 
+        mod tag {
+            use crate::{
+                low_level::{CommitUserInfo, ObjectKind, PackFileEntry, Tag},
+                Error,
+            };
+            use bytes::{Bytes, BytesMut};
+
+            const SIGNATURE: &str =
+                "-----BEGIN PGP SIGNATURE-----\n\nabc\n-----END PGP SIGNATURE-----\n";
+
+            fn example() -> Tag {
+                Tag {
+                    object: [0; 20],
+                    kind: ObjectKind::Commit,
+                    name: "v1.0.0".into(),
+                    tagger: CommitUserInfo::from_unix(
+                        "tagger",
+                        "tagger@example.com",
+                        1_688_494_158,
+                        -330,
+                    )
+                    .unwrap(),
+                    extra_headers: Vec::new(),
+                    message: "release\n\n-----BEGIN notes-----\n".into(),
+                    signature: Some(Bytes::from_static(SIGNATURE.as_bytes())),
+                }
+            }
+
+            fn encode_body(tag: &Tag) -> BytesMut {
+                let mut out = BytesMut::new();
+                tag.encode_to(&mut out).unwrap();
+                out
+            }
+
+            #[test]
+            fn parse_round_trip() {
+                for signature in [None, Some(Bytes::from_static(SIGNATURE.as_bytes()))] {
+                    let tag = Tag {
+                        signature,
+                        ..example()
+                    };
+
+                    let encoded = encode_body(&tag);
+                    assert_eq!(encoded.len(), tag.size());
+
+                    let parsed = Tag::parse(&encoded).unwrap();
+                    assert_eq!(parsed.object, tag.object);
+                    assert_eq!(parsed.kind, tag.kind);
+                    assert_eq!(parsed.name, tag.name);
+                    assert_eq!(parsed.tagger, tag.tagger);
+                    assert_eq!(parsed.message, tag.message);
+                    assert_eq!(parsed.signature, tag.signature);
+                    assert_eq!(encode_body(&parsed), encoded);
+                }
+            }
+
+            #[test]
+            fn extra_headers_round_trip() {
+                let raw = b"object 0000000000000000000000000000000000000000\n\
+                    type commit\n\
+                    tag v1\n\
+                    tagger t <t> 0 +0000\n\
+                    x-custom one\n\
+                    x-multi first\n second\n\
+                    \n\
+                    release\n";
+
+                let parsed = Tag::parse(raw).unwrap();
+                assert_eq!(
+                    parsed.extra_headers,
+                    [
+                        (Bytes::from("x-custom"), Bytes::from("one")),
+                        (Bytes::from("x-multi"), Bytes::from("first\nsecond")),
+                    ]
+                );
+                assert_eq!(parsed.size(), raw.len());
+                assert_eq!(&encode_body(&parsed)[..], raw);
+            }
+
+            #[test]
+            fn matches_git() {
+                let dir = tempfile::TempDir::new().unwrap();
+                crate::test::git(dir.path(), &["init", "--bare", "--quiet"], &[]);
+                crate::test::git(
+                    dir.path(),
+                    &["hash-object", "-t", "tree", "-w", "--stdin"],
+                    &[],
+                );
+
+                let tag = Tag {
+                    object: crate::decode_hash("4b825dc642cb6eb9a060e54bf8d69288fbee4904").unwrap(),
+                    kind: ObjectKind::Tree,
+                    ..example()
+                };
+                let encoded = encode_body(&tag);
+
+                let hash = crate::test::git(dir.path(), &["mktag"], &encoded);
+                assert_eq!(
+                    hex::encode(PackFileEntry::Tag(tag).hash().unwrap()),
+                    hash.trim()
+                );
+
+                let raw = crate::test::git(dir.path(), &["cat-file", "tag", hash.trim()], &[]);
+                assert_eq!(raw.as_bytes(), encoded);
+                assert_eq!(
+                    Tag::parse(raw.as_bytes()).unwrap().signature.as_deref(),
+                    Some(SIGNATURE.as_bytes())
+                );
+            }
+
+            #[test]
+            fn parse_malformed() {
+                for raw in [
+                    &b"type commit\ntag v1\ntagger t <t> 0 +0000\n\n"[..],
+                    b"object 00\ntype commit\ntag v1\ntagger t <t> 0 +0000\n\n",
+                    b"object 0000000000000000000000000000000000000000\ntype ref-delta\ntag v1\ntagger t <t> 0 +0000\n\n",
+                    b"object 0000000000000000000000000000000000000000\ntype commit\ntagger t <t> 0 +0000\n\n",
+                    b"object 0000000000000000000000000000000000000000\ntype commit\ntag v1\n\n",
+                    b"object 0000000000000000000000000000000000000000\ntype commit\ntype commit\ntag v1\ntagger t <t> 0 +0000\n\n",
+                ] {
+                    assert!(
+                        matches!(Tag::parse(raw), Err(Error::MalformedTag(_))),
+                        "{} should be rejected",
+                        String::from_utf8_lossy(raw)
+                    );
+                }
+            }
+        }
+
         mod tree {
-            use crate::low_level::{PackFileEntry, TreeItem, TreeItemKind};
+            use crate::{
+                low_level::{check_hash_width, parse_tree, PackFileEntry, TreeItem, TreeItemKind},
+                Error,
+            };
             use bytes::BytesMut;
 
             fn example() -> PackFileEntry {
@@ -513,7 +4421,6 @@ mod test {
                     kind: TreeItemKind::File,
                     name: "hello".into(),
                     hash: [0u8; 20],
-                    sort_name: "/hello".to_string(),
                 }])
             }
 
@@ -556,6 +4463,301 @@ mod test {
 
                 insta::assert_debug_snapshot!(actual);
             }
+
+            #[test]
+            fn duplicate_entries() {
+                let item = |kind, name: &'static str| TreeItem {
+                    kind,
+                    name: name.into(),
+                    hash: [0; 20],
+                };
+
+                let tree = PackFileEntry::Tree(vec![
+                    item(TreeItemKind::File, "foo"),
+                    item(TreeItemKind::Directory, "foo"),
+                ]);
+                assert!(matches!(
+                    tree.hash(),
+                    Err(Error::DuplicateTreeEntry(name)) if name == "foo"
+                ));
+                assert!(matches!(
+                    tree.encode_to(&mut BytesMut::new()),
+                    Err(Error::DuplicateTreeEntry(name)) if name == "foo"
+                ));
+
+                let tree = PackFileEntry::Tree(vec![
+                    item(TreeItemKind::File, "foo"),
+                    item(TreeItemKind::File, "foo.txt"),
+                ]);
+                assert!(tree.hash().is_ok());
+                assert!(tree.encode_to(&mut BytesMut::new()).is_ok());
+            }
+
+            #[test]
+            fn hash_width() {
+                assert!(check_hash_width(&[0; 20]).is_ok());
+                assert!(matches!(
+                    check_hash_width(&[0; 32]),
+                    Err(Error::HashWidthMismatch {
+                        expected: 20,
+                        actual: 32
+                    })
+                ));
+            }
+
+            #[test]
+            fn duplicate_entries_apart_in_tree_order() {
+                let item = |kind, name: &'static str| TreeItem {
+                    kind,
+                    name: name.into(),
+                    hash: [0; 20],
+                };
+
+                // a directory sorts as if it ended in `/`, so `foo.bar` lands between
+                // the file and the directory of the same name
+                let tree = PackFileEntry::Tree(vec![
+                    item(TreeItemKind::File, "foo"),
+                    item(TreeItemKind::File, "foo.bar"),
+                    item(TreeItemKind::Directory, "foo"),
+                ]);
+                assert!(matches!(
+                    tree.hash(),
+                    Err(Error::DuplicateTreeEntry(name)) if name == "foo"
+                ));
+                assert!(matches!(
+                    tree.encode_to(&mut BytesMut::new()),
+                    Err(Error::DuplicateTreeEntry(name)) if name == "foo"
+                ));
+            }
+
+            #[test]
+            fn parse_round_trip() {
+                let items = [
+                    (TreeItemKind::File, "file.txt"),
+                    (TreeItemKind::Executable, "run.sh"),
+                    (TreeItemKind::Symlink, "link"),
+                    (TreeItemKind::Directory, "dir"),
+                    (TreeItemKind::Submodule, "sub module"),
+                ];
+                let tree = PackFileEntry::Tree(
+                    items
+                        .iter()
+                        .zip(1_u8..)
+                        .map(|(&(kind, name), i)| TreeItem {
+                            kind,
+                            name: name.into(),
+                            hash: [i; 20],
+                        })
+                        .collect(),
+                );
+
+                let mut body = BytesMut::new();
+                tree.encode_body(&mut body).unwrap();
+
+                let parsed = parse_tree(&body).unwrap();
+                assert_eq!(parsed.len(), items.len());
+                for ((item, &(kind, name)), i) in parsed.iter().zip(&items).zip(1_u8..) {
+                    assert_eq!(item.kind, kind);
+                    assert_eq!(&*item.name, name.as_bytes());
+                    assert_eq!(item.hash, [i; 20]);
+                }
+            }
+
+            #[test]
+            fn parse_from_git() {
+                let repo = tempfile::TempDir::new().unwrap();
+                crate::test::git(repo.path(), &["init", "--bare", "--quiet"], &[]);
+
+                let listing = [
+                    "100644 blob 0101010101010101010101010101010101010101\ta.txt",
+                    "100755 blob 0202020202020202020202020202020202020202\tb c.sh",
+                    "120000 blob 0303030303030303030303030303030303030303\tlink",
+                    "040000 tree 0404040404040404040404040404040404040404\tnested",
+                    "160000 commit 0505050505050505050505050505050505050505\tsub",
+                ]
+                .join("\n");
+                let hash =
+                    crate::test::git(repo.path(), &["mktree", "--missing"], listing.as_bytes());
+                let hash = hash.trim();
+                let raw = crate::test::git_raw(repo.path(), &["cat-file", "tree", hash], &[]);
+
+                let parsed = parse_tree(&raw).unwrap();
+                let kinds: Vec<_> = parsed.iter().map(|item| item.kind).collect();
+                assert_eq!(
+                    kinds,
+                    [
+                        TreeItemKind::File,
+                        TreeItemKind::Executable,
+                        TreeItemKind::Symlink,
+                        TreeItemKind::Directory,
+                        TreeItemKind::Submodule,
+                    ]
+                );
+                assert_eq!(&*parsed[1].name, b"b c.sh");
+                assert_eq!(parsed[4].hash, [5; 20]);
+
+                // re-encoding the parsed tree must give back the same object
+                let tree = PackFileEntry::Tree(parsed);
+                assert_eq!(hex::encode(tree.hash().unwrap()), hash);
+            }
+
+            #[test]
+            fn tree_order() {
+                let item = |kind, name: &'static str| TreeItem {
+                    kind,
+                    name: name.into(),
+                    hash: [1; 20],
+                };
+
+                let mut items = vec![
+                    item(TreeItemKind::File, "foo0"),
+                    item(TreeItemKind::Directory, "foo"),
+                    item(TreeItemKind::File, "foo.bar"),
+                    item(TreeItemKind::Submodule, "foo-bar"),
+                    item(TreeItemKind::Directory, "fo"),
+                ];
+                items.sort_unstable_by(TreeItem::cmp_tree_order);
+
+                let names: Vec<_> = items.iter().map(|item| item.name.to_str_lossy()).collect();
+                assert_eq!(names, ["fo", "foo-bar", "foo.bar", "foo", "foo0"]);
+
+                assert!(item(TreeItemKind::File, "foo")
+                    .cmp_tree_order(&item(TreeItemKind::File, "foo.bar"))
+                    .is_lt());
+                assert!(item(TreeItemKind::File, "foo")
+                    .cmp_tree_order(&item(TreeItemKind::File, "foo"))
+                    .is_eq());
+
+                // git sorts the entries given to mktree itself, so the hashes only
+                // match if the order does
+                let repo = tempfile::TempDir::new().unwrap();
+                crate::test::git(repo.path(), &["init", "--bare", "--quiet"], &[]);
+                let listing = [
+                    "040000 tree 0101010101010101010101010101010101010101\tfoo",
+                    "100644 blob 0101010101010101010101010101010101010101\tfoo0",
+                    "100644 blob 0101010101010101010101010101010101010101\tfoo.bar",
+                    "160000 commit 0101010101010101010101010101010101010101\tfoo-bar",
+                    "040000 tree 0101010101010101010101010101010101010101\tfo",
+                ]
+                .join("\n");
+                let hash =
+                    crate::test::git(repo.path(), &["mktree", "--missing"], listing.as_bytes());
+                assert_eq!(
+                    hex::encode(PackFileEntry::Tree(items).hash().unwrap()),
+                    hash.trim()
+                );
+            }
+
+            #[test]
+            fn parse_malformed() {
+                let mut body = BytesMut::new();
+                example().encode_body(&mut body).unwrap();
+                body.extend_from_slice(&body.clone());
+
+                for (data, expected_offset) in [
+                    (&body[..body.len() - 1], 33),
+                    (&body[..36], 33),
+                    (&b"100644 no-nul"[..], 0),
+                    (b"100644", 0),
+                ] {
+                    match parse_tree(data) {
+                        Err(Error::MalformedTree { offset, .. }) => {
+                            assert_eq!(offset, expected_offset);
+                        }
+                        other => panic!("expected MalformedTree, got {other:?}"),
+                    }
+                }
+
+                let mut unknown = b"100645 a\0".to_vec();
+                unknown.extend_from_slice(&[0; 20]);
+                assert!(matches!(
+                    parse_tree(&unknown),
+                    Err(Error::UnknownTreeKind(mode)) if mode == "100645"
+                ));
+
+                let mut non_octal = b"10a644 a\0".to_vec();
+                non_octal.extend_from_slice(&[0; 20]);
+                assert!(matches!(
+                    parse_tree(&non_octal),
+                    Err(Error::UnknownTreeKind(mode)) if mode == "10a644"
+                ));
+            }
+        }
+
+        mod raw {
+            use crate::{
+                high_level::GitRepository,
+                low_level::{ObjectKind, PackFile, PackFileEntry},
+                Error,
+            };
+            use bytes::{Bytes, BytesMut};
+
+            #[test]
+            fn round_trip_from_git() {
+                let mut repo = GitRepository::default();
+                repo.insert(&[], "a.txt", Bytes::from("a")).unwrap();
+                let (commit, entries) = repo.commit("me", "me@example.com", "initial").unwrap();
+
+                let mut pack = BytesMut::new();
+                PackFile::new(&entries).encode_to(&mut pack).unwrap();
+                let dir = crate::test::bare_repo_with_pack(&pack);
+
+                let commit = hex::encode(commit);
+                let tag = crate::test::git(
+                    dir.path(),
+                    &["mktag"],
+                    format!(
+                        "object {commit}\ntype commit\ntag v1\ntagger me <me@example.com> 0 +0000\n\nv1\n"
+                    )
+                    .as_bytes(),
+                );
+                let tag = tag.trim();
+
+                let raw = [
+                    (ObjectKind::Commit, "commit", commit.as_str()),
+                    (ObjectKind::Tag, "tag", tag),
+                ]
+                .map(|(kind, name, hash)| {
+                    let data = crate::test::git_raw(dir.path(), &["cat-file", name, hash], &[]);
+                    PackFileEntry::Raw {
+                        kind,
+                        data: Bytes::from(data),
+                    }
+                });
+
+                assert_eq!(hex::encode(raw[0].hash().unwrap()), commit);
+                assert_eq!(hex::encode(raw[1].hash().unwrap()), tag);
+
+                // the raw objects can be read back by git from a pack of their own
+                let mut pack = BytesMut::new();
+                PackFile::new(&raw).encode_to(&mut pack).unwrap();
+                let dir = crate::test::bare_repo_with_pack(&pack);
+                assert_eq!(
+                    crate::test::git(dir.path(), &["cat-file", "-t", tag], &[]),
+                    "tag\n"
+                );
+                assert_eq!(
+                    crate::test::git(dir.path(), &["cat-file", "-t", &commit], &[]),
+                    "commit\n"
+                );
+            }
+
+            #[test]
+            fn delta_kind() {
+                let entry = PackFileEntry::Raw {
+                    kind: ObjectKind::RefDelta,
+                    data: Bytes::from("abc"),
+                };
+
+                assert!(matches!(
+                    entry.hash(),
+                    Err(Error::InvalidRawKind(ObjectKind::RefDelta))
+                ));
+                assert!(matches!(
+                    entry.encode_to(&mut BytesMut::new()),
+                    Err(Error::InvalidRawKind(ObjectKind::RefDelta))
+                ));
+            }
         }
 
         mod blob {
@@ -575,6 +4777,24 @@ mod test {
                 assert_eq!(actual, expected);
             }
 
+            #[test]
+            fn large_hash_is_streamed() {
+                use sha1::Digest;
+
+                let data = Bytes::from(vec![b'a'; 10_000_000]);
+                let blob = PackFileEntry::Blob(data.clone());
+
+                let mut actual = [0; 20];
+                let allocations = crate::test::count_allocations(|| {
+                    actual = blob.hash().unwrap();
+                });
+                assert_eq!(allocations, 0);
+
+                let mut expected = b"blob 10000000\0".to_vec();
+                expected.extend_from_slice(&data);
+                assert_eq!(actual, <[u8; 20]>::from(sha1::Sha1::digest(&expected)));
+            }
+
             #[test]
             fn uncompressed_size() {
                 let commit = example();
@@ -1,24 +1,54 @@
-use crate::{low_level::PackFile, Error};
-use bytes::{BufMut, BytesMut};
-use std::fmt::Write;
+use crate::{
+    capabilities::{CapabilitySet, Sideband},
+    low_level::{HashOutput, PackFile},
+    Error,
+};
+use bytes::{BufMut, Bytes, BytesMut};
+use indexmap::IndexMap;
+use std::{fmt::Write, ops::RangeInclusive};
 
 /// The maximum length of a pkt-line's data component is 65516 bytes.
 /// Implementations MUST NOT send pkt-line whose length exceeds 65520
 /// (65516 bytes of payload + 4 bytes of length data).
 ///
 /// <https://git-scm.com/docs/protocol-common#_pkt_line_format>
-const MAX_DATA_LEN: usize = 65516;
+pub(crate) const MAX_DATA_LEN: usize = 65516;
+
+/// The side-band channel carrying packfile data.
+///
+/// <https://git-scm.com/docs/protocol-capabilities#_side_band_side_band_64k>
+pub const SIDEBAND_DATA: u8 = 1;
+/// The side-band channel carrying progress messages, which clients print to stderr.
+pub const SIDEBAND_PROGRESS: u8 = 2;
+/// The side-band channel carrying a fatal error message, after which the client
+/// aborts the transfer.
+pub const SIDEBAND_ERROR: u8 = 3;
+
+/// The lengths a data packet's prefix can hold, including the 4 bytes of the prefix
+/// itself. Lengths below this range are the special packets.
+const ALLOWED_PACKET_LENGTH: RangeInclusive<usize> = 4..=MAX_DATA_LEN + 4;
 
 /// A wrapper containing every possible type of message that can be sent to a Git client.
 pub enum PktLine<'a> {
     /// General data sent to a client, generally a UTF-8 encoded string.
     Data(&'a [u8]),
+    /// The same as `Data`, but owning its payload.
+    OwnedData(Bytes),
     /// Similar to a data packet, but used during packfile sending to indicate this
     /// packet is a block of data by appending a byte containing the u8 `1`.
     SidebandData(PackFile<'a>),
+    /// The same as `SidebandData`, but for a packfile that has already been encoded,
+    /// such as one that was cached or built elsewhere, which is framed identically.
+    /// See [`PktLine::sideband_chunks`] for splitting an encoded packfile up so other
+    /// packets can be sent in between the data.
+    SidebandRaw(Bytes),
     /// Similar to a data packet, but used during packfile sending to indicate this
     /// packet is a status message by appending a byte containing the u8 `2`.
     SidebandMsg(&'a [u8]),
+    /// Similar to a data packet, but used during packfile sending to report a fatal
+    /// error by appending a byte containing the u8 `3`. The client prints the message
+    /// and aborts the transfer, so nothing should be sent after it.
+    SidebandErr(&'a [u8]),
     /// Indicates the end of a response.
     Flush,
     /// Separates sections of a response.
@@ -27,16 +57,339 @@ pub enum PktLine<'a> {
     ResponseEnd,
 }
 
+/// The same as [`PktLine`], but owning all of its payloads so it can be held across
+/// await points or sent between tasks, such as through a channel into a sink.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PktLineOwned {
+    /// See [`PktLine::Data`].
+    Data(Bytes),
+    /// An encoded packfile sent on side-band channel 1, see [`PktLine::SidebandRaw`].
+    SidebandData(Bytes),
+    /// See [`PktLine::SidebandMsg`].
+    SidebandMsg(Bytes),
+    /// See [`PktLine::SidebandErr`].
+    SidebandErr(Bytes),
+    /// Indicates the end of a response.
+    Flush,
+    /// Separates sections of a response.
+    Delimiter,
+    /// Indicates the end of the response, allowing the client to send another request.
+    ResponseEnd,
+}
+
+impl PktLineOwned {
+    /// Borrows the pkt-line as a [`PktLine`], which is how it's encoded.
+    #[must_use]
+    pub fn as_pkt_line(&self) -> PktLine<'_> {
+        match self {
+            Self::Data(data) => PktLine::Data(data),
+            Self::SidebandData(data) => PktLine::SidebandRaw(data.clone()),
+            Self::SidebandMsg(msg) => PktLine::SidebandMsg(msg),
+            Self::SidebandErr(msg) => PktLine::SidebandErr(msg),
+            Self::Flush => PktLine::Flush,
+            Self::Delimiter => PktLine::Delimiter,
+            Self::ResponseEnd => PktLine::ResponseEnd,
+        }
+    }
+
+    /// Encodes the pkt-line into `buf`, as [`PktLine::encode_to`] does, returning the
+    /// number of bytes appended.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packet fails to serialise.
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<usize, Error> {
+        self.as_pkt_line().encode_to(buf)
+    }
+
+    /// Encodes the pkt-line into `buf`, as [`PktLine::encode_to_with_sideband`] does,
+    /// returning the number of bytes appended.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packet fails to serialise.
+    pub fn encode_to_with_sideband(
+        &self,
+        buf: &mut BytesMut,
+        sideband: Sideband,
+    ) -> Result<usize, Error> {
+        self.as_pkt_line().encode_to_with_sideband(buf, sideband)
+    }
+}
+
+/// Copies the payload of a [`PktLine`]. A [`PktLine::SidebandData`] packfile is
+/// encoded to do so, which can fail.
+impl TryFrom<PktLine<'_>> for PktLineOwned {
+    type Error = Error;
+
+    fn try_from(line: PktLine<'_>) -> Result<Self, Self::Error> {
+        Ok(match line {
+            PktLine::Data(data) => Self::Data(Bytes::copy_from_slice(data)),
+            PktLine::OwnedData(data) => Self::Data(data),
+            PktLine::SidebandData(packfile) => {
+                let mut data = BytesMut::new();
+                packfile.encode_to(&mut data)?;
+                Self::SidebandData(data.freeze())
+            }
+            PktLine::SidebandRaw(data) => Self::SidebandData(data),
+            PktLine::SidebandMsg(msg) => Self::SidebandMsg(Bytes::copy_from_slice(msg)),
+            PktLine::SidebandErr(msg) => Self::SidebandErr(Bytes::copy_from_slice(msg)),
+            PktLine::Flush => Self::Flush,
+            PktLine::Delimiter => Self::Delimiter,
+            PktLine::ResponseEnd => Self::ResponseEnd,
+        })
+    }
+}
+
+/// A single pkt-line read back out of a buffer by [`PktLine::parse`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parsed<'a> {
+    /// The payload of a data packet, without its length prefix. Any trailing newline
+    /// is left in place.
+    Data(&'a [u8]),
+    /// A `0000` flush packet.
+    Flush,
+    /// A `0001` delimiter packet.
+    Delimiter,
+    /// A `0002` response end packet.
+    ResponseEnd,
+    /// The buffer ends part way through the packet, at least `needed` more bytes
+    /// are required before it can be parsed.
+    Incomplete { needed: usize },
+}
+
+/// A single pkt-line yielded by [`PktLineIter`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Frame<'a> {
+    /// The payload of a data packet, without its length prefix. Any trailing newline
+    /// is left in place.
+    Data(&'a [u8]),
+    /// A `0000` flush packet.
+    Flush,
+    /// A `0001` delimiter packet.
+    Delimiter,
+    /// A `0002` response end packet.
+    ResponseEnd,
+}
+
+/// Iterates over every pkt-line in a buffer, such as protocol traffic captured from
+/// a client or server. Unlike `codec::GitCodec` this doesn't require the
+/// `tokio-util` feature, and yields each packet as-is rather than grouping them
+/// into commands.
+///
+/// Iteration stops after the first error, which is
+/// [`Error::TruncatedPktLine`] if the buffer ends part way through a packet.
+pub struct PktLineIter<'a> {
+    rest: &'a [u8],
+}
+
+impl<'a> PktLineIter<'a> {
+    #[must_use]
+    pub fn new(input: &'a [u8]) -> Self {
+        Self { rest: input }
+    }
+
+    /// The bytes that haven't been iterated over yet.
+    #[must_use]
+    pub fn remaining(&self) -> &'a [u8] {
+        self.rest
+    }
+}
+
+impl<'a> Iterator for PktLineIter<'a> {
+    type Item = Result<Frame<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+
+        let (frame, consumed) = match PktLine::parse(self.rest) {
+            Ok((Parsed::Data(data), consumed)) => (Frame::Data(data), consumed),
+            Ok((Parsed::Flush, consumed)) => (Frame::Flush, consumed),
+            Ok((Parsed::Delimiter, consumed)) => (Frame::Delimiter, consumed),
+            Ok((Parsed::ResponseEnd, consumed)) => (Frame::ResponseEnd, consumed),
+            Ok((Parsed::Incomplete { .. }, _)) => {
+                let remaining = self.rest.len();
+                self.rest = &[];
+                return Some(Err(Error::TruncatedPktLine(remaining)));
+            }
+            Err(e) => {
+                self.rest = &[];
+                return Some(Err(e));
+            }
+        };
+
+        self.rest = &self.rest[consumed..];
+        Some(Ok(frame))
+    }
+}
+
+impl std::iter::FusedIterator for PktLineIter<'_> {}
+
 impl PktLine<'_> {
+    /// Parses the pkt-line at the start of `input`, returning it along with the number
+    /// of bytes it took up, which is `0` if the packet is [`Parsed::Incomplete`].
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidLengthPrefix`] if the length prefix isn't 4 hex digits,
+    /// [`Error::PacketLengthTooSmall`] if it's the reserved length `0003`, or
+    /// [`Error::PacketLengthExceedsSpec`] if it's longer than a packet can be.
+    pub fn parse(input: &[u8]) -> Result<(Parsed<'_>, usize), Error> {
+        let Some(prefix) = input.get(..4) else {
+            return Ok((
+                Parsed::Incomplete {
+                    needed: 4 - input.len(),
+                },
+                0,
+            ));
+        };
+
+        let mut length_bytes = [0_u8; 4];
+        length_bytes.copy_from_slice(prefix);
+
+        let length = length_bytes.iter().try_fold(0_usize, |acc, &c| {
+            let digit = char::from(c)
+                .to_digit(16)
+                .ok_or(Error::InvalidLengthPrefix(length_bytes))?;
+            Ok::<_, Error>(acc << 4 | digit as usize)
+        })?;
+
+        match length {
+            0 => return Ok((Parsed::Flush, 4)),
+            1 => return Ok((Parsed::Delimiter, 4)),
+            2 => return Ok((Parsed::ResponseEnd, 4)),
+            // a data packet's length includes the 4 byte prefix, so 3 can't be one
+            3 => return Err(Error::PacketLengthTooSmall(length)),
+            _ if !ALLOWED_PACKET_LENGTH.contains(&length) => {
+                return Err(Error::PacketLengthExceedsSpec(
+                    ALLOWED_PACKET_LENGTH,
+                    length,
+                ));
+            }
+            _ => {}
+        }
+
+        match input.get(4..length) {
+            Some(data) => Ok((Parsed::Data(data), length)),
+            None => Ok((
+                Parsed::Incomplete {
+                    needed: length - input.len(),
+                },
+                0,
+            )),
+        }
+    }
+
+    /// Reads the pkt-line at the start of `src` as [`PktLine::parse`] does, copying
+    /// any data out into a [`PktLine::OwnedData`]. The special `0000`, `0001` and
+    /// `0002` packets are returned as [`PktLine::Flush`], [`PktLine::Delimiter`] and
+    /// [`PktLine::ResponseEnd`].
+    ///
+    /// Returns `None` if `src` doesn't start with a valid length prefix, or ends
+    /// before the packet does.
+    #[must_use]
+    pub fn from_raw_bytes(src: &[u8]) -> Option<PktLine<'static>> {
+        match PktLine::parse(src).ok()?.0 {
+            Parsed::Data(data) => Some(PktLine::OwnedData(Bytes::copy_from_slice(data))),
+            Parsed::Flush => Some(PktLine::Flush),
+            Parsed::Delimiter => Some(PktLine::Delimiter),
+            Parsed::ResponseEnd => Some(PktLine::ResponseEnd),
+            Parsed::Incomplete { .. } => None,
+        }
+    }
+
+    /// Builds a line of a protocol v1 ref advertisement, in the format
+    /// `<hash> <refname>\n`.
+    #[must_use]
+    pub fn ref_line(hash: HashOutput, refname: &str) -> PktLine<'static> {
+        PktLine::OwnedData(Bytes::from(format!("{} {refname}\n", hex::encode(hash))))
+    }
+
+    /// Builds the first line of a protocol v1 ref advertisement, which carries the
+    /// server's capabilities after a NUL, in the format `<hash> <refname>\0<caps>\n`.
+    #[must_use]
+    pub fn ref_line_with_capabilities(
+        hash: HashOutput,
+        refname: &str,
+        caps: &CapabilitySet,
+    ) -> PktLine<'static> {
+        PktLine::OwnedData(Bytes::from(format!(
+            "{} {refname}\0{caps}\n",
+            hex::encode(hash)
+        )))
+    }
+
+    /// Builds a line of a protocol v2 `ls-refs` response, in the format
+    /// `<hash> <refname>[ symref-target:<target>]\n`. `symref_target` should be
+    /// given for `HEAD` when the client asked for `symrefs`, so clones check out the
+    /// default branch rather than detaching `HEAD`.
+    ///
+    /// <https://git-scm.com/docs/protocol-v2#_ls_refs>
+    #[must_use]
+    pub fn ls_refs_line(
+        hash: HashOutput,
+        refname: &str,
+        symref_target: Option<&str>,
+    ) -> PktLine<'static> {
+        let mut line = format!("{} {refname}", hex::encode(hash));
+
+        if let Some(target) = symref_target {
+            line.push_str(" symref-target:");
+            line.push_str(target);
+        }
+
+        line.push('\n');
+        PktLine::OwnedData(Bytes::from(line))
+    }
+
+    /// Splits an encoded packfile into `SidebandRaw` packets of at most `chunk_size`
+    /// bytes each, allowing the caller to interleave `SidebandMsg` progress messages
+    /// and `Flush`es between the chunks of data.
+    ///
+    /// `chunk_size` is capped to the maximum that will fit in a single packet.
+    pub fn sideband_chunks(data: &Bytes, chunk_size: usize) -> impl Iterator<Item = Self> + '_ {
+        let chunk_size = chunk_size.clamp(1, MAX_DATA_LEN - 1);
+
+        (0..data.len()).step_by(chunk_size).map(move |start| {
+            Self::SidebandRaw(data.slice(start..data.len().min(start + chunk_size)))
+        })
+    }
+
+    /// Encodes the pkt-line into `buf`, splitting it into multiple packets if the
+    /// data is larger than a single packet can hold. Side-band packets are split as
+    /// `side-band-64k` allows, see [`PktLine::encode_to_with_sideband`] for clients
+    /// only supporting `side-band`.
+    ///
+    /// Returns the number of bytes appended to `buf`, across every packet written.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packet (or the packfile it contains) fails to serialise.
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<usize, Error> {
+        self.encode_to_with_sideband(buf, Sideband::SideBand64k)
+    }
+
+    /// Encodes the pkt-line into `buf` as [`PktLine::encode_to`] does, but splitting
+    /// side-band packets so none of them are longer than `sideband` allows. Packets
+    /// that aren't on a side-band channel are unaffected.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packet (or the packfile it contains) fails to serialise.
     #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf), err))]
-    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+    pub fn encode_to_with_sideband(
+        &self,
+        buf: &mut BytesMut,
+        sideband: Sideband,
+    ) -> Result<usize, Error> {
+        let chunk_size = sideband.chunk_size();
+        let start = buf.len();
+
         match self {
-            Self::Data(data) => {
-                for chunk in data.chunks(MAX_DATA_LEN) {
-                    write!(buf, "{:04x}", chunk.len() + 4)?;
-                    buf.extend_from_slice(chunk);
-                }
-            }
+            Self::Data(data) => encode_data(buf, data)?,
+            Self::OwnedData(data) => encode_data(buf, data)?,
             Self::SidebandData(packfile) => {
                 // split the buf off so the cost of counting the bytes to put in the
                 // data line prefix is just the cost of `unsplit` (an atomic decrement)
@@ -45,44 +398,431 @@ impl PktLine<'_> {
                 packfile.encode_to(&mut data_buf)?;
 
                 // write into the buf not the data buf so it's at the start of the msg
-                if data_buf.len() + 5 <= MAX_DATA_LEN - 1 {
+                if data_buf.len() <= chunk_size {
                     write!(buf, "{:04x}", data_buf.len() + 5)?;
-                    buf.put_u8(1); // sideband, 1 = data
+                    buf.put_u8(SIDEBAND_DATA);
                     buf.unsplit(data_buf);
                 } else {
-                    for chunk in data_buf.chunks(MAX_DATA_LEN - 1) {
-                        write!(buf, "{:04x}", chunk.len() + 5)?;
-                        buf.put_u8(1); // sideband, 1 = data
-                        buf.extend_from_slice(chunk);
-                    }
-                }
-            }
-            Self::SidebandMsg(msg) => {
-                for chunk in msg.chunks(MAX_DATA_LEN - 1) {
-                    write!(buf, "{:04x}", chunk.len() + 5)?;
-                    buf.put_u8(2); // sideband, 2 = msg
-                    buf.extend_from_slice(chunk);
+                    encode_sideband(buf, SIDEBAND_DATA, &data_buf, chunk_size)?;
                 }
             }
+            Self::SidebandRaw(data) => encode_sideband(buf, SIDEBAND_DATA, data, chunk_size)?,
+            Self::SidebandMsg(msg) => encode_sideband(buf, SIDEBAND_PROGRESS, msg, chunk_size)?,
+            Self::SidebandErr(msg) => encode_sideband(buf, SIDEBAND_ERROR, msg, chunk_size)?,
             Self::Flush => buf.extend_from_slice(b"0000"),
             Self::Delimiter => buf.extend_from_slice(b"0001"),
             Self::ResponseEnd => buf.extend_from_slice(b"0002"),
         }
 
+        Ok(buf.len() - start)
+    }
+}
+
+/// Writes `data` to `buf`, splitting it into as many packets as required.
+fn encode_data(buf: &mut BytesMut, data: &[u8]) -> Result<(), Error> {
+    for chunk in data.chunks(MAX_DATA_LEN) {
+        write!(buf, "{:04x}", chunk.len() + 4)?;
+        buf.extend_from_slice(chunk);
+    }
+
+    Ok(())
+}
+
+/// Writes `data` to `buf` on the given sideband `channel`, splitting it into as many
+/// packets of at most `chunk_size` bytes of data as required.
+pub(crate) fn encode_sideband(
+    buf: &mut BytesMut,
+    channel: u8,
+    data: &[u8],
+    chunk_size: usize,
+) -> Result<(), Error> {
+    for chunk in data.chunks(chunk_size) {
+        write!(buf, "{:04x}", chunk.len() + 5)?;
+        buf.put_u8(channel);
+        buf.extend_from_slice(chunk);
+    }
+
+    Ok(())
+}
+
+/// Builds the ref advertisement `git upload-pack` starts with for clients using
+/// protocol v0 or v1: each ref as `<hash> <refname>`, the server's capabilities after
+/// a NUL on the first line, and the peeled value of annotated tags on a following
+/// `<hash> <refname>^{}` line, then a flush.
+///
+/// Refs are advertised in the order they're added, so `HEAD` should come first.
+///
+/// <https://git-scm.com/docs/pack-protocol#_reference_discovery>
+#[derive(Debug, Default, Clone)]
+pub struct RefAdvertisement {
+    capabilities: CapabilitySet,
+    /// Each ref's name, hash and peeled hash.
+    refs: Vec<(String, HashOutput, Option<HashOutput>)>,
+}
+
+impl RefAdvertisement {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Advertises a capability, in the form `name` or `name=value`.
+    #[must_use]
+    pub fn capability(mut self, capability: impl Into<String>) -> Self {
+        self.capabilities.insert(capability);
+        self
+    }
+
+    /// Advertises that the ref `name` is a symbolic ref pointing at `target`, see
+    /// [`CapabilitySet::insert_symref`].
+    #[must_use]
+    pub fn symref(mut self, name: &str, target: &str) -> Self {
+        self.capabilities.insert_symref(name, target);
+        self
+    }
+
+    /// Advertises the ref `name` pointing at `hash`.
+    #[must_use]
+    pub fn ref_(mut self, name: impl Into<String>, hash: HashOutput) -> Self {
+        self.refs.push((name.into(), hash, None));
+        self
+    }
+
+    /// Sets the object the last ref added points to once peeled, for a ref pointing
+    /// at an annotated tag.
+    ///
+    /// # Panics
+    ///
+    /// Panics if no refs have been added yet.
+    #[must_use]
+    pub fn peeled(mut self, hash: HashOutput) -> Self {
+        let last = self
+            .refs
+            .last_mut()
+            .expect("peeled must follow the ref it peels");
+        last.2 = Some(hash);
+        self
+    }
+
+    /// Writes the advertisement out to `buf`. A repository without any refs
+    /// advertises its capabilities on a `capabilities^{}` placeholder pointing at
+    /// the zero id instead.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidRefName`] if any of the refnames are invalid, or an
+    /// error if the advertisement fails to serialise.
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        for (name, _, _) in &self.refs {
+            crate::refs::validate_refname(name)?;
+        }
+
+        if self.refs.is_empty() {
+            PktLine::ref_line_with_capabilities([0; 20], "capabilities^{}", &self.capabilities)
+                .encode_to(buf)?;
+        }
+
+        for (i, (name, hash, peeled)) in self.refs.iter().enumerate() {
+            let line = if i == 0 {
+                PktLine::ref_line_with_capabilities(*hash, name, &self.capabilities)
+            } else {
+                PktLine::ref_line(*hash, name)
+            };
+            line.encode_to(buf)?;
+
+            if let Some(peeled) = peeled {
+                PktLine::ref_line(*peeled, &format!("{name}^{{}}")).encode_to(buf)?;
+            }
+        }
+
+        PktLine::Flush.encode_to(buf)?;
+
+        Ok(())
+    }
+}
+
+/// A ref advertisement as received from a server, the client's side of
+/// [`RefAdvertisement`]. This is either a v0 or v1 ref advertisement, with the
+/// server's capabilities after a NUL on the first ref and the peeled value of
+/// annotated tags on `^{}` lines, or a v2 capability advertisement, which lists
+/// capabilities but no refs.
+///
+/// The `# service=git-upload-pack` line and flush a smart HTTP server starts its
+/// response with are skipped over if present.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RefAdvertisementParsed {
+    /// The protocol version, which is 0 unless the server started with a `version`
+    /// line.
+    pub version: u8,
+    /// The object each ref points to, in the order they were advertised.
+    pub refs: IndexMap<String, HashOutput>,
+    /// The object each annotated tag in `refs` points to once peeled.
+    pub peeled: IndexMap<String, HashOutput>,
+    /// The ref each symbolic ref, such as `HEAD`, points to, from the `symref`
+    /// capabilities.
+    pub symrefs: IndexMap<String, String>,
+    pub capabilities: CapabilitySet,
+}
+
+impl RefAdvertisementParsed {
+    /// Parses an advertisement from its packets, such as those yielded by
+    /// [`PktLineIter`], up to and including the flush that ends it.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedRefAdvertisement`] if a ref line isn't an object id
+    /// followed by a refname, the packets end before the flush, or a delimiter or
+    /// response end packet is found, along with any error yielded by `frames`.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(frames), err))]
+    pub fn parse<'a>(
+        frames: impl IntoIterator<Item = Result<Frame<'a>, Error>>,
+    ) -> Result<Self, Error> {
+        let malformed = |reason: &str| Error::MalformedRefAdvertisement(reason.to_string());
+
+        // yields each line without its trailing newline, or `None` for the flush
+        let mut frames = frames.into_iter();
+        let mut next_line = || match frames.next().transpose()? {
+            Some(Frame::Data(line)) => Ok(Some(line.strip_suffix(b"\n").unwrap_or(line))),
+            Some(Frame::Flush) => Ok(None),
+            Some(Frame::Delimiter | Frame::ResponseEnd) => {
+                Err(malformed("unexpected delimiter or response end packet"))
+            }
+            None => Err(malformed("advertisement doesn't end with a flush")),
+        };
+
+        let mut line = next_line()?;
+        let mut out = Self::default();
+
+        if line.is_some_and(|line| line.starts_with(b"# service=")) {
+            if next_line()?.is_some() {
+                return Err(malformed("service line isn't followed by a flush"));
+            }
+            line = next_line()?;
+        }
+
+        if let Some(version) = line.and_then(|line| line.strip_prefix(b"version ")) {
+            out.version = match version {
+                b"1" => 1,
+                b"2" => 2,
+                _ => return Err(malformed("unknown protocol version")),
+            };
+            line = next_line()?;
+        }
+
+        if out.version == 2 {
+            while let Some(capability) = line {
+                out.capabilities
+                    .insert(String::from_utf8_lossy(capability).into_owned());
+                line = next_line()?;
+            }
+
+            return Ok(out);
+        }
+
+        let mut first = true;
+        while let Some(mut ref_line) = line {
+            if std::mem::take(&mut first) {
+                if let Some(nul) = ref_line.iter().position(|&c| c == b'\0') {
+                    out.capabilities = CapabilitySet::parse(&ref_line[nul + 1..]);
+                    ref_line = &ref_line[..nul];
+                }
+            }
+
+            // the shallow boundaries of a shallow repository follow its refs
+            if !ref_line.starts_with(b"shallow ") {
+                let (hash, name) = ref_line
+                    .iter()
+                    .position(|&c| c == b' ')
+                    .map(|space| (&ref_line[..space], &ref_line[space + 1..]))
+                    .ok_or_else(|| malformed("ref line without a refname"))?;
+
+                let mut oid = HashOutput::default();
+                hex::decode_to_slice(hash, &mut oid)
+                    .map_err(|_| malformed("invalid object id in ref line"))?;
+                let name = std::str::from_utf8(name)
+                    .map_err(|_| malformed("refname isn't valid UTF-8"))?;
+
+                match name.strip_suffix("^{}") {
+                    // an empty repository advertises its capabilities on a placeholder
+                    Some("capabilities") => {}
+                    Some(name) => {
+                        out.peeled.insert(name.to_string(), oid);
+                    }
+                    None => {
+                        out.refs.insert(name.to_string(), oid);
+                    }
+                }
+            }
+
+            line = next_line()?;
+        }
+
+        for (name, value) in out.capabilities.iter() {
+            if let Some((symref, target)) = value
+                .filter(|_| name == "symref")
+                .and_then(|value| value.split_once(':'))
+            {
+                out.symrefs.insert(symref.to_string(), target.to_string());
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// Whether the server advertised the capability `name`, such as `side-band-64k`.
+    #[must_use]
+    pub fn supports(&self, name: &str) -> bool {
+        self.capabilities.contains(name)
+    }
+
+    /// The `agent` the server advertised, such as `git/2.39.5`.
+    #[must_use]
+    pub fn agent(&self) -> Option<&str> {
+        self.capabilities.get("agent")
+    }
+}
+
+/// A [`std::io::Write`] that frames everything written to it into pkt-lines, for
+/// handing to code that only knows how to write to a plain writer.
+///
+/// Writes are buffered until a whole packet's worth of data is available, so a packet
+/// is only written early when the writer is flushed. [`PktLineWriter::finish`] must be
+/// called to write out anything still buffered.
+pub struct PktLineWriter<W: std::io::Write> {
+    inner: W,
+    /// The side-band channel to write to, or `None` for plain data packets.
+    channel: Option<u8>,
+    /// The most data a single packet can carry.
+    max_payload: usize,
+    /// Data written that hasn't been framed yet.
+    pending: Vec<u8>,
+    /// The framed packet being written to `inner`.
+    scratch: BytesMut,
+}
+
+impl<W: std::io::Write> PktLineWriter<W> {
+    /// Frames the data written into plain data packets, as [`PktLine::Data`] does.
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            channel: None,
+            max_payload: MAX_DATA_LEN,
+            pending: Vec::new(),
+            scratch: BytesMut::new(),
+        }
+    }
+
+    /// Frames the data written into packets on the side-band `channel`, such as
+    /// [`SIDEBAND_DATA`], none of which are longer than `sideband` allows.
+    pub fn sideband(inner: W, channel: u8, sideband: Sideband) -> Self {
+        Self {
+            channel: Some(channel),
+            max_payload: sideband.chunk_size(),
+            ..Self::new(inner)
+        }
+    }
+
+    /// Writes out anything still buffered as a final packet, followed by a flush
+    /// packet if `flush_pkt` is set, returning the inner writer.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Io`] if writing to the inner writer fails.
+    pub fn finish(mut self, flush_pkt: bool) -> Result<W, Error> {
+        self.write_pending()?;
+
+        if flush_pkt {
+            self.inner.write_all(b"0000")?;
+        }
+
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Frames everything in `pending` as a single packet and writes it out.
+    fn write_pending(&mut self) -> std::io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+
+        self.scratch.clear();
+        match self.channel {
+            Some(channel) => {
+                encode_sideband(&mut self.scratch, channel, &self.pending, self.max_payload)
+            }
+            None => encode_data(&mut self.scratch, &self.pending),
+        }
+        .map_err(std::io::Error::other)?;
+
+        self.inner.write_all(&self.scratch)?;
+        self.pending.clear();
         Ok(())
     }
 }
 
+impl<W: std::io::Write> std::io::Write for PktLineWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let len = buf.len().min(self.max_payload - self.pending.len());
+        self.pending.extend_from_slice(&buf[..len]);
+
+        if self.pending.len() == self.max_payload {
+            self.write_pending()?;
+        }
+
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_pending()?;
+        self.inner.flush()
+    }
+}
+
+impl<'a> PktLine<'a> {
+    /// Builds a data packet for a line of text, appending the trailing newline the
+    /// protocol recommends if `line` doesn't already end with one. Use
+    /// [`PktLine::Data`] for binary payloads that shouldn't be altered.
+    ///
+    /// ```rust
+    /// # use bytes::BytesMut;
+    /// # use packfile::PktLine;
+    /// #
+    /// let mut buf = BytesMut::new();
+    /// PktLine::text("agent=git/2.32.0").encode_to(&mut buf).unwrap();
+    /// assert_eq!(buf.as_ref(), b"0015agent=git/2.32.0\n");
+    /// ```
+    #[must_use]
+    pub fn text(line: &'a str) -> Self {
+        if line.ends_with('\n') {
+            PktLine::Data(line.as_bytes())
+        } else {
+            PktLine::OwnedData(Bytes::from(format!("{line}\n")))
+        }
+    }
+}
+
 impl<'a> From<&'a str> for PktLine<'a> {
     fn from(val: &'a str) -> Self {
         PktLine::Data(val.as_bytes())
     }
 }
 
+impl From<Bytes> for PktLine<'static> {
+    fn from(val: Bytes) -> Self {
+        PktLine::OwnedData(val)
+    }
+}
+
+impl From<String> for PktLine<'static> {
+    fn from(val: String) -> Self {
+        PktLine::OwnedData(Bytes::from(val))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::packet_line::MAX_DATA_LEN;
-    use bytes::BytesMut;
+    use bytes::{Bytes, BytesMut};
 
     #[test]
     fn test_pkt_line() {
@@ -93,6 +833,992 @@ mod test {
         assert_eq!(buffer.as_ref(), b"0015agent=git/2.32.0\n");
     }
 
+    #[test]
+    fn from_owned() {
+        use super::PktLine;
+
+        // owned lines can outlive the data they were built from
+        let lines: Vec<PktLine<'static>> = vec![
+            format!("want {}\n", "01".repeat(20)).into(),
+            Bytes::from_static(b"done\n").into(),
+        ];
+
+        let mut buffer = BytesMut::new();
+        for line in std::thread::spawn(move || lines).join().unwrap() {
+            line.encode_to(&mut buffer).unwrap();
+        }
+        assert_eq!(
+            buffer.as_ref(),
+            b"0032want 0101010101010101010101010101010101010101\n0009done\n"
+        );
+    }
+
+    #[test]
+    fn text() {
+        let encode = |line| {
+            let mut buffer = BytesMut::new();
+            super::PktLine::text(line).encode_to(&mut buffer).unwrap();
+            buffer
+        };
+
+        assert_eq!(encode("hello"), encode("hello\n"));
+        assert_eq!(encode("hello").as_ref(), b"000ahello\n");
+        assert_eq!(encode("").as_ref(), b"0005\n");
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        use super::{Parsed, PktLine};
+
+        let data = Bytes::from(vec![0xaa; 100]);
+        let cases = [
+            (PktLine::Data(b"hello\n"), Parsed::Data(b"hello\n")),
+            (PktLine::OwnedData(data.clone()), Parsed::Data(&data)),
+            (PktLine::SidebandMsg(b"50%"), Parsed::Data(b"\x0250%")),
+            (PktLine::Flush, Parsed::Flush),
+            (PktLine::Delimiter, Parsed::Delimiter),
+            (PktLine::ResponseEnd, Parsed::ResponseEnd),
+        ];
+
+        let mut buffer = BytesMut::new();
+        for (line, _) in &cases {
+            line.encode_to(&mut buffer).unwrap();
+        }
+
+        let mut rest = &buffer[..];
+        for (_, expected) in cases {
+            let (parsed, consumed) = PktLine::parse(rest).unwrap();
+            assert_eq!(parsed, expected);
+            rest = &rest[consumed..];
+        }
+        assert!(rest.is_empty());
+
+        let mut buffer = BytesMut::new();
+        PktLine::SidebandRaw(data.clone())
+            .encode_to(&mut buffer)
+            .unwrap();
+        let (Parsed::Data(parsed), consumed) = PktLine::parse(&buffer).unwrap() else {
+            panic!("sideband data should parse as data");
+        };
+        assert_eq!(parsed[0], 1);
+        assert_eq!(&parsed[1..], &data[..]);
+        assert_eq!(consumed, buffer.len());
+
+        // the largest packet that can be sent
+        let largest = vec![0; MAX_DATA_LEN];
+        let mut buffer = BytesMut::new();
+        PktLine::Data(&largest).encode_to(&mut buffer).unwrap();
+        assert_eq!(
+            PktLine::parse(&buffer).unwrap(),
+            (Parsed::Data(&largest), MAX_DATA_LEN + 4)
+        );
+    }
+
+    #[test]
+    fn from_raw_bytes() {
+        use super::PktLine;
+
+        let mut buffer = BytesMut::new();
+        PktLine::ResponseEnd.encode_to(&mut buffer).unwrap();
+        assert_eq!(buffer.as_ref(), b"0002");
+
+        assert!(matches!(
+            PktLine::from_raw_bytes(b"0000"),
+            Some(PktLine::Flush)
+        ));
+        assert!(matches!(
+            PktLine::from_raw_bytes(b"0001"),
+            Some(PktLine::Delimiter)
+        ));
+        assert!(matches!(
+            PktLine::from_raw_bytes(&buffer),
+            Some(PktLine::ResponseEnd)
+        ));
+        // 0003 is reserved, so isn't a valid length
+        assert!(PktLine::from_raw_bytes(b"0003").is_none());
+
+        let Some(PktLine::OwnedData(data)) = PktLine::from_raw_bytes(b"000ahello\n0000") else {
+            panic!("expected a data packet");
+        };
+        assert_eq!(data.as_ref(), b"hello\n");
+
+        assert!(PktLine::from_raw_bytes(b"zzzz").is_none());
+        assert!(PktLine::from_raw_bytes(b"000ahel").is_none());
+        assert!(PktLine::from_raw_bytes(b"").is_none());
+    }
+
+    #[test]
+    fn pkt_line_iter() {
+        use super::{Frame, PktLineIter};
+        use crate::Error;
+
+        let frames = |input| PktLineIter::new(input).collect::<Result<Vec<_>, _>>();
+
+        assert_eq!(
+            frames(b"0014command=ls-refs\n00010009peel\n000bsymrefs0000").unwrap(),
+            [
+                Frame::Data(b"command=ls-refs\n"),
+                Frame::Delimiter,
+                Frame::Data(b"peel\n"),
+                Frame::Data(b"symrefs"),
+                Frame::Flush,
+            ]
+        );
+        assert_eq!(
+            frames(b"0009fetch00010009have10009have200010009have30000").unwrap(),
+            [
+                Frame::Data(b"fetch"),
+                Frame::Delimiter,
+                Frame::Data(b"have1"),
+                Frame::Data(b"have2"),
+                Frame::Delimiter,
+                Frame::Data(b"have3"),
+                Frame::Flush,
+            ]
+        );
+        assert_eq!(
+            frames(b"000eunpack ok\n0017ok refs/heads/main\n00000002").unwrap(),
+            [
+                Frame::Data(b"unpack ok\n"),
+                Frame::Data(b"ok refs/heads/main\n"),
+                Frame::Flush,
+                Frame::ResponseEnd,
+            ]
+        );
+        assert!(frames(b"").unwrap().is_empty());
+
+        // a partial frame is reported after every complete one
+        for len in 1..b"0009hello".len() {
+            let input = [&b"0009hello"[..], &b"0009hello"[..len]].concat();
+            let mut iter = PktLineIter::new(&input);
+
+            assert_eq!(iter.next().unwrap().unwrap(), Frame::Data(b"hello"));
+            assert!(matches!(
+                iter.next(),
+                Some(Err(Error::TruncatedPktLine(remaining))) if remaining == len
+            ));
+            assert!(iter.next().is_none());
+        }
+
+        for prefix in [&b"xxxx"[..], b"00g0", b"+001", b"00\xff0"] {
+            let mut iter = PktLineIter::new(prefix);
+            assert!(matches!(
+                iter.next(),
+                Some(Err(Error::InvalidLengthPrefix(actual))) if actual == prefix
+            ));
+            assert!(iter.next().is_none());
+        }
+
+        let mut iter = PktLineIter::new(b"0000extra");
+        assert_eq!(iter.next().unwrap().unwrap(), Frame::Flush);
+        assert_eq!(iter.remaining(), b"extra");
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        use super::{Parsed, PktLine};
+
+        for (input, needed) in [(&b""[..], 4), (b"00", 2), (b"0009", 5), (b"0009hell", 1)] {
+            assert_eq!(
+                PktLine::parse(input).unwrap(),
+                (Parsed::Incomplete { needed }, 0)
+            );
+        }
+
+        assert_eq!(
+            PktLine::parse(b"0009hello0000").unwrap(),
+            (Parsed::Data(b"hello"), 9)
+        );
+        assert_eq!(PktLine::parse(b"0004").unwrap(), (Parsed::Data(b""), 4));
+    }
+
+    #[test]
+    fn parse_invalid() {
+        use super::PktLine;
+        use crate::Error;
+
+        for input in [&b"xxxx"[..], b"+001", b"00 4abc", b"000g"] {
+            assert!(
+                matches!(PktLine::parse(input), Err(Error::InvalidLengthPrefix(_))),
+                "{input:?} should be rejected"
+            );
+        }
+
+        assert!(matches!(
+            PktLine::parse(b"0003"),
+            Err(Error::PacketLengthTooSmall(3))
+        ));
+
+        for (input, length) in [(&b"fff1"[..], 0xfff1), (b"ffff", 0xffff)] {
+            assert!(
+                matches!(
+                    PktLine::parse(input),
+                    Err(Error::PacketLengthExceedsSpec(_, actual)) if actual == length
+                ),
+                "{input:?} should be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn sideband_channels() {
+        use super::{PktLine, SIDEBAND_DATA, SIDEBAND_ERROR, SIDEBAND_PROGRESS};
+        use crate::low_level::PackFile;
+
+        // the values are fixed by the protocol
+        assert_eq!(
+            [SIDEBAND_DATA, SIDEBAND_PROGRESS, SIDEBAND_ERROR],
+            [1, 2, 3]
+        );
+
+        for (line, channel) in [
+            (PktLine::SidebandData(PackFile::new(&[])), SIDEBAND_DATA),
+            (PktLine::SidebandRaw(Bytes::from("data")), SIDEBAND_DATA),
+            (PktLine::SidebandMsg(b"progress"), SIDEBAND_PROGRESS),
+            (PktLine::SidebandErr(b"error"), SIDEBAND_ERROR),
+        ] {
+            let mut buffer = BytesMut::new();
+            line.encode_to(&mut buffer).unwrap();
+            assert_eq!(buffer[4], channel);
+        }
+    }
+
+    #[test]
+    fn sideband_err() {
+        let mut buffer = BytesMut::new();
+        super::PktLine::SidebandErr(b"fatal: out of cheese\n")
+            .encode_to(&mut buffer)
+            .unwrap();
+        assert_eq!(buffer.as_ref(), b"001a\x03fatal: out of cheese\n");
+
+        // long messages are split up in the same way as the other sideband packets
+        let msg = vec![b'a'; MAX_DATA_LEN + 10];
+        let mut buffer = BytesMut::new();
+        super::PktLine::SidebandErr(&msg)
+            .encode_to(&mut buffer)
+            .unwrap();
+        assert_eq!(&buffer[..5], b"fff0\x03");
+        assert_eq!(&buffer[MAX_DATA_LEN + 4..][..5], b"0010\x03");
+        assert_eq!(buffer.len(), msg.len() + 10);
+    }
+
+    #[test]
+    fn sideband_err_aborts_git_fetch() {
+        use super::PktLine;
+        use crate::{
+            capabilities::CapabilitySet,
+            test::{git, ScriptServer},
+        };
+        use tempfile::TempDir;
+
+        let caps: CapabilitySet = ["side-band-64k", "agent=packfile/0.1"]
+            .into_iter()
+            .collect();
+
+        let mut response = BytesMut::new();
+        PktLine::ref_line_with_capabilities([1; 20], "refs/heads/master", &caps)
+            .encode_to(&mut response)
+            .unwrap();
+        PktLine::Flush.encode_to(&mut response).unwrap();
+        let advertisement = response.split().freeze();
+        PktLine::text("NAK").encode_to(&mut response).unwrap();
+        PktLine::SidebandErr(b"out of cheese\n")
+            .encode_to(&mut response)
+            .unwrap();
+
+        // a server that advertises a single ref, waits for the client's `done` then
+        // aborts the fetch
+        let server = ScriptServer::after_done(&advertisement, &response);
+
+        let client = TempDir::new().unwrap();
+        git(client.path(), &["init", "--quiet"], &[]);
+
+        let out = server
+            .git(0, "fetch")
+            .current_dir(client.path())
+            .arg(server.url())
+            .arg("refs/heads/master")
+            .output()
+            .unwrap();
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success(), "fetch should fail: {stderr}");
+        assert!(
+            stderr.contains("remote: out of cheese"),
+            "error should be reported: {stderr}"
+        );
+    }
+
+    #[test]
+    fn encode_to_with_sideband() {
+        use super::{Parsed, PktLine};
+        use crate::{
+            capabilities::Sideband,
+            low_level::{PackFile, PackFileEntry},
+        };
+
+        let data = Bytes::from((0..=u8::MAX).cycle().take(200_000).collect::<Vec<_>>());
+        let entries = [PackFileEntry::Blob(data.clone())];
+        let mut pack = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut pack).unwrap();
+        let msg = vec![b'm'; 5000];
+
+        let mut reassembled = Vec::new();
+        for sideband in [Sideband::SideBand, Sideband::SideBand64k] {
+            let mut buffer = BytesMut::new();
+            for line in [
+                PktLine::SidebandData(PackFile::new(&entries)),
+                PktLine::SidebandRaw(data.clone()),
+                PktLine::SidebandMsg(&msg),
+                PktLine::SidebandErr(&msg),
+            ] {
+                line.encode_to_with_sideband(&mut buffer, sideband).unwrap();
+            }
+
+            let mut channels: [Vec<u8>; 4] = Default::default();
+            let mut rest = &buffer[..];
+            while !rest.is_empty() {
+                let (Parsed::Data(packet), consumed) = PktLine::parse(rest).unwrap() else {
+                    panic!("only data packets should be written");
+                };
+                assert!(consumed <= sideband.max_packet_len());
+                channels[usize::from(packet[0])].extend_from_slice(&packet[1..]);
+                rest = &rest[consumed..];
+            }
+
+            let mut expected_data = pack.to_vec();
+            expected_data.extend_from_slice(&data);
+            assert_eq!(channels[1], expected_data);
+            assert_eq!(channels[2], msg);
+            assert_eq!(channels[3], msg);
+            reassembled.push(channels);
+        }
+        assert_eq!(reassembled[0], reassembled[1]);
+
+        // `encode_to` uses the largest packets
+        let mut buffer = BytesMut::new();
+        PktLine::SidebandRaw(data.clone())
+            .encode_to(&mut buffer)
+            .unwrap();
+        let mut expected = BytesMut::new();
+        PktLine::SidebandRaw(data)
+            .encode_to_with_sideband(&mut expected, Sideband::SideBand64k)
+            .unwrap();
+        assert_eq!(buffer, expected);
+    }
+
+    #[test]
+    fn sideband_raw_matches_sideband_data() {
+        use super::PktLine;
+        use crate::{
+            capabilities::Sideband,
+            low_level::{PackFile, PackFileEntry},
+        };
+
+        // incompressible data, so the size of the pack follows the size of the blob
+        let mut state = 1_u32;
+        let noise = std::iter::repeat_with(|| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state.to_le_bytes()[0]
+        })
+        .take(200_000)
+        .collect::<Vec<_>>();
+
+        for size in [0, 10, 950, 1000, MAX_DATA_LEN - 40, MAX_DATA_LEN, 200_000] {
+            let entries = [PackFileEntry::Blob(Bytes::copy_from_slice(&noise[..size]))];
+            let mut pack = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut pack).unwrap();
+            let pack = pack.freeze();
+
+            for sideband in [Sideband::SideBand, Sideband::SideBand64k] {
+                let mut from_data = BytesMut::new();
+                PktLine::SidebandData(PackFile::new(&entries))
+                    .encode_to_with_sideband(&mut from_data, sideband)
+                    .unwrap();
+
+                let mut from_raw = BytesMut::new();
+                PktLine::SidebandRaw(pack.clone())
+                    .encode_to_with_sideband(&mut from_raw, sideband)
+                    .unwrap();
+
+                assert_eq!(from_data, from_raw, "{size} byte blob with {sideband:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn encode_to_returns_length() {
+        use super::{PktLine, PktLineOwned};
+        use crate::{
+            capabilities::Sideband,
+            low_level::{PackFile, PackFileEntry},
+        };
+
+        let data = Bytes::from(vec![b'a'; 200_000]);
+        let entries = [PackFileEntry::Blob(data.clone())];
+
+        // start with something already in the buffer, and part of it split off, so
+        // the count can't just be the length of the buffer
+        let mut buffer = BytesMut::from(&b"0000"[..]);
+        let _split = buffer.split_to(2);
+
+        for line in [
+            PktLine::Data(b"hello\n"),
+            PktLine::Data(&data),
+            PktLine::SidebandData(PackFile::new(&entries)),
+            PktLine::SidebandRaw(data.clone()),
+            PktLine::SidebandMsg(&data),
+            PktLine::Flush,
+            PktLine::Delimiter,
+            PktLine::ResponseEnd,
+        ] {
+            for sideband in [Sideband::SideBand, Sideband::SideBand64k] {
+                let before = buffer.len();
+                let written = line.encode_to_with_sideband(&mut buffer, sideband).unwrap();
+                assert_eq!(written, buffer.len() - before);
+            }
+
+            let before = buffer.len();
+            let written = line.encode_to(&mut buffer).unwrap();
+            assert_eq!(written, buffer.len() - before);
+
+            let before = buffer.len();
+            let written = PktLineOwned::try_from(line)
+                .unwrap()
+                .encode_to(&mut buffer)
+                .unwrap();
+            assert_eq!(written, buffer.len() - before);
+        }
+
+        assert_eq!(PktLine::Flush.encode_to(&mut BytesMut::new()).unwrap(), 4);
+        assert_eq!(
+            PktLine::Data(b"hello\n")
+                .encode_to(&mut BytesMut::new())
+                .unwrap(),
+            10
+        );
+        // 3 full packets and 1 partial one, each with a 5 byte prefix
+        assert_eq!(
+            PktLine::SidebandRaw(data)
+                .encode_to(&mut BytesMut::new())
+                .unwrap(),
+            200_000 + 4 * 5
+        );
+    }
+
+    #[test]
+    fn sideband_boundaries() {
+        use super::{Parsed, PktLine};
+        use crate::{
+            capabilities::Sideband,
+            low_level::{PackFile, PackFileEntry},
+        };
+
+        // incompressible data, so the size of the pack follows the size of the blob
+        let mut state = 1_u32;
+        let noise = std::iter::repeat_with(|| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state.to_le_bytes()[0]
+        })
+        .take(MAX_DATA_LEN + 1)
+        .collect::<Vec<_>>();
+        let noise = Bytes::from(noise);
+
+        // a side-band packet carries 1 byte less than a data packet to make room for
+        // the channel, so the largest packet is the same length either way
+        let largest = Sideband::SideBand64k.chunk_size();
+        assert_eq!(largest, MAX_DATA_LEN - 1);
+
+        let encode_pack = |entries: &[PackFileEntry]| {
+            let mut pack = BytesMut::new();
+            PackFile::new(entries).encode_to(&mut pack).unwrap();
+            pack
+        };
+
+        for len in [
+            MAX_DATA_LEN - 5,
+            MAX_DATA_LEN - 4,
+            largest,
+            MAX_DATA_LEN,
+            MAX_DATA_LEN + 1,
+        ] {
+            let payload = noise.slice(..len);
+
+            // a blob that encodes to a pack of exactly `len` bytes, noise is stored
+            // as-is so the pack's overhead doesn't depend on the blob's size
+            let blob = |size| [PackFileEntry::Blob(noise.slice(..size))];
+            let overhead = encode_pack(&blob(len)).len() - len;
+            let entries = blob(len - overhead);
+            let pack = encode_pack(&entries);
+            assert_eq!(pack.len(), len);
+
+            let mut chunks = BytesMut::new();
+            for line in PktLine::sideband_chunks(&payload, usize::MAX) {
+                line.encode_to(&mut chunks).unwrap();
+            }
+
+            let encode = |line: PktLine<'_>| {
+                let mut buffer = BytesMut::new();
+                line.encode_to(&mut buffer).unwrap();
+                buffer
+            };
+            let cases = [
+                (
+                    encode(PktLine::SidebandData(PackFile::new(&entries))),
+                    &pack[..],
+                ),
+                (encode(PktLine::SidebandRaw(payload.clone())), &payload[..]),
+                (encode(PktLine::SidebandMsg(&payload)), &payload[..]),
+                (encode(PktLine::SidebandErr(&payload)), &payload[..]),
+                (chunks, &payload[..]),
+            ];
+
+            for (buffer, expected) in cases {
+                let mut reassembled = Vec::new();
+                let mut packets = 0;
+                let mut rest = &buffer[..];
+                while !rest.is_empty() {
+                    let (Parsed::Data(packet), consumed) = PktLine::parse(rest).unwrap() else {
+                        panic!("only data packets should be written");
+                    };
+                    assert!(consumed <= MAX_DATA_LEN + 4, "{len} byte payload");
+                    reassembled.extend_from_slice(&packet[1..]);
+                    packets += 1;
+                    rest = &rest[consumed..];
+                }
+
+                assert_eq!(reassembled, expected, "{len} byte payload");
+                assert_eq!(packets, len.div_ceil(largest), "{len} byte payload");
+            }
+        }
+    }
+
+    #[test]
+    fn owned() {
+        use super::{PktLine, PktLineOwned};
+        use crate::low_level::{PackFile, PackFileEntry};
+
+        let entries = [PackFileEntry::Blob(Bytes::from("hello"))];
+        let mut pack = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut pack).unwrap();
+
+        let cases = [
+            (
+                PktLine::Data(b"hello\n"),
+                PktLineOwned::Data("hello\n".into()),
+            ),
+            (
+                PktLine::OwnedData("hello\n".into()),
+                PktLineOwned::Data("hello\n".into()),
+            ),
+            (
+                PktLine::SidebandData(PackFile::new(&entries)),
+                PktLineOwned::SidebandData(pack.clone().freeze()),
+            ),
+            (
+                PktLine::SidebandRaw(pack.clone().freeze()),
+                PktLineOwned::SidebandData(pack.clone().freeze()),
+            ),
+            (
+                PktLine::SidebandMsg(b"50%"),
+                PktLineOwned::SidebandMsg("50%".into()),
+            ),
+            (
+                PktLine::SidebandErr(b"oh no"),
+                PktLineOwned::SidebandErr("oh no".into()),
+            ),
+            (PktLine::Flush, PktLineOwned::Flush),
+            (PktLine::Delimiter, PktLineOwned::Delimiter),
+            (PktLine::ResponseEnd, PktLineOwned::ResponseEnd),
+        ];
+
+        for (line, expected) in cases {
+            let mut borrowed = BytesMut::new();
+            line.encode_to(&mut borrowed).unwrap();
+
+            let owned = PktLineOwned::try_from(line).unwrap();
+            assert_eq!(owned, expected);
+
+            let mut encoded = BytesMut::new();
+            owned.encode_to(&mut encoded).unwrap();
+            assert_eq!(encoded, borrowed);
+        }
+    }
+
+    #[test]
+    fn pkt_line_writer() {
+        use super::{Parsed, PktLine, PktLineWriter, SIDEBAND_DATA};
+        use crate::capabilities::Sideband;
+        use std::io::Write;
+
+        let data = (0..=u8::MAX).cycle().take(300_000).collect::<Vec<_>>();
+
+        for sideband in [None, Some(Sideband::SideBand), Some(Sideband::SideBand64k)] {
+            let (mut writer, channel, max_packet_len) = match sideband {
+                Some(sideband) => (
+                    PktLineWriter::sideband(Vec::new(), SIDEBAND_DATA, sideband),
+                    Some(SIDEBAND_DATA),
+                    sideband.max_packet_len(),
+                ),
+                None => (PktLineWriter::new(Vec::new()), None, MAX_DATA_LEN + 4),
+            };
+            for (i, chunk) in data.chunks(7919).enumerate() {
+                writer.write_all(chunk).unwrap();
+                if i == 3 {
+                    writer.flush().unwrap();
+                }
+            }
+            let out = writer.finish(true).unwrap();
+
+            let mut reassembled = Vec::new();
+            let mut rest = &out[..];
+            loop {
+                let (packet, consumed) = PktLine::parse(rest).unwrap();
+                rest = &rest[consumed..];
+                assert!(consumed <= max_packet_len);
+
+                match (packet, channel) {
+                    (Parsed::Data(payload), None) => reassembled.extend_from_slice(payload),
+                    (Parsed::Data([band, payload @ ..]), Some(channel)) => {
+                        assert_eq!(*band, channel);
+                        reassembled.extend_from_slice(payload);
+                    }
+                    (Parsed::Flush, _) => break,
+                    (packet, _) => panic!("unexpected packet {packet:?}"),
+                }
+            }
+
+            assert!(rest.is_empty());
+            assert_eq!(reassembled, data);
+        }
+
+        // nothing is written for an empty writer, other than the flush if asked for
+        assert!(PktLineWriter::new(Vec::new())
+            .finish(false)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            PktLineWriter::new(Vec::new()).finish(true).unwrap(),
+            b"0000"
+        );
+    }
+
+    #[test]
+    fn ls_refs_lines() {
+        let mut buffer = BytesMut::new();
+        super::PktLine::ls_refs_line([1; 20], "HEAD", Some("refs/heads/main"))
+            .encode_to(&mut buffer)
+            .unwrap();
+        super::PktLine::ls_refs_line([1; 20], "refs/heads/main", None)
+            .encode_to(&mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            buffer.as_ref(),
+            b"00500101010101010101010101010101010101010101 HEAD symref-target:refs/heads/main\n\
+              003d0101010101010101010101010101010101010101 refs/heads/main\n"
+        );
+    }
+
+    #[test]
+    fn symref_head_in_advertisement() {
+        use super::PktLine;
+        use crate::{capabilities::CapabilitySet, test::ScriptServer};
+
+        let mut caps: CapabilitySet = ["side-band-64k"].into_iter().collect();
+        caps.insert_symref("HEAD", "refs/heads/main");
+
+        let mut advertisement = BytesMut::new();
+        PktLine::ref_line_with_capabilities([1; 20], "HEAD", &caps)
+            .encode_to(&mut advertisement)
+            .unwrap();
+        PktLine::ref_line([1; 20], "refs/heads/main")
+            .encode_to(&mut advertisement)
+            .unwrap();
+        PktLine::Flush.encode_to(&mut advertisement).unwrap();
+
+        // a server that only advertises its refs, which is all ls-remote needs
+        let server = ScriptServer::new(&advertisement);
+        let out = server
+            .git(0, "ls-remote")
+            .arg("--symref")
+            .arg(server.url())
+            .arg("HEAD")
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&out.stdout);
+
+        assert!(
+            out.status.success(),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+        assert!(
+            stdout.starts_with("ref: refs/heads/main\tHEAD\n"),
+            "HEAD should be reported as a symref: {stdout}"
+        );
+    }
+
+    #[test]
+    fn ref_advertisement() {
+        use super::RefAdvertisement;
+        use crate::test::{git, ScriptServer};
+        use tempfile::TempDir;
+
+        // lists the refs of a server only sending `advertisement`
+        let ls_remote = |advertisement: &RefAdvertisement| {
+            let mut buf = BytesMut::new();
+            advertisement.encode_to(&mut buf).unwrap();
+
+            let server = ScriptServer::new(&buf);
+            let out = server
+                .git(0, "ls-remote")
+                .arg("--symref")
+                .arg(server.url())
+                .output()
+                .unwrap();
+            assert!(
+                out.status.success(),
+                "{}",
+                String::from_utf8_lossy(&out.stderr)
+            );
+
+            String::from_utf8(out.stdout).unwrap()
+        };
+
+        let repo = TempDir::new().unwrap();
+        let path = repo.path();
+        git(path, &["init", "--quiet", "--initial-branch=main"], &[]);
+        git(
+            path,
+            &[
+                "-c",
+                "user.name=me",
+                "-c",
+                "user.email=me@example.com",
+                "commit",
+                "--quiet",
+                "--allow-empty",
+                "-m",
+                "initial commit",
+            ],
+            &[],
+        );
+        git(
+            path,
+            &[
+                "-c",
+                "user.name=me",
+                "-c",
+                "user.email=me@example.com",
+                "tag",
+                "-a",
+                "v1",
+                "-m",
+                "v1",
+            ],
+            &[],
+        );
+
+        let hash = |rev| {
+            let hex = git(path, &["rev-parse", rev], &[]);
+            crate::decode_hash(hex.trim()).unwrap()
+        };
+
+        let advertisement = RefAdvertisement::new()
+            .capability("side-band-64k")
+            .capability("agent=packfile/0.1")
+            .symref("HEAD", "refs/heads/main")
+            .ref_("HEAD", hash("HEAD"))
+            .ref_("refs/heads/main", hash("main"))
+            .ref_("refs/tags/v1", hash("v1"))
+            .peeled(hash("v1^{}"));
+
+        let expected = git(path, &["ls-remote", "--symref", "."], &[]);
+        assert_eq!(ls_remote(&advertisement), expected);
+
+        // an empty repository only advertises its capabilities
+        let empty = RefAdvertisement::new().capability("side-band-64k");
+        let mut buf = BytesMut::new();
+        empty.encode_to(&mut buf).unwrap();
+        assert_eq!(
+            buf.as_ref(),
+            b"004b0000000000000000000000000000000000000000 capabilities^{}\0side-band-64k\n0000"
+        );
+        assert_eq!(ls_remote(&empty), "");
+
+        assert!(matches!(
+            RefAdvertisement::new()
+                .ref_("refs/heads/bad name", [1; 20])
+                .encode_to(&mut BytesMut::new()),
+            Err(crate::Error::InvalidRefName(_))
+        ));
+    }
+
+    #[test]
+    fn ref_advertisement_parsed() {
+        use super::{PktLineIter, RefAdvertisement, RefAdvertisementParsed};
+        use crate::test::git;
+        use std::process::Command;
+        use tempfile::TempDir;
+
+        let repo = TempDir::new().unwrap();
+        let path = repo.path();
+        let as_me = |args: &[&str]| {
+            let args = [
+                &["-c", "user.name=me", "-c", "user.email=me@example.com"],
+                args,
+            ]
+            .concat();
+            git(path, &args, &[]);
+        };
+        git(path, &["init", "--quiet", "--initial-branch=main"], &[]);
+        as_me(&["commit", "--quiet", "--allow-empty", "-m", "initial commit"]);
+        as_me(&["tag", "-a", "v1", "-m", "v1"]);
+        as_me(&["tag", "light"]);
+
+        // captures the advertisement git gives to a client speaking `protocol`
+        let advertise = |path: &std::path::Path, protocol: &str| {
+            let out = Command::new("git")
+                .args(["upload-pack", "--advertise-refs"])
+                .arg(path)
+                .env("GIT_PROTOCOL", protocol)
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "git exited non-0");
+            out.stdout
+        };
+        let parse =
+            |advertisement: &[u8]| RefAdvertisementParsed::parse(PktLineIter::new(advertisement));
+        let hash = |rev| crate::decode_hash(git(path, &["rev-parse", rev], &[]).trim()).unwrap();
+
+        let v0 = parse(&advertise(path, "")).unwrap();
+        assert_eq!(v0.version, 0);
+        assert_eq!(
+            v0.refs
+                .iter()
+                .map(|(name, oid)| (name.as_str(), *oid))
+                .collect::<Vec<_>>(),
+            [
+                ("HEAD", hash("HEAD")),
+                ("refs/heads/main", hash("main")),
+                ("refs/tags/light", hash("HEAD")),
+                ("refs/tags/v1", hash("v1")),
+            ]
+        );
+        assert_eq!(v0.peeled.len(), 1);
+        assert_eq!(v0.peeled["refs/tags/v1"], hash("HEAD"));
+        assert_eq!(v0.symrefs.len(), 1);
+        assert_eq!(v0.symrefs["HEAD"], "refs/heads/main");
+        assert!(v0.supports("side-band-64k"));
+        assert!(!v0.supports("side-band-128k"));
+        assert!(v0.agent().unwrap().starts_with("git/"));
+
+        let v1 = parse(&advertise(path, "version=1")).unwrap();
+        assert_eq!(v1.version, 1);
+        assert_eq!(RefAdvertisementParsed { version: 0, ..v1 }, v0);
+
+        // smart HTTP responses start by naming the service
+        let mut http = b"001e# service=git-upload-pack\n0000".to_vec();
+        http.extend_from_slice(&advertise(path, ""));
+        assert_eq!(parse(&http).unwrap(), v0);
+
+        let v2 = parse(&advertise(path, "version=2")).unwrap();
+        assert_eq!(v2.version, 2);
+        assert!(v2.refs.is_empty());
+        assert!(v2.supports("ls-refs"));
+        assert_eq!(v2.capabilities.get("fetch"), Some("shallow wait-for-done"));
+        assert_eq!(v2.agent(), v0.agent());
+
+        // an empty repository has no refs to advertise, though newer versions of Git
+        // still send capabilities and a symref for HEAD so those aren't checked
+        let empty = TempDir::new().unwrap();
+        git(empty.path(), &["init", "--quiet", "--bare"], &[]);
+        let empty = parse(&advertise(empty.path(), "")).unwrap();
+        assert!(empty.refs.is_empty());
+        assert!(empty.peeled.is_empty());
+
+        // ours parses back to what was advertised, including the placeholder ref
+        let mut buf = BytesMut::new();
+        RefAdvertisement::new()
+            .capability("side-band-64k")
+            .encode_to(&mut buf)
+            .unwrap();
+        let parsed = parse(&buf).unwrap();
+        assert!(parsed.refs.is_empty());
+        assert!(parsed.supports("side-band-64k"));
+
+        let full = advertise(path, "");
+        for malformed in [
+            &full[..full.len() - 4],
+            b"0009HEAD\n0000",
+            b"000bzz HEAD\n0000",
+            b"0001",
+            b"000eversion 3\n0000",
+            b"001e# service=git-upload-pack\n",
+        ] {
+            assert!(
+                matches!(
+                    parse(malformed),
+                    Err(crate::Error::MalformedRefAdvertisement(_))
+                ),
+                "{}",
+                String::from_utf8_lossy(malformed)
+            );
+        }
+    }
+
+    #[test]
+    fn test_ref_lines() {
+        let caps: crate::capabilities::CapabilitySet =
+            ["multi_ack", "side-band-64k", "agent=packfile/0.1"]
+                .into_iter()
+                .collect();
+
+        let mut buffer = BytesMut::new();
+        super::PktLine::ref_line_with_capabilities([1; 20], "HEAD", &caps)
+            .encode_to(&mut buffer)
+            .unwrap();
+        super::PktLine::ref_line([2; 20], "refs/heads/main")
+            .encode_to(&mut buffer)
+            .unwrap();
+
+        assert_eq!(
+            buffer.as_ref(),
+            b"005d0101010101010101010101010101010101010101 HEAD\0multi_ack side-band-64k agent=packfile/0.1\n\
+              003d0202020202020202020202020202020202020202 refs/heads/main\n"
+        );
+    }
+
+    #[test]
+    fn test_sideband_chunks_interleaved() {
+        let data = Bytes::from(vec![0xaa; 100]);
+
+        let mut chunks = super::PktLine::sideband_chunks(&data, 60);
+        let mut buffer = BytesMut::new();
+        chunks.next().unwrap().encode_to(&mut buffer).unwrap();
+        super::PktLine::SidebandMsg(b"50%")
+            .encode_to(&mut buffer)
+            .unwrap();
+        super::PktLine::Flush.encode_to(&mut buffer).unwrap();
+        chunks.next().unwrap().encode_to(&mut buffer).unwrap();
+        assert!(chunks.next().is_none());
+
+        let mut expected = Vec::new();
+        expected.extend_from_slice(b"0041\x01");
+        expected.extend_from_slice(&[0xaa; 60]);
+        expected.extend_from_slice(b"0008\x0250%");
+        expected.extend_from_slice(b"0000");
+        expected.extend_from_slice(b"002d\x01");
+        expected.extend_from_slice(&[0xaa; 40]);
+
+        assert_eq!(buffer.as_ref(), expected.as_slice());
+    }
+
     #[test]
     fn test_large_pkt_line() {
         let mut buffer = BytesMut::new();
@@ -0,0 +1,145 @@
+//! Types for responding to pushes, which clients send to `git-receive-pack`.
+//!
+//! <https://git-scm.com/docs/pack-protocol#_report_status>
+
+use bytes::BytesMut;
+
+use crate::{util::ArcOrCowStr, Error, PktLine};
+
+/// The report a server sends once it has received a push, saying whether the
+/// packfile unpacked and which of the ref updates were applied. Clients only expect
+/// this when they sent the `report-status` capability.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefUpdateReport {
+    /// Whether the packfile sent by the client was unpacked, with the reason it
+    /// wasn't if it failed.
+    pub unpack_result: Result<(), String>,
+    /// Each ref the client asked to update, in the order it asked, along with the
+    /// reason the update was rejected if it was.
+    pub updates: Vec<(ArcOrCowStr, Result<(), ArcOrCowStr>)>,
+}
+
+impl RefUpdateReport {
+    /// Parses the lines of a report, such as those decoded by
+    /// [`crate::codec::RefUpdateReportDecoder`], with any trailing newlines already
+    /// stripped.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedRefUpdateReport`] if the report doesn't start with an
+    /// `unpack` line, or any of the following lines aren't an `ok` or `ng` line.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(lines), err))]
+    pub fn parse<'a>(lines: impl IntoIterator<Item = &'a [u8]>) -> Result<Self, Error> {
+        let mut lines = lines.into_iter().map(String::from_utf8_lossy);
+
+        let unpack_result = match lines.next() {
+            Some(line) if line == "unpack ok" => Ok(()),
+            Some(line) => match line.strip_prefix("unpack ") {
+                Some(reason) => Err(reason.to_string()),
+                None => return Err(Error::MalformedRefUpdateReport(line.into_owned())),
+            },
+            None => {
+                return Err(Error::MalformedRefUpdateReport(
+                    "missing unpack status".to_string(),
+                ))
+            }
+        };
+
+        let updates = lines
+            .map(|line| {
+                if let Some(refname) = line.strip_prefix("ok ") {
+                    Ok((ArcOrCowStr::from(refname.to_string()), Ok(())))
+                } else if let Some((refname, reason)) = line
+                    .strip_prefix("ng ")
+                    .and_then(|rest| rest.split_once(' '))
+                {
+                    Ok((
+                        ArcOrCowStr::from(refname.to_string()),
+                        Err(ArcOrCowStr::from(reason.to_string())),
+                    ))
+                } else {
+                    Err(Error::MalformedRefUpdateReport(line.into_owned()))
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            unpack_result,
+            updates,
+        })
+    }
+
+    /// Encodes the report into `buf` as a packet per line, followed by a flush.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if any of the packets fail to serialise.
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        let unpack = match &self.unpack_result {
+            Ok(()) => "unpack ok\n".to_string(),
+            Err(reason) => format!("unpack {reason}\n"),
+        };
+        PktLine::Data(unpack.as_bytes()).encode_to(buf)?;
+
+        for (refname, result) in &self.updates {
+            let line = match result {
+                Ok(()) => format!("ok {refname}\n"),
+                Err(reason) => format!("ng {refname} {reason}\n"),
+            };
+            PktLine::Data(line.as_bytes()).encode_to(buf)?;
+        }
+
+        PktLine::Flush.encode_to(buf)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{push::RefUpdateReport, Error};
+
+    #[test]
+    fn parse() {
+        let report = RefUpdateReport::parse([
+            &b"unpack ok"[..],
+            b"ok refs/heads/main",
+            b"ng refs/heads/locked pre-receive hook declined",
+        ])
+        .unwrap();
+        assert_eq!(
+            report,
+            RefUpdateReport {
+                unpack_result: Ok(()),
+                updates: vec![
+                    ("refs/heads/main".into(), Ok(())),
+                    (
+                        "refs/heads/locked".into(),
+                        Err("pre-receive hook declined".into())
+                    ),
+                ],
+            }
+        );
+
+        let report = RefUpdateReport::parse([&b"unpack index-pack abnormal exit"[..]]).unwrap();
+        assert_eq!(
+            report.unpack_result,
+            Err("index-pack abnormal exit".to_string())
+        );
+        assert!(report.updates.is_empty());
+
+        for lines in [
+            &[][..],
+            &[&b"ok refs/heads/main"[..]],
+            &[b"unpack ok", b"ng refs/heads/main"],
+            &[b"unpack ok", b"maybe refs/heads/main"],
+        ] {
+            assert!(
+                matches!(
+                    RefUpdateReport::parse(lines.iter().copied()),
+                    Err(Error::MalformedRefUpdateReport(_))
+                ),
+                "{lines:?} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,576 @@
+//! Random access to the objects within an encoded packfile.
+
+use std::{
+    collections::{HashMap, HashSet},
+    io::Read,
+};
+
+use bytes::Bytes;
+use flate2::read::ZlibDecoder;
+
+use crate::{
+    low_level::{
+        parse_tree, read_entry_header, Commit, HashOutput, ObjectKind, PackFile, PackFileEntry, Tag,
+    },
+    Error,
+};
+
+/// The deepest chain of deltas that will be followed when reading an object. This is
+/// the most `git pack-objects` will ever write, whatever `pack.depth` is set to.
+const MAX_DELTA_DEPTH: usize = 4095;
+
+/// Reads objects out of an encoded packfile by their offset within it, such as the
+/// offsets held in the pack's index. Objects are only decompressed when they're read.
+pub struct PackFileReader {
+    data: Bytes,
+    entries: u32,
+    /// The offset of each object in the packfile, used to find the bases of ref deltas.
+    offsets: HashMap<HashOutput, u64>,
+}
+
+/// A single object read by [`PackFileReader::step`] while resolving a delta chain.
+enum Step {
+    Object(ObjectKind, Bytes),
+    Delta { delta: Bytes, base_offset: u64 },
+}
+
+impl PackFileReader {
+    /// Reads the header of the packfile in `data`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::MalformedPackFile`] if `data` doesn't start with a version 2
+    /// packfile header.
+    pub fn new(data: Bytes) -> Result<Self, Error> {
+        if data.len() < PackFile::header_size() + PackFile::footer_size() {
+            return Err(Error::MalformedPackFile("truncated header".to_string()));
+        }
+
+        if &data[..4] != b"PACK" {
+            return Err(Error::MalformedPackFile("invalid magic".to_string()));
+        }
+
+        let version = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+        if version != 2 {
+            return Err(Error::MalformedPackFile(format!(
+                "unsupported version {version}"
+            )));
+        }
+
+        Ok(Self {
+            entries: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+            data,
+            offsets: HashMap::new(),
+        })
+    }
+
+    /// Sets the offset of each object within the packfile, which is required to read
+    /// any ref deltas since they refer to their base by hash.
+    #[must_use]
+    pub fn with_offsets(mut self, offsets: HashMap<HashOutput, u64>) -> Self {
+        self.offsets = offsets;
+        self
+    }
+
+    /// The number of objects the packfile's header says it contains.
+    #[must_use]
+    pub fn entry_count(&self) -> u32 {
+        self.entries
+    }
+
+    /// Reads the object starting at `offset` bytes into the packfile, resolving it
+    /// against its base if it's a delta.
+    ///
+    /// Commits are parsed with [`Commit::parse`], so any headers it doesn't keep
+    /// will be missing from the returned entry.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the object (or any of the bases it's built from) is
+    /// malformed, or if the base of a ref delta isn't in the offsets given to
+    /// [`PackFileReader::with_offsets`].
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn object_at(&self, offset: u64) -> Result<PackFileEntry, Error> {
+        let (kind, body) = self.resolve(offset)?;
+
+        Ok(match kind {
+            ObjectKind::Commit => PackFileEntry::Commit(Commit::parse(&body)?),
+            ObjectKind::Tree => PackFileEntry::Tree(parse_tree(&body)?),
+            ObjectKind::Blob => PackFileEntry::Blob(body),
+            ObjectKind::Tag => PackFileEntry::Tag(Tag::parse(&body)?),
+            ObjectKind::OfsDelta | ObjectKind::RefDelta => {
+                unreachable!("deltas are resolved to their base's kind")
+            }
+        })
+    }
+
+    /// Reads the object starting at `offset` bytes into the packfile as a
+    /// [`PackFileEntry::Compressed`], so it can be copied into another packfile without
+    /// being compressed again. The object is still inflated once to find its hash.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidRawKind`] if the object is a delta, since it can only
+    /// be copied along with its base, or an error if the object is malformed.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self), err))]
+    pub fn compressed_at(&self, offset: u64) -> Result<PackFileEntry, Error> {
+        let (kind, size, data) = self.entry_at(offset)?;
+        if kind.is_delta() {
+            return Err(Error::InvalidRawKind(kind));
+        }
+
+        let (body, consumed) = self.inflate(data, size, offset)?;
+        let hash = PackFileEntry::Raw { kind, data: body }.hash()?;
+
+        Ok(PackFileEntry::Compressed {
+            kind,
+            uncompressed_size: size as u64,
+            zlib_data: self.data.slice_ref(&data[..consumed]),
+            hash,
+        })
+    }
+
+    /// Reads the header of the entry at `offset`, returning its kind, its
+    /// uncompressed size and the rest of the packfile after the header.
+    fn entry_at(&self, offset: u64) -> Result<(ObjectKind, usize, &[u8]), Error> {
+        let malformed = |reason: &str| {
+            Error::MalformedPackFile(format!("{reason} in object at offset {offset}"))
+        };
+
+        // the footer is the pack's checksum, so no objects can start in it
+        let end = self.data.len() - PackFile::footer_size();
+        let data = usize::try_from(offset)
+            .ok()
+            .filter(|&offset| offset >= PackFile::header_size() && offset < end)
+            .map(|offset| &self.data[offset..end])
+            .ok_or_else(|| malformed("offset out of bounds"))?;
+
+        let (kind, size, header_len) = read_entry_header(data).map_err(|e| match e {
+            Error::MalformedEntryHeader(reason) => malformed(reason),
+            e => e,
+        })?;
+        let size = usize::try_from(size).map_err(|_| malformed("size overflows"))?;
+
+        Ok((kind, size, &data[header_len..]))
+    }
+
+    /// Reads the type and uncompressed body of the object at `offset`, following the
+    /// chain of deltas down to a full object then applying them back up to it.
+    fn resolve(&self, offset: u64) -> Result<(ObjectKind, Bytes), Error> {
+        let mut deltas = Vec::new();
+        let mut visited = HashSet::new();
+        let mut next = offset;
+
+        let (kind, mut body) = loop {
+            if !visited.insert(next) {
+                return Err(Error::MalformedPackFile(format!(
+                    "delta chain at offset {offset} refers back to offset {next}"
+                )));
+            }
+
+            if deltas.len() > MAX_DELTA_DEPTH {
+                return Err(Error::MalformedPackFile(format!(
+                    "delta chain at offset {offset} is too deep"
+                )));
+            }
+
+            match self.step(next)? {
+                Step::Object(kind, body) => break (kind, body),
+                Step::Delta { delta, base_offset } => {
+                    deltas.push(delta);
+                    next = base_offset;
+                }
+            }
+        };
+
+        for delta in deltas.iter().rev() {
+            body = crate::delta::apply(&body, delta)?;
+        }
+
+        Ok((kind, body))
+    }
+
+    /// Reads the object at `offset`, returning either the full object or the delta
+    /// along with the offset of its base.
+    fn step(&self, offset: u64) -> Result<Step, Error> {
+        let malformed = |reason: &str| {
+            Error::MalformedPackFile(format!("{reason} in object at offset {offset}"))
+        };
+
+        let (kind, size, mut data) = self.entry_at(offset)?;
+
+        match kind {
+            ObjectKind::Commit | ObjectKind::Tree | ObjectKind::Blob | ObjectKind::Tag => {
+                Ok(Step::Object(kind, self.inflate(data, size, offset)?.0))
+            }
+            ObjectKind::OfsDelta => {
+                // the base's offset is stored relative to this object as a big-endian
+                // varint, where each continuation adds one to the value so far
+                let mut next_byte = || {
+                    let (&byte, rest) = data
+                        .split_first()
+                        .ok_or_else(|| malformed("truncated base offset"))?;
+                    data = rest;
+                    Ok::<_, Error>(byte)
+                };
+
+                let mut byte = next_byte()?;
+                let mut relative = u64::from(byte & 0b111_1111);
+                while byte & 0b1000_0000 != 0 {
+                    byte = next_byte()?;
+                    relative = relative
+                        .checked_add(1)
+                        .and_then(|v| v.checked_mul(1 << 7))
+                        .ok_or_else(|| malformed("base offset overflows"))?
+                        | u64::from(byte & 0b111_1111);
+                }
+
+                let base_offset = offset
+                    .checked_sub(relative)
+                    .filter(|_| relative != 0)
+                    .ok_or_else(|| malformed("base offset out of bounds"))?;
+
+                let (delta, _) = self.inflate(data, size, offset)?;
+                Ok(Step::Delta { delta, base_offset })
+            }
+            ObjectKind::RefDelta => {
+                let base_hash: HashOutput = data
+                    .get(..20)
+                    .and_then(|hash| hash.try_into().ok())
+                    .ok_or_else(|| malformed("truncated base hash"))?;
+
+                let base_offset = *self.offsets.get(&base_hash).ok_or_else(|| {
+                    malformed(&format!("unknown base {}", hex::encode(base_hash)))
+                })?;
+
+                let (delta, _) = self.inflate(&data[20..], size, offset)?;
+                Ok(Step::Delta { delta, base_offset })
+            }
+        }
+    }
+
+    /// Decompresses the zlib stream at the start of `data`, which should inflate to
+    /// exactly `size` bytes, returning the inflated data and the length of the stream.
+    fn inflate(&self, data: &[u8], size: usize, offset: u64) -> Result<(Bytes, usize), Error> {
+        // zlib can't compress by more than ~1032:1, so cap the allocation rather than
+        // trusting the size from the header outright
+        let mut out = Vec::with_capacity(size.min(self.data.len().saturating_mul(1032)));
+
+        let mut decoder = ZlibDecoder::new(data).take(size as u64 + 1);
+        decoder.read_to_end(&mut out).map_err(Error::Decompress)?;
+
+        if out.len() != size {
+            return Err(Error::MalformedPackFile(format!(
+                "object at offset {offset} inflated to {} bytes, expected {size}",
+                out.len()
+            )));
+        }
+
+        // the stream is only read up to its end, so this is bounded by `data`
+        #[allow(clippy::cast_possible_truncation)]
+        let consumed = decoder.into_inner().total_in() as usize;
+
+        Ok((Bytes::from(out), consumed))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use bytes::{Bytes, BytesMut};
+
+    use crate::{
+        high_level::GitRepository,
+        low_level::{ObjectKind, PackFile, PackFileEntry},
+        reader::PackFileReader,
+        Error,
+    };
+
+    #[test]
+    fn object_at() {
+        let mut repo = GitRepository::default();
+        repo.insert(&["a", "b"], "c.txt", Bytes::from("hello world!"))
+            .unwrap();
+        repo.insert(&[], "d.txt", Bytes::from(vec![0; 1024]))
+            .unwrap();
+        let (_hash, _tag, mut entries) = repo
+            .commit_and_tag("me", "me@example.com", "initial commit", "v1", "release\n")
+            .unwrap();
+
+        let base = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(10)));
+        let target = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(11)));
+        let delta = PackFileEntry::ref_delta(&base, &target).unwrap();
+        entries.extend([base, delta]);
+
+        let mut pack = BytesMut::new();
+        let mut offsets = HashMap::new();
+        PackFile::new(&entries)
+            .encode_recording(&mut pack, |entry, offset, _| {
+                offsets.insert(entry.hash()?, offset);
+                Ok(())
+            })
+            .unwrap();
+
+        let reader = PackFileReader::new(pack.freeze())
+            .unwrap()
+            .with_offsets(offsets.clone());
+        assert_eq!(reader.entry_count() as usize, entries.len());
+
+        for entry in &entries {
+            let hash = entry.hash().unwrap();
+            let actual = reader.object_at(offsets[&hash]).unwrap();
+
+            assert_eq!(actual.hash().unwrap(), hash);
+            assert!(!matches!(actual, PackFileEntry::RefDelta { .. }));
+        }
+
+        assert!(matches!(
+            reader.object_at(offsets[&target.hash().unwrap()]).unwrap(),
+            PackFileEntry::Blob(data) if data == "hello world\n".repeat(11)
+        ));
+    }
+
+    #[test]
+    fn object_at_ofs_delta() {
+        // git repacks the objects using offset deltas
+        let mut repo = GitRepository::default();
+        for i in 0..10 {
+            repo.insert(
+                &[],
+                format!("{i}.txt"),
+                Bytes::from("hello world\n".repeat(100 + i)),
+            )
+            .unwrap();
+        }
+        let (_hash, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let mut pack = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut pack).unwrap();
+        let dir = crate::test::bare_repo_with_pack(&pack);
+
+        let mut objects = entries
+            .iter()
+            .map(|entry| hex::encode(entry.hash().unwrap()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        objects.push('\n');
+        let name = crate::test::git(
+            dir.path(),
+            &[
+                "pack-objects",
+                "--delta-base-offset",
+                "--window=10",
+                "--depth=10",
+                "out",
+            ],
+            objects.as_bytes(),
+        );
+        let pack_path = dir.path().join(format!("out-{}.pack", name.trim()));
+        let verify = crate::test::git(
+            dir.path(),
+            &["verify-pack", "-v", pack_path.to_str().unwrap()],
+            &[],
+        );
+
+        let reader = PackFileReader::new(Bytes::from(std::fs::read(&pack_path).unwrap())).unwrap();
+
+        let mut deltas = 0;
+        for line in verify.lines() {
+            // `<hash> <type> <size> <size in pack> <offset> [<depth> <base>]`
+            let parts: Vec<_> = line.split_whitespace().collect();
+            if parts.len() < 5 || parts[0].len() != 40 {
+                continue;
+            }
+            if parts.len() == 7 {
+                deltas += 1;
+            }
+
+            let entry = reader.object_at(parts[4].parse().unwrap()).unwrap();
+            assert_eq!(hex::encode(entry.hash().unwrap()), parts[0]);
+        }
+        assert!(deltas > 0, "expected git to write some deltas:\n{verify}");
+    }
+
+    #[test]
+    fn compressed_at() {
+        let mut repo = GitRepository::default();
+        repo.insert(&["a", "b"], "c.txt", Bytes::from("hello world!"))
+            .unwrap();
+        repo.insert(&[], "d.txt", Bytes::from(vec![0; 1024]))
+            .unwrap();
+        let (_hash, mut entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let base = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(10)));
+        let target = PackFileEntry::Blob(Bytes::from("hello world\n".repeat(11)));
+        let delta = PackFileEntry::ref_delta(&base, &target).unwrap();
+        entries.extend([base, delta]);
+
+        let mut pack = BytesMut::new();
+        let offsets = PackFile::new(&entries)
+            .encode_to_indexed(&mut pack)
+            .unwrap();
+        let pack = pack.freeze();
+        let reader = PackFileReader::new(pack.clone()).unwrap();
+
+        let (delta_offset, base_offsets) = offsets.split_last().unwrap();
+        assert!(matches!(
+            reader.compressed_at(delta_offset.1),
+            Err(Error::InvalidRawKind(ObjectKind::RefDelta))
+        ));
+
+        let compressed: Vec<_> = base_offsets
+            .iter()
+            .map(|(hash, offset)| {
+                let entry = reader.compressed_at(*offset).unwrap();
+                assert_eq!(entry.hash().unwrap(), *hash);
+                entry.verify().unwrap();
+                entry
+            })
+            .collect();
+
+        // the entries are copied verbatim, so re-encoding them gives the same bytes
+        let mut expected = BytesMut::new();
+        PackFile::new(&entries[..entries.len() - 1])
+            .encode_to(&mut expected)
+            .unwrap();
+        let mut actual = BytesMut::new();
+        PackFile::new(&compressed).encode_to(&mut actual).unwrap();
+        assert_eq!(actual, expected);
+
+        crate::test::verify_pack_file(actual.freeze());
+    }
+
+    #[test]
+    fn compressed_verify() {
+        let mut pack = BytesMut::new();
+        PackFile::new(&[PackFileEntry::Blob(Bytes::from("hello"))])
+            .encode_to(&mut pack)
+            .unwrap();
+        let reader = PackFileReader::new(pack.freeze()).unwrap();
+
+        let PackFileEntry::Compressed {
+            kind,
+            uncompressed_size,
+            zlib_data,
+            hash,
+        } = reader.compressed_at(12).unwrap()
+        else {
+            panic!("expected a compressed entry");
+        };
+
+        for entry in [
+            PackFileEntry::Compressed {
+                kind,
+                uncompressed_size,
+                zlib_data: zlib_data.clone(),
+                hash: [0; 20],
+            },
+            PackFileEntry::Compressed {
+                kind,
+                uncompressed_size: uncompressed_size + 1,
+                zlib_data: zlib_data.clone(),
+                hash,
+            },
+            PackFileEntry::Compressed {
+                kind: ObjectKind::Tree,
+                uncompressed_size,
+                zlib_data: zlib_data.clone(),
+                hash,
+            },
+        ] {
+            assert!(matches!(
+                entry.verify(),
+                Err(Error::CompressedEntryMismatch(_))
+            ));
+        }
+
+        let truncated = PackFileEntry::Compressed {
+            kind,
+            uncompressed_size,
+            zlib_data: zlib_data.slice(..zlib_data.len() - 3),
+            hash,
+        };
+        assert!(truncated.verify().is_err());
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(PackFileReader::new(Bytes::from_static(b"PACK")).is_err());
+        assert!(PackFileReader::new(Bytes::from(vec![0; 32])).is_err());
+
+        let mut pack = BytesMut::new();
+        PackFile::new(&[PackFileEntry::Blob(Bytes::from("hello"))])
+            .encode_to(&mut pack)
+            .unwrap();
+        let reader = PackFileReader::new(pack.freeze()).unwrap();
+
+        assert!(reader.object_at(12).is_ok());
+        assert!(reader.object_at(0).is_err());
+        assert!(reader.object_at(13).is_err());
+        assert!(reader.object_at(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn self_referencing_delta() {
+        use crate::low_level::write_entry_header;
+        use flate2::{write::ZlibEncoder, Compression};
+        use sha1::Digest;
+        use std::io::Write;
+
+        // a ref delta whose base is itself, which has to be rejected rather than
+        // followed forever
+        let delta = [5, 5, 0b1000_0000 | 0b0001_0000, 5];
+        let mut zlib = ZlibEncoder::new(Vec::new(), Compression::default());
+        zlib.write_all(&delta).unwrap();
+
+        let mut pack = BytesMut::new();
+        PackFile::write_header(1, &mut pack);
+        write_entry_header(ObjectKind::RefDelta, delta.len() as u64, &mut pack);
+        pack.extend_from_slice(&[1; 20]);
+        pack.extend_from_slice(&zlib.finish().unwrap());
+        let trailer = sha1::Sha1::digest(&pack);
+        pack.extend_from_slice(&trailer);
+
+        let reader = PackFileReader::new(pack.freeze())
+            .unwrap()
+            .with_offsets(HashMap::from([([1; 20], 12)]));
+        assert!(matches!(
+            reader.object_at(12),
+            Err(Error::MalformedPackFile(reason)) if reason.contains("refers back")
+        ));
+    }
+
+    #[test]
+    fn malformed_header() {
+        let entries = [PackFileEntry::Blob(Bytes::from("hello"))];
+
+        for (magic, version, reason) in [
+            (*b"PACX", 2, "invalid magic"),
+            (*b"PACK", 3, "unsupported version 3"),
+            (*b"PACK", 1, "unsupported version 1"),
+        ] {
+            let mut pack = BytesMut::new();
+            PackFile::new(&entries)
+                .encode_to_with_header(&mut pack, magic, version)
+                .unwrap();
+
+            assert!(matches!(
+                PackFileReader::new(pack.freeze()),
+                Err(Error::MalformedPackFile(actual)) if actual == reason
+            ));
+        }
+
+        let mut pack = BytesMut::new();
+        PackFile::new(&entries)
+            .encode_to_with_header(&mut pack, *b"PACK", 2)
+            .unwrap();
+        let mut expected = BytesMut::new();
+        PackFile::new(&entries).encode_to(&mut expected).unwrap();
+        assert_eq!(pack, expected);
+    }
+}
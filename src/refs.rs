@@ -0,0 +1,261 @@
+//! Builders for the files Git uses to store references on disk.
+
+use std::fmt::Write;
+
+use bytes::BytesMut;
+
+use crate::{low_level::HashOutput, Error};
+
+/// The header written to the start of every `packed-refs` file, this tells Git that
+/// every annotated tag has its peeled value written directly after it and that the
+/// refs are sorted by name so it can binary search them.
+const PACKED_REFS_HEADER: &str = "# pack-refs with: peeled fully-peeled sorted \n";
+
+/// A `packed-refs` file, containing a list of refs and the objects they point to.
+///
+/// <https://git-scm.com/docs/git-pack-refs>
+pub struct PackedRefs<'a> {
+    refs: Vec<&'a (String, HashOutput, Option<HashOutput>)>,
+}
+
+impl<'a> PackedRefs<'a> {
+    /// Builds a `packed-refs` file from a list of `(refname, hash, peeled)` tuples,
+    /// where `peeled` is the hash of the object an annotated tag points to.
+    #[must_use]
+    pub fn new(refs: &'a [(String, HashOutput, Option<HashOutput>)]) -> Self {
+        let mut refs: Vec<_> = refs.iter().collect();
+
+        // git binary searches the file, so we need the refs sorted by name
+        refs.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        Self { refs }
+    }
+
+    /// Writes the `packed-refs` file out to `buf`.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::InvalidRefName`] if any of the refnames are invalid, or an
+    /// error if the file fails to serialise.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(self, buf), err))]
+    pub fn encode_to(&self, buf: &mut BytesMut) -> Result<(), Error> {
+        for (name, _, _) in &self.refs {
+            validate_refname(name)?;
+        }
+
+        buf.write_str(PACKED_REFS_HEADER)?;
+
+        for (name, hash, peeled) in &self.refs {
+            writeln!(buf, "{} {name}", hex::encode(hash))?;
+
+            if let Some(peeled) = peeled {
+                writeln!(buf, "^{}", hex::encode(peeled))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Ensures `name` follows the rules Git has for refnames, as described by
+/// `git check-ref-format --allow-onelevel`. Single-level names such as `HEAD` are
+/// allowed, so callers storing refs under `refs/` should check that separately.
+///
+/// <https://git-scm.com/docs/git-check-ref-format>
+///
+/// # Errors
+///
+/// Returns [`Error::InvalidRefName`] if the name breaks any of the rules.
+pub fn validate_refname(name: &str) -> Result<(), Error> {
+    let invalid = || Error::InvalidRefName(name.to_string());
+
+    if name.is_empty()
+        || name == "@"
+        || name.ends_with('.')
+        || name.contains("..")
+        || name.contains("@{")
+    {
+        return Err(invalid());
+    }
+
+    // rejects control characters, and characters with special meaning in revisions
+    // and globs
+    if name.chars().any(|c| {
+        c.is_ascii_control() || matches!(c, ' ' | '~' | '^' | ':' | '?' | '*' | '[' | '\\')
+    }) {
+        return Err(invalid());
+    }
+
+    // this also catches leading, trailing and consecutive slashes, as they all leave
+    // an empty component
+    #[allow(clippy::case_sensitive_file_extension_comparisons)] // git is case-sensitive
+    for component in name.split('/') {
+        if component.is_empty() || component.starts_with('.') || component.ends_with(".lock") {
+            return Err(invalid());
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    mod validate_refname {
+        use crate::refs::validate_refname;
+        use std::process::Command;
+
+        const VALID: &[&str] = &[
+            "HEAD",
+            "refs/heads/main",
+            "refs/heads/feature/my-branch",
+            "refs/tags/v1.0.0",
+            "refs/heads/ünicode",
+            "refs/heads/a.lockb",
+            "refs/heads/with@sign",
+        ];
+
+        const INVALID: &[&str] = &[
+            "",
+            "@",
+            "refs/heads/has space",
+            "refs/heads/double..dot",
+            "refs/heads/.hidden",
+            "refs/heads/branch.lock",
+            "refs/heads/trailing.",
+            "refs/heads/trailing/",
+            "/refs/heads/leading",
+            "refs//heads/double-slash",
+            "refs/heads/tilde~1",
+            "refs/heads/caret^",
+            "refs/heads/colon:",
+            "refs/heads/question?",
+            "refs/heads/star*",
+            "refs/heads/bracket[",
+            "refs/heads/back\\slash",
+            "refs/heads/reflog@{1}",
+            "refs/heads/control\x07",
+            "refs/heads/delete\x7f",
+        ];
+
+        #[test]
+        fn valid() {
+            for name in VALID {
+                assert!(validate_refname(name).is_ok(), "{name:?} should be valid");
+            }
+        }
+
+        #[test]
+        fn invalid() {
+            for name in INVALID {
+                assert!(
+                    matches!(validate_refname(name), Err(crate::Error::InvalidRefName(n)) if n == *name),
+                    "{name:?} should be invalid"
+                );
+            }
+        }
+
+        #[test]
+        fn matches_git() {
+            for name in VALID.iter().chain(INVALID) {
+                let git_valid = Command::new("git")
+                    .args(["check-ref-format", "--allow-onelevel", name])
+                    .status()
+                    .unwrap()
+                    .success();
+
+                assert_eq!(validate_refname(name).is_ok(), git_valid, "{name:?}");
+            }
+        }
+    }
+
+    mod packed_refs {
+        use crate::{high_level::GitRepository, low_level::PackFile, refs::PackedRefs};
+        use bytes::{Bytes, BytesMut};
+        use std::process::Command;
+
+        #[test]
+        fn encode() {
+            let refs = [
+                ("refs/tags/v1".to_string(), [2; 20], Some([1; 20])),
+                ("refs/heads/main".to_string(), [1; 20], None),
+            ];
+
+            let mut actual = BytesMut::new();
+            PackedRefs::new(&refs).encode_to(&mut actual).unwrap();
+
+            assert_eq!(
+                std::str::from_utf8(&actual).unwrap(),
+                "# pack-refs with: peeled fully-peeled sorted \n\
+                0101010101010101010101010101010101010101 refs/heads/main\n\
+                0202020202020202020202020202020202020202 refs/tags/v1\n\
+                ^0101010101010101010101010101010101010101\n"
+            );
+        }
+
+        #[test]
+        fn encode_invalid_refname() {
+            let refs = [
+                ("refs/heads/main".to_string(), [1; 20], None),
+                ("refs/heads/bad..name".to_string(), [1; 20], None),
+            ];
+
+            let mut actual = BytesMut::new();
+            assert!(matches!(
+                PackedRefs::new(&refs).encode_to(&mut actual),
+                Err(crate::Error::InvalidRefName(_))
+            ));
+            assert!(actual.is_empty());
+        }
+
+        #[test]
+        fn is_readable_by_git() {
+            let mut repo = GitRepository::default();
+            repo.insert(&[], "c.txt", Bytes::from("hello world!"))
+                .unwrap();
+            let (commit, entries) = repo
+                .commit("me", "me@example.com", "initial commit")
+                .unwrap();
+
+            let mut pack = BytesMut::new();
+            PackFile::new(&entries).encode_to(&mut pack).unwrap();
+            let dir = crate::test::bare_repo_with_pack(&pack);
+
+            let tag = crate::test::git(
+                dir.path(),
+                &["mktag"],
+                format!(
+                    "object {}\ntype commit\ntag v1\ntagger me <me@example.com> 0 +0000\n\nv1\n",
+                    hex::encode(commit),
+                )
+                .as_bytes(),
+            );
+            let mut tag_hash = [0; 20];
+            hex::decode_to_slice(tag.trim(), &mut tag_hash).unwrap();
+
+            let refs = [
+                ("refs/tags/v1".to_string(), tag_hash, Some(commit)),
+                ("refs/heads/main".to_string(), commit, None),
+            ];
+
+            let mut packed_refs = BytesMut::new();
+            PackedRefs::new(&refs).encode_to(&mut packed_refs).unwrap();
+            std::fs::write(dir.path().join("packed-refs"), packed_refs).unwrap();
+
+            let out = Command::new("git")
+                .args(["show-ref", "--dereference"])
+                .current_dir(dir.path())
+                .output()
+                .unwrap();
+            assert!(out.status.success(), "git exited non-0");
+
+            assert_eq!(
+                String::from_utf8(out.stdout).unwrap(),
+                format!(
+                    "{commit} refs/heads/main\n{tag} refs/tags/v1\n{commit} refs/tags/v1^{{}}\n",
+                    commit = hex::encode(commit),
+                    tag = hex::encode(tag_hash),
+                )
+            );
+        }
+    }
+}
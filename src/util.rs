@@ -1,18 +1,32 @@
 use std::hash::Hasher;
 use std::{
     borrow::Cow,
-    fmt::{Display, Formatter},
+    fmt::{Debug, Display, Formatter},
     hash::Hash,
     ops::Deref,
     sync::Arc,
 };
 
-#[derive(Debug, Eq)]
+use crate::{low_level::HashOutput, Error};
+
+/// A string that is either reference counted, or a (usually `'static`) [`Cow`].
+#[derive(Debug, Clone, Eq)]
 pub enum ArcOrCowStr {
     Arc(Arc<str>),
     Cow(Cow<'static, str>),
 }
 
+impl ArcOrCowStr {
+    /// Converts a slice of bytes to a string, replacing any invalid UTF-8 sequences with
+    /// `U+FFFD REPLACEMENT CHARACTER`, in the same way as [`String::from_utf8_lossy`].
+    ///
+    /// The input isn't `'static` so the returned value always owns its data.
+    #[must_use]
+    pub fn from_utf8_lossy(bytes: &[u8]) -> Self {
+        Self::Cow(Cow::Owned(String::from_utf8_lossy(bytes).into_owned()))
+    }
+}
+
 impl Hash for ArcOrCowStr {
     fn hash<H: Hasher>(&self, state: &mut H) {
         (**self).hash(state);
@@ -58,6 +72,14 @@ impl AsRef<str> for ArcOrCowStr {
     }
 }
 
+// hashes and compares the same as the string itself, so maps keyed by this can be
+// looked up by `&str`
+impl std::borrow::Borrow<str> for ArcOrCowStr {
+    fn borrow(&self) -> &str {
+        self
+    }
+}
+
 impl Deref for ArcOrCowStr {
     type Target = str;
 
@@ -72,8 +94,181 @@ impl Display for ArcOrCowStr {
     }
 }
 
+/// A byte string that is either reference counted, or a (usually `'static`) [`Cow`].
+///
+/// Git paths are byte strings that aren't necessarily UTF-8, such as the Latin-1 names
+/// of files committed on older systems, so the names of tree items are kept as bytes
+/// to be written back out verbatim.
+#[derive(Clone, Eq)]
+pub enum ArcOrCowBytes {
+    Arc(Arc<[u8]>),
+    Cow(Cow<'static, [u8]>),
+}
+
+impl ArcOrCowBytes {
+    /// Converts the bytes to a string, replacing any invalid UTF-8 sequences with
+    /// `U+FFFD REPLACEMENT CHARACTER`, for showing the name to a user.
+    #[must_use]
+    pub fn to_str_lossy(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self)
+    }
+}
+
+impl Hash for ArcOrCowBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state);
+    }
+}
+
+impl PartialEq<ArcOrCowBytes> for ArcOrCowBytes {
+    fn eq(&self, other: &ArcOrCowBytes) -> bool {
+        **self == **other
+    }
+}
+
+impl From<Arc<[u8]>> for ArcOrCowBytes {
+    fn from(v: Arc<[u8]>) -> Self {
+        Self::Arc(v)
+    }
+}
+
+impl From<&'static [u8]> for ArcOrCowBytes {
+    fn from(v: &'static [u8]) -> Self {
+        Self::Cow(Cow::Borrowed(v))
+    }
+}
+
+impl From<Vec<u8>> for ArcOrCowBytes {
+    fn from(v: Vec<u8>) -> Self {
+        Self::Cow(Cow::Owned(v))
+    }
+}
+
+impl From<&'static str> for ArcOrCowBytes {
+    fn from(v: &'static str) -> Self {
+        Self::Cow(Cow::Borrowed(v.as_bytes()))
+    }
+}
+
+impl From<String> for ArcOrCowBytes {
+    fn from(v: String) -> Self {
+        Self::Cow(Cow::Owned(v.into_bytes()))
+    }
+}
+
+impl From<ArcOrCowStr> for ArcOrCowBytes {
+    fn from(v: ArcOrCowStr) -> Self {
+        match v {
+            ArcOrCowStr::Arc(v) => Self::Arc(v.into()),
+            ArcOrCowStr::Cow(Cow::Borrowed(v)) => Self::Cow(Cow::Borrowed(v.as_bytes())),
+            ArcOrCowStr::Cow(Cow::Owned(v)) => Self::Cow(Cow::Owned(v.into_bytes())),
+        }
+    }
+}
+
+impl AsRef<[u8]> for ArcOrCowBytes {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::Arc(v) => v.as_ref(),
+            Self::Cow(v) => v.as_ref(),
+        }
+    }
+}
+
+// hashes and compares the same as the bytes themselves, so maps keyed by this can be
+// looked up by `&[u8]`
+impl std::borrow::Borrow<[u8]> for ArcOrCowBytes {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Deref for ArcOrCowBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        self.as_ref()
+    }
+}
+
+// written as a byte string literal, so names that aren't UTF-8 are still readable
+impl Debug for ArcOrCowBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "b\"{}\"", self.escape_ascii())
+    }
+}
+
+impl Display for ArcOrCowBytes {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        std::fmt::Display::fmt(&self.to_str_lossy(), f)
+    }
+}
+
+/// Decodes a 40 character hex-encoded hash, such as an object id sent by a client.
+///
+/// # Errors
+///
+/// Returns [`Error::DecodeHash`] if `hex_str` contains anything other than hex
+/// digits, or isn't exactly 40 characters long.
+pub fn decode_hash(hex_str: &str) -> Result<HashOutput, Error> {
+    let mut out = HashOutput::default();
+    hex::decode_to_slice(hex_str, &mut out)?;
+    Ok(out)
+}
+
 #[cfg(test)]
 mod test {
+    mod decode_hash {
+        use crate::{util::decode_hash, Error};
+
+        #[test]
+        fn valid() {
+            assert_eq!(
+                decode_hash("0102030405060708090a0b0c0d0e0f1011121314").unwrap(),
+                [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20]
+            );
+            assert_eq!(
+                decode_hash("FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF").unwrap(),
+                [0xff; 20]
+            );
+        }
+
+        #[test]
+        fn invalid_characters() {
+            assert!(matches!(
+                decode_hash("010203040506070809 a0b0c0d0e0f1011121314"),
+                Err(Error::DecodeHash(hex::FromHexError::InvalidHexCharacter {
+                    c: ' ',
+                    index: 18
+                }))
+            ));
+            assert!(matches!(
+                decode_hash("zz02030405060708090a0b0c0d0e0f1011121314"),
+                Err(Error::DecodeHash(
+                    hex::FromHexError::InvalidHexCharacter { .. }
+                ))
+            ));
+        }
+
+        #[test]
+        fn wrong_length() {
+            for input in ["", "0102", "0102030405060708090a0b0c0d0e0f101112131415"] {
+                assert!(
+                    matches!(
+                        decode_hash(input),
+                        Err(Error::DecodeHash(hex::FromHexError::InvalidStringLength))
+                    ),
+                    "{input:?} should be rejected"
+                );
+            }
+
+            assert!(matches!(
+                decode_hash("0102030405060708090a0b0c0d0e0f101112131"),
+                Err(Error::DecodeHash(hex::FromHexError::OddLength))
+            ));
+        }
+    }
+
     mod arc_or_cow_str {
         use crate::util::ArcOrCowStr;
         use std::borrow::Cow;
@@ -95,6 +290,22 @@ mod test {
             );
         }
 
+        #[test]
+        fn from_utf8_lossy_valid() {
+            assert_eq!(
+                ArcOrCowStr::from_utf8_lossy(b"hello world"),
+                "hello world".into()
+            );
+        }
+
+        #[test]
+        fn from_utf8_lossy_invalid() {
+            assert_eq!(
+                ArcOrCowStr::from_utf8_lossy(b"hello \xF0\x90\x80world"),
+                "hello \u{FFFD}world".into()
+            );
+        }
+
         #[test]
         fn from_string() {
             assert_eq!(
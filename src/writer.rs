@@ -0,0 +1,235 @@
+//! Writes packfiles, along with their indexes, out to disk.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use bytes::BytesMut;
+
+use crate::{
+    error::io_error_at,
+    index::{self, IndexEntry},
+    low_level::{HashOutput, PackFile, PackFileEntry},
+    Error,
+};
+
+/// The paths written to by [`PackFileWriter::write_pair`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackFileWriteResult {
+    /// The checksum of the packfile, which the files are named after.
+    pub pack_hash: HashOutput,
+    pub pack_path: PathBuf,
+    pub index_path: PathBuf,
+}
+
+/// Writes a packfile and its index to a directory, such as `.git/objects/pack`.
+pub struct PackFileWriter;
+
+impl PackFileWriter {
+    /// Writes `entries` to `pack_dir` as a packfile named `pack-<hash>.pack` alongside
+    /// its v2 index, `pack-<hash>.idx`.
+    ///
+    /// Both files are fully written to temporary files before either is moved into
+    /// place, and the index is moved last since Git discovers packs by their index,
+    /// so readers will never see the pack without its index. The temporary files are
+    /// removed if either fails to write or be moved into place.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the packfile fails to encode, or [`Error::IoWithPath`] if the
+    /// files fail to write.
+    #[cfg_attr(feature = "tracing", tracing::instrument(skip(entries), err))]
+    pub fn write_pair(
+        pack_dir: &Path,
+        entries: &[PackFileEntry],
+    ) -> Result<PackFileWriteResult, Error> {
+        let mut pack = BytesMut::new();
+        let mut index_entries = Vec::with_capacity(entries.len());
+
+        let pack_hash =
+            PackFile::new(entries).encode_recording(&mut pack, |entry, offset, encoded| {
+                let mut crc = flate2::Crc::new();
+                crc.update(encoded);
+
+                index_entries.push(IndexEntry {
+                    hash: entry.hash()?,
+                    offset,
+                    crc32: crc.sum(),
+                });
+
+                Ok(())
+            })?;
+
+        let mut index = BytesMut::new();
+        index::encode_v2(&mut index_entries, &pack_hash, &mut index)?;
+
+        let name = hex::encode(pack_hash);
+        let tmp_pack = TempFile::write(pack_dir.join(format!("tmp_pack_{name}")), &pack)?;
+        let tmp_index = TempFile::write(pack_dir.join(format!("tmp_idx_{name}")), &index)?;
+
+        let pack_path = pack_dir.join(format!("pack-{name}.pack"));
+        let index_path = pack_dir.join(format!("pack-{name}.idx"));
+
+        tmp_pack.persist(&pack_path)?;
+        tmp_index.persist(&index_path)?;
+
+        Ok(PackFileWriteResult {
+            pack_hash,
+            pack_path,
+            index_path,
+        })
+    }
+}
+
+/// A file written ahead of being moved into place, which is removed when dropped
+/// unless it's been persisted.
+struct TempFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl TempFile {
+    /// Writes `data` to a new file at `path`, waiting for it to be flushed to disk.
+    /// Anything written is removed again if the write fails part way.
+    fn write(path: PathBuf, data: &[u8]) -> Result<Self, Error> {
+        let file = Self {
+            path,
+            persisted: false,
+        };
+
+        let write = || {
+            let mut out = File::create(&file.path)?;
+            out.write_all(data)?;
+            out.sync_all()
+        };
+        write().map_err(|e| io_error_at(&file.path, e))?;
+
+        Ok(file)
+    }
+
+    /// Moves the file to `path`, after which it's no longer removed on drop.
+    fn persist(mut self, path: &Path) -> Result<(), Error> {
+        std::fs::rename(&self.path, path).map_err(|e| io_error_at(path, e))?;
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            // there's no one to report a failure to, and the file may never have
+            // been created
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{high_level::GitRepository, writer::PackFileWriter};
+    use bytes::Bytes;
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    #[test]
+    fn write_pair() {
+        let mut repo = GitRepository::default();
+        repo.insert(&["a", "b"], "c.txt", Bytes::from("hello world!"))
+            .unwrap();
+        repo.insert(&[], "d.txt", Bytes::from(vec![0; 1024]))
+            .unwrap();
+        let (_hash, entries) = repo
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let result = PackFileWriter::write_pair(dir.path(), &entries).unwrap();
+
+        let name = hex::encode(result.pack_hash);
+        assert_eq!(
+            result.pack_path,
+            dir.path().join(format!("pack-{name}.pack"))
+        );
+        assert_eq!(
+            result.index_path,
+            dir.path().join(format!("pack-{name}.idx"))
+        );
+
+        // no temporary files should be left behind
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 2);
+
+        let out = Command::new("git")
+            .arg("verify-pack")
+            .arg(&result.index_path)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git verify-pack exited non-0");
+
+        // the index should be exactly what git would generate
+        let git_index_path = dir.path().join("git.idx");
+        let out = Command::new("git")
+            .arg("index-pack")
+            .arg("-o")
+            .arg(&git_index_path)
+            .arg(&result.pack_path)
+            .output()
+            .unwrap();
+        assert!(out.status.success(), "git index-pack exited non-0");
+        assert_eq!(String::from_utf8(out.stdout).unwrap().trim(), name);
+
+        assert_eq!(
+            std::fs::read(&result.index_path).unwrap(),
+            std::fs::read(&git_index_path).unwrap()
+        );
+    }
+
+    #[test]
+    fn write_pair_removes_temporary_files() {
+        let (_hash, entries) = GitRepository::default()
+            .commit("me", "me@example.com", "initial commit")
+            .unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let result = PackFileWriter::write_pair(dir.path(), &entries).unwrap();
+
+        // a directory in the way of the index fails the last rename
+        let dir = TempDir::new().unwrap();
+        let index_path = dir.path().join(result.index_path.file_name().unwrap());
+        std::fs::create_dir(&index_path).unwrap();
+        std::fs::write(index_path.join("file"), "").unwrap();
+
+        let err = PackFileWriter::write_pair(dir.path(), &entries).unwrap_err();
+        assert!(
+            matches!(&err, crate::Error::IoWithPath { path, .. } if *path == index_path),
+            "{err}"
+        );
+
+        let leftover: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("tmp_"))
+            .collect();
+        assert!(leftover.is_empty(), "{leftover:?}");
+    }
+
+    #[test]
+    fn write_pair_missing_dir() {
+        let dir = TempDir::new().unwrap();
+        let missing = dir.path().join("missing");
+
+        let err = PackFileWriter::write_pair(&missing, &[]).unwrap_err();
+
+        assert!(matches!(
+            &err,
+            crate::Error::IoWithPath { path, source }
+                if path.starts_with(&missing) && source.kind() == std::io::ErrorKind::NotFound
+        ));
+        assert!(
+            err.to_string().contains(&missing.display().to_string()),
+            "{err}"
+        );
+    }
+}